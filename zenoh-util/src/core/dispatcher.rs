@@ -15,13 +15,15 @@
 #[macro_export]
 macro_rules! dispatch_fn {
     () => {};
-    (async fn $fn_name:ident(&self $(, $($p_name:ident: $p_type:ty),*)? $(,)? ) $(-> $r_type:ty)?; $($tail:tt)*) => {
+    ($(#[$meta:meta])* async fn $fn_name:ident(&self $(, $($p_name:ident: $p_type:ty),*)? $(,)? ) $(-> $r_type:ty)?; $($tail:tt)*) => {
+        $(#[$meta])*
         pub(crate) async fn $fn_name(&self $(, $($p_name: $p_type),*)? ) $(-> $r_type)? {
             dispatch!(self {this => this.$fn_name($($($p_name),*)? ).await})
         }
         dispatch_fn!($($tail)*);
     };
-    (fn $fn_name:ident(&self $(, $($p_name:ident: $p_type:ty),*)? $(,)? ) $(-> $r_type:ty)?; $($tail:tt)*) => {
+    ($(#[$meta:meta])* fn $fn_name:ident(&self $(, $($p_name:ident: $p_type:ty),*)? $(,)? ) $(-> $r_type:ty)?; $($tail:tt)*) => {
+        $(#[$meta])*
         pub(crate) fn $fn_name(&self $(, $($p_name: $p_type),*)? ) $(-> $r_type)? {
             dispatch!(self {this => this.$fn_name($($($p_name),*)? )})
         }
@@ -49,6 +49,7 @@ fn criterion_benchmark(c: &mut Criterion) {
                     )),
                     kind: Some(0),
                     encoding: Some(0),
+                    ..DataInfo::default()
                 });
                 let payload = RBuf::from(vec![0; *s]);
 
@@ -104,6 +105,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         )),
         kind: Some(0),
         encoding: Some(0),
+        ..DataInfo::default()
     });
     let payload = RBuf::from(vec![0; 1024]);
     let msg = Arc::new(ZenohMessage::make_data(
@@ -0,0 +1,98 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+#[macro_use]
+extern crate criterion;
+use async_std::sync::Arc;
+use async_std::task;
+use criterion::{BenchmarkId, Criterion, Throughput};
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, Reliability, SubInfo, SubMode,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::protocol::session::DummyPrimitives;
+use zenoh::net::routing::pubsub::*;
+use zenoh::net::routing::resource::*;
+use zenoh::net::routing::router::Tables;
+use zenoh::net::routing::OutSession;
+
+async fn open_subscribers(
+    tables: &mut Tables,
+    key_expr: &str,
+    count: usize,
+    primitives: OutSession,
+) {
+    let sub_info = SubInfo {
+        reliability: Reliability::Reliable,
+        mode: SubMode::Push,
+        period: None,
+    };
+    for i in 0..count {
+        let mut id = [0u8; 16];
+        id[..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+        let mut face = tables
+            .open_face(PeerId::new(16, id), whatami::CLIENT, primitives.clone())
+            .await
+            .upgrade()
+            .unwrap();
+        declare_client_subscription(tables, &mut face, 0, key_expr, &sub_info)
+            .await
+            .unwrap();
+    }
+}
+
+fn route_data_bench(c: &mut Criterion) {
+    task::block_on(async {
+        let mut route_data_bench = c.benchmark_group("route_data_fanout");
+        for subscribers in [1, 8, 64, 256].iter() {
+            let mut tables = Tables::new(PeerId::new(0, [0; 16]), whatami::ROUTER, None);
+            let primitives = OutSession::Primitives(Arc::new(DummyPrimitives {}));
+            let mut face0 = tables
+                .open_face(PeerId::new(0, [0; 16]), whatami::CLIENT, primitives.clone())
+                .await
+                .upgrade()
+                .unwrap();
+            declare_resource(&mut tables, &mut face0, 1, 0, "/bench/fanout/data").await;
+            open_subscribers(&mut tables, "/bench/fanout/*", *subscribers, primitives).await;
+
+            for size in [8, 1024, 65536].iter() {
+                let payload = RBuf::from(vec![0u8; *size]);
+                route_data_bench.throughput(Throughput::Bytes((*size * *subscribers) as u64));
+                route_data_bench.bench_function(
+                    BenchmarkId::new(format!("{}_subscribers", subscribers), size),
+                    |b| {
+                        b.iter(|| {
+                            task::block_on(async {
+                                route_data(
+                                    &tables,
+                                    &face0,
+                                    1,
+                                    "",
+                                    CongestionControl::Drop,
+                                    None,
+                                    payload.clone(),
+                                    None,
+                                )
+                                .await;
+                            })
+                        })
+                    },
+                );
+            }
+        }
+        route_data_bench.finish();
+    });
+}
+
+criterion_group!(benches, route_data_bench);
+criterion_main!(benches);
@@ -87,7 +87,8 @@ fn tables_bench(c: &mut Criterion) {
                     "",
                     &sub_info,
                 )
-                .await;
+                .await
+                .unwrap();
             }
 
             let face0 = face0.upgrade().unwrap();
@@ -253,6 +253,20 @@ impl Default for CongestionControl {
     }
 }
 
+/// The transmission lane of a message: the control lane is served before the
+/// data one, which carries the bulk of the traffic.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Priority {
+    Control,
+    Data,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Data
+    }
+}
+
 impl FromStr for CongestionControl {
     type Err = ZError;
 
@@ -70,6 +70,10 @@ impl fmt::Debug for RBufPos {
     }
 }
 
+/// A buffer made of reference counted slices. Cloning it shares the bytes of
+/// its slices, which can't be mutated through it, and only copies the handles
+/// of the slices and its own read position: a clone costs around a hundred
+/// bytes with a single slice, whatever the size of the payload.
 #[derive(Clone, Default)]
 pub struct RBuf {
     zero: Option<ArcSlice>,
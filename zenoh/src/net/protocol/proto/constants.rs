@@ -19,6 +19,8 @@ pub mod data_kind {
     pub const PUT: ZInt = 0;
     pub const PATCH: ZInt = 1;
     pub const DELETE: ZInt = 2;
+    /// An empty sample telling an idle subscriber that its route is alive.
+    pub const KEEP_ALIVE: ZInt = 3;
 
     pub const DEFAULT: ZInt = PUT;
 
@@ -27,6 +29,7 @@ pub mod data_kind {
             0 => "PUT".to_string(),
             1 => "PATCH".to_string(),
             2 => "DELETE".to_string(),
+            3 => "KEEP_ALIVE".to_string(),
             i => i.to_string(),
         }
     }
@@ -12,7 +12,7 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 use super::core::*;
-use super::io::RBuf;
+use super::io::{RBuf, WBuf};
 use super::link::Locator;
 use std::fmt;
 
@@ -39,6 +39,7 @@ use std::fmt;
 ///
 /// ENC values:
 /// - 0x00 => Zenoh Properties
+/// - 0x20 => Routing path of a Data message: Num of PeerIds followed by the PeerIds
 /// ```
 #[derive(Debug, Clone, PartialEq)]
 pub struct Attachment {
@@ -50,6 +51,31 @@ impl Attachment {
     pub fn make(encoding: u8, buffer: RBuf) -> Attachment {
         Attachment { encoding, buffer }
     }
+
+    /// The attachment carrying the routing path of a Data message, see
+    /// [`DataInfo::routing_path`].
+    pub fn make_routing_path(path: &[PeerId]) -> Attachment {
+        let mut wbuf = WBuf::new(64, true);
+        wbuf.write_usize_as_zint(path.len());
+        for pid in path {
+            wbuf.write_peerid(pid);
+        }
+        Attachment::make(zmsg::attachment::ROUTING_PATH, RBuf::from(wbuf))
+    }
+
+    /// The routing path carried by this attachment, if it is one.
+    pub fn read_routing_path(&self) -> Option<Vec<PeerId>> {
+        if self.encoding != zmsg::attachment::ROUTING_PATH {
+            return None;
+        }
+        let mut buffer = self.buffer.clone();
+        let len = buffer.read_zint_as_usize()?;
+        let mut path = Vec::new();
+        for _ in 0..len {
+            path.push(buffer.read_peerid()?);
+        }
+        Some(path)
+    }
 }
 
 /// # ReplyContext decorator
@@ -139,6 +165,7 @@ mod imsg {
 
     pub(super) mod attachment {
         pub(crate) const PROPERTIES: u8 = 0x00;
+        pub(crate) const ROUTING_PATH: u8 = 0x20;
     }
 }
 
@@ -171,6 +198,7 @@ pub mod zmsg {
         use super::imsg;
 
         pub const PROPERTIES: u8 = imsg::attachment::PROPERTIES;
+        pub const ROUTING_PATH: u8 = imsg::attachment::ROUTING_PATH;
     }
 
     // Zenoh message flags
@@ -411,7 +439,7 @@ pub struct Declare {
 /// ~   encoding    ~ if G==1
 /// +---------------+
 /// ```
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct DataInfo {
     pub source_id: Option<PeerId>,
     pub source_sn: Option<ZInt>,
@@ -420,6 +448,20 @@ pub struct DataInfo {
     pub timestamp: Option<Timestamp>,
     pub kind: Option<ZInt>,
     pub encoding: Option<ZInt>,
+    /// The routers the data went through, in order, when they record it.
+    /// Not part of the encoding above: it is carried in a routing path
+    /// [`Attachment`] of the Data message, which the nodes that don't know
+    /// it ignore.
+    pub routing_path: Option<Vec<PeerId>>,
+}
+
+impl DataInfo {
+    /// A `DataInfo` with none of its fields set, like `DataInfo::default()`.
+    /// Complete the struct literals with `..DataInfo::default()` so that they
+    /// keep building when fields are added.
+    pub fn new() -> DataInfo {
+        DataInfo::default()
+    }
 }
 
 impl PartialOrd for DataInfo {
@@ -468,6 +468,7 @@ impl RBuf {
             timestamp,
             kind,
             encoding,
+            ..DataInfo::default()
         })
     }
 
@@ -19,7 +19,7 @@ mod primitives;
 mod transport;
 
 use super::core;
-use super::core::{PeerId, WhatAmI, ZInt};
+use super::core::{PeerId, Priority, WhatAmI, ZInt};
 use super::io;
 use super::link;
 use super::link::Link;
@@ -202,6 +202,23 @@ impl Session {
         transport.schedule(message).await;
         Ok(())
     }
+
+    /// Schedule a message on the transmission queue of the given priority.
+    #[inline(always)]
+    pub async fn schedule_with_priority(
+        &self,
+        message: ZenohMessage,
+        priority: Priority,
+    ) -> ZResult<()> {
+        log::trace!("{:?}. Schedule ({:?}): {:?}", self, priority, message);
+        let transport = zweak!(self.0, STR_ERR);
+        let queue = match priority {
+            Priority::Control => defaults::QUEUE_PRIO_CTRL,
+            Priority::Data => defaults::QUEUE_PRIO_DATA,
+        };
+        transport.schedule_with_priority(message, queue).await;
+        Ok(())
+    }
 }
 
 impl Eq for Session {}
@@ -75,10 +75,17 @@ impl DeMux {
 
             ZenohBody::Data(Data {
                 key,
-                data_info,
+                mut data_info,
                 payload,
             }) => match msg.reply_context {
                 None => {
+                    let path = msg
+                        .attachment
+                        .as_ref()
+                        .and_then(|attachment| attachment.read_routing_path());
+                    if let (Some(info), Some(path)) = (data_info.as_mut(), path) {
+                        info.routing_path = Some(path);
+                    }
                     self.primitives
                         .send_data(
                             &key,
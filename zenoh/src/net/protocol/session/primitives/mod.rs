@@ -21,7 +21,8 @@ use super::proto;
 use super::session;
 
 use super::core::{
-    CongestionControl, PeerId, QueryConsolidation, QueryTarget, Reliability, ResKey, SubInfo, ZInt,
+    CongestionControl, PeerId, Priority, QueryConsolidation, QueryTarget, Reliability, ResKey,
+    SubInfo, ZInt,
 };
 use super::io::RBuf;
 use super::proto::{DataInfo, RoutingContext};
@@ -29,6 +30,35 @@ use async_trait::async_trait;
 pub use demux::*;
 pub use mux::*;
 
+/// A set of data samples delivered at once.
+pub type DataBatch = Vec<(ResKey, RBuf, Option<DataInfo>)>;
+
+/// A set of subscribers declared at once.
+pub type SubscriberBatch = Vec<(ResKey, SubInfo)>;
+
+/// The outcome of the sending of a data message, as reported by the
+/// transport.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryAck {
+    /// Handed over to the transport, which doesn't acknowledge it.
+    Sent,
+    /// Acknowledged by the transport. Never reported by `Mux`, whose
+    /// transport doesn't acknowledge the data.
+    Acked,
+    /// Not delivered.
+    Failed,
+}
+
+/// The reason why the router removed a subscription on its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForcedUndeclare {
+    /// The subscription was not declared again within its lease.
+    LeaseExpired,
+    /// The subscription was revoked, e.g. because the face is no longer
+    /// authorized to subscribe.
+    Revoked,
+}
+
 #[async_trait]
 pub trait Primitives {
     async fn decl_resource(&self, rid: ZInt, reskey: &ResKey);
@@ -45,6 +75,26 @@ pub trait Primitives {
     );
     async fn forget_subscriber(&self, reskey: &ResKey, routing_context: Option<RoutingContext>);
 
+    /// Notify that the router removed the subscription of this face on its
+    /// own, unlike `forget_subscriber` which is sent when the subscription is
+    /// undeclared by the face. Implementations that can't be notified ignore
+    /// it.
+    async fn forced_forget_subscriber(&self, _reskey: &ResKey, _reason: ForcedUndeclare) {}
+
+    /// Declare several subscribers in a single frame. Implementations that
+    /// don't support batching keep this default, one `decl_subscriber` per
+    /// subscriber.
+    async fn decl_subscriber_batch(
+        &self,
+        batch: SubscriberBatch,
+        routing_context: Option<RoutingContext>,
+    ) {
+        for (reskey, sub_info) in batch {
+            self.decl_subscriber(&reskey, &sub_info, routing_context)
+                .await;
+        }
+    }
+
     async fn decl_queryable(&self, reskey: &ResKey, routing_context: Option<RoutingContext>);
     async fn forget_queryable(&self, reskey: &ResKey, routing_context: Option<RoutingContext>);
 
@@ -58,6 +108,78 @@ pub trait Primitives {
         routing_context: Option<RoutingContext>,
     );
 
+    /// Send data on the transmission lane of the given priority.
+    /// Implementations without lanes fall back to `send_data`.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_data_with_priority(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        _priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) {
+        self.send_data(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            data_info,
+            routing_context,
+        )
+        .await
+    }
+
+    /// Send data on the transmission lane of the given priority, reporting
+    /// whether the transport acknowledged it. Implementations that can't tell
+    /// fall back to `send_data_with_priority` and report `DeliveryAck::Sent`.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_data_acked(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) -> DeliveryAck {
+        self.send_data_with_priority(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            priority,
+            data_info,
+            routing_context,
+        )
+        .await;
+        DeliveryAck::Sent
+    }
+
+    /// Send several data samples in a single delivery. Implementations that
+    /// don't support batching keep this default, one `send_data` per sample.
+    async fn send_data_batch(
+        &self,
+        batch: DataBatch,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+    ) {
+        for (reskey, payload, data_info) in batch {
+            self.send_data(
+                &reskey,
+                payload,
+                reliability,
+                congestion_control,
+                data_info,
+                None,
+            )
+            .await;
+        }
+    }
+
     async fn send_query(
         &self,
         reskey: &ResKey,
@@ -11,11 +11,23 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-use super::core::{CongestionControl, PeerId, Reliability, ResKey, ZInt};
+use super::core::{CongestionControl, PeerId, Priority, Reliability, ResKey, ZInt};
 use super::core::{QueryConsolidation, QueryTarget, SubInfo};
 use super::io::RBuf;
-use super::proto::{zmsg, DataInfo, Declaration, ReplyContext, RoutingContext, ZenohMessage};
+use super::proto::{
+    zmsg, Attachment, DataInfo, Declaration, ReplyContext, RoutingContext, ZenohMessage,
+};
 use super::session::Session;
+use super::{DeliveryAck, ForcedUndeclare};
+
+/// The routing path of the data is carried in an attachment, see
+/// [`DataInfo::routing_path`].
+fn routing_path_attachment(data_info: &Option<DataInfo>) -> Option<Attachment> {
+    data_info
+        .as_ref()
+        .and_then(|info| info.routing_path.as_ref())
+        .map(|path| Attachment::make_routing_path(path))
+}
 
 pub struct Mux {
     handler: Session,
@@ -77,6 +89,27 @@ impl Mux {
             .await;
     }
 
+    /// The protocol has no dedicated message, so the remote face is sent the
+    /// undeclaration of its subscription.
+    pub(crate) async fn forced_forget_subscriber(&self, reskey: &ResKey, reason: ForcedUndeclare) {
+        log::debug!("Subscriber {:?} forcibly forgotten: {:?}", reskey, reason);
+        self.forget_subscriber(reskey, None).await;
+    }
+
+    pub(crate) async fn decl_subscriber_batch(
+        &self,
+        batch: super::SubscriberBatch,
+        routing_context: Option<RoutingContext>,
+    ) {
+        let decls = batch
+            .into_iter()
+            .map(|(key, info)| Declaration::Subscriber { key, info })
+            .collect();
+        self.handler
+            .handle_message(ZenohMessage::make_declare(decls, routing_context, None))
+            .await;
+    }
+
     pub(crate) async fn decl_publisher(
         &self,
         reskey: &ResKey,
@@ -142,6 +175,7 @@ impl Mux {
         data_info: Option<DataInfo>,
         routing_context: Option<RoutingContext>,
     ) {
+        let attachment = routing_path_attachment(&data_info);
         self.handler
             .handle_message(ZenohMessage::make_data(
                 reskey.clone(),
@@ -151,11 +185,77 @@ impl Mux {
                 data_info,
                 routing_context,
                 None,
-                None,
+                attachment,
             ))
             .await;
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_data_with_priority(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) {
+        let attachment = routing_path_attachment(&data_info);
+        self.handler
+            .schedule_with_priority(
+                ZenohMessage::make_data(
+                    reskey.clone(),
+                    payload,
+                    reliability,
+                    congestion_control,
+                    data_info,
+                    routing_context,
+                    None,
+                    attachment,
+                ),
+                priority,
+            )
+            .await;
+    }
+
+    /// The transport doesn't acknowledge the data: this reports
+    /// `DeliveryAck::Sent` for a scheduled message and `DeliveryAck::Failed`
+    /// for a message that could not be scheduled, never `DeliveryAck::Acked`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_data_acked(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) -> DeliveryAck {
+        let attachment = routing_path_attachment(&data_info);
+        match self
+            .handler
+            .schedule_with_priority(
+                ZenohMessage::make_data(
+                    reskey.clone(),
+                    payload,
+                    reliability,
+                    congestion_control,
+                    data_info,
+                    routing_context,
+                    None,
+                    attachment,
+                ),
+                priority,
+            )
+            .await
+        {
+            Ok(()) => DeliveryAck::Sent,
+            Err(_) => DeliveryAck::Failed,
+        }
+    }
+
     pub(crate) async fn send_query(
         &self,
         reskey: &ResKey,
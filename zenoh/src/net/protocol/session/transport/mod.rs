@@ -267,19 +267,24 @@ impl SessionTransport {
     /*        SCHEDULE AND SEND TX       */
     /*************************************/
     /// Schedule a Zenoh message on the transmission queue    
+    pub(crate) async fn schedule(&self, message: ZenohMessage) {
+        self.schedule_with_priority(message, QUEUE_PRIO_DATA).await;
+    }
+
+    /// Schedule a Zenoh message on the transmission queue of the given priority
     #[cfg(feature = "zero-copy")]
-    pub(crate) async fn schedule(&self, mut message: ZenohMessage) {
+    pub(crate) async fn schedule_with_priority(&self, mut message: ZenohMessage, priority: usize) {
         if self.is_shm {
             message.inc_ref_shm();
         } else {
             message.flatten_shm();
         }
-        self.schedule_first_fit(message).await;
+        self.schedule_first_fit(message, priority).await;
     }
 
     #[cfg(not(feature = "zero-copy"))]
-    pub(crate) async fn schedule(&self, message: ZenohMessage) {
-        self.schedule_first_fit(message).await;
+    pub(crate) async fn schedule_with_priority(&self, message: ZenohMessage, priority: usize) {
+        self.schedule_first_fit(message, priority).await;
     }
 
     /*************************************/
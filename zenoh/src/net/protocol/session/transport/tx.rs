@@ -12,26 +12,25 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 use super::proto::ZenohMessage;
-use super::session::defaults::QUEUE_PRIO_DATA;
 use super::SessionTransport;
 use zenoh_util::zasyncread;
 
 impl SessionTransport {
     #[inline(always)]
-    pub(super) async fn schedule_first_fit(&self, msg: ZenohMessage) {
+    pub(super) async fn schedule_first_fit(&self, msg: ZenohMessage, priority: usize) {
         let guard = zasyncread!(self.links);
         for cl in guard.iter() {
             let link = cl.get_link();
             if msg.is_reliable() && link.is_reliable() {
-                cl.schedule_zenoh_message(msg, QUEUE_PRIO_DATA).await;
+                cl.schedule_zenoh_message(msg, priority).await;
                 return;
             } else if !msg.is_reliable() && !link.is_reliable() {
-                cl.schedule_zenoh_message(msg, QUEUE_PRIO_DATA).await;
+                cl.schedule_zenoh_message(msg, priority).await;
                 return;
             }
         }
         match guard.get(0) {
-            Some(cl) => cl.schedule_zenoh_message(msg, QUEUE_PRIO_DATA).await,
+            Some(cl) => cl.schedule_zenoh_message(msg, priority).await,
             None => log::trace!("Message dropped because the session has no links: {}", msg),
         }
     }
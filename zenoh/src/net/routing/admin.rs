@@ -0,0 +1,180 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use async_std::sync::Arc;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use super::protocol::core::{queryable, rname, whatami, PeerId, ResKey, ZInt};
+use super::protocol::io::RBuf;
+use super::protocol::proto::{encoding, DataInfo};
+
+use super::face::FaceState;
+use super::resource::Resource;
+use super::router::Tables;
+use super::snapshot::export_subscriptions;
+
+/// The parts of the tables served to the admin queries, below
+/// `/@/router/<pid>/`.
+const ADMIN_TOPICS: [&str; 3] = ["subscriptions", "faces", "trees"];
+
+/// The faces allowed to query the routing tables through the reserved
+/// `/@/router/<pid>/subscriptions`, `/@/router/<pid>/faces` and
+/// `/@/router/<pid>/trees` keys (see `Tables::set_admin_access`). The queries
+/// of the other faces on these keys get no reply. When disabled, these keys
+/// are routed as any other.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminAccess {
+    /// The admin queries are routed as any other. This is the default.
+    Disabled,
+    /// Only the faces with one of these peer ids are served.
+    Allowed(Vec<PeerId>),
+    /// All the faces are served.
+    All,
+}
+
+impl Default for AdminAccess {
+    fn default() -> Self {
+        AdminAccess::Disabled
+    }
+}
+
+/// The root of the keys of the routing tables of the given router.
+pub fn admin_root(pid: &PeerId) -> String {
+    format!("/@/router/{}", pid)
+}
+
+fn hex_pid(pid: &PeerId) -> String {
+    hex::encode_upper(pid.as_slice())
+}
+
+/// The samples of a part of the tables, as key and JSON value: the
+/// subscriptions grouped by key expression, the faces by id and the trees of
+/// the routers and peers networks by root.
+fn admin_samples(tables: &Tables, root: &str, topic: &str) -> Vec<(String, Value)> {
+    match topic {
+        "subscriptions" => {
+            let mut records: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+            for record in &export_subscriptions(tables).subscriptions {
+                records
+                    .entry(record.key_expr.clone())
+                    .or_default()
+                    .push(record.to_json());
+            }
+            records
+                .into_iter()
+                .map(|(key_expr, records)| {
+                    (
+                        [root, "/subscriptions", &key_expr].concat(),
+                        Value::Array(records),
+                    )
+                })
+                .collect()
+        }
+        "faces" => {
+            let mut faces: Vec<&Arc<FaceState>> = tables.faces.values().collect();
+            faces.sort_by_key(|face| face.id);
+            faces
+                .into_iter()
+                .map(|face| {
+                    let subscriptions: Vec<String> = tables
+                        .face_subscriptions(face.id)
+                        .into_iter()
+                        .map(|(key_expr, _)| key_expr)
+                        .collect();
+                    (
+                        format!("{}/faces/{}", root, face.id),
+                        json!({
+                            "pid": hex_pid(&face.pid),
+                            "whatami": whatami::to_string(face.whatami),
+                            "subscriptions": subscriptions,
+                        }),
+                    )
+                })
+                .collect()
+        }
+        _ => {
+            let mut samples = vec![];
+            for (net, net_type) in &[("routers", whatami::ROUTER), ("peers", whatami::PEER)] {
+                for tree in tables.routing_trees(*net_type) {
+                    let childs: Vec<String> = tree.childs.iter().map(hex_pid).collect();
+                    samples.push((
+                        format!("{}/trees/{}/{}", root, net, hex_pid(&tree.root)),
+                        json!({
+                            "parent": tree.parent.as_ref().map(hex_pid),
+                            "childs": childs,
+                        }),
+                    ));
+                }
+            }
+            samples
+        }
+    }
+}
+
+/// Reply to a query on the routing tables of this router from the tables
+/// themselves, one JSON sample per matching key. Return false, leaving the
+/// query to be routed, if the admin queries are disabled or if it doesn't
+/// target them.
+pub(crate) async fn reply_admin_query(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    prefix: &Resource,
+    suffix: &str,
+    qid: ZInt,
+) -> bool {
+    if tables.admin_access == AdminAccess::Disabled {
+        return false;
+    }
+    let name = [&prefix.name(), suffix].concat();
+    let root = admin_root(&tables.pid);
+    let topic = match name
+        .strip_prefix(root.as_str())
+        .and_then(|name| name.strip_prefix('/'))
+        .and_then(|name| name.split('/').next())
+    {
+        Some(topic) if ADMIN_TOPICS.contains(&topic) => topic,
+        _ => return false,
+    };
+    let allowed = match &tables.admin_access {
+        AdminAccess::Allowed(pids) => pids.contains(&face.pid),
+        _ => true,
+    };
+    if allowed {
+        log::debug!("Reply admin query {}:{} on {}", face, qid, name);
+        for (key, value) in admin_samples(tables, &root, topic) {
+            if !rname::intersect(&name, &key) {
+                continue;
+            }
+            let info = DataInfo {
+                encoding: Some(encoding::APP_JSON),
+                ..DataInfo::default()
+            };
+            face.primitives
+                .clone()
+                .send_reply_data(
+                    qid,
+                    queryable::EVAL,
+                    tables.pid.clone(),
+                    ResKey::RName(key),
+                    Some(info),
+                    RBuf::from(value.to_string().as_bytes()),
+                )
+                .await;
+        }
+    } else {
+        log::warn!("Deny admin query {}:{} on {}", face, qid, name);
+    }
+    face.primitives.clone().send_reply_final(qid).await;
+    true
+}
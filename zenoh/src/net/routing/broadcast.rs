@@ -0,0 +1,124 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use futures::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::metrics::RoutingMetrics;
+
+struct ChannelState<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    waker: Option<Waker>,
+    sender_dropped: bool,
+}
+
+/// The sending side of a lagging channel, see [`lagging_channel`].
+pub(crate) struct LaggingSender<T> {
+    state: Arc<Mutex<ChannelState<T>>>,
+}
+
+/// The receiving side of a lagging channel, see [`lagging_channel`]. The
+/// stream ends once the sender is dropped and the buffered events consumed.
+pub(crate) struct LaggingReceiver<T> {
+    state: Arc<Mutex<ChannelState<T>>>,
+}
+
+/// A channel buffering at most `capacity` events, whose sender never waits:
+/// when the buffer is full, the oldest event is dropped to make room for the
+/// new one, so that a lagging receiver always gets the latest events.
+pub(crate) fn lagging_channel<T>(capacity: usize) -> (LaggingSender<T>, LaggingReceiver<T>) {
+    let state = Arc::new(Mutex::new(ChannelState {
+        buffer: VecDeque::new(),
+        capacity: capacity.max(1),
+        waker: None,
+        sender_dropped: false,
+    }));
+    (
+        LaggingSender {
+            state: state.clone(),
+        },
+        LaggingReceiver { state },
+    )
+}
+
+impl<T> LaggingSender<T> {
+    /// Whether the receiver was dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.state) == 1
+    }
+
+    /// Buffer the event, returning true if the oldest buffered event was
+    /// dropped for it.
+    pub(crate) fn send(&self, event: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let dropped = state.buffer.len() >= state.capacity;
+        if dropped {
+            state.buffer.pop_front();
+        }
+        state.buffer.push_back(event);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        dropped
+    }
+}
+
+impl<T> Drop for LaggingSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.sender_dropped = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Stream for LaggingReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.buffer.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if state.sender_dropped => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Send the event to all the listeners without waiting, forgetting the closed
+/// ones. The events a lagging listener loses are counted in
+/// [`RoutingMetrics::sub_events_dropped`].
+pub(crate) fn broadcast<T: Clone>(
+    listeners: &mut Vec<LaggingSender<T>>,
+    event: T,
+    metrics: &RoutingMetrics,
+) {
+    listeners.retain(|listener| {
+        if listener.is_closed() {
+            return false;
+        }
+        if listener.send(event.clone()) {
+            metrics.sub_events_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    });
+}
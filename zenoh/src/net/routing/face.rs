@@ -11,20 +11,177 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-use async_std::sync::{Arc, RwLock};
+use async_std::channel::{bounded, Sender};
+use async_std::future::timeout;
+use async_std::sync::{Arc, RwLock, Weak};
 use std::collections::HashMap;
 use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zenoh_util::sync::get_mut_unchecked;
 use zenoh_util::zasyncwrite;
+use zenoh_util::zconfigurable;
 
+use super::fair_queue::FairScheduler;
+use super::metrics::RoutingMetrics;
 use super::protocol::core::{
-    whatami, CongestionControl, PeerId, QueryConsolidation, QueryTarget, Reliability, ResKey,
-    SubInfo, WhatAmI, ZInt,
+    rname, whatami, CongestionControl, PeerId, Priority, QueryConsolidation, QueryTarget,
+    Reliability, ResKey, SubInfo, WhatAmI, ZInt,
 };
 use super::protocol::io::RBuf;
 use super::protocol::proto::{DataInfo, RoutingContext};
+use super::protocol::session::DeliveryAck;
 use super::router::*;
 use super::OutSession;
 
+zconfigurable! {
+    static ref SEND_QUEUE_CAPACITY: usize = 1024;
+}
+
+/// A data message waiting in the send queue of a face.
+pub(super) struct DataDelivery {
+    pub(super) reskey: ResKey,
+    pub(super) payload: RBuf,
+    pub(super) reliability: Reliability,
+    pub(super) congestion_control: CongestionControl,
+    pub(super) priority: Priority,
+    pub(super) data_info: Option<DataInfo>,
+    pub(super) routing_context: Option<RoutingContext>,
+    /// The tracking of the deliveries of the subscription tracking the
+    /// acknowledgments or the delivery errors through which the data are
+    /// delivered, if any.
+    pub(super) tracked: Option<DeliveryTracking>,
+}
+
+impl DataDelivery {
+    /// Send the data to the given face, see [`FaceState::send_in_time`],
+    /// recording whether the transport acknowledged them in the delivery
+    /// tracking, if any. Nothing is sent to a quarantined face.
+    pub(super) async fn send(mut self, face: &FaceState, metrics: &RoutingMetrics) {
+        if face.quarantined.load(Ordering::Relaxed) {
+            self.fail(DeliveryError::Dropped);
+            return;
+        }
+        let tracked = self.tracked.take();
+        let reskey = self.reskey.clone();
+        let acked = tracked.is_some();
+        let primitives = face.primitives.clone();
+        let ack = face
+            .send_in_time(
+                metrics,
+                async move { self.transmit(&primitives, acked).await },
+            )
+            .await;
+        if let Some(tracked) = tracked {
+            match ack {
+                Some(ack) => tracked.record(&reskey, ack),
+                None => tracked.fail(&reskey, DeliveryError::TimedOut),
+            }
+        }
+    }
+
+    async fn transmit(self, primitives: &OutSession, acked: bool) -> DeliveryAck {
+        if acked {
+            primitives
+                .send_data_acked(
+                    &self.reskey,
+                    self.payload,
+                    self.reliability,
+                    self.congestion_control,
+                    self.priority,
+                    self.data_info,
+                    self.routing_context,
+                )
+                .await
+        } else {
+            primitives
+                .send_data_with_priority(
+                    &self.reskey,
+                    self.payload,
+                    self.reliability,
+                    self.congestion_control,
+                    self.priority,
+                    self.data_info,
+                    self.routing_context,
+                )
+                .await;
+            DeliveryAck::Sent
+        }
+    }
+
+    /// Record in the delivery tracking, if any, that the data were not sent.
+    pub(super) fn fail(self, error: DeliveryError) {
+        if let Some(tracked) = self.tracked {
+            tracked.fail(&self.reskey, error);
+        }
+    }
+}
+
+/// The data routed to a paused face. When buffering, only the latest data
+/// for each key is kept.
+pub(super) struct PausedDeliveries {
+    pub(super) buffer: Option<std::sync::Mutex<BufferedDeliveries>>,
+}
+
+/// The latest data buffered for each key of a paused face, replayed in the
+/// order it was routed.
+#[derive(Default)]
+pub(super) struct BufferedDeliveries {
+    counter: u64,
+    latest: HashMap<ResKey, (u64, DataDelivery)>,
+}
+
+impl BufferedDeliveries {
+    /// Buffer the given data, returning the data it replaces, if any.
+    pub(super) fn push(&mut self, delivery: DataDelivery) -> Option<DataDelivery> {
+        self.counter += 1;
+        self.latest
+            .insert(delivery.reskey.clone(), (self.counter, delivery))
+            .map(|(_, replaced)| replaced)
+    }
+
+    /// The buffered data, in the order it was routed.
+    pub(super) fn into_ordered(self) -> Vec<DataDelivery> {
+        let mut deliveries: Vec<(u64, DataDelivery)> =
+            self.latest.into_iter().map(|(_, d)| d).collect();
+        deliveries.sort_by_key(|(order, _)| *order);
+        deliveries
+            .into_iter()
+            .map(|(_, delivery)| delivery)
+            .collect()
+    }
+}
+
+/// The keep-alive of an idle subscription, see `SubOptions::heartbeat`.
+pub(super) struct SubHeartbeat {
+    pub(super) res: Weak<Resource>,
+    pub(super) name: String,
+    pub(super) interval: Duration,
+    pub(super) last_activity: Instant,
+}
+
+/// The representation of the keys sent to a face, see
+/// `Tables::set_face_key_repr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyRepr {
+    /// A numeric id with a suffix when the face has a mapping for a prefix of
+    /// the key, the full name otherwise.
+    Auto,
+    /// Like `Auto`, also declaring to the face a numeric id for each of its
+    /// subscriptions so that the matching data are sent with it.
+    Numeric,
+    /// Always the full name.
+    Name,
+}
+
+impl Default for KeyRepr {
+    fn default() -> KeyRepr {
+        KeyRepr::Auto
+    }
+}
+
 pub struct FaceState {
     pub(super) id: usize,
     pub(super) pid: PeerId,
@@ -35,10 +192,25 @@ pub struct FaceState {
     pub(super) remote_mappings: HashMap<ZInt, Arc<Resource>>,
     pub(super) local_subs: Vec<Arc<Resource>>,
     pub(super) remote_subs: Vec<Arc<Resource>>,
+    pub(super) sub_aliases: HashMap<ZInt, Vec<Arc<Resource>>>,
+    pub(super) sub_leases: Vec<(Arc<Resource>, Instant)>,
+    pub(super) next_alias: ZInt,
     pub(super) local_qabls: Vec<Arc<Resource>>,
     pub(super) remote_qabls: Vec<Arc<Resource>>,
     pub(super) next_qid: ZInt,
     pub(super) pending_queries: HashMap<ZInt, Arc<Query>>,
+    pub(super) max_payload_size: Option<usize>,
+    pub(super) send_queue: Option<Sender<DataDelivery>>,
+    pub(super) link_class: Option<String>,
+    pub(super) tenant_id: Option<String>,
+    pub(super) paused: Option<PausedDeliveries>,
+    pub(super) prefetched: Vec<Arc<Resource>>,
+    pub(super) send_timeout: Option<Duration>,
+    pub(super) quarantine_on_timeout: bool,
+    pub(super) quarantined: AtomicBool,
+    pub(super) unrouted_samples: AtomicUsize,
+    pub(super) key_repr: KeyRepr,
+    pub(super) heartbeats: Mutex<Vec<SubHeartbeat>>,
 }
 
 impl FaceState {
@@ -59,13 +231,156 @@ impl FaceState {
             remote_mappings: HashMap::new(),
             local_subs: Vec::new(),
             remote_subs: Vec::new(),
+            sub_aliases: HashMap::new(),
+            sub_leases: Vec::new(),
+            next_alias: 0,
             local_qabls: Vec::new(),
             remote_qabls: Vec::new(),
             next_qid: 0,
             pending_queries: HashMap::new(),
+            max_payload_size: None,
+            send_queue: None,
+            link_class: None,
+            tenant_id: None,
+            paused: None,
+            prefetched: Vec::new(),
+            send_timeout: None,
+            quarantine_on_timeout: false,
+            quarantined: AtomicBool::new(false),
+            unrouted_samples: AtomicUsize::new(0),
+            key_repr: KeyRepr::default(),
+            heartbeats: Mutex::new(Vec::new()),
         })
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Start a task delivering the data sent to the face one after the
+    /// other, in the order they were queued. Deliveries to different faces
+    /// then proceed concurrently while each face still observes the
+    /// publication order. With a fair scheduler, each delivery first waits
+    /// for an opportunity of its priority class. The queue holds at most
+    /// `SEND_QUEUE_CAPACITY` data, see `send_delivery` for a full queue.
+    pub(super) fn start_send_queue(
+        face: &mut Arc<FaceState>,
+        scheduler: Option<Arc<FairScheduler>>,
+        metrics: Arc<RoutingMetrics>,
+    ) {
+        if face.send_queue.is_none() {
+            let (sender, receiver) = bounded::<DataDelivery>(*SEND_QUEUE_CAPACITY);
+            let weak = Arc::downgrade(face);
+            async_std::task::spawn(async move {
+                while let Ok(delivery) = receiver.recv().await {
+                    let face = match weak.upgrade() {
+                        Some(face) => face,
+                        None => break,
+                    };
+                    let _permit = match &scheduler {
+                        Some(scheduler) => Some(scheduler.acquire(delivery.priority).await),
+                        None => None,
+                    };
+                    delivery.send(&face, &metrics).await;
+                }
+            });
+            get_mut_unchecked(face).send_queue = Some(sender);
+        }
+    }
+
+    /// Run a send to this face, returning its output if it completes within
+    /// the send timeout of the face, if any. Otherwise the timeout is
+    /// counted and the face quarantined if requested, while the send
+    /// completes in the background: a message partly written to a transport
+    /// is never abandoned.
+    pub(super) async fn send_in_time<F>(
+        &self,
+        metrics: &RoutingMetrics,
+        send: F,
+    ) -> Option<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let send_timeout = match self.send_timeout {
+            Some(send_timeout) => send_timeout,
+            None => return Some(send.await),
+        };
+        let mut send = Box::pin(send);
+        match timeout(send_timeout, &mut send).await {
+            Ok(output) => Some(output),
+            Err(_) => {
+                metrics.send_timeouts.fetch_add(1, Ordering::Relaxed);
+                if self.quarantine_on_timeout {
+                    log::warn!("Send to {} timed out, quarantine it", self);
+                    self.quarantined.store(true, Ordering::Relaxed);
+                } else {
+                    log::warn!("Send to {} timed out", self);
+                }
+                async_std::task::spawn(send);
+                None
+            }
+        }
+    }
+
+    /// Stop the send queue task once the already queued data are delivered.
+    pub(super) fn stop_send_queue(&mut self) {
+        self.send_queue = None;
+    }
+
+    /// Reset the heartbeats of the subscriptions matching a key delivered to
+    /// this face.
+    pub(super) fn reset_heartbeats(&self, reskey: &ResKey) {
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        if heartbeats.is_empty() {
+            return;
+        }
+        let name = match reskey {
+            ResKey::RName(name) => name.clone(),
+            ResKey::RId(rid) => match self.get_mapping(rid) {
+                Some(res) => res.name(),
+                None => return,
+            },
+            ResKey::RIdWithSuffix(rid, suffix) => match self.get_mapping(rid) {
+                Some(res) => [&res.name(), suffix.as_str()].concat(),
+                None => return,
+            },
+        };
+        let now = Instant::now();
+        for heartbeat in heartbeats.iter_mut() {
+            if rname::intersect(&heartbeat.name, &name) {
+                heartbeat.last_activity = now;
+            }
+        }
+    }
+
+    /// Return the resources of the subscriptions whose heartbeat is due,
+    /// which are then considered active again.
+    pub(super) fn take_due_heartbeats(&self, now: Instant) -> Vec<Arc<Resource>> {
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        heartbeats.retain(|heartbeat| heartbeat.res.strong_count() > 0);
+        let mut due = vec![];
+        for heartbeat in heartbeats.iter_mut() {
+            if heartbeat.last_activity + heartbeat.interval <= now {
+                if let Some(res) = heartbeat.res.upgrade() {
+                    due.push(res);
+                }
+                heartbeat.last_activity = now;
+            }
+        }
+        due
+    }
+
+    /// The time at which the next heartbeat of this face is due, if any.
+    pub(super) fn next_heartbeat(&self) -> Option<Instant> {
+        self.heartbeats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|heartbeat| heartbeat.last_activity + heartbeat.interval)
+            .min()
+    }
+
     #[inline]
     #[allow(clippy::trivially_copy_pass_by_ref)]
     pub(super) fn get_mapping(&self, prefixid: &ZInt) -> Option<&std::sync::Arc<Resource>> {
@@ -135,7 +450,8 @@ impl Face {
                         }
                     };
 
-                    declare_router_subscription(
+                    // The rejected declarations are already logged
+                    let _ = declare_router_subscription(
                         &mut tables,
                         &mut self.state.clone(),
                         prefixid,
@@ -143,7 +459,7 @@ impl Face {
                         sub_info,
                         router,
                     )
-                    .await
+                    .await;
                 }
 
                 None => {
@@ -172,7 +488,8 @@ impl Face {
                         }
                     };
 
-                    declare_peer_subscription(
+                    // The rejected declarations are already logged
+                    let _ = declare_peer_subscription(
                         &mut tables,
                         &mut self.state.clone(),
                         prefixid,
@@ -180,7 +497,7 @@ impl Face {
                         sub_info,
                         peer,
                     )
-                    .await
+                    .await;
                 }
 
                 None => {
@@ -189,14 +506,16 @@ impl Face {
                 }
             },
             _ => {
-                declare_client_subscription(
+                // The rejected declarations are already logged
+                let _ = declare_client_subscription(
                     &mut tables,
                     &mut self.state.clone(),
                     prefixid,
                     suffix,
                     sub_info,
                 )
-                .await
+                .await;
+                tables.schedule_flap_release(self.tables.clone());
             }
         }
     }
@@ -281,7 +600,8 @@ impl Face {
             },
             _ => {
                 forget_client_subscription(&mut tables, &mut self.state.clone(), prefixid, suffix)
-                    .await
+                    .await;
+                tables.schedule_flap_release(self.tables.clone());
             }
         }
     }
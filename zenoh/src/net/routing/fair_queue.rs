@@ -0,0 +1,123 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use async_std::channel::{bounded, Sender};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use super::protocol::core::Priority;
+
+/// The weighted fair queuing of the deliveries of the parallel fan-out across
+/// the priority classes: while both classes have pending deliveries, they get
+/// delivery opportunities in proportion to their weights, so that the `Data`
+/// class still makes progress under a sustained load of `Control` data. A
+/// weight of 0 is handled as 1.
+#[derive(Debug, Clone)]
+pub struct FairQueuing {
+    pub control_weight: usize,
+    pub data_weight: usize,
+    /// The number of deliveries in progress at once across all the faces.
+    pub max_in_flight: usize,
+}
+
+#[inline]
+fn class(priority: Priority) -> usize {
+    match priority {
+        Priority::Control => 0,
+        Priority::Data => 1,
+    }
+}
+
+struct SchedulerState {
+    available: usize,
+    waiters: [VecDeque<Sender<()>>; 2],
+    current: usize,
+    credits: usize,
+}
+
+/// A deficit round robin scheduler of the delivery opportunities, shared by
+/// the send queues of the faces.
+pub(crate) struct FairScheduler {
+    weights: [usize; 2],
+    state: Mutex<SchedulerState>,
+}
+
+/// A delivery opportunity, given back to the scheduler when dropped.
+pub(crate) struct Permit<'a> {
+    scheduler: &'a FairScheduler,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+impl FairScheduler {
+    pub(crate) fn new(config: &FairQueuing) -> FairScheduler {
+        let weights = [config.control_weight.max(1), config.data_weight.max(1)];
+        FairScheduler {
+            weights,
+            state: Mutex::new(SchedulerState {
+                available: config.max_in_flight.max(1),
+                waiters: [VecDeque::new(), VecDeque::new()],
+                current: 0,
+                credits: weights[0],
+            }),
+        }
+    }
+
+    /// Wait for an opportunity to deliver data of the given priority.
+    pub(crate) async fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.waiters.iter().all(VecDeque::is_empty) {
+                state.available -= 1;
+                return Permit { scheduler: self };
+            }
+            let (sender, receiver) = bounded(1);
+            state.waiters[class(priority)].push_back(sender);
+            receiver
+        };
+        let _ = receiver.recv().await;
+        Permit { scheduler: self }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = self.next_waiter(&mut state) {
+            // The waiter may have been dropped while waiting
+            if waiter.try_send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+
+    /// Serve the current class while it has credits and waiters, then move
+    /// to the next class, refilling its credits with its weight.
+    fn next_waiter(&self, state: &mut SchedulerState) -> Option<Sender<()>> {
+        for _ in 0..=state.waiters.len() {
+            if state.credits > 0 {
+                let current = state.current;
+                if let Some(waiter) = state.waiters[current].pop_front() {
+                    state.credits -= 1;
+                    return Some(waiter);
+                }
+            }
+            state.current = (state.current + 1) % state.waiters.len();
+            state.credits = self.weights[state.current];
+        }
+        None
+    }
+}
@@ -0,0 +1,74 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use async_std::sync::Arc;
+use std::collections::HashSet;
+use zenoh_util::zconfigurable;
+
+zconfigurable! {
+    static ref MIN_INTERNER_PURGE: usize = 1024;
+}
+
+/// A set of shared strings, so that the resource names and name chunks and
+/// the cached value keys that are repeated across the routing tables share
+/// the same storage. The strings are interned when the resources are
+/// registered, the routed messages only sharing the names of their resources.
+/// Interned strings compare and hash as their content.
+#[derive(Default)]
+pub struct KeyInterner {
+    keys: HashSet<Arc<str>>,
+    /// The number of strings left by the last purge.
+    purged_len: usize,
+}
+
+impl KeyInterner {
+    pub fn new() -> KeyInterner {
+        KeyInterner::default()
+    }
+
+    /// Returns the shared string equal to `key`, inserting it if needed.
+    pub fn intern(&mut self, key: &str) -> Arc<str> {
+        match self.keys.get(key) {
+            Some(interned) => interned.clone(),
+            None => {
+                let interned: Arc<str> = Arc::from(key);
+                self.keys.insert(interned.clone());
+                interned
+            }
+        }
+    }
+
+    /// Returns the shared string equal to `key`, if it is interned.
+    pub fn get(&self, key: &str) -> Option<Arc<str>> {
+        self.keys.get(key).cloned()
+    }
+
+    /// Number of distinct strings currently interned.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Release the strings that are no longer referenced outside the
+    /// interner. The strings are only scanned once their number doubled since
+    /// the last purge, so that purging takes a constant amortized time.
+    pub fn purge(&mut self) {
+        if self.keys.len() > 2 * self.purged_len.max(*MIN_INTERNER_PURGE) {
+            self.keys.retain(|key| Arc::strong_count(key) > 1);
+            self.purged_len = self.keys.len();
+        }
+    }
+}
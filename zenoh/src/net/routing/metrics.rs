@@ -0,0 +1,297 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Upper bounds of the buckets of a [`FanOutHistogram`]. The fan-outs above
+/// the last bound are counted in an additional, last bucket.
+pub const FAN_OUT_BUCKETS: [usize; 6] = [0, 1, 2, 4, 8, 16];
+
+/// Distribution of the number of faces that declarations were propagated to.
+#[derive(Default)]
+pub struct FanOutHistogram {
+    buckets: [AtomicUsize; 7],
+}
+
+impl FanOutHistogram {
+    pub(crate) fn record(&self, fan_out: usize) {
+        let bucket = FAN_OUT_BUCKETS
+            .iter()
+            .position(|bound| fan_out <= *bound)
+            .unwrap_or_else(|| FAN_OUT_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of propagations in each bucket, the bucket `i` counting the
+    /// fan-outs up to `FAN_OUT_BUCKETS[i]` and above the previous bound.
+    pub fn buckets(&self) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Total number of propagations.
+    pub fn count(&self) -> usize {
+        self.buckets().iter().sum()
+    }
+}
+
+/// Counters of the routing events that are not visible to the faces.
+#[derive(Default)]
+pub struct RoutingMetrics {
+    pub(crate) oversized_payloads_dropped: AtomicUsize,
+    pub(crate) duplicate_sub_contexts: AtomicUsize,
+    pub(crate) pulls_on_push_subs: AtomicUsize,
+    pub(crate) unregistered_data_routes: AtomicUsize,
+    pub(crate) sub_events_dropped: AtomicUsize,
+    pub(crate) retained_evictions: AtomicUsize,
+    pub(crate) stale_data_dropped: AtomicUsize,
+    pub(crate) route_cache_hits: AtomicUsize,
+    pub(crate) dampened_sub_propagations: AtomicUsize,
+    pub(crate) resent_declarations_ignored: AtomicUsize,
+    pub(crate) dead_lettered_samples: AtomicUsize,
+    pub(crate) send_timeouts: AtomicUsize,
+    pub(crate) best_effort_shed: AtomicUsize,
+    pub(crate) shadow_deliveries: AtomicUsize,
+    pub(crate) unknown_rids: AtomicUsize,
+    pub(crate) foreign_rids: AtomicUsize,
+    pub(crate) key_anomalies: AtomicUsize,
+    pub(crate) deferred_rebuilds: AtomicUsize,
+    pub(crate) deferred_propagations: AtomicUsize,
+    pub(crate) uninitialized_net_drops: AtomicUsize,
+    pub(crate) read_through_saturated: AtomicUsize,
+    pub(crate) loops_detected: AtomicUsize,
+    pub(crate) duplicates_suppressed: AtomicUsize,
+    pub(crate) tree_walks: AtomicUsize,
+    pub(crate) send_queue_drops: AtomicUsize,
+    pub(crate) router_sub_fan_out: FanOutHistogram,
+    pub(crate) peer_sub_fan_out: FanOutHistogram,
+}
+
+impl RoutingMetrics {
+    /// Number of data messages not forwarded to a face because their payload
+    /// exceeded the maximum payload size of that face.
+    pub fn oversized_payloads_dropped(&self) -> usize {
+        self.oversized_payloads_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of subscriptions declared by a face on a resource whose context
+    /// for that face already held a subscription the face didn't track. The
+    /// redeclarations of a tracked subscription are not counted.
+    pub fn duplicate_sub_contexts(&self) -> usize {
+        self.duplicate_sub_contexts.load(Ordering::Relaxed)
+    }
+
+    /// Number of pulls received for push subscriptions.
+    pub fn pulls_on_push_subs(&self) -> usize {
+        self.pulls_on_push_subs.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages whose routes were not precomputed and had to
+    /// be built from the matching resources on reception.
+    pub fn unregistered_data_routes(&self) -> usize {
+        self.unregistered_data_routes.load(Ordering::Relaxed)
+    }
+
+    /// Number of subscription or matching key events not delivered to an
+    /// observer because it lagged too far behind.
+    pub fn sub_events_dropped(&self) -> usize {
+        self.sub_events_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of retained values evicted because too many keys were retained
+    /// (see `Tables::set_max_retained_values`).
+    pub fn retained_evictions(&self) -> usize {
+        self.retained_evictions.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages not delivered to a face because they were
+    /// older than the maximum staleness of its subscriptions.
+    pub fn stale_data_dropped(&self) -> usize {
+        self.stale_data_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages routed on a key with no registered resource
+    /// whose routes were found in the route cache.
+    pub fn route_cache_hits(&self) -> usize {
+        self.route_cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of client subscription declarations and undeclarations whose
+    /// propagation was held down because the subscription was flapping.
+    pub fn dampened_sub_propagations(&self) -> usize {
+        self.dampened_sub_propagations.load(Ordering::Relaxed)
+    }
+
+    /// Number of subscription declarations ignored because they repeated the
+    /// idempotency token of a previous declaration of the same face.
+    pub fn resent_declarations_ignored(&self) -> usize {
+        self.resent_declarations_ignored.load(Ordering::Relaxed)
+    }
+
+    /// Number of data that matched no subscription and were delivered to the
+    /// dead letter face.
+    pub fn dead_lettered_samples(&self) -> usize {
+        self.dead_lettered_samples.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages whose delivery to a face was abandoned because
+    /// it didn't complete within the send timeout of that face.
+    pub fn send_timeouts(&self) -> usize {
+        self.send_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages not delivered to a face that has no reliable
+    /// subscription because the memory pressure was high.
+    pub fn best_effort_shed(&self) -> usize {
+        self.best_effort_shed.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages routed to a face only through shadow
+    /// subscriptions, and thus not delivered.
+    pub fn shadow_deliveries(&self) -> usize {
+        self.shadow_deliveries.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages routed with a rid that no face declared.
+    pub fn unknown_rids(&self) -> usize {
+        self.unknown_rids.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages routed with a rid that the sending face never
+    /// declared but other faces did.
+    pub fn foreign_rids(&self) -> usize {
+        self.foreign_rids.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages routed for a malformed key, which are dropped,
+    /// or for a key outside the expected keys (see `Tables::set_expected_keys`).
+    pub fn key_anomalies(&self) -> usize {
+        self.key_anomalies.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the route rebuilds deferred by a `DeferredRebuild`
+    /// guard were performed.
+    pub fn deferred_rebuilds(&self) -> usize {
+        self.deferred_rebuilds.load(Ordering::Relaxed)
+    }
+
+    /// Number of subscriptions and queryables whose propagation over the
+    /// routers or peers network was deferred because the network was not
+    /// initialized yet. They are propagated once its trees are computed.
+    pub fn deferred_propagations(&self) -> usize {
+        self.deferred_propagations.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages and queries received from a router or peer
+    /// before the network they were routed on was initialized. They are
+    /// dropped.
+    pub fn uninitialized_net_drops(&self) -> usize {
+        self.uninitialized_net_drops.load(Ordering::Relaxed)
+    }
+
+    /// Number of pulls on an empty cache answered without reading through
+    /// the routers and peers, because too many read through queries were in
+    /// flight.
+    pub fn read_through_saturated(&self) -> usize {
+        self.read_through_saturated.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages dropped because the same sample was already
+    /// routed within the loop detection window (see
+    /// `Tables::set_loop_detection`).
+    pub fn loops_detected(&self) -> usize {
+        self.loops_detected.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages not delivered to a face because their payload
+    /// was identical to the last one delivered to its deduplicating
+    /// subscription (see `SubOptions::dedup`).
+    pub fn duplicates_suppressed(&self) -> usize {
+        self.duplicates_suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the faces to the childs of a routers or peers tree
+    /// were looked up to propagate a subscription, rather than reused from
+    /// the previous propagations along the same tree.
+    pub fn tree_walks(&self) -> usize {
+        self.tree_walks.load(Ordering::Relaxed)
+    }
+
+    /// Number of data messages with a `Drop` congestion control not delivered
+    /// to a face because its send queue was full.
+    pub fn send_queue_drops(&self) -> usize {
+        self.send_queue_drops.load(Ordering::Relaxed)
+    }
+
+    /// Number of routers each router subscription was propagated to over the
+    /// routers network.
+    pub fn router_sub_fan_out(&self) -> &FanOutHistogram {
+        &self.router_sub_fan_out
+    }
+
+    /// Number of peers each peer subscription was propagated to over the
+    /// peers network.
+    pub fn peer_sub_fan_out(&self) -> &FanOutHistogram {
+        &self.peer_sub_fan_out
+    }
+
+    /// The name and value of each counter, e.g. to export them.
+    pub fn counters(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            (
+                "oversized_payloads_dropped",
+                self.oversized_payloads_dropped(),
+            ),
+            ("duplicate_sub_contexts", self.duplicate_sub_contexts()),
+            ("pulls_on_push_subs", self.pulls_on_push_subs()),
+            ("unregistered_data_routes", self.unregistered_data_routes()),
+            ("sub_events_dropped", self.sub_events_dropped()),
+            ("retained_evictions", self.retained_evictions()),
+            ("stale_data_dropped", self.stale_data_dropped()),
+            ("route_cache_hits", self.route_cache_hits()),
+            (
+                "dampened_sub_propagations",
+                self.dampened_sub_propagations(),
+            ),
+            (
+                "resent_declarations_ignored",
+                self.resent_declarations_ignored(),
+            ),
+            ("dead_lettered_samples", self.dead_lettered_samples()),
+            ("send_timeouts", self.send_timeouts()),
+            ("best_effort_shed", self.best_effort_shed()),
+            ("shadow_deliveries", self.shadow_deliveries()),
+            ("unknown_rids", self.unknown_rids()),
+            ("foreign_rids", self.foreign_rids()),
+            ("key_anomalies", self.key_anomalies()),
+            ("deferred_rebuilds", self.deferred_rebuilds()),
+            ("deferred_propagations", self.deferred_propagations()),
+            ("uninitialized_net_drops", self.uninitialized_net_drops()),
+            ("read_through_saturated", self.read_through_saturated()),
+            ("loops_detected", self.loops_detected()),
+            ("duplicates_suppressed", self.duplicates_suppressed()),
+            ("tree_walks", self.tree_walks()),
+            ("send_queue_drops", self.send_queue_drops()),
+        ]
+    }
+
+    /// The name of each fan-out histogram, with the histogram.
+    pub fn histograms(&self) -> Vec<(&'static str, &FanOutHistogram)> {
+        vec![
+            ("router_sub_fan_out", &self.router_sub_fan_out),
+            ("peer_sub_fan_out", &self.peer_sub_fan_out),
+        ]
+    }
+}
@@ -11,21 +11,34 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
+pub mod admin;
+pub mod broadcast;
 pub mod face;
+pub mod fair_queue;
+pub mod interner;
+pub mod metrics;
 pub mod network;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod partition;
 pub mod pubsub;
 pub mod queries;
 pub mod resource;
+pub mod route_cache;
 pub mod router;
+pub mod snapshot;
 
 use super::super::Session;
 use super::protocol;
 use super::protocol::core::{
-    CongestionControl, PeerId, QueryConsolidation, QueryTarget, Reliability, ResKey, SubInfo, ZInt,
+    CongestionControl, PeerId, Priority, QueryConsolidation, QueryTarget, Reliability, ResKey,
+    SubInfo, ZInt,
 };
 use super::protocol::io::RBuf;
 use super::protocol::proto::{DataInfo, RoutingContext};
-use super::protocol::session::{Mux, Primitives};
+use super::protocol::session::{
+    DataBatch, DeliveryAck, ForcedUndeclare, Mux, Primitives, SubscriberBatch,
+};
 use super::runtime;
 use super::runtime::AdminSpace;
 use async_std::sync::Arc;
@@ -61,6 +74,8 @@ OutSession(
         routing_context: Option<RoutingContext>,
     );
 
+    async fn forced_forget_subscriber(&self, reskey: &ResKey, reason: ForcedUndeclare);
+
     async fn decl_queryable(&self, reskey: &ResKey, routing_context: Option<RoutingContext>);
 
     async fn forget_queryable(
@@ -79,6 +94,30 @@ OutSession(
         routing_context: Option<RoutingContext>,
     );
 
+    #[allow(clippy::too_many_arguments)]
+    async fn send_data_with_priority(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    );
+
+    #[allow(clippy::too_many_arguments)]
+    async fn send_data_acked(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) -> DeliveryAck;
+
     async fn send_query(
         &self,
         reskey: &ResKey,
@@ -111,3 +150,57 @@ OutSession(
 
     async fn send_close(&self);
 });
+
+impl OutSession {
+    /// Declare several subscribers, in a single frame to a transport.
+    pub(crate) async fn decl_subscriber_batch(
+        &self,
+        batch: SubscriberBatch,
+        routing_context: Option<RoutingContext>,
+    ) {
+        match self {
+            OutSession::Transport(mux) => mux.decl_subscriber_batch(batch, routing_context).await,
+            OutSession::Primitives(primitives) => {
+                primitives
+                    .decl_subscriber_batch(batch, routing_context)
+                    .await
+            }
+            _ => {
+                for (reskey, sub_info) in batch {
+                    self.decl_subscriber(&reskey, &sub_info, routing_context)
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Send several data samples, one by one unless the primitives support
+    /// batching.
+    pub(crate) async fn send_data_batch(
+        &self,
+        batch: DataBatch,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+    ) {
+        match self {
+            OutSession::Primitives(primitives) => {
+                primitives
+                    .send_data_batch(batch, reliability, congestion_control)
+                    .await
+            }
+            _ => {
+                for (reskey, payload, data_info) in batch {
+                    self.send_data(
+                        &reskey,
+                        payload,
+                        reliability,
+                        congestion_control,
+                        data_info,
+                        None,
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+}
@@ -74,6 +74,15 @@ impl Link {
     }
 }
 
+/// A read-only snapshot of the local node's position in the propagation tree
+/// rooted at `root`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeView {
+    pub root: PeerId,
+    pub parent: Option<PeerId>,
+    pub childs: Vec<PeerId>,
+}
+
 #[derive(Clone)]
 pub(crate) struct Tree {
     pub(crate) parent: Option<NodeIndex>,
@@ -577,6 +586,91 @@ impl Network {
         free_index
     }
 
+    /// Add a link between two nodes without any underlying session, so that
+    /// the propagation over the network can be tested. The nodes that are not
+    /// yet known are added to the graph.
+    #[cfg(feature = "testing")]
+    pub(crate) fn add_simulated_link(
+        &mut self,
+        pid1: PeerId,
+        pid2: PeerId,
+        whatami: whatami::Type,
+    ) {
+        let idx1 = self.get_or_add_simulated_node(pid1.clone(), whatami);
+        let idx2 = self.get_or_add_simulated_node(pid2.clone(), whatami);
+        self.update_edge(idx1, idx2);
+        if !self.graph[idx1].links.contains(&pid2) {
+            self.graph[idx1].links.push(pid2);
+            self.graph[idx1].sn += 1;
+        }
+        if !self.graph[idx2].links.contains(&pid1) {
+            self.graph[idx2].links.push(pid1);
+            self.graph[idx2].sn += 1;
+        }
+    }
+
+    /// Remove a link added with `add_simulated_link` and return the nodes
+    /// that are no longer reachable from the local node.
+    #[cfg(feature = "testing")]
+    pub(crate) fn remove_simulated_link(
+        &mut self,
+        pid1: &PeerId,
+        pid2: &PeerId,
+    ) -> Vec<(NodeIndex, Node)> {
+        if let (Some(idx1), Some(idx2)) = (self.get_idx(pid1), self.get_idx(pid2)) {
+            if let Some((edge, _)) = self.graph.find_edge_undirected(idx1, idx2) {
+                self.graph.remove_edge(edge);
+            }
+            if self.graph[idx1].links.contains(pid2) {
+                self.graph[idx1].links.retain(|link| link != pid2);
+                self.graph[idx1].sn += 1;
+            }
+            if self.graph[idx2].links.contains(pid1) {
+                self.graph[idx2].links.retain(|link| link != pid1);
+                self.graph[idx2].sn += 1;
+            }
+        }
+        self.remove_detached_nodes()
+    }
+
+    #[cfg(feature = "testing")]
+    fn get_or_add_simulated_node(&mut self, pid: PeerId, whatami: whatami::Type) -> NodeIndex {
+        match self.get_idx(&pid) {
+            Some(idx) => idx,
+            None => {
+                log::debug!("{} Add node (simulated) {}", self.name, pid);
+                self.add_node(Node {
+                    pid,
+                    whatami,
+                    locators: None,
+                    sn: 0,
+                    links: vec![],
+                })
+            }
+        }
+    }
+
+    /// Return a snapshot of the parent and childs of the local node in each
+    /// of the trees computed by the last call to `compute_trees`.
+    pub(crate) fn tree_views(&self) -> Vec<TreeView> {
+        self.trees
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, tree)| {
+                let root = self.graph.node_weight(NodeIndex::new(idx))?;
+                Some(TreeView {
+                    root: root.pid.clone(),
+                    parent: tree.parent.map(|parent| self.graph[parent].pid.clone()),
+                    childs: tree
+                        .childs
+                        .iter()
+                        .map(|child| self.graph[*child].pid.clone())
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
     pub(crate) async fn remove_link(&mut self, session: &Session) -> Vec<(NodeIndex, Node)> {
         let pid = session.get_pid().unwrap();
         log::trace!("{} remove_link {}", self.name, pid);
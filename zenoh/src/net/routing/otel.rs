@@ -0,0 +1,69 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+//! Export of the [`RoutingMetrics`] as OpenTelemetry instruments.
+use async_std::sync::Arc;
+use opentelemetry::metrics::{Meter, ObserverResult};
+use opentelemetry::KeyValue;
+
+use super::metrics::{RoutingMetrics, FAN_OUT_BUCKETS};
+
+/// The prefix of the names of the exported instruments.
+pub const INSTRUMENT_PREFIX: &str = "zenoh.routing.";
+
+/// Register with the given meter a sum observer for each counter of the
+/// metrics, named after the counter with the [`INSTRUMENT_PREFIX`], and one for
+/// each fan-out histogram, observing the count of each bucket with a `le`
+/// attribute holding its upper bound (`+Inf` for the last one).
+pub fn register_metrics(metrics: &Arc<RoutingMetrics>, meter: &Meter) {
+    for (name, _) in metrics.counters() {
+        let metrics = metrics.clone();
+        meter
+            .u64_sum_observer(
+                [INSTRUMENT_PREFIX, name].concat(),
+                move |result: ObserverResult<u64>| {
+                    if let Some((_, value)) = metrics
+                        .counters()
+                        .into_iter()
+                        .find(|(counter, _)| *counter == name)
+                    {
+                        result.observe(value as u64, &[]);
+                    }
+                },
+            )
+            .init();
+    }
+    for (name, _) in metrics.histograms() {
+        let metrics = metrics.clone();
+        meter
+            .u64_sum_observer(
+                [INSTRUMENT_PREFIX, name].concat(),
+                move |result: ObserverResult<u64>| {
+                    if let Some((_, histogram)) = metrics
+                        .histograms()
+                        .into_iter()
+                        .find(|(histogram, _)| *histogram == name)
+                    {
+                        for (idx, count) in histogram.buckets().into_iter().enumerate() {
+                            let bound = FAN_OUT_BUCKETS
+                                .get(idx)
+                                .map(|bound| bound.to_string())
+                                .unwrap_or_else(|| "+Inf".to_string());
+                            result.observe(count as u64, &[KeyValue::new("le", bound)]);
+                        }
+                    }
+                },
+            )
+            .init();
+    }
+}
@@ -0,0 +1,194 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use async_std::sync::{Arc, RwLock, Weak};
+use uhlc::HLC;
+use zenoh_util::zasyncwrite;
+
+use super::protocol::core::{rname, CongestionControl, PeerId, SubInfo, WhatAmI};
+use super::protocol::io::RBuf;
+use super::protocol::proto::DataInfo;
+
+use super::face::FaceState;
+use super::pubsub::{
+    declare_client_subscription, forget_client_subscription, full_reentrant_route_data,
+};
+use super::router::Tables;
+use super::OutSession;
+
+/// A face opened on every partition of some [`PartitionedTables`].
+pub struct PartitionedFace {
+    faces: Vec<Weak<FaceState>>,
+}
+
+impl PartitionedFace {
+    /// The face opened on the given partition.
+    pub fn face(&self, partition: usize) -> &Weak<FaceState> {
+        &self.faces[partition]
+    }
+}
+
+/// Routing tables sharded by key. Each partition owns the keys matching its
+/// key expression in its own [`Tables`], so that partitions are locked and
+/// routed independently. The keys matching none of them go to a last, default
+/// partition. All the keys are given as complete resource names.
+pub struct PartitionedTables {
+    key_exprs: Vec<String>,
+    partitions: Vec<Arc<RwLock<Tables>>>,
+}
+
+impl PartitionedTables {
+    /// Create the tables of the partitions owning the given key expressions,
+    /// followed by the default partition. `hlc` is called once per partition.
+    pub fn new(
+        pid: PeerId,
+        whatami: WhatAmI,
+        key_exprs: Vec<String>,
+        mut hlc: impl FnMut() -> Option<HLC>,
+    ) -> Self {
+        let partitions = (0..=key_exprs.len())
+            .map(|_| Arc::new(RwLock::new(Tables::new(pid.clone(), whatami, hlc()))))
+            .collect();
+        PartitionedTables {
+            key_exprs,
+            partitions,
+        }
+    }
+
+    /// The number of partitions, the default one included.
+    pub fn len(&self) -> usize {
+        self.partitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty()
+    }
+
+    pub fn tables(&self, partition: usize) -> &Arc<RwLock<Tables>> {
+        &self.partitions[partition]
+    }
+
+    /// The partition owning a resource name: the first one whose key
+    /// expression matches it, the default one otherwise.
+    pub fn partition(&self, name: &str) -> usize {
+        self.key_exprs
+            .iter()
+            .position(|expr| rname::intersect(expr, name))
+            .unwrap_or_else(|| self.key_exprs.len())
+    }
+
+    /// The partitions owning keys matched by a key expression. A wildcard
+    /// expression included in the first partition it intersects only concerns
+    /// that partition, otherwise it concerns all the partitions it intersects
+    /// and the default one.
+    pub fn partitions_of(&self, expr: &str) -> Vec<usize> {
+        if !expr.contains('*') {
+            return vec![self.partition(expr)];
+        }
+        // The keys of the expression are owned by the first partition they
+        // intersect, which must be the first one the expression intersects
+        if let Some(idx) = self
+            .key_exprs
+            .iter()
+            .position(|key_expr| rname::intersect(key_expr, expr))
+        {
+            if rname::include(&self.key_exprs[idx], expr) {
+                return vec![idx];
+            }
+        }
+        self.key_exprs
+            .iter()
+            .enumerate()
+            .filter(|(_, key_expr)| rname::intersect(key_expr, expr))
+            .map(|(idx, _)| idx)
+            .chain(std::iter::once(self.key_exprs.len()))
+            .collect()
+    }
+
+    pub async fn open_face(
+        &self,
+        pid: PeerId,
+        whatami: WhatAmI,
+        primitives: OutSession,
+    ) -> PartitionedFace {
+        let mut faces = Vec::with_capacity(self.partitions.len());
+        for tables in &self.partitions {
+            faces.push(
+                zasyncwrite!(tables)
+                    .open_face(pid.clone(), whatami, primitives.clone())
+                    .await,
+            );
+        }
+        PartitionedFace { faces }
+    }
+
+    pub async fn close_face(&self, face: &PartitionedFace) {
+        for (tables, face) in self.partitions.iter().zip(face.faces.iter()) {
+            zasyncwrite!(tables).close_face(face).await;
+        }
+    }
+
+    /// Declare a client subscription in the partitions concerned by its key
+    /// expression.
+    pub async fn declare_subscription(
+        &self,
+        face: &PartitionedFace,
+        expr: &str,
+        sub_info: &SubInfo,
+    ) {
+        for idx in self.partitions_of(expr) {
+            if let Some(mut face) = face.faces[idx].upgrade() {
+                let mut tables = zasyncwrite!(self.partitions[idx]);
+                // The rejected declarations are already logged
+                let _ =
+                    declare_client_subscription(&mut tables, &mut face, 0, expr, sub_info).await;
+            }
+        }
+    }
+
+    pub async fn forget_subscription(&self, face: &PartitionedFace, expr: &str) {
+        for idx in self.partitions_of(expr) {
+            if let Some(mut face) = face.faces[idx].upgrade() {
+                let mut tables = zasyncwrite!(self.partitions[idx]);
+                forget_client_subscription(&mut tables, &mut face, 0, expr).await;
+            }
+        }
+    }
+
+    /// Route data through the partitions concerned by its key, only the
+    /// owning one for a resource name.
+    pub async fn route_data(
+        &self,
+        face: &PartitionedFace,
+        name: &str,
+        congestion_control: CongestionControl,
+        info: Option<DataInfo>,
+        payload: RBuf,
+    ) {
+        for idx in self.partitions_of(name) {
+            if let Some(face) = face.faces[idx].upgrade() {
+                full_reentrant_route_data(
+                    &self.partitions[idx],
+                    &face,
+                    0,
+                    name,
+                    congestion_control,
+                    info.clone(),
+                    payload.clone(),
+                    None,
+                )
+                .await;
+            }
+        }
+    }
+}
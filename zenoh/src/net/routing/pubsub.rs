@@ -11,55 +11,154 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-use async_std::sync::{Arc, RwLock};
+use async_std::channel::TrySendError;
+use async_std::sync::{Arc, RwLock, Weak};
+use futures::channel::oneshot;
+use futures::FutureExt;
 use petgraph::graph::NodeIndex;
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::Hasher;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use zenoh_util::sync::get_mut_unchecked;
 use zenoh_util::zasyncread;
+use zenoh_util::zconfigurable;
 
+use super::protocol::core::rname;
 use super::protocol::core::{
-    whatami, CongestionControl, PeerId, Reliability, SubInfo, SubMode, ZInt,
+    whatami, CongestionControl, PeerId, Period, Priority, Reliability, ResKey, SubInfo, SubMode,
+    ZInt,
 };
 use super::protocol::io::RBuf;
-use super::protocol::proto::{DataInfo, RoutingContext};
+use super::protocol::proto::{data_kind, encoding, DataInfo, RoutingContext};
+use super::protocol::session::{ForcedUndeclare, SubscriberBatch};
 
-use super::face::FaceState;
+use super::face::{
+    BufferedDeliveries, DataDelivery, FaceState, KeyRepr, PausedDeliveries, SubHeartbeat,
+};
+use super::metrics::RoutingMetrics;
 use super::network::Network;
-use super::resource::{elect_router, PullCaches, Resource, Route, SessionContext};
-use super::router::Tables;
+use super::queries::{query_read_through, query_retained_values};
+use super::resource::{
+    elect_router, DeliveryTracking, PendingBatch, PullCaches, Resource, Route, SessionContext,
+};
+use super::router::{RoutingError, Tables};
 
-#[inline]
-async fn send_sourced_subscription_to_net_childs(
+zconfigurable! {
+    static ref MAX_TRACKED_PUBLISHERS: usize = 16;
+    static ref MAX_TRACKED_PUBLICATIONS: usize = 1024;
+}
+
+/// The subscription declarations pending for the downstream faces, sent as a
+/// single frame per face and routing context when flushed.
+#[derive(Default)]
+struct PendingSubscriptions {
+    batches: HashMap<(usize, Option<RoutingContext>), (Arc<FaceState>, SubscriberBatch)>,
+}
+
+impl PendingSubscriptions {
+    fn push(
+        &mut self,
+        face: &Arc<FaceState>,
+        reskey: ResKey,
+        sub_info: &SubInfo,
+        routing_context: Option<RoutingContext>,
+    ) {
+        self.batches
+            .entry((face.id, routing_context))
+            .or_insert_with(|| (face.clone(), vec![]))
+            .1
+            .push((reskey, sub_info.clone()));
+    }
+
+    async fn flush(self) {
+        for ((_, routing_context), (face, mut batch)) in self.batches {
+            if batch.len() == 1 {
+                let (reskey, sub_info) = batch.pop().unwrap();
+                face.primitives
+                    .decl_subscriber(&reskey, &sub_info, routing_context)
+                    .await;
+            } else {
+                face.primitives
+                    .decl_subscriber_batch(batch, routing_context)
+                    .await;
+            }
+        }
+    }
+}
+
+/// The faces to the given childs of the local node.
+fn child_faces(tables: &Tables, net: &Network, childs: &[NodeIndex]) -> Vec<Arc<FaceState>> {
+    let mut faces = vec![];
+    for child in childs {
+        if net.graph.contains_node(*child) {
+            match tables.get_face(&net.graph[*child].pid) {
+                Some(face) => faces.push(face.clone()),
+                None => {
+                    log::trace!("Unable to find face for pid {}", net.graph[*child].pid)
+                }
+            }
+        }
+    }
+    faces
+}
+
+/// The faces to the childs of the local node in a tree.
+pub(crate) type ChildFaces = Arc<Vec<Arc<FaceState>>>;
+
+/// The faces to the childs of the local node in the tree of the given node,
+/// cached until the trees or the faces change, so that the declarations sent
+/// in a burst along the same tree don't walk it again.
+fn tree_child_faces(
     tables: &Tables,
     net: &Network,
-    childs: &[NodeIndex],
+    net_type: whatami::Type,
+    tree_sid: usize,
+) -> ChildFaces {
+    let key = (net_type, tree_sid);
+    if let Some(faces) = tables.tree_child_faces.lock().unwrap().get(&key) {
+        return faces.clone();
+    }
+    tables.metrics.tree_walks.fetch_add(1, Ordering::Relaxed);
+    let faces = Arc::new(child_faces(tables, net, &net.trees[tree_sid].childs));
+    tables
+        .tree_child_faces
+        .lock()
+        .unwrap()
+        .insert(key, faces.clone());
+    faces
+}
+
+#[inline]
+async fn send_sourced_subscription_to_faces(
+    tables: &Tables,
+    faces: &[Arc<FaceState>],
     res: &Arc<Resource>,
     src_face: Option<&Arc<FaceState>>,
     sub_info: &SubInfo,
     routing_context: Option<RoutingContext>,
-) {
-    for child in childs {
-        if net.graph.contains_node(*child) {
-            match tables.get_face(&net.graph[*child].pid).cloned() {
-                Some(mut someface) => {
-                    if src_face.is_none() || someface.id != src_face.unwrap().id {
-                        let reskey = Resource::decl_key(res, &mut someface).await;
+    pending: &mut PendingSubscriptions,
+) -> usize {
+    let mut fan_out = 0;
+    for face in faces {
+        if (src_face.is_none() || face.id != src_face.unwrap().id)
+            && tables.propagates_subs_to(face)
+        {
+            let mut someface = face.clone();
+            let reskey = Resource::decl_key(res, &mut someface).await;
 
-                        log::debug!("Send subscription {} on {}", res.name(), someface);
+            log::debug!("Send subscription {} on {}", res.name(), someface);
 
-                        someface
-                            .primitives
-                            .decl_subscriber(&reskey, sub_info, routing_context)
-                            .await;
-                    }
-                }
-                None => {
-                    log::trace!("Unable to find face for pid {}", net.graph[*child].pid)
-                }
-            }
+            pending.push(&someface, reskey, sub_info, routing_context);
+            fan_out += 1;
         }
     }
+    fan_out
 }
 
 async fn propagate_simple_subscription(
@@ -71,11 +170,7 @@ async fn propagate_simple_subscription(
     for dst_face in &mut tables.faces.values_mut() {
         if src_face.id != dst_face.id
             && !dst_face.local_subs.contains(res)
-            && match tables.whatami {
-                whatami::ROUTER => dst_face.whatami == whatami::CLIENT,
-                whatami::PEER => dst_face.whatami == whatami::CLIENT,
-                _ => (src_face.whatami == whatami::CLIENT || dst_face.whatami == whatami::CLIENT),
-            }
+            && simple_propagation_allowed(tables.whatami, src_face, dst_face)
         {
             get_mut_unchecked(dst_face).local_subs.push(res.clone());
             let reskey = Resource::decl_key(res, dst_face).await;
@@ -95,20 +190,40 @@ async fn propagate_sourced_subscription(
     source: &PeerId,
     net_type: whatami::Type,
 ) {
-    let net = tables.get_net(net_type).unwrap();
+    let net = match tables.get_net(net_type) {
+        Some(net) => net,
+        None => {
+            log::debug!(
+                "Defer the propagation of sub {}: network not initialized",
+                res.name()
+            );
+            tables
+                .metrics
+                .deferred_propagations
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
     match net.get_idx(source) {
         Some(tree_sid) => {
             if net.trees.len() > tree_sid.index() {
-                send_sourced_subscription_to_net_childs(
+                let mut pending = PendingSubscriptions::default();
+                let faces = tree_child_faces(tables, net, net_type, tree_sid.index());
+                let fan_out = send_sourced_subscription_to_faces(
                     tables,
-                    net,
-                    &net.trees[tree_sid.index()].childs,
+                    &faces,
                     res,
                     src_face,
                     sub_info,
                     Some(tree_sid.index() as ZInt),
+                    &mut pending,
                 )
                 .await;
+                pending.flush().await;
+                match net_type {
+                    whatami::ROUTER => tables.metrics.router_sub_fan_out.record(fan_out),
+                    _ => tables.metrics.peer_sub_fan_out.record(fan_out),
+                }
             } else {
                 log::trace!("Tree for node {} not yet ready", source);
             }
@@ -127,8 +242,9 @@ async fn register_router_subscription(
     res: &mut Arc<Resource>,
     sub_info: &SubInfo,
     router: PeerId,
-) {
-    if !res.context().router_subs.contains(&router) {
+) -> bool {
+    let created = !res.context().router_subs.contains(&router);
+    if created {
         // Register router subscription
         {
             log::debug!(
@@ -149,12 +265,13 @@ async fn register_router_subscription(
 
         // Propagate subscription to peers
         if face.whatami != whatami::PEER {
-            register_peer_subscription(tables, face, res, sub_info, tables.pid.clone()).await
+            register_peer_subscription(tables, face, res, sub_info, tables.pid.clone()).await;
         }
     }
 
     // Propagate subscription to clients
     propagate_simple_subscription(tables, res, sub_info, face).await;
+    created
 }
 
 pub async fn declare_router_subscription(
@@ -164,16 +281,22 @@ pub async fn declare_router_subscription(
     suffix: &str,
     sub_info: &SubInfo,
     router: PeerId,
-) {
+) -> Result<DeclareOutcome, RoutingError> {
     match tables.get_mapping(&face, &prefixid).cloned() {
         Some(mut prefix) => {
             let mut res = Resource::make_resource(tables, &mut prefix, suffix);
             Resource::match_resource(&tables, &mut res);
-            register_router_subscription(tables, face, &mut res, sub_info, router).await;
+            let created =
+                register_router_subscription(tables, face, &mut res, sub_info, router).await;
 
             compute_matches_data_routes(tables, &mut res);
+            notify_sub_change(tables, &res, face, whatami::ROUTER, SubChange::Declared);
+            Ok(DeclareOutcome::new(tables, &res, created))
+        }
+        None => {
+            log::error!("Declare router subscription for unknown rid {}!", prefixid);
+            Err(RoutingError::UnknownPrefix(prefixid))
         }
-        None => log::error!("Declare router subscription for unknown rid {}!", prefixid),
     }
 }
 
@@ -183,8 +306,13 @@ async fn register_peer_subscription(
     res: &mut Arc<Resource>,
     sub_info: &SubInfo,
     peer: PeerId,
-) {
-    if !res.context().peer_subs.contains(&peer) {
+) -> bool {
+    get_mut_unchecked(res)
+        .context_mut()
+        .peer_sub_reliabilities
+        .insert(peer.clone(), sub_info.reliability);
+    let created = !res.context().peer_subs.contains(&peer);
+    if created {
         // Register peer subscription
         {
             log::debug!("Register peer subscription {} (peer: {})", res.name(), peer);
@@ -199,6 +327,7 @@ async fn register_peer_subscription(
         propagate_sourced_subscription(tables, res, sub_info, Some(face), &peer, whatami::PEER)
             .await;
     }
+    created
 }
 
 pub async fn declare_peer_subscription(
@@ -208,452 +337,1376 @@ pub async fn declare_peer_subscription(
     suffix: &str,
     sub_info: &SubInfo,
     peer: PeerId,
-) {
+) -> Result<DeclareOutcome, RoutingError> {
     match tables.get_mapping(&face, &prefixid).cloned() {
         Some(mut prefix) => {
             let mut res = Resource::make_resource(tables, &mut prefix, suffix);
             Resource::match_resource(&tables, &mut res);
-            register_peer_subscription(tables, face, &mut res, sub_info, peer).await;
+            let previous_reliability = peer_subs_reliability(&res);
+            let created = register_peer_subscription(tables, face, &mut res, sub_info, peer).await;
 
             if tables.whatami == whatami::ROUTER {
+                // The router subscription requests the strongest reliability
+                // of the peer subscriptions
                 let mut propa_sub_info = sub_info.clone();
                 propa_sub_info.mode = SubMode::Push;
-                register_router_subscription(
-                    tables,
-                    face,
-                    &mut res,
-                    &propa_sub_info,
-                    tables.pid.clone(),
-                )
-                .await;
+                propa_sub_info.reliability =
+                    peer_subs_reliability(&res).unwrap_or(sub_info.reliability);
+                let pid = tables.pid.clone();
+                if res.context().router_subs.contains(&pid) {
+                    if previous_reliability != Some(propa_sub_info.reliability) {
+                        propagate_sourced_subscription(
+                            tables,
+                            &res,
+                            &propa_sub_info,
+                            Some(face),
+                            &pid,
+                            whatami::ROUTER,
+                        )
+                        .await;
+                    }
+                } else {
+                    register_router_subscription(tables, face, &mut res, &propa_sub_info, pid)
+                        .await;
+                }
             }
 
             compute_matches_data_routes(tables, &mut res);
+            notify_sub_change(tables, &res, face, whatami::PEER, SubChange::Declared);
+            Ok(DeclareOutcome::new(tables, &res, created))
         }
-        None => log::error!("Declare router subscription for unknown rid {}!", prefixid),
+        None => {
+            log::error!("Declare router subscription for unknown rid {}!", prefixid);
+            Err(RoutingError::UnknownPrefix(prefixid))
+        }
+    }
+}
+
+/// The strongest reliability of the peer subscriptions of a resource, `None`
+/// if it has no peer subscription.
+fn peer_subs_reliability(res: &Resource) -> Option<Reliability> {
+    let reliabilities = &res.context.as_ref()?.peer_sub_reliabilities;
+    if reliabilities.is_empty() {
+        None
+    } else if reliabilities
+        .values()
+        .any(|reliability| *reliability == Reliability::Reliable)
+    {
+        Some(Reliability::Reliable)
+    } else {
+        Some(Reliability::BestEffort)
+    }
+}
+
+/// Whether a subscription redeclared by a face with `new_info` replaces its
+/// existing one with `old_info`, see [`ModeConflict`].
+fn replaces_subscription(
+    policy: ModeConflict,
+    res: &Resource,
+    old_info: &SubInfo,
+    new_info: &SubInfo,
+) -> Result<bool, RoutingError> {
+    match policy {
+        ModeConflict::LastWins => Ok(true),
+        ModeConflict::Reject if old_info.mode != new_info.mode => {
+            log::warn!(
+                "Reject subscription {} redeclared as {:?} instead of {:?}",
+                res.name(),
+                new_info.mode,
+                old_info.mode
+            );
+            Err(RoutingError::ModeConflict(res.name()))
+        }
+        ModeConflict::Reject => Ok(true),
+        ModeConflict::StrongestWins => Ok(old_info.mode == SubMode::Pull),
     }
 }
 
 async fn register_client_subscription(
-    _tables: &mut Tables,
+    tables: &mut Tables,
     face: &mut Arc<FaceState>,
     res: &mut Arc<Resource>,
     sub_info: &SubInfo,
-) {
+    options: &SubOptions,
+) -> Result<bool, RoutingError> {
     // Register subscription
-    {
+    let redeclared = {
         let res = get_mut_unchecked(res);
         log::debug!("Register subscription {} for {}", res.name(), face);
-        match res.session_ctxs.get_mut(&face.id) {
-            Some(mut ctx) => match &ctx.subs {
+        // Update the existing context in place, if any, so that its cached
+        // values and mappings are preserved.
+        let redeclared = match res.session_ctxs.get(&face.id) {
+            Some(ctx) => match ctx.subs() {
                 Some(info) => {
-                    if SubMode::Pull == info.mode {
-                        get_mut_unchecked(&mut ctx).subs = Some(sub_info.clone());
+                    if replaces_subscription(tables.mode_conflict, res, &info, sub_info)? {
+                        ctx.set_subs(sub_info, options);
                     }
+                    true
                 }
                 None => {
-                    get_mut_unchecked(&mut ctx).subs = Some(sub_info.clone());
+                    ctx.set_subs(sub_info, options);
+                    false
                 }
             },
             None => {
-                res.session_ctxs.insert(
-                    face.id,
-                    Arc::new(SessionContext {
-                        face: face.clone(),
-                        local_rid: None,
-                        remote_rid: None,
-                        subs: Some(sub_info.clone()),
-                        qabl: false,
-                        last_values: HashMap::new(),
-                    }),
-                );
+                let ctx = SessionContext::new(face.clone(), None, None);
+                ctx.set_subs(sub_info, options);
+                res.session_ctxs.insert(face.id, Arc::new(ctx));
+                false
             }
+        };
+        if let Some(ctx) = res.session_ctxs.get(&face.id) {
+            ctx.set_cache_budget(tables.pull_cache_budget.clone());
+        }
+        redeclared
+    };
+    // A redeclaration is legitimate, but the face must already track the
+    // subscription held by its context
+    let untracked = redeclared && !face.remote_subs.iter().any(|sub| Arc::ptr_eq(sub, res));
+    if untracked {
+        log::warn!(
+            "Subscription {} already registered for {} without being tracked",
+            res.name(),
+            face
+        );
+        tables
+            .metrics
+            .duplicate_sub_contexts
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    if options.data_filter.is_some()
+        || options.source_filter.is_some()
+        || options.max_staleness.is_some()
+        || options.transform.is_some()
+        || options.accepted_encoding.is_some()
+        || options.dedup.is_some()
+        || options.track_acks
+        || options.batching.is_some()
+        || options.reordering.is_some()
+        || options.on_delivery_error.is_some()
+        || options.shadow
+    {
+        tables.data_filters = true;
+    }
+    if options.receive_own {
+        tables.loopback_subs = true;
+    }
+    if options.priority != Priority::default() {
+        tables.priority_subs = true;
+    }
+    if face.key_repr == KeyRepr::Numeric {
+        Resource::decl_key(res, face).await;
+    }
+    {
+        let weak = Arc::downgrade(res);
+        let mut heartbeats = face.heartbeats.lock().unwrap();
+        heartbeats.retain(|heartbeat| !heartbeat.res.ptr_eq(&weak));
+        if let Some(interval) = options.heartbeat {
+            heartbeats.push(SubHeartbeat {
+                res: weak,
+                name: res.name(),
+                interval,
+                last_activity: Instant::now(),
+            });
+        }
+    }
+    if options.batching.is_some() {
+        if let Some(ctx) = res.session_ctxs.get(&face.id) {
+            let weak = Arc::downgrade(ctx);
+            tables.batching_subs.retain(|(face, ctx)| {
+                face.strong_count() > 0 && ctx.strong_count() > 0 && !ctx.ptr_eq(&weak)
+            });
+            tables.batching_subs.push((Arc::downgrade(face), weak));
+        }
+    }
+    if options.reordering.is_some() {
+        if let Some(ctx) = res.session_ctxs.get(&face.id) {
+            let weak = Arc::downgrade(ctx);
+            tables.reordering_subs.retain(|(face, ctx)| {
+                face.strong_count() > 0 && ctx.strong_count() > 0 && !ctx.ptr_eq(&weak)
+            });
+            tables.reordering_subs.push((Arc::downgrade(face), weak));
+        }
+    }
+    {
+        let face = get_mut_unchecked(face);
+        face.sub_leases.retain(|(sub, _)| !Arc::ptr_eq(sub, res));
+        if let Some(lease) = options.lease {
+            face.sub_leases.push((res.clone(), Instant::now() + lease));
+        }
+        if !redeclared || untracked {
+            face.remote_subs.push(res.clone());
         }
     }
-    get_mut_unchecked(face).remote_subs.push(res.clone());
+    Ok(!redeclared)
 }
 
-pub async fn declare_client_subscription(
-    tables: &mut Tables,
-    face: &mut Arc<FaceState>,
-    prefixid: ZInt,
-    suffix: &str,
-    sub_info: &SubInfo,
-) {
-    match tables.get_mapping(&face, &prefixid).cloned() {
-        Some(mut prefix) => {
-            let mut res = Resource::make_resource(tables, &mut prefix, suffix);
-            Resource::match_resource(&tables, &mut res);
+/// The resolution of a subscription redeclared by a face with another mode
+/// than its existing one (see `Tables::set_mode_conflict`). The redeclaration
+/// of a subscription with the same mode is always accepted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModeConflict {
+    /// The redeclared subscription replaces the existing one.
+    LastWins,
+    /// The redeclaration is rejected with `RoutingError::ModeConflict` when
+    /// the modes differ, and replaces the existing subscription otherwise.
+    Reject,
+    /// A push subscription, which is delivered all the data, is kept over a
+    /// pull one: a pull subscription is replaced by any redeclaration while a
+    /// push subscription is never replaced. This is the default.
+    StrongestWins,
+}
 
-            register_client_subscription(tables, face, &mut res, sub_info).await;
-            match tables.whatami {
-                whatami::ROUTER => {
-                    let mut propa_sub_info = sub_info.clone();
-                    propa_sub_info.mode = SubMode::Push;
-                    register_router_subscription(
-                        tables,
-                        face,
-                        &mut res,
-                        &propa_sub_info,
-                        tables.pid.clone(),
-                    )
-                    .await;
-                }
-                whatami::PEER => {
-                    let mut propa_sub_info = sub_info.clone();
-                    propa_sub_info.mode = SubMode::Push;
-                    register_peer_subscription(
-                        tables,
-                        face,
-                        &mut res,
-                        &propa_sub_info,
-                        tables.pid.clone(),
-                    )
-                    .await;
-                }
-                _ => {
-                    propagate_simple_subscription(tables, &res, sub_info, face).await;
-                }
-            }
+impl Default for ModeConflict {
+    fn default() -> Self {
+        ModeConflict::StrongestWins
+    }
+}
 
-            compute_matches_data_routes(tables, &mut res);
+/// The reaction to the data published on keys that no subscription matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmptyRoute {
+    /// Drop the data.
+    Drop,
+    /// Drop the data, warning about the first ones published by each face.
+    LogOnce,
+    /// Drop the data, closing the publishing face, likely misconfigured, once
+    /// it published the given number of them.
+    CloseFace(usize),
+}
+
+impl Default for EmptyRoute {
+    fn default() -> Self {
+        EmptyRoute::Drop
+    }
+}
+
+/// Whether a subscription was declared or undeclared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubChange {
+    Declared,
+    Undeclared,
+}
+
+/// A change of the subscriptions registered in the routing tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubEvent {
+    /// The name of the subscribed resource.
+    pub resource: String,
+    /// The id of the face the change was received from.
+    pub face_id: usize,
+    /// The kind of the subscription: router, peer or client.
+    pub kind: whatami::Type,
+    pub change: SubChange,
+}
+
+/// A newly registered concrete key matching an existing client subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchingKeyEvent {
+    /// The name of the new resource.
+    pub key: String,
+    /// The name of the matching subscribed resource.
+    pub subscription: String,
+    /// The id of the face of the subscription.
+    pub face_id: usize,
+}
+
+/// Notify the client subscriptions matched by a newly registered resource,
+/// unless it is a wildcard expression.
+pub(crate) fn notify_new_matching_key(tables: &mut Tables, res: &Arc<Resource>) {
+    if tables.matching_key_listeners.is_empty() || res.context.is_none() {
+        return;
+    }
+    let key = res.name();
+    if key.contains('*') {
+        return;
+    }
+    let mut events = vec![];
+    for mres in res
+        .context()
+        .matches
+        .iter()
+        .filter_map(|mres| mres.upgrade())
+    {
+        if Arc::ptr_eq(&mres, res) {
+            continue;
         }
-        None => log::error!("Declare subscription for unknown rid {}!", prefixid),
+        for ctx in mres.session_ctxs.values().filter(|ctx| ctx.has_subs()) {
+            events.push(MatchingKeyEvent {
+                key: key.clone(),
+                subscription: mres.name(),
+                face_id: ctx.face.id,
+            });
+        }
+    }
+    for event in events {
+        tables.notify_matching_key(event);
     }
 }
 
 #[inline]
-async fn send_forget_sourced_subscription_to_net_childs(
-    tables: &Tables,
-    net: &Network,
-    childs: &[NodeIndex],
+fn notify_sub_change(
+    tables: &mut Tables,
     res: &Arc<Resource>,
-    src_face: Option<&Arc<FaceState>>,
-    routing_context: Option<RoutingContext>,
+    face: &FaceState,
+    kind: whatami::Type,
+    change: SubChange,
 ) {
-    for child in childs {
-        if net.graph.contains_node(*child) {
-            match tables.get_face(&net.graph[*child].pid).cloned() {
-                Some(mut someface) => {
-                    if src_face.is_none() || someface.id != src_face.unwrap().id {
-                        let reskey = Resource::decl_key(res, &mut someface).await;
+    tables.notify_subscription(SubEvent {
+        resource: res.name(),
+        face_id: face.id,
+        kind,
+        change,
+    });
+}
 
-                        log::debug!("Send forget subscription {} on {}", res.name(), someface);
+/// A predicate on the `DataInfo` of the routed data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataInfoPredicate {
+    /// The data kind is the given one (`data_kind::DEFAULT` when absent).
+    Kind(ZInt),
+    /// The encoding is the given one (`encoding::DEFAULT` when absent).
+    Encoding(ZInt),
+    Not(Box<DataInfoPredicate>),
+    And(Vec<DataInfoPredicate>),
+    Or(Vec<DataInfoPredicate>),
+}
 
-                        someface
-                            .primitives
-                            .forget_subscriber(&reskey, routing_context)
-                            .await;
-                    }
-                }
-                None => {
-                    log::trace!("Unable to find face for pid {}", net.graph[*child].pid)
-                }
+impl DataInfoPredicate {
+    pub fn matches(&self, info: &Option<DataInfo>) -> bool {
+        match self {
+            DataInfoPredicate::Kind(kind) => {
+                info.as_ref()
+                    .and_then(|info| info.kind)
+                    .unwrap_or(data_kind::DEFAULT)
+                    == *kind
             }
+            DataInfoPredicate::Encoding(enc) => {
+                info.as_ref()
+                    .and_then(|info| info.encoding)
+                    .unwrap_or(encoding::DEFAULT)
+                    == *enc
+            }
+            DataInfoPredicate::Not(predicate) => !predicate.matches(info),
+            DataInfoPredicate::And(predicates) => predicates.iter().all(|p| p.matches(info)),
+            DataInfoPredicate::Or(predicates) => predicates.iter().any(|p| p.matches(info)),
         }
     }
 }
 
-async fn propagate_forget_simple_subscription(tables: &mut Tables, res: &Arc<Resource>) {
-    for face in tables.faces.values_mut() {
-        if face.local_subs.contains(res) {
-            let reskey = Resource::get_best_key(res, "", face.id);
-            face.primitives.forget_subscriber(&reskey, None).await;
+/// A stateful transform of the data delivered to a client subscription, e.g.
+/// to downsample them. Each subscription owns its transform, which keeps its
+/// state behind interior mutability as it may be invoked concurrently.
+pub trait PayloadTransform: Send + Sync {
+    /// Return the payload to deliver, possibly modified, or `None` to drop
+    /// the data for this subscription.
+    fn transform(&self, info: &Option<DataInfo>, payload: &RBuf) -> Option<RBuf>;
+}
 
-            get_mut_unchecked(face).local_subs.retain(|sub| sub != res);
-        }
-    }
+/// The reason why data pushed to a reliable subscription were not delivered,
+/// see `SubOptions::on_delivery_error`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryError {
+    /// The transport failed to deliver the data.
+    Failed,
+    /// The send of the data didn't complete within the send timeout of the
+    /// face.
+    TimedOut,
+    /// The data were dropped before reaching the transport, the face being
+    /// paused, quarantined or closing.
+    Dropped,
 }
 
-async fn propagate_forget_sourced_subscription(
-    tables: &Tables,
-    res: &Arc<Resource>,
-    src_face: Option<&Arc<FaceState>>,
-    source: &PeerId,
-    net_type: whatami::Type,
-) {
-    let net = tables.get_net(net_type).unwrap();
-    match net.get_idx(source) {
-        Some(tree_sid) => {
-            send_forget_sourced_subscription_to_net_childs(
-                tables,
-                net,
-                &net.trees[tree_sid.index()].childs,
-                res,
-                src_face,
-                Some(tree_sid.index() as ZInt),
-            )
-            .await;
-        }
-        None => log::error!(
-            "Error propagating sub {}: cannot get index of {}!",
-            res.name(),
-            source
-        ),
-    }
+/// The handler of the data the router ultimately failed to deliver to a
+/// reliable client subscription, e.g. to trigger a recovery.
+pub trait DeliveryErrorHandler: Send + Sync {
+    /// Called with the key the data were sent with to the face.
+    fn on_delivery_error(&self, reskey: &ResKey, error: DeliveryError);
 }
 
-async fn unregister_router_subscription(
-    tables: &mut Tables,
-    res: &mut Arc<Resource>,
-    router: &PeerId,
-) {
-    log::debug!(
-        "Unregister router subscription {} (router: {})",
-        res.name(),
-        router
-    );
-    get_mut_unchecked(res)
-        .context_mut()
-        .router_subs
-        .retain(|sub| sub != router);
+/// A converter of the payloads from an encoding to another, registered with
+/// `Tables::add_encoding_converter`.
+pub trait EncodingConverter: Send + Sync {
+    /// Return the converted payload, or `None` if it can't be converted.
+    fn convert(&self, payload: &RBuf) -> Option<RBuf>;
+}
 
-    if res.context().router_subs.is_empty() {
-        tables.router_subs.retain(|sub| !Arc::ptr_eq(sub, &res));
+/// What happens to the data pushed to a subscription that don't have its
+/// accepted encoding (see `SubOptions::accepted_encoding`) and that no
+/// converter converts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnconvertedPolicy {
+    /// Deliver the data as they are.
+    Deliver,
+    /// Drop the data for this subscription.
+    Drop,
+}
 
-        undeclare_peer_subscription(tables, None, res, &tables.pid.clone()).await;
-        propagate_forget_simple_subscription(tables, res).await;
+impl Default for UnconvertedPolicy {
+    fn default() -> Self {
+        UnconvertedPolicy::Deliver
     }
 }
 
-async fn undeclare_router_subscription(
-    tables: &mut Tables,
-    face: Option<&Arc<FaceState>>,
-    res: &mut Arc<Resource>,
-    router: &PeerId,
-) {
-    if res.context().router_subs.contains(router) {
-        unregister_router_subscription(tables, res, router).await;
-        propagate_forget_sourced_subscription(tables, res, face, router, whatami::ROUTER).await;
+/// The bound of the history replayed to a new subscription, see
+/// `SubOptions::history`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryBound {
+    /// The latest samples, at most this number of them.
+    Count(usize),
+    /// The samples no older than this.
+    Since(Duration),
+}
+
+/// An observer of the routing loops, registered with
+/// `Tables::set_loop_detection`.
+pub trait LoopObserver: Send + Sync {
+    /// Called when the data received from `face` for the given key is a
+    /// sample already routed within the loop detection window. The data are
+    /// dropped.
+    fn loop_detected(&self, face: &FaceState, key: &str, source_id: &PeerId, source_sn: ZInt);
+}
+
+/// A custom routing policy, e.g. a geo-aware forwarding, registered with
+/// `Tables::set_routing_policy`. It is consulted for each routed data once the
+/// built-in rules computed its route, and can only further restrict it.
+pub trait RoutingPolicy: Send + Sync {
+    /// Whether the data routed from `src_face` for the given key are
+    /// forwarded to `dst_face`.
+    fn allow_forward(&self, _src_face: &FaceState, _dst_face: &FaceState, _resname: &str) -> bool {
+        true
+    }
+
+    /// The reliability of the data routed from `src_face` to `dst_face` for
+    /// the given key, given the reliability chosen by the built-in rules.
+    fn adjust_reliability(
+        &self,
+        _src_face: &FaceState,
+        _dst_face: &FaceState,
+        _resname: &str,
+        reliability: Reliability,
+    ) -> Reliability {
+        reliability
     }
 }
 
-pub async fn forget_router_subscription(
-    tables: &mut Tables,
-    face: &mut Arc<FaceState>,
-    prefixid: ZInt,
-    suffix: &str,
-    router: &PeerId,
-) {
-    match tables.get_mapping(&face, &prefixid) {
-        Some(prefix) => match Resource::get_resource(prefix, suffix) {
-            Some(mut res) => {
-                undeclare_router_subscription(tables, Some(face), &mut res, router).await;
-                Resource::clean(&mut res)
+/// What happens to the value of a new key routed to a pull subscription whose
+/// cache already holds `SubOptions::max_cached_keys` keys. The values of the
+/// cached keys are always updated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheFullPolicy {
+    /// Evict the least recently cached key.
+    EvictOldest,
+    /// Drop the value of the new key, preserving the cached ones.
+    RejectNew,
+    /// Evict the keys cached longer than this ago, then the least recently
+    /// cached one if none of them was.
+    EvictExpiredFirst(Duration),
+}
+
+impl Default for CacheFullPolicy {
+    fn default() -> Self {
+        CacheFullPolicy::EvictOldest
+    }
+}
+
+/// A storage holding the history of the data, e.g. provided by a storage
+/// plugin, registered with `Tables::set_history_storage`.
+pub trait HistoryStorage: Send + Sync {
+    /// The stored samples whose key matches the given key expression, within
+    /// the bound, as key, info and payload, from the oldest to the latest.
+    fn history(&self, key_expr: &str, bound: HistoryBound)
+        -> Vec<(String, Option<DataInfo>, RBuf)>;
+}
+
+/// A summary of the subscriptions matching a key, so that a publisher can
+/// adapt its send parameters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubProfile {
+    /// The strongest reliability requested by the subscribers, `None` if there
+    /// is no subscriber.
+    pub reliability: Option<Reliability>,
+    /// Whether some of the subscribers pull the data.
+    pub has_pull: bool,
+}
+
+/// Router side options of a client subscription.
+#[derive(Clone, Default)]
+pub struct SubOptions {
+    /// Deliver the retained values matching the subscription, both the local
+    /// ones and the ones held by the directly connected routers and peers.
+    pub query_retained: bool,
+    /// Deliver the values cached for a pull subscription in a single batch
+    /// rather than one by one.
+    pub batch_pulls: bool,
+    /// Only deliver the data whose `DataInfo` matches this predicate.
+    pub data_filter: Option<DataInfoPredicate>,
+    /// Only deliver the data originating from this peer: either published by
+    /// it (`DataInfo::source_id`) or entered into the routers network through
+    /// it (`DataInfo::first_router_id`). Data without `DataInfo` are dropped.
+    pub source_filter: Option<PeerId>,
+    /// Drop rather than deliver the data whose timestamp is older than this.
+    /// Data without timestamp are always delivered.
+    pub max_staleness: Option<Duration>,
+    /// A token identifying the declaration: once accepted, a declaration
+    /// resent by the face with the same token is ignored and returns the
+    /// outcome of the accepted one, see `Tables::set_idempotency_tokens`.
+    pub idempotency_token: Option<u64>,
+    /// Transform the data pushed to the subscription. It is invoked on the
+    /// routing path for each routed data, after the data filter, so it should
+    /// be cheap. The routes of the matching keys are then filtered for each
+    /// routed data, and a modified payload is delivered as a separate copy.
+    pub transform: Option<Arc<dyn PayloadTransform>>,
+    /// Also deliver the data published by the face of the subscription
+    /// itself, which are never echoed back by default.
+    pub receive_own: bool,
+    /// Route the data as if the subscription existed, counting the data it
+    /// would receive in `RoutingMetrics::shadow_deliveries`, but never
+    /// deliver them. A face is still served through its other subscriptions.
+    pub shadow: bool,
+    /// The transmission lane of the data pushed to the subscription. A face
+    /// with several subscriptions matching the routed key is served on the
+    /// lane of the highest priority.
+    pub priority: Priority,
+    /// Send an empty sample of kind `data_kind::KEEP_ALIVE` to the
+    /// subscription when no data was delivered to it for this interval, so
+    /// that the subscriber knows its route is alive. It is sent like the data
+    /// of the subscription, but not while the face is paused, and is not
+    /// passed to the callbacks of the `net::Session` subscribers.
+    pub heartbeat: Option<Duration>,
+    /// Undeclare the subscription, notifying the face with
+    /// `Primitives::forced_forget_subscriber`, unless it is declared again
+    /// within this duration, see `Tables::expire_subscriptions`.
+    pub lease: Option<Duration>,
+    /// Deliver the history held by the storage of the tables, if any (see
+    /// `Tables::set_history_storage`), within this bound when the
+    /// subscription is declared, before any live data.
+    pub history: Option<HistoryBound>,
+    /// Cache at most this number of keys for a pull subscription, handling
+    /// the new keys with `cache_full` once full. Unbounded by default.
+    pub max_cached_keys: Option<usize>,
+    pub cache_full: CacheFullPolicy,
+    /// Convert the data pushed to the subscription to this encoding with the
+    /// converters of the tables, before its transform. The data no converter
+    /// converts are handled with `unconverted`.
+    pub accepted_encoding: Option<ZInt>,
+    pub unconverted: UnconvertedPolicy,
+    /// Skip the data whose payload is identical to the last one delivered to
+    /// the subscription within the window of the deduplication.
+    pub dedup: Option<Dedup>,
+    /// Track whether the transport acknowledged the data pushed to the
+    /// subscription, see `Tables::delivery_stats`.
+    pub track_acks: bool,
+    /// Buffer the data pushed to the subscription and deliver them in
+    /// batches, in order, see `Tables::flush_batches`.
+    pub batching: Option<Batching>,
+    /// Deliver the data pushed to the subscription in the order of the
+    /// sequence numbers of their source, see `Tables::flush_reorders`.
+    pub reordering: Option<Reordering>,
+    /// Notify the data the router ultimately failed to deliver to the
+    /// subscription if it is reliable. Never called for a best effort one.
+    pub on_delivery_error: Option<Arc<dyn DeliveryErrorHandler>>,
+}
+
+/// The batching of the data pushed to a subscription. A batch is delivered
+/// once it holds `max_samples` data, or with the next data or flush of the
+/// batches once `window` elapsed since its first data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Batching {
+    pub window: Duration,
+    pub max_samples: usize,
+}
+
+/// The reordering of the data pushed to a subscription by their
+/// `DataInfo::source_sn`, for each `DataInfo::source_id`. The data received
+/// ahead of a gap are buffered until the gap is filled. The gap is skipped
+/// once more than `max_window` data of the source are buffered, or with the
+/// next data of the source or flush of the reorderings once `timeout`
+/// elapsed since it was detected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reordering {
+    pub max_window: usize,
+    pub timeout: Duration,
+}
+
+/// The deduplication of the data pushed to a subscription, by hash of their
+/// payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dedup {
+    /// The time during which a delivered payload is remembered.
+    pub window: Duration,
+    /// Compare the payload with the last one delivered for the same key,
+    /// rather than with the last one delivered to the subscription for any
+    /// of its keys.
+    pub by_key: bool,
+}
+
+/// A future returned by the declaration of a client subscription, resolving
+/// once the data routes matching the subscription are built, so that the data
+/// routed afterwards reach it. It resolves at once unless the rebuilds of the
+/// routes are deferred (see `Tables::defer_rebuilds`), in which case it
+/// resolves when they are flushed, or when the tables are dropped.
+#[derive(Default)]
+pub struct SubscriptionReady(Option<oneshot::Receiver<()>>);
+
+impl SubscriptionReady {
+    fn new(tables: &mut Tables) -> SubscriptionReady {
+        match tables.deferred_rebuilds {
+            Some(_) => {
+                let (sender, receiver) = oneshot::channel();
+                tables.rebuild_waiters.push(sender);
+                SubscriptionReady(Some(receiver))
             }
-            None => log::error!("Undeclare unknown router subscription!"),
-        },
-        None => log::error!("Undeclare router subscription with unknown prefix!"),
+            None => SubscriptionReady(None),
+        }
+    }
+
+    /// Whether the routes of the subscription are already built.
+    pub fn is_ready(&self) -> bool {
+        self.0.is_none()
     }
 }
 
-async fn unregister_peer_subscription(tables: &mut Tables, res: &mut Arc<Resource>, peer: &PeerId) {
-    log::debug!(
-        "Unregister peer subscription {} (peer: {})",
-        res.name(),
-        peer
-    );
-    get_mut_unchecked(res)
-        .context_mut()
-        .peer_subs
-        .retain(|sub| sub != peer);
+impl Future for SubscriptionReady {
+    type Output = ();
 
-    if res.context().peer_subs.is_empty() {
-        tables.peer_subs.retain(|sub| !Arc::ptr_eq(sub, &res));
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.0.as_mut() {
+            Some(receiver) => match receiver.poll_unpin(cx) {
+                Poll::Ready(_) => {
+                    self.0 = None;
+                    Poll::Ready(())
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            None => Poll::Ready(()),
+        }
     }
 }
 
-async fn undeclare_peer_subscription(
-    tables: &mut Tables,
-    face: Option<&Arc<FaceState>>,
-    res: &mut Arc<Resource>,
-    peer: &PeerId,
-) {
-    if res.context().peer_subs.contains(&peer) {
-        unregister_peer_subscription(tables, res, peer).await;
-        propagate_forget_sourced_subscription(tables, res, face, peer, whatami::PEER).await;
+/// The outcome of an accepted subscription declaration, returned by all the
+/// subscription declaration functions. What else the callers need to know
+/// about a declaration is added here rather than to their signature.
+#[non_exhaustive]
+pub struct DeclareOutcome {
+    /// The complete name of the resource the subscription was declared on.
+    pub resname: String,
+    /// Whether the declaration registered a new subscription of the face, or
+    /// of the router or peer it was declared for, rather than updating an
+    /// existing one.
+    pub created: bool,
+    /// Resolves once the data routes matching the subscription are built.
+    pub ready: SubscriptionReady,
+}
+
+impl DeclareOutcome {
+    fn new(tables: &mut Tables, res: &Resource, created: bool) -> DeclareOutcome {
+        DeclareOutcome {
+            resname: res.name(),
+            created,
+            ready: SubscriptionReady::new(tables),
+        }
     }
 }
 
-pub async fn forget_peer_subscription(
+/// The memory used by the outbound buffers, as reported by the transport, and
+/// the high watermark above which the data routed to the faces that have no
+/// reliable subscription are dropped (see `Tables::set_memory_pressure`).
+#[derive(Debug)]
+pub struct MemoryPressure {
+    used: AtomicUsize,
+    high_watermark: usize,
+}
+
+impl MemoryPressure {
+    pub fn new(high_watermark: usize) -> MemoryPressure {
+        MemoryPressure {
+            used: AtomicUsize::new(0),
+            high_watermark,
+        }
+    }
+
+    /// Report the number of bytes currently used by the outbound buffers.
+    pub fn set_used(&self, bytes: usize) {
+        self.used.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn is_high(&self) -> bool {
+        self.used() > self.high_watermark
+    }
+}
+
+/// The dampening of the subscriptions that a face keeps declaring and
+/// undeclaring: after `max_cycles` undeclarations of the same subscription
+/// within `window`, its declarations and undeclarations are still registered
+/// locally but only propagated once `hold_down` has elapsed.
+#[derive(Debug, Clone)]
+pub struct FlapDampening {
+    pub max_cycles: usize,
+    pub window: Duration,
+    pub hold_down: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct FlapState {
+    cycles: VecDeque<Instant>,
+    held_until: Option<Instant>,
+    propagated: bool,
+}
+
+impl FlapState {
+    #[inline]
+    pub(crate) fn held_until(&self) -> Option<Instant> {
+        self.held_until
+    }
+}
+
+/// Record a declaration or undeclaration of the subscription of `face` on
+/// `res` and return true if its propagation must be held down.
+fn dampen_propagation(
     tables: &mut Tables,
-    face: &mut Arc<FaceState>,
-    prefixid: ZInt,
-    suffix: &str,
-    peer: &PeerId,
-) {
-    match tables.get_mapping(&face, &prefixid) {
-        Some(prefix) => match Resource::get_resource(prefix, suffix) {
-            Some(mut res) => {
-                undeclare_peer_subscription(tables, Some(face), &mut res, peer).await;
+    face: &FaceState,
+    res: &Arc<Resource>,
+    declared: bool,
+) -> bool {
+    let dampening = match &tables.flap_dampening {
+        Some(dampening) => dampening.clone(),
+        None => return false,
+    };
+    let now = Instant::now();
+    let state = tables
+        .flap_states
+        .entry((face.id, res.name()))
+        .or_insert_with(FlapState::default);
+    if let Some(held_until) = state.held_until {
+        if now < held_until {
+            tables
+                .metrics
+                .dampened_sub_propagations
+                .fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+    }
+    state.held_until = None;
+    state.propagated = declared;
+    if !declared {
+        state.cycles.push_back(now);
+        while let Some(cycle) = state.cycles.front() {
+            if now.duration_since(*cycle) > dampening.window {
+                state.cycles.pop_front();
+            } else {
+                break;
+            }
+        }
+        if state.cycles.len() >= dampening.max_cycles {
+            log::warn!(
+                "Subscription {} of {} is flapping: hold down its propagation for {:?}",
+                res.name(),
+                face,
+                dampening.hold_down
+            );
+            state.cycles.clear();
+            state.held_until = Some(now + dampening.hold_down);
+        }
+    }
+    false
+}
 
-                if tables.whatami == whatami::ROUTER
-                    && !res.session_ctxs.values().any(|ctx| ctx.subs.is_some())
-                    && !tables.peer_subs.iter().any(|res| {
-                        res.context()
-                            .peer_subs
-                            .iter()
-                            .any(|peer| peer != &tables.pid)
-                    })
-                {
-                    undeclare_router_subscription(tables, None, &mut res, &tables.pid.clone())
-                        .await;
+/// Propagate the subscriptions whose hold down has elapsed, as declared or
+/// undeclared depending on their current state. Returns the time at which
+/// the next hold down elapses, if any.
+pub(crate) async fn release_dampened_subscriptions(tables: &mut Tables) -> Option<Instant> {
+    let now = Instant::now();
+    let released: Vec<(usize, String)> = tables
+        .flap_states
+        .iter()
+        .filter(|(_, state)| state.held_until.map(|until| until <= now).unwrap_or(false))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for (face_id, name) in released {
+        let state = tables
+            .flap_states
+            .get_mut(&(face_id, name.clone()))
+            .unwrap();
+        state.held_until = None;
+        let propagated = state.propagated;
+        let face = tables.faces.get(&face_id).cloned();
+        let res = Resource::get_resource(&tables.root_res, &name);
+        let sub_info = res
+            .as_ref()
+            .and_then(|res| res.session_ctxs.get(&face_id))
+            .and_then(|ctx| ctx.subs());
+        match (face, res) {
+            (Some(mut face), Some(mut res)) if sub_info.is_some() != propagated => {
+                log::debug!("Release dampened subscription {} of {}", name, face);
+                match sub_info {
+                    Some(sub_info) => {
+                        propagate_client_subscription(tables, &mut face, &mut res, &sub_info).await;
+                        compute_matches_data_routes(tables, &mut res);
+                    }
+                    None => propagate_client_forget(tables, &mut res).await,
+                }
+                if let Some(state) = tables.flap_states.get_mut(&(face_id, name)) {
+                    state.propagated = !propagated;
                 }
+            }
+            _ => (),
+        }
+    }
+    tables.flap_states.retain(|_, state| {
+        state.propagated || state.held_until.is_some() || !state.cycles.is_empty()
+    });
+    tables
+        .flap_states
+        .values()
+        .filter_map(|state| state.held_until)
+        .min()
+}
 
-                Resource::clean(&mut res)
+/// The period of the client subscriptions of a resource, but the one of the
+/// given face if any, requiring the most frequent deliveries: `None` if one
+/// of them has no period.
+fn client_subs_period(res: &Resource, except_face: Option<usize>) -> Option<Period> {
+    let mut fastest: Option<Period> = None;
+    for sub_info in res
+        .session_ctxs
+        .values()
+        .filter(|ctx| ctx.face.whatami == whatami::CLIENT && Some(ctx.face.id) != except_face)
+        .filter_map(|ctx| ctx.subs())
+    {
+        match sub_info.period {
+            Some(period) => {
+                if fastest.map_or(true, |fastest| period.period < fastest.period) {
+                    fastest = Some(period);
+                }
             }
-            None => log::error!("Undeclare unknown peer subscription!"),
-        },
-        None => log::error!("Undeclare peer subscription with unknown prefix!"),
+            None => return None,
+        }
     }
+    fastest
 }
 
-pub(crate) async fn undeclare_client_subscription(
+/// Propagate a client subscription to the routers, peers or clients. The
+/// routers and peers are given the period of the fastest client subscription
+/// on the resource, so the subscription already propagated for other clients
+/// is propagated again when the new one changes this period. The period of
+/// a router subscription also covering peer subscriptions is not changed.
+async fn propagate_client_subscription(
     tables: &mut Tables,
     face: &mut Arc<FaceState>,
     res: &mut Arc<Resource>,
+    sub_info: &SubInfo,
 ) {
-    log::debug!("Unregister client subscription {} for {}", res.name(), face);
-    if let Some(mut ctx) = get_mut_unchecked(res).session_ctxs.get_mut(&face.id) {
-        get_mut_unchecked(&mut ctx).subs = None;
-    }
-    get_mut_unchecked(face)
-        .remote_subs
-        .retain(|x| !Arc::ptr_eq(&x, &res));
-
     match tables.whatami {
         whatami::ROUTER => {
-            if !res.session_ctxs.values().any(|ctx| ctx.subs.is_some())
-                && !tables.peer_subs.iter().any(|res| {
-                    res.context()
-                        .peer_subs
-                        .iter()
-                        .any(|peer| *peer != tables.pid)
-                })
+            let pid = tables.pid.clone();
+            // The router subscription also stands for the subscriptions of
+            // the peers, whose period is not known
+            let peer_subs = res.context().peer_subs.iter().any(|peer| *peer != pid);
+            let mut propa_sub_info = sub_info.clone();
+            propa_sub_info.mode = SubMode::Push;
+            if !peer_subs {
+                propa_sub_info.period = client_subs_period(res, None);
+            }
+            let created =
+                register_router_subscription(tables, face, res, &propa_sub_info, pid.clone()).await;
+            if !created
+                && !peer_subs
+                && propa_sub_info.period != client_subs_period(res, Some(face.id))
             {
-                undeclare_router_subscription(tables, None, res, &tables.pid.clone()).await;
+                propagate_sourced_subscription(
+                    tables,
+                    res,
+                    &propa_sub_info,
+                    Some(face),
+                    &pid,
+                    whatami::ROUTER,
+                )
+                .await;
+                if res.context().peer_subs.contains(&pid) {
+                    propagate_sourced_subscription(
+                        tables,
+                        res,
+                        &propa_sub_info,
+                        Some(face),
+                        &pid,
+                        whatami::PEER,
+                    )
+                    .await;
+                }
             }
         }
         whatami::PEER => {
-            if !res.session_ctxs.values().any(|ctx| ctx.subs.is_some())
-                && !tables.peer_subs.iter().any(|res| {
-                    res.context()
-                        .peer_subs
-                        .iter()
-                        .any(|peer| *peer != tables.pid)
-                })
-            {
-                undeclare_peer_subscription(tables, None, res, &tables.pid.clone()).await;
+            let mut propa_sub_info = sub_info.clone();
+            propa_sub_info.mode = SubMode::Push;
+            propa_sub_info.period = client_subs_period(res, None);
+            let pid = tables.pid.clone();
+            let created =
+                register_peer_subscription(tables, face, res, &propa_sub_info, pid.clone()).await;
+            if !created && propa_sub_info.period != client_subs_period(res, Some(face.id)) {
+                propagate_sourced_subscription(
+                    tables,
+                    res,
+                    &propa_sub_info,
+                    Some(face),
+                    &pid,
+                    whatami::PEER,
+                )
+                .await;
             }
         }
         _ => {
-            if !res.session_ctxs.values().any(|ctx| ctx.subs.is_some()) {
-                propagate_forget_simple_subscription(tables, res).await;
-            }
+            propagate_simple_subscription(tables, res, sub_info, face).await;
         }
     }
+}
 
-    let mut client_subs: Vec<Arc<FaceState>> = res
-        .session_ctxs
-        .values()
-        .filter_map(|ctx| {
-            if ctx.subs.is_some() {
-                Some(ctx.face.clone())
-            } else {
-                None
-            }
-        })
-        .collect();
-    if client_subs.len() == 1
-        && !tables.router_subs.iter().any(|res| {
-            res.context()
-                .peer_subs
-                .iter()
-                .any(|peer| *peer != tables.pid)
-        })
-        && !tables.peer_subs.iter().any(|res| {
-            res.context()
-                .peer_subs
-                .iter()
-                .any(|peer| *peer != tables.pid)
-        })
-    {
-        let face = &mut client_subs[0];
-        if face.local_subs.contains(&res) {
-            let reskey = Resource::get_best_key(&res, "", face.id);
-            face.primitives.forget_subscriber(&reskey, None).await;
+/// A declaration accepted with an idempotency token, whose outcome is
+/// returned again when the face resends it.
+#[derive(Clone)]
+pub(crate) struct TokenDeclaration {
+    at: Instant,
+    resname: String,
+    created: bool,
+}
 
-            get_mut_unchecked(face)
-                .local_subs
-                .retain(|sub| sub != &(*res));
-        }
-    }
+/// The declaration of the given face accepted with the same token within the
+/// idempotency window, if any.
+fn accepted_declaration(tables: &Tables, face: &FaceState, token: u64) -> Option<TokenDeclaration> {
+    tables
+        .idempotency_tokens
+        .get(&(face.id, token))
+        .filter(|declaration| declaration.at.elapsed() < tables.idempotency_window)
+}
 
-    Resource::clean(res)
+/// Record the idempotency token of an accepted declaration of the given face.
+fn record_idempotency_token(
+    tables: &Tables,
+    face: &FaceState,
+    token: u64,
+    outcome: &DeclareOutcome,
+) {
+    tables.idempotency_tokens.insert(
+        (face.id, token),
+        TokenDeclaration {
+            at: Instant::now(),
+            resname: outcome.resname.clone(),
+            created: outcome.created,
+        },
+    );
 }
 
-pub async fn forget_client_subscription(
+pub async fn declare_client_subscription(
     tables: &mut Tables,
     face: &mut Arc<FaceState>,
     prefixid: ZInt,
     suffix: &str,
-) {
-    match tables.get_mapping(&face, &prefixid) {
-        Some(prefix) => match Resource::get_resource(prefix, suffix) {
-            Some(mut res) => {
-                undeclare_client_subscription(tables, face, &mut res).await;
-            }
-            None => log::error!("Undeclare unknown subscription!"),
+    sub_info: &SubInfo,
+) -> Result<DeclareOutcome, RoutingError> {
+    declare_client_subscription_with_options(
+        tables,
+        face,
+        prefixid,
+        suffix,
+        sub_info,
+        &SubOptions::default(),
+    )
+    .await
+}
+
+/// Whether the face reached the subscription limit and cannot declare a
+/// subscription on a resource it doesn't already subscribe to.
+fn exceeds_subs_limit(tables: &Tables, face: &FaceState, res: &Option<Arc<Resource>>) -> bool {
+    match tables.max_subs_per_face {
+        Some(max) if face_subs_count(face) >= max => match res {
+            Some(res) => !face.remote_subs.iter().any(|sub| Arc::ptr_eq(sub, res)),
+            None => true,
         },
-        None => log::error!("Undeclare subscription with unknown prefix!"),
+        _ => false,
     }
 }
 
-pub(crate) async fn pubsub_new_client_face(tables: &mut Tables, face: &mut Arc<FaceState>) {
-    let sub_info = SubInfo {
-        reliability: Reliability::Reliable, // TODO
-        mode: SubMode::Push,
-        period: None,
+pub async fn declare_client_subscription_with_options(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    prefixid: ZInt,
+    suffix: &str,
+    sub_info: &SubInfo,
+    options: &SubOptions,
+) -> Result<DeclareOutcome, RoutingError> {
+    if let Some(token) = options.idempotency_token {
+        if let Some(declaration) = accepted_declaration(tables, face, token) {
+            log::debug!(
+                "Ignore resent subscription {} {} for {} (token {})",
+                prefixid,
+                suffix,
+                face,
+                token
+            );
+            tables
+                .metrics
+                .resent_declarations_ignored
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(DeclareOutcome {
+                resname: declaration.resname,
+                created: declaration.created,
+                ready: SubscriptionReady::new(tables),
+            });
+        }
+    }
+    let result = match tables.get_mapping(&face, &prefixid).cloned() {
+        Some(mut prefix) => {
+            if exceeds_subs_limit(tables, face, &Resource::get_resource(&prefix, suffix)) {
+                let max = tables.max_subs_per_face.unwrap_or_default();
+                log::warn!(
+                    "Reject subscription {}{} of {}: limit of {} subscriptions reached",
+                    prefix.name(),
+                    suffix,
+                    face,
+                    max
+                );
+                if tables.close_on_subs_limit {
+                    face.primitives.send_close().await;
+                }
+                return Err(RoutingError::SubscriptionLimit(max));
+            }
+            let mut res = Resource::make_resource(tables, &mut prefix, suffix);
+            Resource::match_resource(&tables, &mut res);
+            subscribe_client_resource(tables, face, &mut res, sub_info, options).await
+        }
+        None => {
+            log::error!("Declare subscription for unknown rid {}!", prefixid);
+            Err(RoutingError::UnknownPrefix(prefixid))
+        }
     };
-    for sub in &tables.router_subs {
-        get_mut_unchecked(face).local_subs.push(sub.clone());
-        let reskey = Resource::decl_key(&sub, face).await;
-        face.primitives
-            .decl_subscriber(&reskey, &sub_info, None)
-            .await;
+    if let (Some(token), Ok(outcome)) = (options.idempotency_token, &result) {
+        record_idempotency_token(tables, face, token, outcome);
     }
+    result
 }
 
-pub(crate) async fn pubsub_remove_node(
+async fn subscribe_client_resource(
     tables: &mut Tables,
-    node: &PeerId,
-    net_type: whatami::Type,
-) {
-    match net_type {
-        whatami::ROUTER => {
-            for mut res in tables
-                .router_subs
-                .iter()
-                .filter(|res| res.context().router_subs.contains(node))
-                .cloned()
-                .collect::<Vec<Arc<Resource>>>()
-            {
-                unregister_router_subscription(tables, &mut res, node).await;
-                Resource::clean(&mut res)
+    face: &mut Arc<FaceState>,
+    res: &mut Arc<Resource>,
+    sub_info: &SubInfo,
+    options: &SubOptions,
+) -> Result<DeclareOutcome, RoutingError> {
+    let created = register_client_subscription(tables, face, res, sub_info, options).await?;
+    res.trace(format_args!(
+        "Declare subscription {} of {} ({:?})",
+        res.name(),
+        face,
+        sub_info
+    ));
+    if !dampen_propagation(tables, face, res, true) {
+        propagate_client_subscription(tables, face, res, sub_info).await;
+    }
+
+    compute_matches_data_routes(tables, res);
+    notify_sub_change(tables, res, face, whatami::CLIENT, SubChange::Declared);
+
+    if options.query_retained {
+        send_retained_values(tables, face, res, sub_info).await;
+        query_retained_values(tables, face, res).await;
+    }
+    if let Some(bound) = options.history {
+        send_history_values(tables, face, res, sub_info, bound).await;
+    }
+    Ok(DeclareOutcome::new(tables, res, created))
+}
+
+/// Declare a single logical subscription covering several key expressions,
+/// all relative to the same prefix, and return the handle of this alias
+/// group. The group counts as one subscription towards the subscription limit
+/// of the face, and is undeclared at once with
+/// [`forget_client_subscription_alias`].
+pub async fn declare_client_subscription_alias(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    prefixid: ZInt,
+    suffixes: &[&str],
+    sub_info: &SubInfo,
+    options: &SubOptions,
+) -> Result<ZInt, RoutingError> {
+    let mut prefix = match tables.get_mapping(&face, &prefixid).cloned() {
+        Some(prefix) => prefix,
+        None => {
+            log::error!("Declare subscription alias for unknown rid {}!", prefixid);
+            return Err(RoutingError::UnknownPrefix(prefixid));
+        }
+    };
+    if let Some(max) = tables.max_subs_per_face {
+        if face_subs_count(face) >= max {
+            log::warn!(
+                "Reject subscription alias {:?} of {}: limit of {} subscriptions reached",
+                suffixes,
+                face,
+                max
+            );
+            if tables.close_on_subs_limit {
+                face.primitives.send_close().await;
             }
+            return Err(RoutingError::SubscriptionLimit(max));
         }
-        whatami::PEER => {
-            for mut res in tables
-                .peer_subs
-                .iter()
-                .filter(|res| res.context().peer_subs.contains(node))
-                .cloned()
-                .collect::<Vec<Arc<Resource>>>()
-            {
-                unregister_peer_subscription(tables, &mut res, node).await;
+    }
+    // Only the subscriptions created by the alias belong to the group, those
+    // the face already declared on its own are left to it
+    let mut group = Vec::with_capacity(suffixes.len());
+    for suffix in suffixes {
+        let mut res = Resource::make_resource(tables, &mut prefix, suffix);
+        Resource::match_resource(&tables, &mut res);
+        match subscribe_client_resource(tables, face, &mut res, sub_info, options).await {
+            Ok(outcome) => {
+                if outcome.created {
+                    group.push(res);
+                }
+            }
+            Err(e) => {
+                for mut res in group {
+                    undeclare_client_subscription(tables, face, &mut res).await;
+                }
+                return Err(e);
+            }
+        }
+    }
+    let face_mut = get_mut_unchecked(face);
+    let alias = face_mut.next_alias;
+    face_mut.next_alias += 1;
+    face_mut.sub_aliases.insert(alias, group);
+    Ok(alias)
+}
+
+/// Undeclare all the subscriptions of an alias group declared with
+/// [`declare_client_subscription_alias`]. The subscriptions the face declared
+/// on its own before the alias are kept.
+pub async fn forget_client_subscription_alias(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    alias: ZInt,
+) -> Result<(), RoutingError> {
+    match get_mut_unchecked(face).sub_aliases.remove(&alias) {
+        Some(group) => {
+            for mut res in group {
+                if face.remote_subs.iter().any(|sub| Arc::ptr_eq(sub, &res)) {
+                    undeclare_client_subscription(tables, face, &mut res).await;
+                } else {
+                    // Already undeclared on its own
+                    tables.clean_resource(&mut res);
+                }
+            }
+            Ok(())
+        }
+        None => {
+            log::error!("Undeclare unknown subscription alias {}!", alias);
+            Err(RoutingError::UnknownSubscription(alias.to_string()))
+        }
+    }
+}
+
+/// The number of subscriptions of the face, each alias group counting as a
+/// single subscription.
+fn face_subs_count(face: &FaceState) -> usize {
+    let grouped = face
+        .remote_subs
+        .iter()
+        .filter(|sub| {
+            face.sub_aliases
+                .values()
+                .any(|group| group.iter().any(|res| Arc::ptr_eq(res, sub)))
+        })
+        .count();
+    face.remote_subs.len() - grouped + face.sub_aliases.len()
+}
+
+#[inline]
+async fn send_forget_sourced_subscription_to_net_childs(
+    tables: &Tables,
+    net: &Network,
+    childs: &[NodeIndex],
+    res: &Arc<Resource>,
+    src_face: Option<&Arc<FaceState>>,
+    routing_context: Option<RoutingContext>,
+) {
+    for child in childs {
+        if net.graph.contains_node(*child) {
+            match tables.get_face(&net.graph[*child].pid).cloned() {
+                Some(mut someface) => {
+                    if (src_face.is_none() || someface.id != src_face.unwrap().id)
+                        && tables.propagates_subs_to(&someface)
+                    {
+                        let reskey = Resource::decl_key(res, &mut someface).await;
+
+                        log::debug!("Send forget subscription {} on {}", res.name(), someface);
+
+                        someface
+                            .primitives
+                            .forget_subscriber(&reskey, routing_context)
+                            .await;
+                    }
+                }
+                None => {
+                    log::trace!("Unable to find face for pid {}", net.graph[*child].pid)
+                }
+            }
+        }
+    }
+}
+
+async fn propagate_forget_simple_subscription(tables: &mut Tables, res: &Arc<Resource>) {
+    for face in tables.faces.values_mut() {
+        if face.local_subs.contains(res) {
+            let reskey = Resource::get_face_key(res, "", face);
+            face.primitives.forget_subscriber(&reskey, None).await;
+
+            get_mut_unchecked(face).local_subs.retain(|sub| sub != res);
+        }
+    }
+}
+
+async fn propagate_forget_sourced_subscription(
+    tables: &Tables,
+    res: &Arc<Resource>,
+    src_face: Option<&Arc<FaceState>>,
+    source: &PeerId,
+    net_type: whatami::Type,
+) {
+    let net = match tables.get_net(net_type) {
+        Some(net) => net,
+        None => {
+            log::debug!(
+                "Skip the propagation of the undeclaration of sub {}: network not initialized",
+                res.name()
+            );
+            return;
+        }
+    };
+    match net.get_idx(source) {
+        Some(tree_sid) => {
+            send_forget_sourced_subscription_to_net_childs(
+                tables,
+                net,
+                &net.trees[tree_sid.index()].childs,
+                res,
+                src_face,
+                Some(tree_sid.index() as ZInt),
+            )
+            .await;
+        }
+        None => log::error!(
+            "Error propagating sub {}: cannot get index of {}!",
+            res.name(),
+            source
+        ),
+    }
+}
+
+async fn unregister_router_subscription(
+    tables: &mut Tables,
+    res: &mut Arc<Resource>,
+    router: &PeerId,
+) {
+    log::debug!(
+        "Unregister router subscription {} (router: {})",
+        res.name(),
+        router
+    );
+    get_mut_unchecked(res)
+        .context_mut()
+        .router_subs
+        .retain(|sub| sub != router);
+
+    if res.context().router_subs.is_empty() {
+        tables.router_subs.retain(|sub| !Arc::ptr_eq(sub, &res));
+
+        undeclare_peer_subscription(tables, None, res, &tables.pid.clone()).await;
+        propagate_forget_simple_subscription(tables, res).await;
+    }
+}
+
+async fn undeclare_router_subscription(
+    tables: &mut Tables,
+    face: Option<&Arc<FaceState>>,
+    res: &mut Arc<Resource>,
+    router: &PeerId,
+) {
+    if res.context().router_subs.contains(router) {
+        unregister_router_subscription(tables, res, router).await;
+        propagate_forget_sourced_subscription(tables, res, face, router, whatami::ROUTER).await;
+    }
+}
+
+pub async fn forget_router_subscription(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    prefixid: ZInt,
+    suffix: &str,
+    router: &PeerId,
+) {
+    match tables.get_mapping(&face, &prefixid) {
+        Some(prefix) => match Resource::get_resource(prefix, suffix) {
+            Some(mut res) => {
+                undeclare_router_subscription(tables, Some(face), &mut res, router).await;
+                notify_sub_change(tables, &res, face, whatami::ROUTER, SubChange::Undeclared);
+                tables.clean_resource(&mut res)
+            }
+            None => log::error!("Undeclare unknown router subscription!"),
+        },
+        None => log::error!("Undeclare router subscription with unknown prefix!"),
+    }
+}
+
+async fn unregister_peer_subscription(tables: &mut Tables, res: &mut Arc<Resource>, peer: &PeerId) {
+    log::debug!(
+        "Unregister peer subscription {} (peer: {})",
+        res.name(),
+        peer
+    );
+    get_mut_unchecked(res)
+        .context_mut()
+        .peer_subs
+        .retain(|sub| sub != peer);
+    get_mut_unchecked(res)
+        .context_mut()
+        .peer_sub_reliabilities
+        .remove(peer);
+
+    if res.context().peer_subs.is_empty() {
+        tables.peer_subs.retain(|sub| !Arc::ptr_eq(sub, &res));
+    }
+}
+
+async fn undeclare_peer_subscription(
+    tables: &mut Tables,
+    face: Option<&Arc<FaceState>>,
+    res: &mut Arc<Resource>,
+    peer: &PeerId,
+) {
+    if res.context().peer_subs.contains(&peer) {
+        unregister_peer_subscription(tables, res, peer).await;
+        propagate_forget_sourced_subscription(tables, res, face, peer, whatami::PEER).await;
+    }
+}
+
+pub async fn forget_peer_subscription(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    prefixid: ZInt,
+    suffix: &str,
+    peer: &PeerId,
+) {
+    match tables.get_mapping(&face, &prefixid) {
+        Some(prefix) => match Resource::get_resource(prefix, suffix) {
+            Some(mut res) => {
+                undeclare_peer_subscription(tables, Some(face), &mut res, peer).await;
 
                 if tables.whatami == whatami::ROUTER
-                    && !res.session_ctxs.values().any(|ctx| ctx.subs.is_some())
+                    && !res.session_ctxs.values().any(|ctx| ctx.has_subs())
                     && !tables.peer_subs.iter().any(|res| {
                         res.context()
                             .peer_subs
@@ -665,488 +1718,2542 @@ pub(crate) async fn pubsub_remove_node(
                         .await;
                 }
 
-                Resource::clean(&mut res)
+                notify_sub_change(tables, &res, face, whatami::PEER, SubChange::Undeclared);
+                tables.clean_resource(&mut res)
             }
-        }
-        _ => (),
+            None => log::error!("Undeclare unknown peer subscription!"),
+        },
+        None => log::error!("Undeclare peer subscription with unknown prefix!"),
     }
 }
 
-pub(crate) async fn pubsub_tree_change(
-    tables: &mut Tables,
-    new_childs: &[Vec<NodeIndex>],
-    net_type: whatami::Type,
-) {
-    // propagate subs to new childs
-    for (tree_sid, tree_childs) in new_childs.iter().enumerate() {
-        if !tree_childs.is_empty() {
-            let net = tables.get_net(net_type).unwrap();
-            let tree_idx = NodeIndex::new(tree_sid);
-            if net.graph.contains_node(tree_idx) {
-                let tree_id = net.graph[tree_idx].pid.clone();
-
-                let subs_res = match net_type {
-                    whatami::ROUTER => &tables.router_subs,
-                    _ => &tables.peer_subs,
-                };
-
-                for res in subs_res {
-                    let subs = match net_type {
-                        whatami::ROUTER => &res.context().router_subs,
-                        _ => &res.context().peer_subs,
-                    };
-                    for sub in subs {
+/// Propagate the undeclaration of a client subscription to the routers, peers
+/// or clients, unless other subscriptions remain.
+async fn propagate_client_forget(tables: &mut Tables, res: &mut Arc<Resource>) {
+    match tables.whatami {
+        whatami::ROUTER => {
+            if !res.session_ctxs.values().any(|ctx| ctx.has_subs())
+                && !tables.peer_subs.iter().any(|res| {
+                    res.context()
+                        .peer_subs
+                        .iter()
+                        .any(|peer| *peer != tables.pid)
+                })
+            {
+                undeclare_router_subscription(tables, None, res, &tables.pid.clone()).await;
+            }
+        }
+        whatami::PEER => {
+            if !res.session_ctxs.values().any(|ctx| ctx.has_subs())
+                && !tables.peer_subs.iter().any(|res| {
+                    res.context()
+                        .peer_subs
+                        .iter()
+                        .any(|peer| *peer != tables.pid)
+                })
+            {
+                undeclare_peer_subscription(tables, None, res, &tables.pid.clone()).await;
+            }
+        }
+        _ => {
+            if !res.session_ctxs.values().any(|ctx| ctx.has_subs()) {
+                propagate_forget_simple_subscription(tables, res).await;
+            }
+        }
+    }
+}
+
+pub(crate) async fn undeclare_client_subscription(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    res: &mut Arc<Resource>,
+) {
+    log::debug!("Unregister client subscription {} for {}", res.name(), face);
+    res.trace(format_args!(
+        "Undeclare subscription {} of {}",
+        res.name(),
+        face
+    ));
+    if let Some(ctx) = res.session_ctxs.get(&face.id) {
+        ctx.clear_subs();
+    }
+    get_mut_unchecked(face)
+        .remote_subs
+        .retain(|x| !Arc::ptr_eq(&x, &res));
+    get_mut_unchecked(face)
+        .sub_leases
+        .retain(|(sub, _)| !Arc::ptr_eq(sub, res));
+    let weak = Arc::downgrade(res);
+    face.heartbeats
+        .lock()
+        .unwrap()
+        .retain(|heartbeat| !heartbeat.res.ptr_eq(&weak));
+    notify_sub_change(tables, res, face, whatami::CLIENT, SubChange::Undeclared);
+
+    if !dampen_propagation(tables, face, res, false) {
+        propagate_client_forget(tables, res).await;
+    }
+
+    let mut client_subs: Vec<Arc<FaceState>> = res
+        .session_ctxs
+        .values()
+        .filter_map(|ctx| {
+            if ctx.has_subs() {
+                Some(ctx.face.clone())
+            } else {
+                None
+            }
+        })
+        .collect();
+    if client_subs.len() == 1
+        && !tables.router_subs.iter().any(|res| {
+            res.context()
+                .peer_subs
+                .iter()
+                .any(|peer| *peer != tables.pid)
+        })
+        && !tables.peer_subs.iter().any(|res| {
+            res.context()
+                .peer_subs
+                .iter()
+                .any(|peer| *peer != tables.pid)
+        })
+    {
+        let face = &mut client_subs[0];
+        if face.local_subs.contains(&res) {
+            let reskey = Resource::get_face_key(&res, "", face);
+            face.primitives.forget_subscriber(&reskey, None).await;
+
+            get_mut_unchecked(face)
+                .local_subs
+                .retain(|sub| sub != &(*res));
+        }
+    }
+
+    compute_matches_data_routes(tables, res);
+    tables.clean_resource(res)
+}
+
+pub async fn forget_client_subscription(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    prefixid: ZInt,
+    suffix: &str,
+) {
+    match tables.get_mapping(&face, &prefixid) {
+        Some(prefix) => match Resource::get_resource(prefix, suffix) {
+            Some(mut res) => {
+                undeclare_client_subscription(tables, face, &mut res).await;
+            }
+            None => log::error!("Undeclare unknown subscription!"),
+        },
+        None => log::error!("Undeclare subscription with unknown prefix!"),
+    }
+}
+
+/// Undeclare a subscription of a client face on the router's own initiative,
+/// notifying the face with the reason.
+async fn force_undeclare_client_subscription(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    res: &mut Arc<Resource>,
+    reason: ForcedUndeclare,
+) {
+    log::debug!(
+        "Force undeclare subscription {} of {}: {:?}",
+        res.name(),
+        face,
+        reason
+    );
+    let reskey = Resource::get_face_key(res, "", face);
+    undeclare_client_subscription(tables, face, res).await;
+    face.primitives
+        .forced_forget_subscriber(&reskey, reason)
+        .await;
+}
+
+/// Revoke a subscription of a client face, e.g. because the face is no longer
+/// authorized to subscribe. Unlike [`forget_client_subscription`], the face is
+/// notified that its subscription was removed.
+pub async fn revoke_client_subscription(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    prefixid: ZInt,
+    suffix: &str,
+) -> Result<(), RoutingError> {
+    let prefix = tables
+        .get_mapping(&face, &prefixid)
+        .ok_or(RoutingError::UnknownPrefix(prefixid))?;
+    let name = [&prefix.name(), suffix].concat();
+    match Resource::get_resource(prefix, suffix) {
+        Some(mut res) if face.remote_subs.iter().any(|sub| Arc::ptr_eq(sub, &res)) => {
+            force_undeclare_client_subscription(tables, face, &mut res, ForcedUndeclare::Revoked)
+                .await;
+            Ok(())
+        }
+        _ => Err(RoutingError::UnknownSubscription(name)),
+    }
+}
+
+/// Undeclare the subscriptions whose lease expired, see `SubOptions::lease`.
+/// Returns the time at which the next lease expires, if any.
+pub(crate) async fn expire_subscriptions(tables: &mut Tables) -> Option<Instant> {
+    let now = Instant::now();
+    let expired: Vec<(Arc<FaceState>, Arc<Resource>)> = tables
+        .faces
+        .values()
+        .flat_map(|face| {
+            face.sub_leases
+                .iter()
+                .filter(|(_, deadline)| *deadline <= now)
+                .map(move |(res, _)| (face.clone(), res.clone()))
+        })
+        .collect();
+    for (mut face, mut res) in expired {
+        force_undeclare_client_subscription(
+            tables,
+            &mut face,
+            &mut res,
+            ForcedUndeclare::LeaseExpired,
+        )
+        .await;
+    }
+    next_sub_lease(tables)
+}
+
+/// The time at which the next subscription lease expires, if any.
+pub(crate) fn next_sub_lease(tables: &Tables) -> Option<Instant> {
+    tables
+        .faces
+        .values()
+        .flat_map(|face| face.sub_leases.iter().map(|(_, deadline)| *deadline))
+        .min()
+}
+
+/// Undeclare all the subscriptions of the given face. Each subscription is
+/// torn down independently and its outcome is reported, so that a failing
+/// entry doesn't prevent the others from being cleaned.
+pub async fn undeclare_all_for_face(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+) -> Vec<(String, Result<(), RoutingError>)> {
+    let mut outcomes = vec![];
+    for mut res in face.remote_subs.clone() {
+        let name = res.name();
+        let result = match res.session_ctxs.get(&face.id) {
+            Some(ctx) if ctx.has_subs() => {
+                undeclare_client_subscription(tables, face, &mut res).await;
+                Ok(())
+            }
+            _ => Err(RoutingError::UnknownSubscription(name.clone())),
+        };
+        if let Some(ctx) = res.session_ctxs.get(&face.id) {
+            if ctx.local_rid.is_none() && ctx.remote_rid.is_none() {
+                get_mut_unchecked(&mut res).session_ctxs.remove(&face.id);
+                tables.clean_resource(&mut res);
+            }
+        }
+        outcomes.push((name, result));
+    }
+    get_mut_unchecked(face).remote_subs.clear();
+    get_mut_unchecked(face).sub_aliases.clear();
+    get_mut_unchecked(face).sub_leases.clear();
+    outcomes
+}
+
+pub(crate) async fn pubsub_new_client_face(tables: &mut Tables, face: &mut Arc<FaceState>) {
+    let sub_info = SubInfo {
+        reliability: Reliability::Reliable, // TODO
+        mode: SubMode::Push,
+        period: None,
+    };
+    let mut pending = PendingSubscriptions::default();
+    for sub in &tables.router_subs {
+        get_mut_unchecked(face).local_subs.push(sub.clone());
+        let reskey = Resource::decl_key(&sub, face).await;
+        pending.push(face, reskey, &sub_info, None);
+    }
+    pending.flush().await;
+}
+
+/// Register the resources of the publications known for the peer of the given
+/// face and compute their routes, so that the first data published on them
+/// does not have to build its routes on reception.
+pub(crate) fn pubsub_prefetch_routes(tables: &mut Tables, face: &mut Arc<FaceState>) {
+    if let Some(key_exprs) = tables.known_publications.get(&face.pid).cloned() {
+        for key_expr in key_exprs {
+            let mut root = tables.root_res.clone();
+            let mut res = Resource::make_resource(tables, &mut root, &key_expr);
+            Resource::match_resource(&tables, &mut res);
+            compute_data_routes(tables, &mut res);
+            log::trace!("Prefetched routes of {} for {}", res.name(), face);
+            get_mut_unchecked(face).prefetched.push(res);
+        }
+    }
+}
+
+/// Whether the simple propagation forwards the subscriptions of `src_face` to
+/// `dst_face`, given their roles and the role of the tables.
+#[inline]
+fn simple_propagation_allowed(
+    whatami: whatami::Type,
+    src_face: &FaceState,
+    dst_face: &FaceState,
+) -> bool {
+    match whatami {
+        whatami::ROUTER => dst_face.whatami == whatami::CLIENT,
+        whatami::PEER => dst_face.whatami == whatami::CLIENT,
+        _ => (src_face.whatami == whatami::CLIENT || dst_face.whatami == whatami::CLIENT),
+    }
+}
+
+/// The subscriptions the simple propagation declares to the given face under
+/// its current role: those of the routers for a client of routers or peers
+/// tables, those of the other faces it is allowed to receive for client
+/// tables.
+fn simple_subscriptions_for(tables: &Tables, face: &FaceState) -> Vec<(Arc<Resource>, SubInfo)> {
+    let default_info = SubInfo {
+        reliability: Reliability::Reliable, // TODO
+        mode: SubMode::Push,
+        period: None,
+    };
+    let mut subs: Vec<(Arc<Resource>, SubInfo)> = vec![];
+    if tables.whatami != whatami::CLIENT {
+        if face.whatami == whatami::CLIENT {
+            for sub in &tables.router_subs {
+                subs.push((sub.clone(), default_info.clone()));
+            }
+        }
+        return subs;
+    }
+    for src_face in tables.faces.values() {
+        if src_face.id == face.id || !simple_propagation_allowed(tables.whatami, src_face, face) {
+            continue;
+        }
+        for res in &src_face.remote_subs {
+            if !subs.iter().any(|(sub, _)| Arc::ptr_eq(sub, res)) {
+                let sub_info = res
+                    .session_ctxs
+                    .get(&src_face.id)
+                    .and_then(|ctx| ctx.subs())
+                    .unwrap_or_else(|| default_info.clone());
+                subs.push((res.clone(), sub_info));
+            }
+        }
+    }
+    subs
+}
+
+/// Declare to the given face the subscriptions the simple propagation now
+/// forwards to it and, if `forget`, undeclare those it no longer does.
+async fn reconcile_simple_subscriptions(
+    tables: &Tables,
+    face: &mut Arc<FaceState>,
+    forget: bool,
+) -> (usize, usize) {
+    let wanted = simple_subscriptions_for(tables, face);
+    let stale: Vec<Arc<Resource>> = if forget {
+        face.local_subs
+            .iter()
+            .filter(|res| !wanted.iter().any(|(sub, _)| Arc::ptr_eq(sub, res)))
+            .cloned()
+            .collect()
+    } else {
+        vec![]
+    };
+    for res in &stale {
+        let reskey = Resource::get_face_key(res, "", face);
+        face.primitives.forget_subscriber(&reskey, None).await;
+        get_mut_unchecked(face).local_subs.retain(|sub| sub != res);
+    }
+    let mut declared = 0;
+    for (res, sub_info) in wanted {
+        if !face.local_subs.contains(&res) {
+            get_mut_unchecked(face).local_subs.push(res.clone());
+            let reskey = Resource::decl_key(&res, face).await;
+            face.primitives
+                .decl_subscriber(&reskey, &sub_info, None)
+                .await;
+            declared += 1;
+        }
+    }
+    (declared, stale.len())
+}
+
+/// Re-evaluate the subscriptions propagated to and from a face whose role
+/// changed from `old_whatami`, then rebuild the data routes. In client tables
+/// the role of the face changes what it receives and what the other faces
+/// receive from it, in routers or peers tables only what it receives as a
+/// client. The subscriptions propagated through the routers and peers
+/// networks follow the link states and are left untouched.
+pub(crate) async fn pubsub_face_whatami_changed(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    old_whatami: whatami::Type,
+) {
+    let (mut declared, mut forgotten) = (0, 0);
+    if tables.whatami == whatami::CLIENT {
+        let mut faces: Vec<Arc<FaceState>> = tables.faces.values().cloned().collect();
+        for dst_face in &mut faces {
+            let (d, f) = reconcile_simple_subscriptions(tables, dst_face, true).await;
+            declared += d;
+            forgotten += f;
+        }
+    } else {
+        let (d, f) =
+            reconcile_simple_subscriptions(tables, face, old_whatami == whatami::CLIENT).await;
+        declared += d;
+        forgotten += f;
+    }
+    let rebuilt = rebuild_all_data_routes(tables);
+    log::debug!(
+        "Role of {} changed from {}: {} subscriptions declared, {} forgotten, {} routes rebuilt",
+        face,
+        whatami::to_string(old_whatami),
+        declared,
+        forgotten,
+        rebuilt
+    );
+}
+
+pub(crate) async fn pubsub_remove_node(
+    tables: &mut Tables,
+    node: &PeerId,
+    net_type: whatami::Type,
+) {
+    match net_type {
+        whatami::ROUTER => {
+            for mut res in tables
+                .router_subs
+                .iter()
+                .filter(|res| res.context().router_subs.contains(node))
+                .cloned()
+                .collect::<Vec<Arc<Resource>>>()
+            {
+                unregister_router_subscription(tables, &mut res, node).await;
+                tables.clean_resource(&mut res)
+            }
+        }
+        whatami::PEER => {
+            for mut res in tables
+                .peer_subs
+                .iter()
+                .filter(|res| res.context().peer_subs.contains(node))
+                .cloned()
+                .collect::<Vec<Arc<Resource>>>()
+            {
+                unregister_peer_subscription(tables, &mut res, node).await;
+
+                if tables.whatami == whatami::ROUTER
+                    && !res.session_ctxs.values().any(|ctx| ctx.has_subs())
+                    && !tables.peer_subs.iter().any(|res| {
+                        res.context()
+                            .peer_subs
+                            .iter()
+                            .any(|peer| peer != &tables.pid)
+                    })
+                {
+                    undeclare_router_subscription(tables, None, &mut res, &tables.pid.clone())
+                        .await;
+                }
+
+                tables.clean_resource(&mut res)
+            }
+        }
+        _ => (),
+    }
+}
+
+pub(crate) async fn pubsub_tree_change(
+    tables: &mut Tables,
+    new_childs: &[Vec<NodeIndex>],
+    net_type: whatami::Type,
+) {
+    tables.tree_child_faces.lock().unwrap().clear();
+    // propagate subs to new childs, batching them per child
+    let mut pending = PendingSubscriptions::default();
+    for (tree_sid, tree_childs) in new_childs.iter().enumerate() {
+        if !tree_childs.is_empty() {
+            let net = match tables.get_net(net_type) {
+                Some(net) => net,
+                None => return,
+            };
+            let tree_idx = NodeIndex::new(tree_sid);
+            if net.graph.contains_node(tree_idx) {
+                let tree_id = net.graph[tree_idx].pid.clone();
+                let faces = child_faces(tables, net, tree_childs);
+
+                let subs_res = match net_type {
+                    whatami::ROUTER => &tables.router_subs,
+                    _ => &tables.peer_subs,
+                };
+
+                for res in subs_res {
+                    let subs = match net_type {
+                        whatami::ROUTER => &res.context().router_subs,
+                        _ => &res.context().peer_subs,
+                    };
+                    for sub in subs {
                         if *sub == tree_id {
                             let sub_info = SubInfo {
                                 reliability: Reliability::Reliable, // TODO
                                 mode: SubMode::Push,
                                 period: None,
                             };
-                            send_sourced_subscription_to_net_childs(
-                                tables,
-                                net,
-                                tree_childs,
-                                res,
-                                None,
-                                &sub_info,
-                                Some(tree_sid as ZInt),
-                            )
-                            .await;
+                            send_sourced_subscription_to_faces(
+                                tables,
+                                &faces,
+                                res,
+                                None,
+                                &sub_info,
+                                Some(tree_sid as ZInt),
+                                &mut pending,
+                            )
+                            .await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    pending.flush().await;
+
+    // recompute routes
+    compute_data_routes_from(tables, &mut tables.root_res.clone());
+}
+
+#[inline]
+fn insert_faces_for_subs(
+    route: &mut Route,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+    tables: &Tables,
+    net: &Network,
+    source: usize,
+    subs: &HashSet<PeerId>,
+) {
+    if net.trees.len() > source {
+        for sub in subs {
+            if let Some(sub_idx) = net.get_idx(sub) {
+                if net.trees[source].directions.len() > sub_idx.index() {
+                    if let Some(direction) = net.trees[source].directions[sub_idx.index()] {
+                        if net.graph.contains_node(direction) {
+                            if let Some(face) = tables.get_face(&net.graph[direction].pid) {
+                                route.entry(face.id).or_insert_with(|| {
+                                    let reskey = Resource::get_face_key(prefix, suffix, face);
+                                    (face.clone(), reskey, Some(source as u64))
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    } else {
+        log::trace!("Tree for node {} not yet ready", source);
+    }
+}
+
+fn compute_data_route(
+    tables: &Tables,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+    source: Option<usize>,
+    source_type: whatami::Type,
+) -> Arc<Route> {
+    let mut route = HashMap::new();
+    let res_name = [&prefix.name(), suffix].concat();
+    let res = Resource::get_resource(prefix, suffix);
+    let matches = res
+        .as_ref()
+        .and_then(|res| res.context.as_ref())
+        .map(|ctx| Cow::from(&ctx.matches))
+        .unwrap_or_else(|| Cow::from(Resource::get_matches(tables, &res_name)));
+
+    // Without shared nodes, e.g. before the networks are initialized, these
+    // tables are the master of all the keys
+    let master = tables.whatami != whatami::ROUTER
+        || tables.shared_nodes.is_empty()
+        || *elect_router(&res_name, &tables.shared_nodes) == tables.pid;
+
+    for mres in matches.iter() {
+        let mres = mres.upgrade().unwrap();
+        if tables.whatami == whatami::ROUTER {
+            if let Some(net) = tables
+                .routers_net
+                .as_ref()
+                .filter(|_| master || source_type == whatami::ROUTER)
+            {
+                let router_source = match source_type {
+                    whatami::ROUTER => source.unwrap(),
+                    _ => net.idx.index(),
+                };
+                insert_faces_for_subs(
+                    &mut route,
+                    prefix,
+                    suffix,
+                    tables,
+                    net,
+                    router_source,
+                    &mres.context().router_subs,
+                );
+            }
+
+            if let Some(net) = tables
+                .peers_net
+                .as_ref()
+                .filter(|_| master || source_type != whatami::ROUTER)
+            {
+                let peer_source = match source_type {
+                    whatami::PEER => source.unwrap(),
+                    _ => net.idx.index(),
+                };
+                insert_faces_for_subs(
+                    &mut route,
+                    prefix,
+                    suffix,
+                    tables,
+                    net,
+                    peer_source,
+                    &mres.context().peer_subs,
+                );
+            }
+        }
+
+        if let Some(net) = tables
+            .peers_net
+            .as_ref()
+            .filter(|_| tables.whatami == whatami::PEER)
+        {
+            let peer_source = match source_type {
+                whatami::ROUTER | whatami::PEER => source.unwrap(),
+                _ => net.idx.index(),
+            };
+            insert_faces_for_subs(
+                &mut route,
+                prefix,
+                suffix,
+                tables,
+                net,
+                peer_source,
+                &mres.context().peer_subs,
+            );
+        }
+    }
+
+    // A face holding several subscriptions matching the key gets the data
+    // once, with the routed key expressed with its own mappings, whichever
+    // subscriptions matched. The faces reached through the routers and peers
+    // networks were all inserted above with their routing context, so that
+    // they are never routed to without it, whatever the order of the matches.
+    if tables.whatami != whatami::ROUTER || master || source_type == whatami::ROUTER {
+        for mres in matches.iter() {
+            let mres = mres.upgrade().unwrap();
+            for (sid, context) in &mres.session_ctxs {
+                if let Some(subinfo) = context.subs() {
+                    if subinfo.mode == SubMode::Push {
+                        route.entry(*sid).or_insert_with(|| {
+                            let reskey = Resource::get_face_key(prefix, suffix, &context.face);
+                            (context.face.clone(), reskey, None)
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Arc::new(route)
+}
+
+fn compute_matching_pulls(
+    tables: &Tables,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+) -> Arc<PullCaches> {
+    let mut pull_caches = vec![];
+    let res = Resource::get_resource(prefix, suffix);
+    let matches = res
+        .as_ref()
+        .and_then(|res| res.context.as_ref())
+        .map(|ctx| Cow::from(&ctx.matches))
+        .unwrap_or_else(|| {
+            Cow::from(Resource::get_matches(
+                tables,
+                &[&prefix.name(), suffix].concat(),
+            ))
+        });
+
+    for mres in matches.iter() {
+        let mres = mres.upgrade().unwrap();
+        for context in mres.session_ctxs.values() {
+            if let Some(subinfo) = context.subs() {
+                if subinfo.mode == SubMode::Pull {
+                    pull_caches.push(context.clone());
+                }
+            }
+        }
+    }
+    Arc::new(pull_caches)
+}
+
+pub(crate) fn compute_data_routes(tables: &mut Tables, res: &mut Arc<Resource>) {
+    tables.route_cache.clear();
+    if res.context.is_some() {
+        let mut res_mut = res.clone();
+        let res_mut = get_mut_unchecked(&mut res_mut);
+        if tables.whatami == whatami::ROUTER {
+            let indexes = tables.net_indexes(whatami::ROUTER);
+            let routers_data_routes = &mut res_mut.context_mut().routers_data_routes;
+            routers_data_routes.clear();
+            if let Some(max_idx) = indexes.iter().max() {
+                routers_data_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+            }
+
+            for idx in &indexes {
+                routers_data_routes[idx.index()] =
+                    compute_data_route(tables, res, "", Some(idx.index()), whatami::ROUTER);
+            }
+        }
+        if tables.whatami == whatami::ROUTER || tables.whatami == whatami::PEER {
+            let indexes = tables.net_indexes(whatami::PEER);
+            let peers_data_routes = &mut res_mut.context_mut().peers_data_routes;
+            peers_data_routes.clear();
+            if let Some(max_idx) = indexes.iter().max() {
+                peers_data_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+            }
+
+            for idx in &indexes {
+                peers_data_routes[idx.index()] =
+                    compute_data_route(tables, res, "", Some(idx.index()), whatami::PEER);
+            }
+        }
+        if tables.whatami == whatami::CLIENT {
+            res_mut.context_mut().client_data_route =
+                Some(compute_data_route(tables, res, "", None, whatami::CLIENT));
+        }
+        res_mut.context_mut().matching_pulls = compute_matching_pulls(tables, res, "");
+    }
+}
+
+fn compute_data_routes_from(tables: &mut Tables, res: &mut Arc<Resource>) {
+    compute_data_routes(tables, res);
+    let res = get_mut_unchecked(res);
+    for child in res.childs.values_mut() {
+        compute_data_routes_from(tables, child);
+    }
+}
+
+/// Recompute the matches and the data routes of all the registered resources
+/// so that none is left to be computed on the routing path. Return the number
+/// of resources whose routes were built.
+pub(crate) fn rebuild_all_data_routes(tables: &mut Tables) -> usize {
+    let mut resources = vec![];
+    let mut stack = vec![tables.root_res.clone()];
+    while let Some(res) = stack.pop() {
+        stack.extend(res.childs.values().cloned());
+        if res.context.is_some() {
+            resources.push(res);
+        }
+    }
+    for res in &mut resources {
+        Resource::match_resource(tables, res);
+    }
+    for res in &mut resources {
+        compute_data_routes(tables, res);
+    }
+    log::debug!("Rebuilt the data routes of {} resources", resources.len());
+    resources.len()
+}
+
+pub(crate) fn compute_matches_data_routes(tables: &mut Tables, res: &mut Arc<Resource>) {
+    // Record the resources rather than keeping them alive, so that they can
+    // still be cleaned while the rebuilds are deferred
+    if let Some(deferred) = tables.deferred_rebuilds.as_mut() {
+        if res.context.is_some() {
+            for res in
+                std::iter::once(Arc::downgrade(res)).chain(res.context().matches.iter().cloned())
+            {
+                if !deferred.iter().any(|other| other.ptr_eq(&res)) {
+                    deferred.push(res);
+                }
+            }
+        }
+        return;
+    }
+    if res.context.is_some() {
+        compute_data_routes(tables, res);
+
+        let resclone = res.clone();
+        for match_ in &mut get_mut_unchecked(res).context_mut().matches {
+            if !Arc::ptr_eq(&match_.upgrade().unwrap(), &resclone) {
+                compute_data_routes(tables, &mut match_.upgrade().unwrap());
+            }
+        }
+    }
+}
+
+/// Rebuild once the data routes of the resources recorded while the rebuilds
+/// were deferred.
+pub(crate) fn flush_deferred_rebuilds(tables: &mut Tables) {
+    let mut resources: Vec<Arc<Resource>> = match tables.deferred_rebuilds.take() {
+        Some(deferred) => deferred
+            .iter()
+            .filter_map(|res| res.upgrade())
+            .filter(|res| res.context.is_some())
+            .collect(),
+        None => return,
+    };
+    for res in &mut resources {
+        compute_data_routes(tables, res);
+    }
+    tables
+        .metrics
+        .deferred_rebuilds
+        .fetch_add(1, Ordering::Relaxed);
+    log::debug!(
+        "Rebuilt the deferred data routes of {} resources",
+        resources.len()
+    );
+    for waiter in tables.rebuild_waiters.drain(..) {
+        let _ = waiter.send(());
+    }
+}
+
+macro_rules! treat_timestamp {
+    ($hlc:expr, $info:expr, $untimestamped_kinds:expr) => {
+        // if an HLC was configured (via Config.add_timestamp),
+        // check DataInfo and add a timestamp if there isn't, unless its kind
+        // is configured not to get one
+        match $hlc {
+            Some(hlc) => {
+                if let Some(mut data_info) = $info {
+                    if let Some(ref ts) = data_info.timestamp {
+                        // Timestamp is present; update HLC with it (possibly raising error if delta exceed)
+                        match hlc.update_with_timestamp(ts).await {
+                            Ok(()) => Some(data_info),
+                            Err(e) => {
+                                log::error!(
+                                    "Error treating timestamp for received Data ({}): drop it!",
+                                    e
+                                );
+                                return;
+                            }
+                        }
+                    } else if $untimestamped_kinds.contains(&data_info.kind.unwrap_or(data_kind::DEFAULT)) {
+                        Some(data_info)
+                    } else {
+                        // Timestamp not present; add one
+                        data_info.timestamp = Some(hlc.new_timestamp().await);
+                        log::trace!("Adding timestamp to DataInfo: {:?}", data_info.timestamp);
+                        Some(data_info)
+                    }
+                } else if $untimestamped_kinds.contains(&data_kind::DEFAULT) {
+                    None
+                } else {
+                    // No DataInfo; add one with a Timestamp
+                    Some(
+                        DataInfo {
+                            timestamp: Some(hlc.new_timestamp().await),
+                            ..DataInfo::default()
+                        }
+                    )
+                }
+            },
+            None => $info,
+        };
+    }
+}
+
+/// The payload and data info delivered to the faces for which they were
+/// converted or transformed, by face id.
+type Transformed = HashMap<usize, (RBuf, Option<DataInfo>)>;
+
+/// The tracking of the deliveries of the subscriptions tracking the
+/// acknowledgments or the delivery errors through which the data are
+/// delivered, by face id.
+type Tracked = HashMap<usize, DeliveryTracking>;
+
+/// The contexts of the subscriptions batching their data through which the
+/// data are delivered, by face id.
+type Batched = HashMap<usize, Arc<SessionContext>>;
+
+/// The contexts of the subscriptions reordering their data through which the
+/// data are delivered, by face id.
+type Reordered = HashMap<usize, Arc<SessionContext>>;
+
+/// The full name, network type and local context of a data route.
+pub(crate) type DataRouteKey = (String, whatami::Type, Option<usize>);
+
+#[inline]
+fn get_data_route(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    res: &Option<Arc<Resource>>,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+    routing_context: Option<RoutingContext>,
+) -> Arc<Route> {
+    let compute = |local_context, net_type| {
+        let key = if tables.route_cache.is_enabled() {
+            let key = ([&prefix.name(), suffix].concat(), net_type, local_context);
+            if let Some(route) = tables.route_cache.get(&key) {
+                tables
+                    .metrics
+                    .route_cache_hits
+                    .fetch_add(1, Ordering::Relaxed);
+                return route;
+            }
+            Some(key)
+        } else {
+            None
+        };
+        tables
+            .metrics
+            .unregistered_data_routes
+            .fetch_add(1, Ordering::Relaxed);
+        let route = compute_data_route(tables, prefix, suffix, local_context, net_type);
+        if let Some(key) = key {
+            tables.route_cache.insert(key, route.clone());
+        }
+        route
+    };
+    let route = match tables.whatami {
+        whatami::ROUTER => match face.whatami {
+            whatami::ROUTER => {
+                match tables.net_local_context(whatami::ROUTER, face, routing_context) {
+                    Some(local_context) => res
+                        .as_ref()
+                        .and_then(|res| res.routers_data_route(local_context))
+                        .unwrap_or_else(|| compute(Some(local_context), whatami::ROUTER)),
+                    None => Arc::new(Route::new()),
+                }
+            }
+            whatami::PEER => match tables.net_local_context(whatami::PEER, face, routing_context) {
+                Some(local_context) => res
+                    .as_ref()
+                    .and_then(|res| res.peers_data_route(local_context))
+                    .unwrap_or_else(|| compute(Some(local_context), whatami::PEER)),
+                None => Arc::new(Route::new()),
+            },
+            _ => res
+                .as_ref()
+                .and_then(|res| res.routers_data_route(0))
+                .unwrap_or_else(|| compute(None, whatami::CLIENT)),
+        },
+        whatami::PEER => match face.whatami {
+            whatami::ROUTER | whatami::PEER => {
+                match tables.net_local_context(whatami::PEER, face, routing_context) {
+                    Some(local_context) => res
+                        .as_ref()
+                        .and_then(|res| res.peers_data_route(local_context))
+                        .unwrap_or_else(|| compute(Some(local_context), whatami::PEER)),
+                    None => Arc::new(Route::new()),
+                }
+            }
+            _ => res
+                .as_ref()
+                .and_then(|res| res.peers_data_route(0))
+                .unwrap_or_else(|| compute(None, whatami::CLIENT)),
+        },
+        _ => res
+            .as_ref()
+            .and_then(|res| res.client_data_route())
+            .unwrap_or_else(|| compute(None, whatami::CLIENT)),
+    };
+    let route = isolate_tenant(tables, face, route);
+    apply_routing_policy(tables, face, prefix, suffix, route)
+}
+
+/// Remove from the route the faces the routing policy, if any, does not
+/// forward the data to.
+fn apply_routing_policy(
+    tables: &Tables,
+    face: &FaceState,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+    route: Arc<Route>,
+) -> Arc<Route> {
+    let policy = match &tables.routing_policy {
+        Some(policy) => policy,
+        None => return route,
+    };
+    let resname = [&prefix.name(), suffix].concat();
+    let denied: Vec<usize> = route
+        .values()
+        .filter(|(outface, _, _)| !policy.allow_forward(face, outface, &resname))
+        .map(|(outface, _, _)| outface.id)
+        .collect();
+    if denied.is_empty() {
+        return route;
+    }
+    let mut route = (*route).clone();
+    for id in denied {
+        route.remove(&id);
+    }
+    Arc::new(route)
+}
+
+/// The reliabilities of the faces of the route the data are not delivered
+/// reliably to: the client faces with only best effort subscriptions matching
+/// the data when `Tables::set_subscriber_reliability` is enabled, as adjusted
+/// by the routing policy, if any.
+fn adjust_reliabilities(
+    tables: &Tables,
+    face: &FaceState,
+    route: &Route,
+    matches: &mut RoutedMatches,
+) -> HashMap<usize, Reliability> {
+    let reliable = if tables.subscriber_reliability
+        && route
+            .values()
+            .any(|(outface, _, _)| outface.whatami == whatami::CLIENT)
+    {
+        Some(reliable_faces(tables, route, matches))
+    } else {
+        None
+    };
+    if reliable.is_none() && tables.routing_policy.is_none() {
+        return HashMap::new();
+    }
+    let resname = matches.resname();
+    route
+        .values()
+        .filter_map(|(outface, _, _)| {
+            let reliability = match &reliable {
+                Some(reliable) if !reliable.contains(&outface.id) => Reliability::BestEffort,
+                _ => Reliability::Reliable,
+            };
+            let reliability = match &tables.routing_policy {
+                Some(policy) => policy.adjust_reliability(face, outface, &resname, reliability),
+                None => reliability,
+            };
+            match reliability {
+                Reliability::Reliable => None,
+                reliability => Some((outface.id, reliability)),
+            }
+        })
+        .collect()
+}
+
+/// Remove from the route the faces of another tenant than the given face.
+#[inline]
+fn isolate_tenant(tables: &Tables, face: &FaceState, route: Arc<Route>) -> Arc<Route> {
+    if !tables.tenants
+        || route
+            .values()
+            .all(|(outface, _, _)| tables.same_tenant(face, outface))
+    {
+        return route;
+    }
+    Arc::new(
+        route
+            .iter()
+            .filter(|(_, (outface, _, _))| tables.same_tenant(face, outface))
+            .map(|(id, dir)| (*id, dir.clone()))
+            .collect(),
+    )
+}
+
+/// Whether the data were published by `source` or entered the routers
+/// network through it.
+fn originates_from(info: &Option<DataInfo>, source: &PeerId) -> bool {
+    info.as_ref()
+        .map(|info| {
+            info.source_id.as_ref() == Some(source) || info.first_router_id.as_ref() == Some(source)
+        })
+        .unwrap_or(false)
+}
+
+/// The hash of a payload, for the deduplication of the subscriptions.
+fn hash_payload(payload: &RBuf) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for slice in payload.as_slices() {
+        hasher.write(slice.as_slice());
+    }
+    hasher.finish()
+}
+
+/// The resources matching the key of routed data, shared by the filters of
+/// its route: a key without resource of its own is matched against the
+/// resource tree at most once per routed data, and only if a filter needs it.
+struct RoutedMatches<'a> {
+    res: &'a Option<Arc<Resource>>,
+    prefix: &'a Arc<Resource>,
+    suffix: &'a str,
+    walked: Option<Vec<Weak<Resource>>>,
+}
+
+impl<'a> RoutedMatches<'a> {
+    fn new(res: &'a Option<Arc<Resource>>, prefix: &'a Arc<Resource>, suffix: &'a str) -> Self {
+        RoutedMatches {
+            res,
+            prefix,
+            suffix,
+            walked: None,
+        }
+    }
+
+    fn resname(&self) -> String {
+        [&self.prefix.name(), self.suffix].concat()
+    }
+
+    fn get(&mut self, tables: &Tables) -> &[Weak<Resource>] {
+        let res: &'a Option<Arc<Resource>> = self.res;
+        if let Some(ctx) = res.as_ref().and_then(|res| res.context.as_ref()) {
+            return &ctx.matches;
+        }
+        let resname = self.resname();
+        self.walked
+            .get_or_insert_with(|| Resource::get_matches(tables, &resname))
+    }
+}
+
+/// Remove from the route the client faces whose subscriptions matching the
+/// routed key all filter out the given data, either with their `DataInfo`
+/// predicate or source filter, because the data are staler than they accept
+/// or because their transform drops them or they can't be converted to their
+/// accepted encoding, and the client faces whose only accepting
+/// subscriptions are shadow ones. Also return the data converted or
+/// transformed for each face, and the subscriptions through which the data
+/// are tracked, batched or reordered.
+fn filter_data_route(
+    tables: &Tables,
+    route: Arc<Route>,
+    matches: &mut RoutedMatches,
+    info: &Option<DataInfo>,
+    payload: &RBuf,
+) -> (Arc<Route>, Transformed, Tracked, Batched, Reordered) {
+    let (prefix, suffix) = (matches.prefix, matches.suffix);
+    let matches = matches.get(tables);
+    let age = info
+        .as_ref()
+        .and_then(|info| info.timestamp.as_ref())
+        .map(|ts| {
+            SystemTime::now()
+                .duration_since(ts.get_time().to_system_time())
+                .unwrap_or_default()
+        });
+    let mut rejected = vec![];
+    let mut transformed = HashMap::new();
+    let mut tracked = HashMap::new();
+    let mut batched = HashMap::new();
+    let mut reordered = HashMap::new();
+    let mut payload_hash = None;
+    for face_id in route.keys() {
+        let mut subscribed = false;
+        let mut stale = false;
+        let mut shadowed = false;
+        let mut duplicate = false;
+        let mut accepted = false;
+        for mres in matches.iter() {
+            if let Some(mres) = mres.upgrade() {
+                if let Some(ctx) = mres.session_ctxs.get(face_id) {
+                    if ctx.has_subs() {
+                        subscribed = true;
+                        let options = ctx.sub_options();
+                        if let Some(filter) = &options.data_filter {
+                            if !filter.matches(info) {
+                                continue;
+                            }
+                        }
+                        if let Some(source) = &options.source_filter {
+                            if !originates_from(info, source) {
+                                continue;
+                            }
+                        }
+                        if let (Some(max), Some(age)) = (options.max_staleness, age) {
+                            if age > max {
+                                stale = true;
+                                continue;
+                            }
+                        }
+                        let converted = match options.accepted_encoding {
+                            Some(accepted) => {
+                                match convert_encoding(tables, accepted, info, payload) {
+                                    Ok(converted) => converted,
+                                    Err(()) if options.unconverted == UnconvertedPolicy::Drop => {
+                                        continue
+                                    }
+                                    Err(()) => None,
+                                }
+                            }
+                            None => None,
+                        };
+                        let data = match &options.transform {
+                            Some(transform) => {
+                                let (payload, info) = match &converted {
+                                    Some((payload, info)) => (payload, info),
+                                    None => (payload, info),
+                                };
+                                match transform.transform(info, payload) {
+                                    Some(payload) => Some((payload, info.clone())),
+                                    None => continue,
+                                }
+                            }
+                            None => converted,
+                        };
+                        if options.shadow {
+                            shadowed = true;
+                            continue;
+                        }
+                        if let Some(dedup) = &options.dedup {
+                            let hash = *payload_hash.get_or_insert_with(|| hash_payload(payload));
+                            let key = if dedup.by_key {
+                                Some([&prefix.name(), suffix].concat())
+                            } else {
+                                None
+                            };
+                            if ctx.is_duplicate(dedup, key, hash) {
+                                duplicate = true;
+                                continue;
+                            }
+                        }
+                        if let Some(data) = data {
+                            transformed.insert(*face_id, data);
+                        }
+                        if let Some(tracking) = ctx.delivery_tracking(&options) {
+                            tracked.insert(*face_id, tracking);
+                        }
+                        if options.batching.is_some() {
+                            batched.insert(*face_id, ctx.clone());
                         }
+                        if options.reordering.is_some() {
+                            reordered.insert(*face_id, ctx.clone());
+                        }
+                        accepted = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if subscribed && !accepted {
+            if shadowed {
+                tables
+                    .metrics
+                    .shadow_deliveries
+                    .fetch_add(1, Ordering::Relaxed);
+            } else if stale {
+                tables
+                    .metrics
+                    .stale_data_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+            } else if duplicate {
+                tables
+                    .metrics
+                    .duplicates_suppressed
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            rejected.push(*face_id);
+        }
+    }
+    let route = if rejected.is_empty() {
+        route
+    } else {
+        Arc::new(
+            route
+                .iter()
+                .filter(|(face_id, _)| !rejected.contains(face_id))
+                .map(|(face_id, dest)| (*face_id, dest.clone()))
+                .collect(),
+        )
+    };
+    (route, transformed, tracked, batched, reordered)
+}
+
+/// The data converted to the given encoding (`encoding::DEFAULT` when
+/// absent): `Ok(None)` if they already have it, `Err(())` if no converter of
+/// the tables converts them.
+fn convert_encoding(
+    tables: &Tables,
+    accepted: ZInt,
+    info: &Option<DataInfo>,
+    payload: &RBuf,
+) -> Result<Option<(RBuf, Option<DataInfo>)>, ()> {
+    let current = info
+        .as_ref()
+        .and_then(|info| info.encoding)
+        .unwrap_or(encoding::DEFAULT);
+    if current == accepted {
+        return Ok(None);
+    }
+    let payload = tables
+        .encoding_converters
+        .get(&(current, accepted))
+        .and_then(|converter| converter.convert(payload))
+        .ok_or(())?;
+    let mut info = info.clone().unwrap_or_default();
+    info.encoding = Some(accepted);
+    Ok(Some((payload, Some(info))))
+}
+
+/// The faces of the route that have a reliable subscription matching the
+/// routed key. The routers and peers faces are always reliable.
+fn reliable_faces(tables: &Tables, route: &Route, matches: &mut RoutedMatches) -> HashSet<usize> {
+    let matches = matches.get(tables);
+    route
+        .values()
+        .filter(|(face, _, _)| {
+            face.whatami != whatami::CLIENT
+                || matches
+                    .iter()
+                    .filter_map(|mres| mres.upgrade())
+                    .any(|mres| {
+                        mres.session_ctxs
+                            .get(&face.id)
+                            .and_then(|ctx| ctx.subs())
+                            .map(|sub_info| sub_info.reliability == Reliability::Reliable)
+                            .unwrap_or(false)
+                    })
+        })
+        .map(|(face, _, _)| face.id)
+        .collect()
+}
+
+/// The reliable faces of the route, if they must be dispatched first.
+#[inline]
+fn get_reliable_faces(
+    tables: &Tables,
+    route: &Route,
+    matches: &mut RoutedMatches,
+) -> Option<HashSet<usize>> {
+    if tables.reliable_first && route.len() > 1 {
+        Some(reliable_faces(tables, route, matches))
+    } else {
+        None
+    }
+}
+
+/// Whether the data routed by `srcface` must be echoed back to it, because
+/// one of its subscriptions matching the routed key asked to receive its own
+/// data.
+fn receives_own(
+    tables: &Tables,
+    route: &Route,
+    srcface: &FaceState,
+    matches: &mut RoutedMatches,
+) -> bool {
+    if !tables.loopback_subs || !route.contains_key(&srcface.id) {
+        return false;
+    }
+    matches
+        .get(tables)
+        .iter()
+        .filter_map(|mres| mres.upgrade())
+        .any(|mres| {
+            mres.session_ctxs
+                .get(&srcface.id)
+                .map(|ctx| ctx.has_subs() && ctx.sub_options().receive_own)
+                .unwrap_or(false)
+        })
+}
+
+/// The faces of the route that have a control priority subscription matching
+/// the routed key.
+fn control_faces(tables: &Tables, route: &Route, matches: &mut RoutedMatches) -> HashSet<usize> {
+    if !tables.priority_subs {
+        return HashSet::new();
+    }
+    let mut faces = HashSet::new();
+    for mres in matches.get(tables).iter().filter_map(|mres| mres.upgrade()) {
+        for (face_id, ctx) in &mres.session_ctxs {
+            if route.contains_key(face_id)
+                && ctx.has_subs()
+                && ctx.sub_options().priority == Priority::Control
+            {
+                faces.insert(*face_id);
+            }
+        }
+    }
+    faces
+}
+
+/// Remove from the route the faces that have no reliable subscription when
+/// the memory pressure is above its high watermark.
+fn shed_best_effort(
+    tables: &Tables,
+    route: Arc<Route>,
+    srcface: &FaceState,
+    matches: &mut RoutedMatches,
+) -> Arc<Route> {
+    match &tables.memory_pressure {
+        Some(pressure) if pressure.is_high() => (),
+        _ => return route,
+    }
+    let reliable = reliable_faces(tables, &route, matches);
+    if reliable.len() == route.len() {
+        return route;
+    }
+    let shed = route
+        .keys()
+        .filter(|face_id| **face_id != srcface.id && !reliable.contains(face_id))
+        .count();
+    tables
+        .metrics
+        .best_effort_shed
+        .fetch_add(shed, Ordering::Relaxed);
+    Arc::new(
+        route
+            .iter()
+            .filter(|(face_id, _)| reliable.contains(face_id))
+            .map(|(face_id, dest)| (*face_id, dest.clone()))
+            .collect(),
+    )
+}
+
+#[inline]
+fn get_matching_pulls(
+    tables: &Tables,
+    face: &FaceState,
+    res: &Option<Arc<Resource>>,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+) -> Arc<PullCaches> {
+    let pulls = res
+        .as_ref()
+        .and_then(|res| res.context.as_ref())
+        .map(|ctx| ctx.matching_pulls.clone())
+        .unwrap_or_else(|| compute_matching_pulls(tables, prefix, suffix));
+    if tables.tenants
+        && !pulls
+            .iter()
+            .all(|context| tables.same_tenant(face, &context.face))
+    {
+        return Arc::new(
+            pulls
+                .iter()
+                .filter(|context| tables.same_tenant(face, &context.face))
+                .cloned()
+                .collect(),
+        );
+    }
+    pulls
+}
+
+struct DeliveryPolicy {
+    max_payload_size: Option<usize>,
+    send_delete: bool,
+    congestion_controls: Vec<(whatami::Type, CongestionControl)>,
+    metrics: Arc<RoutingMetrics>,
+    /// Deliver the data to their source face as well.
+    loopback: bool,
+    /// The faces served on the control lane.
+    control_faces: HashSet<usize>,
+    /// The reliability of the faces the data are not delivered reliably to.
+    reliabilities: HashMap<usize, Reliability>,
+    /// The tracking of the deliveries of the faces served through a
+    /// subscription tracking the acknowledgments or the delivery errors.
+    tracked: Tracked,
+    /// The contexts of the subscriptions batching the data delivered to the
+    /// faces.
+    batched: Batched,
+    /// The contexts of the subscriptions reordering the data delivered to
+    /// the faces.
+    reordered: Reordered,
+}
+
+impl DeliveryPolicy {
+    #[inline]
+    fn new(tables: &Tables) -> DeliveryPolicy {
+        DeliveryPolicy {
+            max_payload_size: tables.max_payload_size,
+            send_delete: tables.oversized_payload_delete,
+            congestion_controls: tables.congestion_controls.clone(),
+            metrics: tables.metrics.clone(),
+            loopback: false,
+            control_faces: HashSet::new(),
+            reliabilities: HashMap::new(),
+            tracked: HashMap::new(),
+            batched: HashMap::new(),
+            reordered: HashMap::new(),
+        }
+    }
+
+    #[inline]
+    fn tracked(&self, outface: &FaceState) -> Option<DeliveryTracking> {
+        self.tracked.get(&outface.id).cloned()
+    }
+
+    #[inline]
+    fn batched(&self, outface: &FaceState) -> Option<&Arc<SessionContext>> {
+        self.batched.get(&outface.id)
+    }
+
+    #[inline]
+    fn reordered(&self, outface: &FaceState) -> Option<&Arc<SessionContext>> {
+        self.reordered.get(&outface.id)
+    }
+
+    #[inline]
+    fn reliability(&self, outface: &FaceState) -> Reliability {
+        self.reliabilities
+            .get(&outface.id)
+            .copied()
+            .unwrap_or(Reliability::Reliable)
+    }
+
+    #[inline]
+    fn priority(&self, outface: &FaceState) -> Priority {
+        if self.control_faces.contains(&outface.id) {
+            Priority::Control
+        } else {
+            Priority::Data
+        }
+    }
+
+    #[inline]
+    fn delivers_to(&self, srcface: &FaceState, outface: &FaceState) -> bool {
+        self.loopback || srcface.id != outface.id
+    }
+
+    #[inline]
+    fn accepts(&self, outface: &FaceState, payload: &RBuf) -> bool {
+        match outface.max_payload_size.or(self.max_payload_size) {
+            Some(max) if payload.len() > max => {
+                self.metrics
+                    .oversized_payloads_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    #[inline]
+    fn congestion_control(
+        &self,
+        outface: &FaceState,
+        congestion_control: CongestionControl,
+    ) -> CongestionControl {
+        self.congestion_controls
+            .iter()
+            .find(|(whatami, _)| *whatami == outface.whatami)
+            .map(|(_, congestion_control)| *congestion_control)
+            .unwrap_or(congestion_control)
+    }
+}
+
+/// Send data to the given face, see [`send_delivery`]. The data delivered
+/// through a subscription reordering them are first buffered in its
+/// `reordered` context, the data that became in order being sent instead.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+async fn deliver_data(
+    outface: &FaceState,
+    metrics: &RoutingMetrics,
+    reskey: &ResKey,
+    payload: RBuf,
+    reliability: Reliability,
+    congestion_control: CongestionControl,
+    priority: Priority,
+    data_info: Option<DataInfo>,
+    routing_context: Option<RoutingContext>,
+    tracked: Option<DeliveryTracking>,
+    reordered: Option<&Arc<SessionContext>>,
+) {
+    let delivery = DataDelivery {
+        reskey: reskey.clone(),
+        payload,
+        reliability,
+        congestion_control,
+        priority,
+        data_info,
+        routing_context,
+        tracked,
+    };
+    match reordered {
+        Some(ctx) => {
+            for delivery in ctx.reorder(delivery) {
+                send_delivery(outface, metrics, delivery).await;
+            }
+        }
+        None => send_delivery(outface, metrics, delivery).await,
+    }
+}
+
+/// Send data to the given face, through its send queue if it has one. When
+/// the queue is full, data with a `Drop` congestion control are dropped while
+/// the others wait for room in the queue. Nothing is sent to paused faces,
+/// the data being buffered if requested, nor to quarantined faces. The
+/// outcome is recorded in the `tracked` delivery tracking of the data, if any.
+async fn send_delivery(outface: &FaceState, metrics: &RoutingMetrics, delivery: DataDelivery) {
+    if let Some(PausedDeliveries { buffer }) = &outface.paused {
+        let dropped = match buffer {
+            Some(buffer) => buffer.lock().unwrap().push(delivery),
+            None => Some(delivery),
+        };
+        if let Some(delivery) = dropped {
+            delivery.fail(DeliveryError::Dropped);
+        }
+        return;
+    }
+    if outface.quarantined.load(Ordering::Relaxed) {
+        delivery.fail(DeliveryError::Dropped);
+        return;
+    }
+    outface.reset_heartbeats(&delivery.reskey);
+    match &outface.send_queue {
+        Some(queue) => match queue.try_send(delivery) {
+            Ok(()) => (),
+            Err(TrySendError::Full(delivery)) => match delivery.congestion_control {
+                CongestionControl::Drop => {
+                    log::trace!("Send queue of {} is full, drop data", outface);
+                    metrics.send_queue_drops.fetch_add(1, Ordering::Relaxed);
+                    delivery.fail(DeliveryError::Dropped);
+                }
+                CongestionControl::Block => {
+                    if let Err(err) = queue.send(delivery).await {
+                        log::error!("Send queue of {} is closed!", outface);
+                        err.into_inner().fail(DeliveryError::Dropped);
                     }
                 }
+            },
+            Err(TrySendError::Closed(delivery)) => {
+                log::error!("Send queue of {} is closed!", outface);
+                delivery.fail(DeliveryError::Dropped);
+            }
+        },
+        None => delivery.send(outface, metrics).await,
+    }
+}
+
+/// Buffer data for a subscription batching its deliveries, delivering the
+/// batch if it is full or its window elapsed.
+#[allow(clippy::too_many_arguments)]
+async fn batch_data(
+    outface: &Arc<FaceState>,
+    metrics: &RoutingMetrics,
+    ctx: &SessionContext,
+    reskey: &ResKey,
+    payload: RBuf,
+    reliability: Reliability,
+    congestion_control: CongestionControl,
+    info: Option<DataInfo>,
+) {
+    let batching = match ctx.sub_options().batching {
+        Some(batching) => batching,
+        None => return,
+    };
+    let sample = (reskey.clone(), payload, info);
+    let batch = ctx.push_batched(&batching, sample, reliability, congestion_control);
+    if let Some(batch) = batch {
+        send_batch(outface, metrics, batch).await;
+    }
+}
+
+async fn send_batch(outface: &Arc<FaceState>, metrics: &RoutingMetrics, batch: PendingBatch) {
+    log::trace!("Send batch of {} data to {}", batch.samples.len(), outface);
+    let primitives = outface.primitives.clone();
+    outface
+        .send_in_time(metrics, async move {
+            primitives
+                .send_data_batch(batch.samples, batch.reliability, batch.congestion_control)
+                .await
+        })
+        .await;
+}
+
+/// Deliver the batches of the subscriptions whose window elapsed. Returns the
+/// time at which the next batch is due, if any.
+pub(crate) async fn flush_batches(tables: &Tables) -> Option<Instant> {
+    let now = Instant::now();
+    let mut next: Option<Instant> = None;
+    for (face, ctx) in &tables.batching_subs {
+        if let (Some(face), Some(ctx)) = (face.upgrade(), ctx.upgrade()) {
+            if let Some(batch) = ctx.take_due_batch(now) {
+                send_batch(&face, &tables.metrics, batch).await;
+            }
+            if let Some(deadline) = ctx.batch_deadline() {
+                next = Some(next.map_or(deadline, |next| next.min(deadline)));
+            }
+        }
+    }
+    next
+}
+
+/// Deliver the data buffered by the subscriptions reordering their data whose
+/// gap timed out. Returns the time at which the next gap times out, if any.
+pub(crate) async fn flush_reorders(tables: &Tables) -> Option<Instant> {
+    let now = Instant::now();
+    let mut next: Option<Instant> = None;
+    for (face, ctx) in &tables.reordering_subs {
+        if let (Some(face), Some(ctx)) = (face.upgrade(), ctx.upgrade()) {
+            for delivery in ctx.take_due_reorders(now) {
+                send_delivery(&face, &tables.metrics, delivery).await;
+            }
+            if let Some(deadline) = ctx.reorder_deadline() {
+                next = Some(next.map_or(deadline, |next| next.min(deadline)));
+            }
+        }
+    }
+    next
+}
+
+/// Send a keep-alive to the subscriptions that were delivered no data for
+/// their heartbeat interval. Returns the time at which the next heartbeat is
+/// due, if any.
+pub(crate) async fn send_heartbeats(tables: &Tables) -> Option<Instant> {
+    let now = Instant::now();
+    for face in tables.faces.values() {
+        for res in face.take_due_heartbeats(now) {
+            // A paused face is delivered nothing, and its keep-alives must
+            // not replace the data buffered for it
+            if face.paused.is_some() {
+                continue;
             }
+            let (reliability, priority) = match res.session_ctxs.get(&face.id) {
+                Some(ctx) => match ctx.subs() {
+                    Some(sub_info) => (sub_info.reliability, ctx.sub_options().priority),
+                    None => continue,
+                },
+                None => continue,
+            };
+            log::trace!("Send heartbeat {} to {}", res.name(), face);
+            let delivery = DataDelivery {
+                reskey: Resource::get_face_key(&res, "", face),
+                payload: RBuf::new(),
+                reliability,
+                congestion_control: CongestionControl::Drop,
+                priority,
+                data_info: Some(DataInfo {
+                    kind: Some(data_kind::KEEP_ALIVE),
+                    ..DataInfo::default()
+                }),
+                routing_context: None,
+                tracked: None,
+            };
+            send_delivery(face, &tables.metrics, delivery).await;
         }
     }
+    tables
+        .faces
+        .values()
+        .filter_map(|face| face.next_heartbeat())
+        .min()
+}
 
-    // recompute routes
-    compute_data_routes_from(tables, &mut tables.root_res.clone());
+/// Pause the delivery of data to the given face. Its subscriptions stay
+/// registered. If `buffer_latest` is set, the latest data routed for each key
+/// is delivered when the face is resumed.
+pub fn pause_face(tables: &mut Tables, face_id: usize, buffer_latest: bool) {
+    match tables.faces.get_mut(&face_id) {
+        Some(face) => {
+            log::debug!("Pause {}", face);
+            get_mut_unchecked(face).paused = Some(PausedDeliveries {
+                buffer: if buffer_latest {
+                    Some(std::sync::Mutex::new(BufferedDeliveries::default()))
+                } else {
+                    None
+                },
+            });
+        }
+        None => log::error!("Pause unknown face {}!", face_id),
+    }
 }
 
-#[inline]
-fn insert_faces_for_subs(
-    route: &mut Route,
-    prefix: &Arc<Resource>,
-    suffix: &str,
-    tables: &Tables,
-    net: &Network,
-    source: usize,
-    subs: &HashSet<PeerId>,
-) {
-    if net.trees.len() > source {
-        for sub in subs {
-            if let Some(sub_idx) = net.get_idx(sub) {
-                if net.trees[source].directions.len() > sub_idx.index() {
-                    if let Some(direction) = net.trees[source].directions[sub_idx.index()] {
-                        if net.graph.contains_node(direction) {
-                            if let Some(face) = tables.get_face(&net.graph[direction].pid) {
-                                route.entry(face.id).or_insert_with(|| {
-                                    let reskey = Resource::get_best_key(prefix, suffix, face.id);
-                                    (face.clone(), reskey, Some(source as u64))
-                                });
-                            }
-                        }
-                    }
+/// Resume the delivery of data to the given face, first delivering the data
+/// buffered while it was paused. This also lifts the quarantine of a face
+/// whose sends timed out.
+pub async fn resume_face(tables: &mut Tables, face_id: usize) {
+    match tables.faces.get_mut(&face_id) {
+        Some(face) => {
+            log::debug!("Resume {}", face);
+            face.quarantined.store(false, Ordering::Relaxed);
+            if let Some(PausedDeliveries {
+                buffer: Some(buffer),
+            }) = get_mut_unchecked(face).paused.take()
+            {
+                for delivery in buffer.into_inner().unwrap().into_ordered() {
+                    send_delivery(face, &tables.metrics, delivery).await;
                 }
             }
         }
-    } else {
-        log::trace!("Tree for node {} not yet ready", source);
+        None => log::error!("Resume unknown face {}!", face_id),
     }
 }
 
-fn compute_data_route(
-    tables: &Tables,
-    prefix: &Arc<Resource>,
-    suffix: &str,
-    source: Option<usize>,
-    source_type: whatami::Type,
-) -> Arc<Route> {
-    let mut route = HashMap::new();
-    let res_name = [&prefix.name(), suffix].concat();
-    let res = Resource::get_resource(prefix, suffix);
-    let matches = res
-        .as_ref()
-        .map(|res| res.context.as_ref())
-        .flatten()
-        .map(|ctx| Cow::from(&ctx.matches))
-        .unwrap_or_else(|| Cow::from(Resource::get_matches(tables, &res_name)));
+/// A data message held while the routing is paused.
+pub(crate) struct HeldData {
+    face: Weak<FaceState>,
+    congestion_control: CongestionControl,
+    info: Option<DataInfo>,
+    payload: RBuf,
+    routing_context: Option<RoutingContext>,
+}
 
-    let master = tables.whatami != whatami::ROUTER
-        || *elect_router(&res_name, &tables.shared_nodes) == tables.pid;
+/// The routing of all the data paused with `Tables::pause_routing`. When
+/// buffering, only the latest data for each key is kept.
+pub(crate) struct PausedRouting {
+    buffer: Option<std::sync::Mutex<HashMap<String, HeldData>>>,
+}
 
-    for mres in matches.iter() {
-        let mres = mres.upgrade().unwrap();
-        if tables.whatami == whatami::ROUTER {
-            if master || source_type == whatami::ROUTER {
-                let net = tables.routers_net.as_ref().unwrap();
-                let router_source = match source_type {
-                    whatami::ROUTER => source.unwrap(),
-                    _ => net.idx.index(),
-                };
-                insert_faces_for_subs(
-                    &mut route,
-                    prefix,
-                    suffix,
+/// Pause the routing of all the data, e.g. during a maintenance. The
+/// declarations are still processed. If `buffer_latest` is set, the latest
+/// data routed for each key is routed when the routing is resumed, otherwise
+/// the data are dropped.
+pub fn pause_routing(tables: &mut Tables, buffer_latest: bool) {
+    log::debug!("Pause routing");
+    tables.paused_routing = Some(PausedRouting {
+        buffer: if buffer_latest {
+            Some(std::sync::Mutex::new(HashMap::new()))
+        } else {
+            None
+        },
+    });
+}
+
+/// Resume the routing of the data, first routing the data buffered while it
+/// was paused, along the current routes.
+pub async fn resume_routing(tables: &mut Tables) {
+    log::debug!("Resume routing");
+    if let Some(PausedRouting {
+        buffer: Some(buffer),
+    }) = tables.paused_routing.take()
+    {
+        for (name, held) in buffer.into_inner().unwrap() {
+            if let Some(face) = held.face.upgrade() {
+                route_data(
                     tables,
-                    net,
-                    router_source,
-                    &mres.context().router_subs,
-                );
+                    &face,
+                    0,
+                    &name,
+                    held.congestion_control,
+                    held.info,
+                    held.payload,
+                    held.routing_context,
+                )
+                .await;
             }
+        }
+    }
+}
 
-            if master || source_type != whatami::ROUTER {
-                let net = tables.peers_net.as_ref().unwrap();
-                let peer_source = match source_type {
-                    whatami::PEER => source.unwrap(),
-                    _ => net.idx.index(),
-                };
-                insert_faces_for_subs(
-                    &mut route,
-                    prefix,
-                    suffix,
-                    tables,
-                    net,
-                    peer_source,
-                    &mres.context().peer_subs,
+/// Hold the data if the routing is paused, returning true if so.
+#[allow(clippy::too_many_arguments)]
+fn hold_data(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    prefix: &Arc<Resource>,
+    suffix: &str,
+    congestion_control: CongestionControl,
+    info: &Option<DataInfo>,
+    payload: &RBuf,
+    routing_context: Option<RoutingContext>,
+) -> bool {
+    match &tables.paused_routing {
+        Some(paused) => {
+            if let Some(buffer) = &paused.buffer {
+                buffer.lock().unwrap().insert(
+                    [&prefix.name(), suffix].concat(),
+                    HeldData {
+                        face: Arc::downgrade(face),
+                        congestion_control,
+                        info: info.clone(),
+                        payload: payload.clone(),
+                        routing_context,
+                    },
                 );
             }
+            true
         }
+        None => false,
+    }
+}
 
-        if tables.whatami == whatami::PEER {
-            let net = tables.peers_net.as_ref().unwrap();
-            let peer_source = match source_type {
-                whatami::ROUTER | whatami::PEER => source.unwrap(),
-                _ => net.idx.index(),
-            };
-            insert_faces_for_subs(
-                &mut route,
-                prefix,
-                suffix,
-                tables,
-                net,
-                peer_source,
-                &mres.context().peer_subs,
-            );
+/// The faces recently seen publishing on each key, with the time they were
+/// last seen, enabled with `Tables::track_publishers`. At most
+/// `MAX_TRACKED_PUBLISHERS` faces are kept per key and
+/// `MAX_TRACKED_PUBLICATIONS` keys are kept, the least recently published
+/// ones being dropped first.
+pub(crate) struct PublisherTracking {
+    max_age: Duration,
+    publishers: std::sync::Mutex<HashMap<String, Vec<(PeerId, Instant)>>>,
+}
+
+/// Track the faces publishing on each key, forgetting them after `max_age`
+/// without publishing. `None` disables the tracking.
+pub fn track_publishers(tables: &mut Tables, max_age: Option<Duration>) {
+    tables.publishers = max_age.map(|max_age| PublisherTracking {
+        max_age,
+        publishers: std::sync::Mutex::new(HashMap::new()),
+    });
+}
+
+/// Record that the face published on the given key.
+fn record_publisher(tables: &Tables, face: &FaceState, prefix: &Arc<Resource>, suffix: &str) {
+    if let Some(tracking) = &tables.publishers {
+        let now = Instant::now();
+        let alive = |seen: &Instant| now.duration_since(*seen) < tracking.max_age;
+        let name = [&prefix.name(), suffix].concat();
+        let mut publishers = tracking.publishers.lock().unwrap();
+        if !publishers.contains_key(&name) && publishers.len() >= *MAX_TRACKED_PUBLICATIONS {
+            publishers.retain(|_, faces| {
+                faces.retain(|(_, seen)| alive(seen));
+                !faces.is_empty()
+            });
+            if publishers.len() >= *MAX_TRACKED_PUBLICATIONS {
+                let oldest = publishers
+                    .iter()
+                    .min_by_key(|(_, faces)| faces.last().map(|(_, seen)| *seen))
+                    .map(|(name, _)| name.clone());
+                if let Some(oldest) = oldest {
+                    publishers.remove(&oldest);
+                }
+            }
         }
+        // The faces of a key are kept ordered by the time they were last seen
+        let faces = publishers.entry(name).or_insert_with(Vec::new);
+        faces.retain(|(pid, seen)| *pid != face.pid && alive(seen));
+        if faces.len() >= *MAX_TRACKED_PUBLISHERS {
+            faces.remove(0);
+        }
+        faces.push((face.pid.clone(), now));
+    }
+}
 
-        if tables.whatami != whatami::ROUTER || master || source_type == whatami::ROUTER {
-            for (sid, context) in &mres.session_ctxs {
-                if let Some(subinfo) = &context.subs {
-                    if subinfo.mode == SubMode::Push {
-                        route.entry(*sid).or_insert_with(|| {
-                            let reskey = Resource::get_best_key(prefix, suffix, *sid);
-                            (context.face.clone(), reskey, None)
-                        });
-                    }
+/// The peers recently seen publishing on the keys matching the given key
+/// expression, with the time they were last seen.
+pub fn publishers(tables: &Tables, expr: &str) -> Vec<(PeerId, Instant)> {
+    let mut result: Vec<(PeerId, Instant)> = vec![];
+    if let Some(tracking) = &tables.publishers {
+        let now = Instant::now();
+        let publishers = tracking.publishers.lock().unwrap();
+        for (_, faces) in publishers
+            .iter()
+            .filter(|(name, _)| rname::intersect(name, expr))
+        {
+            for (pid, seen) in faces
+                .iter()
+                .filter(|(_, seen)| now.duration_since(*seen) < tracking.max_age)
+            {
+                match result.iter_mut().find(|(other, _)| other == pid) {
+                    Some(entry) => entry.1 = entry.1.max(*seen),
+                    None => result.push((pid.clone(), *seen)),
                 }
             }
         }
     }
-    Arc::new(route)
+    result
 }
 
-fn compute_matching_pulls(
-    tables: &Tables,
-    prefix: &Arc<Resource>,
-    suffix: &str,
-) -> Arc<PullCaches> {
-    let mut pull_caches = vec![];
-    let res = Resource::get_resource(prefix, suffix);
-    let matches = res
-        .as_ref()
-        .map(|res| res.context.as_ref())
-        .flatten()
-        .map(|ctx| Cow::from(&ctx.matches))
-        .unwrap_or_else(|| {
-            Cow::from(Resource::get_matches(
+/// Move the subscriptions of the face `from` to the face `to`, e.g. when a
+/// session is upgraded to a new transport. The subscriptions declared by
+/// `from` are registered for `to` with the same info, options and cached
+/// values before being removed from `from`, so the routed data are always
+/// delivered to one of them. Since the subscriptions remain, nothing is
+/// propagated to the other faces. A subscription that can't be registered for
+/// `to` is kept for `from`. The subscriptions declared to `from` are declared
+/// to `to` with the info of the subscribers they come from, and undeclared
+/// from `from`.
+pub async fn migrate_subscriptions(
+    tables: &mut Tables,
+    from: &mut Arc<FaceState>,
+    to: &mut Arc<FaceState>,
+) {
+    log::debug!("Migrate subscriptions from {} to {}", from, to);
+    let remote_subs = std::mem::take(&mut get_mut_unchecked(from).remote_subs);
+    for mut res in remote_subs {
+        let old_ctx = match get_mut_unchecked(&mut res).session_ctxs.get_mut(&from.id) {
+            Some(ctx) => ctx.clone(),
+            None => continue,
+        };
+        if let Some(sub_info) = old_ctx.subs() {
+            match register_client_subscription(
                 tables,
-                &[&prefix.name(), suffix].concat(),
-            ))
-        });
-
-    for mres in matches.iter() {
-        let mres = mres.upgrade().unwrap();
-        for context in mres.session_ctxs.values() {
-            if let Some(subinfo) = &context.subs {
-                if subinfo.mode == SubMode::Pull {
-                    pull_caches.push(context.clone());
+                to,
+                &mut res,
+                &sub_info,
+                &old_ctx.sub_options(),
+            )
+            .await
+            {
+                Ok(_) => {
+                    if let Some(ctx) = res.session_ctxs.get(&to.id) {
+                        ctx.cache_values(old_ctx.cached_values());
+                    }
+                    notify_sub_change(tables, &res, to, whatami::CLIENT, SubChange::Declared);
+                }
+                Err(e) => {
+                    log::warn!("Keep subscription {} of {}: {}", res.name(), from, e);
+                    get_mut_unchecked(from).remote_subs.push(res);
+                    continue;
                 }
             }
         }
+        old_ctx.clear_subs();
+        old_ctx.clear_cache();
+        if old_ctx.local_rid.is_none() && old_ctx.remote_rid.is_none() && !old_ctx.qabl {
+            get_mut_unchecked(&mut res).session_ctxs.remove(&from.id);
+        }
+        compute_matches_data_routes(tables, &mut res);
+        notify_sub_change(tables, &res, from, whatami::CLIENT, SubChange::Undeclared);
+        tables.clean_resource(&mut res);
+    }
+
+    let default_info = SubInfo {
+        reliability: Reliability::Reliable, // TODO
+        mode: SubMode::Push,
+        period: None,
+    };
+    let local_subs = std::mem::take(&mut get_mut_unchecked(from).local_subs);
+    for res in local_subs {
+        if !to.local_subs.iter().any(|sub| Arc::ptr_eq(sub, &res)) {
+            let sub_info = res
+                .session_ctxs
+                .values()
+                .filter(|ctx| ctx.face.id != from.id && ctx.face.id != to.id)
+                .find_map(|ctx| ctx.subs())
+                .unwrap_or_else(|| default_info.clone());
+            get_mut_unchecked(to).local_subs.push(res.clone());
+            let reskey = Resource::decl_key(&res, to).await;
+            to.primitives
+                .decl_subscriber(&reskey, &sub_info, None)
+                .await;
+        }
+        let reskey = Resource::get_face_key(&res, "", from);
+        from.primitives.forget_subscriber(&reskey, None).await;
     }
-    Arc::new(pull_caches)
 }
 
-pub(crate) fn compute_data_routes(tables: &mut Tables, res: &mut Arc<Resource>) {
-    if res.context.is_some() {
-        let mut res_mut = res.clone();
-        let res_mut = get_mut_unchecked(&mut res_mut);
-        if tables.whatami == whatami::ROUTER {
-            let indexes = tables
-                .routers_net
-                .as_ref()
-                .unwrap()
-                .graph
-                .node_indices()
-                .collect::<Vec<NodeIndex>>();
-            let max_idx = indexes.iter().max().unwrap();
-            let routers_data_routes = &mut res_mut.context_mut().routers_data_routes;
-            routers_data_routes.clear();
-            routers_data_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+#[inline]
+async fn send_delete_sample(
+    outface: &FaceState,
+    metrics: &RoutingMetrics,
+    reskey: &ResKey,
+    congestion_control: CongestionControl,
+    data_info: Option<DataInfo>,
+    context: Option<RoutingContext>,
+) {
+    let data_info = match data_info {
+        Some(mut info) => {
+            info.kind = Some(data_kind::DELETE);
+            info
+        }
+        None => DataInfo {
+            kind: Some(data_kind::DELETE),
+            ..DataInfo::default()
+        },
+    };
+    deliver_data(
+        outface,
+        metrics,
+        reskey,
+        RBuf::new(),
+        Reliability::Reliable,
+        congestion_control,
+        Priority::default(),
+        Some(data_info),
+        context,
+        None,
+        None,
+    )
+    .await
+}
 
-            for idx in &indexes {
-                routers_data_routes[idx.index()] =
-                    compute_data_route(tables, res, "", Some(idx.index()), whatami::ROUTER);
+macro_rules! send_to_first {
+    ($route:expr, $srcface:expr, $payload:expr, $transformed:expr, $congestion_control:expr, $data_info:expr, $policy:expr) => {
+        let (outface, reskey, context) = $route.values().next().unwrap();
+        let mut transformed = $transformed;
+        let (payload, data_info) = transformed
+            .remove(&outface.id)
+            .unwrap_or(($payload, $data_info));
+        if $policy.delivers_to($srcface, outface) {
+            if $policy.accepts(outface, &payload) {
+                match $policy.batched(outface) {
+                    Some(ctx) => {
+                        batch_data(
+                            outface,
+                            &$policy.metrics,
+                            ctx,
+                            reskey,
+                            payload,
+                            $policy.reliability(outface),
+                            $policy.congestion_control(outface, $congestion_control),
+                            data_info,
+                        )
+                        .await
+                    }
+                    None => {
+                        deliver_data(
+                            outface,
+                            &$policy.metrics,
+                            reskey,
+                            payload,
+                            $policy.reliability(outface),
+                            $policy.congestion_control(outface, $congestion_control),
+                            $policy.priority(outface),
+                            data_info,
+                            *context,
+                            $policy.tracked(outface),
+                            $policy.reordered(outface),
+                        )
+                        .await
+                    }
+                }
+            } else if $policy.send_delete {
+                send_delete_sample(
+                    outface,
+                    &$policy.metrics,
+                    reskey,
+                    $policy.congestion_control(outface, $congestion_control),
+                    data_info,
+                    *context,
+                )
+                .await
             }
         }
-        if tables.whatami == whatami::ROUTER || tables.whatami == whatami::PEER {
-            let indexes = tables
-                .peers_net
-                .as_ref()
-                .unwrap()
-                .graph
-                .node_indices()
-                .collect::<Vec<NodeIndex>>();
-            let max_idx = indexes.iter().max().unwrap();
-            let peers_data_routes = &mut res_mut.context_mut().peers_data_routes;
-            peers_data_routes.clear();
-            peers_data_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+    };
+}
 
-            for idx in &indexes {
-                peers_data_routes[idx.index()] =
-                    compute_data_route(tables, res, "", Some(idx.index()), whatami::PEER);
+macro_rules! send_to_all {
+    ($route:expr, $srcface:expr, $payload:expr, $transformed:expr, $reliable:expr, $congestion_control:expr, $data_info:expr, $policy:expr) => {
+        // The reliable faces first, then the others
+        let reliable = &$reliable;
+        let is_reliable = |face_id: &usize| {
+            reliable
+                .as_ref()
+                .map(|reliable| reliable.contains(face_id))
+                .unwrap_or(true)
+        };
+        for (outface, reskey, context) in $route
+            .values()
+            .filter(|(outface, _, _)| is_reliable(&outface.id))
+            .chain(
+                $route
+                    .values()
+                    .filter(|(outface, _, _)| !is_reliable(&outface.id)),
+            )
+        {
+            if $policy.delivers_to($srcface, outface) {
+                let (payload, data_info) = match $transformed.get(&outface.id) {
+                    Some((payload, data_info)) => (payload, data_info),
+                    None => (&$payload, &$data_info),
+                };
+                if $policy.accepts(outface, payload) {
+                    // The faces given the same payload share its bytes, each
+                    // one only gets its own handles and read position
+                    match $policy.batched(outface) {
+                        Some(ctx) => {
+                            batch_data(
+                                outface,
+                                &$policy.metrics,
+                                ctx,
+                                reskey,
+                                payload.clone(),
+                                $policy.reliability(outface),
+                                $policy.congestion_control(outface, $congestion_control),
+                                data_info.clone(),
+                            )
+                            .await
+                        }
+                        None => {
+                            deliver_data(
+                                outface,
+                                &$policy.metrics,
+                                reskey,
+                                payload.clone(),
+                                $policy.reliability(outface),
+                                $policy.congestion_control(outface, $congestion_control),
+                                $policy.priority(outface),
+                                data_info.clone(),
+                                *context,
+                                $policy.tracked(outface),
+                                $policy.reordered(outface),
+                            )
+                            .await
+                        }
+                    }
+                } else if $policy.send_delete {
+                    send_delete_sample(
+                        outface,
+                        &$policy.metrics,
+                        reskey,
+                        $policy.congestion_control(outface, $congestion_control),
+                        data_info.clone(),
+                        *context,
+                    )
+                    .await
+                }
             }
         }
-        if tables.whatami == whatami::CLIENT {
-            res_mut.context_mut().client_data_route =
-                Some(compute_data_route(tables, res, "", None, whatami::CLIENT));
+    };
+}
+
+macro_rules! cache_data {
+    (
+        $matching_pulls:expr,
+        $prefix:expr,
+        $res:expr,
+        $suffix:expr,
+        $payload:expr,
+        $info:expr
+    ) => {
+        let name = Resource::shared_name(&$prefix, &$res, $suffix);
+        for context in $matching_pulls.iter().filter(|context| {
+            context
+                .sub_options()
+                .data_filter
+                .as_ref()
+                .map(|filter| filter.matches(&$info))
+                .unwrap_or(true)
+        }) {
+            context.cache_value(name.clone(), $info.clone(), $payload.clone());
         }
-        res_mut.context_mut().matching_pulls = compute_matching_pulls(tables, res, "");
-    }
+    };
 }
 
-fn compute_data_routes_from(tables: &mut Tables, res: &mut Arc<Resource>) {
-    compute_data_routes(tables, res);
-    let res = get_mut_unchecked(res);
-    for child in res.childs.values_mut() {
-        compute_data_routes_from(tables, child);
+/// Append the local pid to the routing path of the routed data, if enabled
+/// (see `Tables::set_record_routing_path`).
+#[inline]
+fn append_routing_path(tables: &Tables, info: Option<DataInfo>) -> Option<DataInfo> {
+    if !tables.record_routing_path {
+        return info;
     }
+    let mut info = info.unwrap_or_default();
+    info.routing_path
+        .get_or_insert_with(Vec::new)
+        .push(tables.pid.clone());
+    Some(info)
+}
+
+macro_rules! retain_data {
+    ($tables:expr, $prefix:expr, $suffix:expr, $payload:expr, $info:expr) => {
+        if let Some(retained) = &$tables.retained {
+            let name = [&$prefix.name(), $suffix].concat();
+            if !name.contains('*') {
+                let mut retained = zasynclock!(retained);
+                match $info.as_ref().and_then(|info| info.kind) {
+                    Some(data_kind::DELETE) => {
+                        retained.remove(&name);
+                    }
+                    _ => {
+                        let evicted = retained.insert(name, ($info.clone(), $payload.clone()));
+                        $tables
+                            .metrics
+                            .retained_evictions
+                            .fetch_add(evicted, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    };
 }
 
-pub(crate) fn compute_matches_data_routes(tables: &mut Tables, res: &mut Arc<Resource>) {
-    if res.context.is_some() {
-        compute_data_routes(tables, res);
-
-        let resclone = res.clone();
-        for match_ in &mut get_mut_unchecked(res).context_mut().matches {
-            if !Arc::ptr_eq(&match_.upgrade().unwrap(), &resclone) {
-                compute_data_routes(tables, &mut match_.upgrade().unwrap());
+macro_rules! record_history {
+    ($tables:expr, $prefix:expr, $suffix:expr, $payload:expr, $info:expr) => {
+        if !$tables.history_depths.is_empty() {
+            let name = [&$prefix.name(), $suffix].concat();
+            if let Some(depth) = Tables::history_depth_of(&$tables.history_depths, &name) {
+                let mut history = $tables.history.lock().unwrap();
+                let values = history.entry(name).or_insert_with(VecDeque::new);
+                if values.len() == depth {
+                    values.pop_front();
+                }
+                values.push_back(($info.clone(), $payload.clone()));
             }
         }
-    }
+    };
 }
 
-macro_rules! treat_timestamp {
-    ($hlc:expr, $info:expr) => {
-        // if an HLC was configured (via Config.add_timestamp),
-        // check DataInfo and add a timestamp if there isn't
-        match $hlc {
-            Some(hlc) => {
-                if let Some(mut data_info) = $info {
-                    if let Some(ref ts) = data_info.timestamp {
-                        // Timestamp is present; update HLC with it (possibly raising error if delta exceed)
-                        match hlc.update_with_timestamp(ts).await {
-                            Ok(()) => Some(data_info),
-                            Err(e) => {
-                                log::error!(
-                                    "Error treating timestamp for received Data ({}): drop it!",
-                                    e
-                                );
-                                return;
+/// If enabled with [`Tables::set_delete_on_clean`], remove the retained values
+/// of the given cleaned resources and send a DELETE for each of them to the
+/// matching push subscribers.
+pub(crate) async fn drop_cleaned_retained(tables: &Tables, cleaned: Vec<String>) {
+    if !tables.delete_on_clean || cleaned.is_empty() {
+        return;
+    }
+    if let Some(retained) = &tables.retained {
+        let dropped: Vec<String> = {
+            let mut retained = zasynclock!(retained);
+            cleaned
+                .into_iter()
+                .filter(|name| retained.remove(name).is_some())
+                .collect()
+        };
+        for name in dropped {
+            log::debug!("Send DELETE for cleaned retained resource {}", name);
+            let mut outfaces = HashSet::new();
+            for mres in Resource::get_matches(tables, &name) {
+                if let Some(mres) = mres.upgrade() {
+                    for (face_id, ctx) in &mres.session_ctxs {
+                        if let Some(sub_info) = ctx.subs() {
+                            if sub_info.mode == SubMode::Push && outfaces.insert(*face_id) {
+                                let reskey =
+                                    Resource::get_face_key(&tables.root_res, &name, &ctx.face);
+                                send_delete_sample(
+                                    &ctx.face,
+                                    &tables.metrics,
+                                    &reskey,
+                                    CongestionControl::Block,
+                                    None,
+                                    None,
+                                )
+                                .await;
                             }
                         }
-                    } else {
-                        // Timestamp not present; add one
-                        data_info.timestamp = Some(hlc.new_timestamp().await);
-                        log::trace!("Adding timestamp to DataInfo: {:?}", data_info.timestamp);
-                        Some(data_info)
                     }
-                } else {
-                    // No DataInfo; add one with a Timestamp
-                    Some(
-                        DataInfo {
-                            source_id: None,
-                            source_sn: None,
-                            first_router_id: None,
-                            first_router_sn: None,
-                            timestamp: Some(hlc.new_timestamp().await),
-                            kind: None,
-                            encoding: None,
-                        }
-                    )
                 }
-            },
-            None => $info,
-        };
+            }
+        }
     }
 }
 
-#[inline]
-fn get_data_route(
+async fn send_retained_values(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    res: &Arc<Resource>,
+    sub_info: &SubInfo,
+) {
+    if let Some(retained) = &tables.retained {
+        let res_name = res.name();
+        let values: Vec<(String, Option<DataInfo>, RBuf)> = zasynclock!(retained)
+            .iter()
+            .filter(|(name, _)| rname::intersect(&res_name, name))
+            .map(|(name, (info, payload))| (name.clone(), info.clone(), payload.clone()))
+            .collect();
+        for (name, info, payload) in values {
+            let reskey = Resource::get_face_key(&tables.root_res, &name, face);
+            face.primitives
+                .send_data(
+                    &reskey,
+                    payload,
+                    sub_info.reliability,
+                    CongestionControl::Block,
+                    info,
+                    None,
+                )
+                .await;
+        }
+    }
+}
+
+/// Deliver to a new subscription the samples held by the history storage for
+/// its key expression within the given bound.
+async fn send_history_values(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    res: &Arc<Resource>,
+    sub_info: &SubInfo,
+    bound: HistoryBound,
+) {
+    if let Some(storage) = &tables.history_storage {
+        for (name, info, payload) in storage.history(&res.name(), bound) {
+            let reskey = Resource::get_face_key(&tables.root_res, &name, face);
+            face.primitives
+                .send_data(
+                    &reskey,
+                    payload,
+                    sub_info.reliability,
+                    CongestionControl::Block,
+                    info,
+                    None,
+                )
+                .await;
+        }
+    }
+}
+
+/// Apply the empty route policy of the tables to data published by the given
+/// face that match no subscription.
+async fn on_empty_route(
     tables: &Tables,
     face: &Arc<FaceState>,
-    res: &Option<Arc<Resource>>,
     prefix: &Arc<Resource>,
     suffix: &str,
-    routing_context: Option<RoutingContext>,
-) -> Arc<Route> {
-    match tables.whatami {
-        whatami::ROUTER => match face.whatami {
-            whatami::ROUTER => {
-                let routers_net = tables.routers_net.as_ref().unwrap();
-                let local_context =
-                    routers_net.get_local_context(routing_context.unwrap(), face.link_id);
-                res.as_ref()
-                    .map(|res| res.routers_data_route(local_context))
-                    .flatten()
-                    .unwrap_or_else(|| {
-                        compute_data_route(
-                            tables,
-                            prefix,
-                            suffix,
-                            Some(local_context),
-                            whatami::ROUTER,
-                        )
-                    })
-            }
-            whatami::PEER => {
-                let peers_net = tables.peers_net.as_ref().unwrap();
-                let local_context =
-                    peers_net.get_local_context(routing_context.unwrap(), face.link_id);
-                res.as_ref()
-                    .map(|res| res.peers_data_route(local_context))
-                    .flatten()
-                    .unwrap_or_else(|| {
-                        compute_data_route(
-                            tables,
-                            prefix,
-                            suffix,
-                            Some(local_context),
-                            whatami::PEER,
-                        )
-                    })
-            }
-            _ => res
-                .as_ref()
-                .map(|res| res.routers_data_route(0))
-                .flatten()
-                .unwrap_or_else(|| {
-                    compute_data_route(tables, prefix, suffix, None, whatami::CLIENT)
-                }),
-        },
-        whatami::PEER => match face.whatami {
-            whatami::ROUTER | whatami::PEER => {
-                let peers_net = tables.peers_net.as_ref().unwrap();
-                let local_context =
-                    peers_net.get_local_context(routing_context.unwrap(), face.link_id);
-                res.as_ref()
-                    .map(|res| res.peers_data_route(local_context))
-                    .flatten()
-                    .unwrap_or_else(|| {
-                        compute_data_route(
-                            tables,
-                            prefix,
-                            suffix,
-                            Some(local_context),
-                            whatami::PEER,
-                        )
-                    })
-            }
-            _ => res
-                .as_ref()
-                .map(|res| res.peers_data_route(0))
-                .flatten()
-                .unwrap_or_else(|| {
-                    compute_data_route(tables, prefix, suffix, None, whatami::CLIENT)
-                }),
-        },
-        _ => res
-            .as_ref()
-            .map(|res| res.client_data_route())
-            .flatten()
-            .unwrap_or_else(|| compute_data_route(tables, prefix, suffix, None, whatami::CLIENT)),
+) {
+    if tables.empty_route == EmptyRoute::Drop {
+        return;
+    }
+    let count = face.unrouted_samples.fetch_add(1, Ordering::Relaxed) + 1;
+    match tables.empty_route {
+        EmptyRoute::LogOnce if count == 1 => log::warn!(
+            "{} publishes data matching no subscription, e.g. on {}{}: drop them",
+            face,
+            prefix.name(),
+            suffix
+        ),
+        EmptyRoute::CloseFace(max) if count == max.max(1) => {
+            log::warn!(
+                "Close {}: published {} data matching no subscription, last on {}{}",
+                face,
+                count,
+                prefix.name(),
+                suffix
+            );
+            face.primitives.send_close().await;
+        }
+        _ => (),
     }
 }
 
-#[inline]
-fn get_matching_pulls(
+/// Deliver data that match no subscription to the dead letter face, if any,
+/// unless they were published by the dead letter face itself.
+async fn send_to_dead_letter(
     tables: &Tables,
-    res: &Option<Arc<Resource>>,
+    face: &Arc<FaceState>,
     prefix: &Arc<Resource>,
     suffix: &str,
-) -> Arc<PullCaches> {
-    res.as_ref()
-        .map(|res| res.context.as_ref())
-        .flatten()
-        .map(|ctx| ctx.matching_pulls.clone())
-        .unwrap_or_else(|| compute_matching_pulls(tables, prefix, suffix))
+    congestion_control: CongestionControl,
+    payload: &RBuf,
+    info: &Option<DataInfo>,
+) {
+    if let Some(outface) = tables
+        .dead_letter_face
+        .filter(|id| *id != face.id)
+        .and_then(|id| tables.faces.get(&id))
+    {
+        log::trace!(
+            "Route data for res {}{} to dead letter {}",
+            prefix.name(),
+            suffix,
+            outface
+        );
+        tables
+            .metrics
+            .dead_lettered_samples
+            .fetch_add(1, Ordering::Relaxed);
+        let reskey = Resource::get_face_key(prefix, suffix, outface);
+        deliver_data(
+            outface,
+            &tables.metrics,
+            &reskey,
+            payload.clone(),
+            Reliability::Reliable,
+            congestion_control,
+            Priority::default(),
+            info.clone(),
+            None,
+            None,
+            None,
+        )
+        .await
+    }
 }
 
-macro_rules! send_to_first {
-    ($route:expr, $srcface:expr, $payload:expr, $congestion_control:expr, $data_info:expr) => {
-        let (outface, reskey, context) = $route.values().next().unwrap();
-        if $srcface.id != outface.id {
-            outface
-                .primitives
-                .send_data(
-                    &reskey,
-                    $payload,
-                    Reliability::Reliable, // TODO: Need to check the active subscriptions to determine the right reliability value
-                    $congestion_control,
-                    $data_info,
-                    *context,
-                )
-                .await
+/// Report data routed with a rid the face never declared. A rid that other
+/// faces declared hints at prefix mappings mixed up by the face, and is
+/// reported apart from a rid unknown to all faces.
+fn report_unknown_rid(tables: &Tables, face: &FaceState, rid: ZInt) {
+    let foreign = tables
+        .faces
+        .values()
+        .filter(|other| other.id != face.id)
+        .find(|other| other.remote_mappings.contains_key(&rid));
+    match foreign {
+        Some(other) => {
+            log::warn!(
+                "Route data from {} with rid {} only declared by {}!",
+                face,
+                rid,
+                other
+            );
+            tables.metrics.foreign_rids.fetch_add(1, Ordering::Relaxed);
+        }
+        None => {
+            log::error!("Route data with unknown rid {}!", rid);
+            tables.metrics.unknown_rids.fetch_add(1, Ordering::Relaxed);
         }
     }
 }
 
-macro_rules! send_to_all {
-    ($route:expr, $srcface:expr, $payload:expr, $congestion_control:expr, $data_info:expr) => {
-        for (outface, reskey, context) in $route.values() {
-            if $srcface.id != outface.id {
-                outface
-                    .primitives
-                    .send_data(
-                        &reskey,
-                        $payload.clone(),
-                        Reliability::Reliable, // TODO: Need to check the active subscriptions to determine the right reliability value
-                        $congestion_control,
-                        $data_info.clone(),
-                        *context,
-                    )
-                    .await
-            }
+#[inline]
+fn is_malformed_key(name: &str) -> bool {
+    !name.starts_with('/') || name.chars().any(char::is_control)
+}
+
+/// Check the name that the routed key resolves to. Return false for a
+/// malformed name, whose data must be dropped. A name outside the expected
+/// keys is only reported.
+fn check_routed_key(tables: &Tables, face: &FaceState, prefix: &Resource, suffix: &str) -> bool {
+    let name = [&prefix.name(), suffix].concat();
+    if is_malformed_key(&name) {
+        log::warn!("Drop data from {} for malformed key '{}'", face, name);
+        tables.metrics.key_anomalies.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    if let Some(expected) = &tables.expected_keys {
+        if !expected.iter().any(|expr| rname::intersect(expr, &name)) {
+            log::warn!("Route data from {} for unexpected key {}", face, name);
+            tables.metrics.key_anomalies.fetch_add(1, Ordering::Relaxed);
         }
     }
+    true
 }
 
-macro_rules! cache_data {
-    (
-        $matching_pulls:expr,
-        $prefix:expr,
-        $suffix:expr,
-        $payload:expr,
-        $info:expr
-    ) => {
-        for context in $matching_pulls.iter() {
-            get_mut_unchecked(&mut context.clone()).last_values.insert(
-                [&$prefix.name(), $suffix].concat(),
-                ($info.clone(), $payload.clone()),
+/// Record the sample identified by the source of the data, if any. Returns
+/// false if it was already routed within the loop detection window, in which
+/// case it is reported and must be dropped.
+fn check_loop(
+    tables: &Tables,
+    face: &FaceState,
+    prefix: &Resource,
+    suffix: &str,
+    info: &Option<DataInfo>,
+) -> bool {
+    if !tables.recent_samples.is_enabled() {
+        return true;
+    }
+    let (source_id, source_sn) = match info {
+        Some(DataInfo {
+            source_id: Some(source_id),
+            source_sn: Some(source_sn),
+            ..
+        }) => (source_id, *source_sn),
+        _ => return true,
+    };
+    let now = Instant::now();
+    let key = (source_id.clone(), source_sn);
+    match tables.recent_samples.get(&key) {
+        Some(seen) if now.duration_since(seen) < tables.loop_window => {
+            let name = [&prefix.name(), suffix].concat();
+            log::warn!(
+                "Drop data from {} for {}: sample {}:{} already routed",
+                face,
+                name,
+                source_id,
+                source_sn
             );
+            tables
+                .metrics
+                .loops_detected
+                .fetch_add(1, Ordering::Relaxed);
+            if let Some(observer) = &tables.loop_observer {
+                observer.loop_detected(face, &name, source_id, source_sn);
+            }
+            false
         }
-    };
+        _ => {
+            tables.recent_samples.insert(key, now);
+            true
+        }
+    }
+}
+
+/// The route that the data published by the given face on the given key would
+/// follow. Unlike [`route_data`], which reports and drops the data it cannot
+/// route, an unknown rid or a malformed key is returned as an error, while a
+/// key that no face subscribed to has an empty route.
+pub fn get_route(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    rid: ZInt,
+    suffix: &str,
+    routing_context: Option<RoutingContext>,
+) -> Result<Arc<Route>, RoutingError> {
+    let prefix = tables
+        .get_mapping(face, &rid)
+        .ok_or(RoutingError::UnknownPrefix(rid))?;
+    let name = [&prefix.name(), suffix].concat();
+    if is_malformed_key(&name) {
+        return Err(RoutingError::MalformedKey(name));
+    }
+    let res = Resource::get_resource(prefix, suffix);
+    Ok(get_data_route(
+        tables,
+        face,
+        &res,
+        prefix,
+        suffix,
+        routing_context,
+    ))
 }
 
 #[inline]
@@ -1164,29 +4271,113 @@ pub async fn route_data(
     match tables.get_mapping(&face, &rid).cloned() {
         Some(prefix) => {
             log::trace!("Route data for res {}{}", prefix.name(), suffix,);
+            if !check_routed_key(&tables, face, &prefix, suffix)
+                || !check_loop(&tables, face, &prefix, suffix, &info)
+            {
+                return;
+            }
+            record_publisher(&tables, face, &prefix, suffix);
+            if hold_data(
+                &tables,
+                face,
+                &prefix,
+                suffix,
+                congestion_control,
+                &info,
+                &payload,
+                routing_context,
+            ) {
+                return;
+            }
 
             let res = Resource::get_resource(&prefix, suffix);
             let route = get_data_route(&tables, face, &res, &prefix, suffix, routing_context);
-            let matching_pulls = get_matching_pulls(&tables, &res, &prefix, suffix);
+            let matching_pulls = get_matching_pulls(&tables, face, &res, &prefix, suffix);
+            if let Some(res) = &res {
+                res.trace(format_args!(
+                    "Route data on {} from {} to {} faces and {} pull subscriptions",
+                    res.name(),
+                    face,
+                    route.len(),
+                    matching_pulls.len()
+                ));
+            }
+            #[cfg(feature = "stats")]
+            if let Some(res) = &res {
+                res.stats.record(payload.len());
+            }
+
+            if route.is_empty() && matching_pulls.is_empty() {
+                send_to_dead_letter(
+                    &tables,
+                    face,
+                    &prefix,
+                    suffix,
+                    congestion_control,
+                    &payload,
+                    &info,
+                )
+                .await;
+                on_empty_route(&tables, face, &prefix, suffix).await;
+            }
 
-            if !(route.is_empty() && matching_pulls.is_empty()) {
-                let data_info = treat_timestamp!(&tables.hlc, info);
+            if !(route.is_empty()
+                && matching_pulls.is_empty()
+                && tables.retained.is_none()
+                && tables.history_depths.is_empty())
+            {
+                let data_info = treat_timestamp!(&tables.hlc, info, tables.untimestamped_kinds);
+                retain_data!(tables, prefix, suffix, payload, data_info);
+                record_history!(tables, prefix, suffix, payload, data_info);
+                let data_info = append_routing_path(&tables, data_info);
+                let mut matches = RoutedMatches::new(&res, &prefix, suffix);
+                let (route, transformed, tracked, batched, reordered) = if tables.data_filters {
+                    filter_data_route(&tables, route, &mut matches, &data_info, &payload)
+                } else {
+                    (
+                        route,
+                        HashMap::new(),
+                        HashMap::new(),
+                        HashMap::new(),
+                        HashMap::new(),
+                    )
+                };
+                let route = shed_best_effort(&tables, route, face, &mut matches);
+                let mut policy = DeliveryPolicy::new(&tables);
+                policy.loopback = receives_own(&tables, &route, face, &mut matches);
+                policy.control_faces = control_faces(&tables, &route, &mut matches);
+                policy.reliabilities = adjust_reliabilities(&tables, face, &route, &mut matches);
+                policy.tracked = tracked;
+                policy.batched = batched;
+                policy.reordered = reordered;
+                let reliable = get_reliable_faces(&tables, &route, &mut matches);
 
                 if route.len() == 1 && matching_pulls.len() == 0 {
-                    send_to_first!(route, face, payload, congestion_control, data_info);
+                    send_to_first!(
+                        route,
+                        face,
+                        payload,
+                        transformed,
+                        congestion_control,
+                        data_info,
+                        policy
+                    );
                 } else {
-                    if !matching_pulls.is_empty() {
-                        let lock = zasynclock!(tables.pull_caches_lock);
-                        cache_data!(matching_pulls, prefix, suffix, payload, data_info);
-                        drop(lock);
-                    }
-                    send_to_all!(route, face, payload, congestion_control, data_info);
+                    cache_data!(matching_pulls, prefix, res, suffix, payload, data_info);
+                    send_to_all!(
+                        route,
+                        face,
+                        payload,
+                        transformed,
+                        reliable,
+                        congestion_control,
+                        data_info,
+                        policy
+                    );
                 }
             }
         }
-        None => {
-            log::error!("Route data with unknown rid {}!", rid);
-        }
+        None => report_unknown_rid(&tables, face, rid),
     }
 }
 
@@ -1206,34 +4397,178 @@ pub async fn full_reentrant_route_data(
     match tables.get_mapping(&face, &rid).cloned() {
         Some(prefix) => {
             log::trace!("Route data for res {}{}", prefix.name(), suffix,);
+            if !check_routed_key(&tables, face, &prefix, suffix)
+                || !check_loop(&tables, face, &prefix, suffix, &info)
+            {
+                return;
+            }
+            record_publisher(&tables, face, &prefix, suffix);
+            if hold_data(
+                &tables,
+                face,
+                &prefix,
+                suffix,
+                congestion_control,
+                &info,
+                &payload,
+                routing_context,
+            ) {
+                return;
+            }
 
             let res = Resource::get_resource(&prefix, suffix);
             let route = get_data_route(&tables, face, &res, &prefix, suffix, routing_context);
-            let matching_pulls = get_matching_pulls(&tables, &res, &prefix, suffix);
+            let matching_pulls = get_matching_pulls(&tables, face, &res, &prefix, suffix);
+            if let Some(res) = &res {
+                res.trace(format_args!(
+                    "Route data on {} from {} to {} faces and {} pull subscriptions",
+                    res.name(),
+                    face,
+                    route.len(),
+                    matching_pulls.len()
+                ));
+            }
+            #[cfg(feature = "stats")]
+            if let Some(res) = &res {
+                res.stats.record(payload.len());
+            }
+
+            if route.is_empty() && matching_pulls.is_empty() {
+                send_to_dead_letter(
+                    &tables,
+                    face,
+                    &prefix,
+                    suffix,
+                    congestion_control,
+                    &payload,
+                    &info,
+                )
+                .await;
+                on_empty_route(&tables, face, &prefix, suffix).await;
+            }
 
-            if !(route.is_empty() && matching_pulls.is_empty()) {
-                let data_info = treat_timestamp!(&tables.hlc, info);
+            if !(route.is_empty()
+                && matching_pulls.is_empty()
+                && tables.retained.is_none()
+                && tables.history_depths.is_empty())
+            {
+                let data_info = treat_timestamp!(&tables.hlc, info, tables.untimestamped_kinds);
+                retain_data!(tables, prefix, suffix, payload, data_info);
+                record_history!(tables, prefix, suffix, payload, data_info);
+                let data_info = append_routing_path(&tables, data_info);
+                let mut matches = RoutedMatches::new(&res, &prefix, suffix);
+                let (route, transformed, tracked, batched, reordered) = if tables.data_filters {
+                    filter_data_route(&tables, route, &mut matches, &data_info, &payload)
+                } else {
+                    (
+                        route,
+                        HashMap::new(),
+                        HashMap::new(),
+                        HashMap::new(),
+                        HashMap::new(),
+                    )
+                };
+                let route = shed_best_effort(&tables, route, face, &mut matches);
+                let mut policy = DeliveryPolicy::new(&tables);
+                policy.loopback = receives_own(&tables, &route, face, &mut matches);
+                policy.control_faces = control_faces(&tables, &route, &mut matches);
+                policy.reliabilities = adjust_reliabilities(&tables, face, &route, &mut matches);
+                policy.tracked = tracked;
+                policy.batched = batched;
+                policy.reordered = reordered;
+                let reliable = get_reliable_faces(&tables, &route, &mut matches);
 
                 if route.len() == 1 && matching_pulls.len() == 0 {
                     drop(tables);
-                    send_to_first!(route, face, payload, congestion_control, data_info);
+                    send_to_first!(
+                        route,
+                        face,
+                        payload,
+                        transformed,
+                        congestion_control,
+                        data_info,
+                        policy
+                    );
                 } else {
-                    if !matching_pulls.is_empty() {
-                        let lock = zasynclock!(tables.pull_caches_lock);
-                        cache_data!(matching_pulls, prefix, suffix, payload, data_info);
-                        drop(lock);
-                    }
+                    cache_data!(matching_pulls, prefix, res, suffix, payload, data_info);
                     drop(tables);
-                    send_to_all!(route, face, payload, congestion_control, data_info);
+                    send_to_all!(
+                        route,
+                        face,
+                        payload,
+                        transformed,
+                        reliable,
+                        congestion_control,
+                        data_info,
+                        policy
+                    );
                 }
             }
         }
-        None => {
-            log::error!("Route data with unknown rid {}!", rid);
+        None => report_unknown_rid(&tables, face, rid),
+    }
+}
+
+/// Send the values cached for the pull subscription of the given face on the
+/// given resource, removing them from its cache. The values are sent, one by
+/// one or in a batch, in the order they were cached, a key cached again being
+/// sent after the others.
+pub(crate) async fn send_pulled_values(tables: &Tables, face: &Arc<FaceState>, res: &Resource) {
+    let ctx = match res.session_ctxs.get(&face.id) {
+        Some(ctx) => ctx,
+        None => return,
+    };
+    let subinfo = match ctx.subs() {
+        Some(subinfo) => subinfo,
+        None => return,
+    };
+    // Only the delivered values are removed from the cache afterwards, so that
+    // the values cached in the meantime are delivered by the next pull
+    let values = ctx.cached_values();
+    let delivered = values.versions();
+    if ctx.sub_options().batch_pulls {
+        let batch = values
+            .into_iter()
+            .map(|(name, (info, data))| {
+                (
+                    Resource::get_face_key(&tables.root_res, &name, face),
+                    data,
+                    info,
+                )
+            })
+            .collect();
+        let primitives = face.primitives.clone();
+        let reliability = subinfo.reliability;
+        face.send_in_time(&tables.metrics, async move {
+            primitives
+                .send_data_batch(
+                    batch,
+                    reliability,
+                    CongestionControl::Drop, // TODO: Default value for the time being
+                )
+                .await
+        })
+        .await;
+    } else {
+        for (name, (info, data)) in values {
+            let reskey = Resource::get_face_key(&tables.root_res, &name, face);
+            face.primitives
+                .send_data(
+                    &reskey,
+                    data,
+                    subinfo.reliability,
+                    CongestionControl::Drop, // TODO: Default value for the time being
+                    info,
+                    None,
+                )
+                .await;
         }
     }
+    ctx.remove_delivered(&delivered);
 }
 
+/// Deliver to the face the values cached for its pull subscription, in the
+/// order they were cached.
 pub async fn pull_data(
     tables: &mut Tables,
     face: &Arc<FaceState>,
@@ -1243,30 +4578,34 @@ pub async fn pull_data(
     _pull_id: ZInt,
     _max_samples: &Option<ZInt>,
 ) {
-    match tables.get_mapping(&face, &rid) {
-        Some(prefix) => match Resource::get_resource(prefix, suffix) {
-            Some(mut res) => {
-                let res = get_mut_unchecked(&mut res);
-                match res.session_ctxs.get_mut(&face.id) {
-                    Some(mut ctx) => match &ctx.subs {
-                        Some(subinfo) => {
-                            let lock = zasynclock!(tables.pull_caches_lock);
-                            for (name, (info, data)) in &ctx.last_values {
-                                let reskey =
-                                    Resource::get_best_key(&tables.root_res, name, face.id);
-                                face.primitives
-                                    .send_data(
-                                        &reskey,
-                                        data.clone(),
-                                        subinfo.reliability,
-                                        CongestionControl::Drop, // TODO: Default value for the time being
-                                        info.clone(),
-                                        None,
-                                    )
-                                    .await;
+    match tables.get_mapping(&face, &rid).cloned() {
+        Some(prefix) => match Resource::get_resource(&prefix, suffix) {
+            Some(res) => {
+                res.trace(format_args!("Pull data on {} from {}", res.name(), face));
+                match res.session_ctxs.get(&face.id) {
+                    Some(ctx) => match ctx.subs() {
+                        Some(subinfo) if subinfo.mode == SubMode::Push => {
+                            log::trace!(
+                                "Pull data for push subscription {} from {}",
+                                [&prefix.name(), suffix].concat(),
+                                face
+                            );
+                            // Nothing is cached for it and the protocol has no
+                            // completion of a pull, so the pull is ignored
+                            tables
+                                .metrics
+                                .pulls_on_push_subs
+                                .fetch_add(1, Ordering::Relaxed);
+                        }
+                        Some(_) => {
+                            // Read through the directly connected routers and
+                            // peers on a cache miss, the values being sent when
+                            // they have all replied
+                            if ctx.has_cached_values()
+                                || !query_read_through(tables, face, &res).await
+                            {
+                                send_pulled_values(tables, face, &res).await;
                             }
-                            get_mut_unchecked(&mut ctx).last_values.clear();
-                            drop(lock);
                         }
                         None => {
                             log::error!(
@@ -1295,3 +4634,61 @@ pub async fn pull_data(
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::protocol::session::DummyPrimitives;
+    use super::super::OutSession;
+    use super::*;
+
+    #[test]
+    fn test_undeclare_all_for_face_reports_failures() {
+        async_std::task::block_on(async {
+            let mut tables = Tables::new(PeerId::new(0, [0; 16]), whatami::CLIENT, None);
+            let sub_info = SubInfo {
+                reliability: Reliability::Reliable,
+                mode: SubMode::Push,
+                period: None,
+            };
+            let mut face = tables
+                .open_face(
+                    PeerId::new(0, [1; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(DummyPrimitives::new())),
+                )
+                .await
+                .upgrade()
+                .unwrap();
+            for suffix in &["/test/a", "/test/b", "/test/c"] {
+                declare_client_subscription(&mut tables, &mut face, 0, suffix, &sub_info)
+                    .await
+                    .unwrap();
+            }
+
+            // A tracked resource without subscription context for the face
+            let mut root = tables.root_res.clone();
+            let broken = Resource::make_resource(&mut tables, &mut root, "/test/broken");
+            get_mut_unchecked(&mut face)
+                .remote_subs
+                .insert(1, broken.clone());
+
+            let outcomes = undeclare_all_for_face(&mut tables, &mut face).await;
+            let names: Vec<&str> = outcomes.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(names, vec!["/test/a", "/test/broken", "/test/b", "/test/c"]);
+            assert_eq!(
+                outcomes[1].1,
+                Err(RoutingError::UnknownSubscription(
+                    "/test/broken".to_string()
+                ))
+            );
+            assert!(outcomes
+                .iter()
+                .filter(|(name, _)| name != "/test/broken")
+                .all(|(_, result)| result.is_ok()));
+            for name in &["/test/a", "/test/b", "/test/c"] {
+                assert!(!tables.has_subscriber(name));
+            }
+            assert!(face.remote_subs.is_empty());
+        });
+    }
+}
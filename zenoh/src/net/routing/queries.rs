@@ -15,20 +15,40 @@ use async_std::sync::Arc;
 use petgraph::graph::NodeIndex;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use zenoh_util::sync::get_mut_unchecked;
 
-use super::protocol::core::{whatami, PeerId, QueryConsolidation, QueryTarget, ResKey, ZInt};
+use super::protocol::core::rname;
+use super::protocol::core::{
+    queryable, whatami, CongestionControl, PeerId, QueryConsolidation, QueryTarget, Reliability,
+    ResKey, ZInt,
+};
 use super::protocol::io::RBuf;
 use super::protocol::proto::{DataInfo, RoutingContext};
 
+use super::admin::reply_admin_query;
 use super::face::FaceState;
 use super::network::Network;
+use super::pubsub::send_pulled_values;
 use super::resource::{elect_router, Resource, Route, SessionContext};
 use super::router::Tables;
 
+/// Prefix of the reserved keys of the queries exchanged between routers and
+/// peers to retrieve the retained values matching a subscription:
+/// `/@/retained/<origin pid><key expression>`. These queries are propagated
+/// along the tree of their origin.
+pub(crate) const RETAINED_PREFIX: &str = "/@/retained/";
+
 pub(crate) struct Query {
     src_face: Arc<FaceState>,
     src_qid: ZInt,
+    retained: bool,
+    /// The pull subscription resource whose cache the replies of a read
+    /// through query populate.
+    read_through: Option<Arc<Resource>>,
+    /// Set when the pull of a read through query is canceled, see
+    /// [`cancel_pulls`].
+    canceled: AtomicBool,
 }
 
 #[inline]
@@ -91,7 +111,20 @@ async fn propagate_sourced_queryable(
     source: &PeerId,
     net_type: whatami::Type,
 ) {
-    let net = tables.get_net(net_type).unwrap();
+    let net = match tables.get_net(net_type) {
+        Some(net) => net,
+        None => {
+            log::debug!(
+                "Defer the propagation of qabl {}: network not initialized",
+                res.name()
+            );
+            tables
+                .metrics
+                .deferred_propagations
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
     match net.get_idx(source) {
         Some(tree_sid) => {
             if net.trees.len() > tree_sid.index() {
@@ -226,17 +259,9 @@ async fn register_client_queryable(
         match res.session_ctxs.get_mut(&face.id) {
             Some(mut ctx) => get_mut_unchecked(&mut ctx).qabl = true,
             None => {
-                res.session_ctxs.insert(
-                    face.id,
-                    Arc::new(SessionContext {
-                        face: face.clone(),
-                        local_rid: None,
-                        remote_rid: None,
-                        subs: None,
-                        qabl: true,
-                        last_values: HashMap::new(),
-                    }),
-                );
+                let mut ctx = SessionContext::new(face.clone(), None, None);
+                ctx.qabl = true;
+                res.session_ctxs.insert(face.id, Arc::new(ctx));
             }
         }
     }
@@ -308,7 +333,7 @@ async fn send_forget_sourced_queryable_to_net_childs(
 async fn propagate_forget_simple_queryable(tables: &mut Tables, res: &mut Arc<Resource>) {
     for face in tables.faces.values_mut() {
         if face.local_qabls.contains(res) {
-            let reskey = Resource::get_best_key(res, "", face.id);
+            let reskey = Resource::get_face_key(res, "", face);
             face.primitives.forget_queryable(&reskey, None).await;
 
             get_mut_unchecked(face)
@@ -325,7 +350,16 @@ async fn propagate_forget_sourced_queryable(
     source: &PeerId,
     net_type: whatami::Type,
 ) {
-    let net = tables.get_net(net_type).unwrap();
+    let net = match tables.get_net(net_type) {
+        Some(net) => net,
+        None => {
+            log::debug!(
+                "Skip the propagation of the undeclaration of qabl {}: network not initialized",
+                res.name()
+            );
+            return;
+        }
+    };
     match net.get_idx(source) {
         Some(tree_sid) => {
             send_forget_sourced_queryable_to_net_childs(
@@ -392,7 +426,7 @@ pub async fn forget_router_queryable(
         Some(prefix) => match Resource::get_resource(prefix, suffix) {
             Some(mut res) => {
                 undeclare_router_queryable(tables, Some(face), &mut res, router).await;
-                Resource::clean(&mut res)
+                tables.clean_resource(&mut res)
             }
             None => log::error!("Undeclare unknown router queryable!"),
         },
@@ -448,7 +482,7 @@ pub async fn forget_peer_queryable(
                     undeclare_router_queryable(tables, None, &mut res, &tables.pid.clone()).await;
                 }
 
-                Resource::clean(&mut res)
+                tables.clean_resource(&mut res)
             }
             None => log::error!("Undeclare unknown peer queryable!"),
         },
@@ -528,7 +562,7 @@ pub(crate) async fn undeclare_client_queryable(
     {
         let face = &mut client_qabls[0];
         if face.local_qabls.contains(&res) {
-            let reskey = Resource::get_best_key(&res, "", face.id);
+            let reskey = Resource::get_face_key(&res, "", face);
             face.primitives.forget_queryable(&reskey, None).await;
 
             get_mut_unchecked(face)
@@ -537,7 +571,7 @@ pub(crate) async fn undeclare_client_queryable(
         }
     }
 
-    Resource::clean(res)
+    tables.clean_resource(res)
 }
 
 pub async fn forget_client_queryable(
@@ -580,7 +614,7 @@ pub(crate) async fn queries_remove_node(
                 .collect::<Vec<Arc<Resource>>>()
             {
                 unregister_router_queryable(tables, &mut res, node).await;
-                Resource::clean(&mut res)
+                tables.clean_resource(&mut res)
             }
         }
         whatami::PEER => {
@@ -605,7 +639,7 @@ pub(crate) async fn queries_remove_node(
                     undeclare_router_queryable(tables, None, &mut res, &tables.pid.clone()).await;
                 }
 
-                Resource::clean(&mut res)
+                tables.clean_resource(&mut res)
             }
         }
         _ => (),
@@ -620,7 +654,10 @@ pub(crate) async fn queries_tree_change(
     // propagate qabls to new childs
     for (tree_sid, tree_childs) in new_childs.iter().enumerate() {
         if !tree_childs.is_empty() {
-            let net = tables.get_net(net_type).unwrap();
+            let net = match tables.get_net(net_type) {
+                Some(net) => net,
+                None => return,
+            };
             let tree_idx = NodeIndex::new(tree_sid);
             if net.graph.contains_node(tree_idx) {
                 let tree_id = net.graph[tree_idx].pid.clone();
@@ -675,7 +712,7 @@ fn insert_faces_for_qabls(
                         if net.graph.contains_node(direction) {
                             if let Some(face) = tables.get_face(&net.graph[direction].pid) {
                                 route.entry(face.id).or_insert_with(|| {
-                                    let reskey = Resource::get_best_key(prefix, suffix, face.id);
+                                    let reskey = Resource::get_face_key(prefix, suffix, face);
                                     (face.clone(), reskey, Some(source as u64))
                                 });
                             }
@@ -701,19 +738,24 @@ fn compute_query_route(
     let res = Resource::get_resource(prefix, suffix);
     let matches = res
         .as_ref()
-        .map(|res| res.context.as_ref())
-        .flatten()
+        .and_then(|res| res.context.as_ref())
         .map(|ctx| Cow::from(&ctx.matches))
         .unwrap_or_else(|| Cow::from(Resource::get_matches(tables, &res_name)));
 
+    // Without shared nodes, e.g. before the networks are initialized, these
+    // tables are the master of all the keys
     let master = tables.whatami != whatami::ROUTER
+        || tables.shared_nodes.is_empty()
         || *elect_router(&res_name, &tables.shared_nodes) == tables.pid;
 
     for mres in matches.iter() {
         let mres = mres.upgrade().unwrap();
         if tables.whatami == whatami::ROUTER {
-            if master || source_type == whatami::ROUTER {
-                let net = tables.routers_net.as_ref().unwrap();
+            if let Some(net) = tables
+                .routers_net
+                .as_ref()
+                .filter(|_| master || source_type == whatami::ROUTER)
+            {
                 let router_source = match source_type {
                     whatami::ROUTER => source.unwrap(),
                     _ => net.idx.index(),
@@ -729,8 +771,11 @@ fn compute_query_route(
                 );
             }
 
-            if master || source_type != whatami::ROUTER {
-                let net = tables.peers_net.as_ref().unwrap();
+            if let Some(net) = tables
+                .peers_net
+                .as_ref()
+                .filter(|_| master || source_type != whatami::ROUTER)
+            {
                 let peer_source = match source_type {
                     whatami::PEER => source.unwrap(),
                     _ => net.idx.index(),
@@ -747,8 +792,11 @@ fn compute_query_route(
             }
         }
 
-        if tables.whatami == whatami::PEER {
-            let net = tables.peers_net.as_ref().unwrap();
+        if let Some(net) = tables
+            .peers_net
+            .as_ref()
+            .filter(|_| tables.whatami == whatami::PEER)
+        {
             let peer_source = match source_type {
                 whatami::ROUTER | whatami::PEER => source.unwrap(),
                 _ => net.idx.index(),
@@ -768,7 +816,7 @@ fn compute_query_route(
             for (sid, context) in &mres.session_ctxs {
                 if context.qabl {
                     route.entry(*sid).or_insert_with(|| {
-                        let reskey = Resource::get_best_key(prefix, suffix, *sid);
+                        let reskey = Resource::get_face_key(prefix, suffix, &context.face);
                         (context.face.clone(), reskey, None)
                     });
                 }
@@ -783,17 +831,12 @@ pub(crate) fn compute_query_routes(tables: &mut Tables, res: &mut Arc<Resource>)
         let mut res_mut = res.clone();
         let res_mut = get_mut_unchecked(&mut res_mut);
         if tables.whatami == whatami::ROUTER {
-            let indexes = tables
-                .routers_net
-                .as_ref()
-                .unwrap()
-                .graph
-                .node_indices()
-                .collect::<Vec<NodeIndex>>();
-            let max_idx = indexes.iter().max().unwrap();
+            let indexes = tables.net_indexes(whatami::ROUTER);
             let routers_query_routes = &mut res_mut.context_mut().routers_query_routes;
             routers_query_routes.clear();
-            routers_query_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+            if let Some(max_idx) = indexes.iter().max() {
+                routers_query_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+            }
 
             for idx in &indexes {
                 routers_query_routes[idx.index()] =
@@ -801,17 +844,12 @@ pub(crate) fn compute_query_routes(tables: &mut Tables, res: &mut Arc<Resource>)
             }
         }
         if tables.whatami == whatami::ROUTER || tables.whatami == whatami::PEER {
-            let indexes = tables
-                .peers_net
-                .as_ref()
-                .unwrap()
-                .graph
-                .node_indices()
-                .collect::<Vec<NodeIndex>>();
-            let max_idx = indexes.iter().max().unwrap();
+            let indexes = tables.net_indexes(whatami::PEER);
             let peers_query_routes = &mut res_mut.context_mut().peers_query_routes;
             peers_query_routes.clear();
-            peers_query_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+            if let Some(max_idx) = indexes.iter().max() {
+                peers_query_routes.resize_with(max_idx.index() + 1, || Arc::new(HashMap::new()));
+            }
 
             for idx in &indexes {
                 peers_query_routes[idx.index()] =
@@ -868,77 +906,82 @@ pub async fn route_query(
                 suffix,
             );
 
+            if face.whatami != whatami::CLIENT {
+                let key = [&prefix.name(), suffix].concat();
+                if let Some((origin, expr)) = parse_retained_key(&key) {
+                    route_retained_query(tables, face, &key, origin, expr, qid).await;
+                    return;
+                }
+            }
+            if reply_admin_query(tables, face, prefix, suffix, qid).await {
+                return;
+            }
+
             let route = match tables.whatami {
                 whatami::ROUTER => match face.whatami {
                     whatami::ROUTER => {
-                        let routers_net = tables.routers_net.as_ref().unwrap();
-                        let local_context =
-                            routers_net.get_local_context(routing_context.unwrap(), face.link_id);
-                        Resource::get_resource(prefix, suffix)
-                            .map(|res| res.routers_query_route(local_context))
-                            .flatten()
-                            .unwrap_or_else(|| {
-                                compute_query_route(
-                                    tables,
-                                    prefix,
-                                    suffix,
-                                    Some(local_context),
-                                    whatami::ROUTER,
-                                )
-                            })
+                        match tables.net_local_context(whatami::ROUTER, face, routing_context) {
+                            Some(local_context) => Resource::get_resource(prefix, suffix)
+                                .and_then(|res| res.routers_query_route(local_context))
+                                .unwrap_or_else(|| {
+                                    compute_query_route(
+                                        tables,
+                                        prefix,
+                                        suffix,
+                                        Some(local_context),
+                                        whatami::ROUTER,
+                                    )
+                                }),
+                            None => Arc::new(Route::new()),
+                        }
                     }
                     whatami::PEER => {
-                        let peers_net = tables.peers_net.as_ref().unwrap();
-                        let local_context =
-                            peers_net.get_local_context(routing_context.unwrap(), face.link_id);
-                        Resource::get_resource(prefix, suffix)
-                            .map(|res| res.peers_query_route(local_context))
-                            .flatten()
-                            .unwrap_or_else(|| {
-                                compute_query_route(
-                                    tables,
-                                    prefix,
-                                    suffix,
-                                    Some(local_context),
-                                    whatami::PEER,
-                                )
-                            })
+                        match tables.net_local_context(whatami::PEER, face, routing_context) {
+                            Some(local_context) => Resource::get_resource(prefix, suffix)
+                                .and_then(|res| res.peers_query_route(local_context))
+                                .unwrap_or_else(|| {
+                                    compute_query_route(
+                                        tables,
+                                        prefix,
+                                        suffix,
+                                        Some(local_context),
+                                        whatami::PEER,
+                                    )
+                                }),
+                            None => Arc::new(Route::new()),
+                        }
                     }
                     _ => Resource::get_resource(prefix, suffix)
-                        .map(|res| res.routers_query_route(0))
-                        .flatten()
+                        .and_then(|res| res.routers_query_route(0))
                         .unwrap_or_else(|| {
                             compute_query_route(tables, prefix, suffix, None, whatami::CLIENT)
                         }),
                 },
                 whatami::PEER => match face.whatami {
                     whatami::ROUTER | whatami::PEER => {
-                        let peers_net = tables.peers_net.as_ref().unwrap();
-                        let local_context =
-                            peers_net.get_local_context(routing_context.unwrap(), face.link_id);
-                        Resource::get_resource(prefix, suffix)
-                            .map(|res| res.peers_query_route(local_context))
-                            .flatten()
-                            .unwrap_or_else(|| {
-                                compute_query_route(
-                                    tables,
-                                    prefix,
-                                    suffix,
-                                    Some(local_context),
-                                    whatami::PEER,
-                                )
-                            })
+                        match tables.net_local_context(whatami::PEER, face, routing_context) {
+                            Some(local_context) => Resource::get_resource(prefix, suffix)
+                                .and_then(|res| res.peers_query_route(local_context))
+                                .unwrap_or_else(|| {
+                                    compute_query_route(
+                                        tables,
+                                        prefix,
+                                        suffix,
+                                        Some(local_context),
+                                        whatami::PEER,
+                                    )
+                                }),
+                            None => Arc::new(Route::new()),
+                        }
                     }
                     _ => Resource::get_resource(prefix, suffix)
-                        .map(|res| res.peers_query_route(0))
-                        .flatten()
+                        .and_then(|res| res.peers_query_route(0))
                         .unwrap_or_else(|| {
                             compute_query_route(tables, prefix, suffix, None, whatami::CLIENT)
                         }),
                 },
                 _ => Resource::get_resource(prefix, suffix)
-                    .map(|res| res.client_query_route())
-                    .flatten()
+                    .and_then(|res| res.client_query_route())
                     .unwrap_or_else(|| {
                         compute_query_route(tables, prefix, suffix, None, whatami::CLIENT)
                     }),
@@ -953,6 +996,9 @@ pub async fn route_query(
                 let query = Arc::new(Query {
                     src_face: face.clone(),
                     src_qid: qid,
+                    retained: false,
+                    read_through: None,
+                    canceled: AtomicBool::new(false),
                 });
 
                 for (outface, reskey, context) in route.values() {
@@ -987,9 +1033,243 @@ pub async fn route_query(
     }
 }
 
+/// The key of the retained query of `origin` for the values matching `res`,
+/// see [`RETAINED_PREFIX`].
+fn retained_key(origin: &PeerId, res: &Resource) -> String {
+    format!("{}{}{}", RETAINED_PREFIX, origin, res.name())
+}
+
+/// The origin and the key expression of a retained query key, if it is one.
+fn parse_retained_key(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix(RETAINED_PREFIX)?;
+    rest.find('/').map(|idx| rest.split_at(idx))
+}
+
+/// The faces of the childs of the local node in the routers or peers tree
+/// rooted at `origin`, except the face the query came from.
+fn retained_query_faces(
+    tables: &Tables,
+    origin: &str,
+    src_face: &Arc<FaceState>,
+) -> Vec<Arc<FaceState>> {
+    let net = match tables.get_net(tables.whatami) {
+        Some(net) => net,
+        None => return vec![],
+    };
+    let tree = net
+        .graph
+        .node_indices()
+        .find(|idx| net.graph[*idx].pid.to_string() == origin)
+        .and_then(|idx| net.trees.get(idx.index()));
+    match tree {
+        Some(tree) => tree
+            .childs
+            .iter()
+            .filter(|child| net.graph.contains_node(**child))
+            .filter_map(|child| tables.get_face(&net.graph[*child].pid))
+            .filter(|face| face.id != src_face.id)
+            .cloned()
+            .collect(),
+        None => {
+            log::trace!("Tree for node {} not yet ready", origin);
+            vec![]
+        }
+    }
+}
+
+/// Send the retained query on `key` to the given faces, the replies being
+/// handled by `query`.
+async fn send_retained_query(outfaces: Vec<Arc<FaceState>>, key: &str, query: &Arc<Query>) {
+    for mut outface in outfaces {
+        let outface_mut = get_mut_unchecked(&mut outface);
+        outface_mut.next_qid += 1;
+        let qid = outface_mut.next_qid;
+        outface_mut.pending_queries.insert(qid, query.clone());
+
+        log::trace!("Send retained query {} to {}", key, outface);
+
+        outface
+            .primitives
+            .send_query(
+                &ResKey::RName(key.to_string()),
+                "",
+                qid,
+                QueryTarget::default(),
+                QueryConsolidation::none(),
+                None,
+            )
+            .await
+    }
+}
+
+/// Reply the values retained by this router to a retained query and forward
+/// it to the childs of this router in the tree of its origin, relaying their
+/// replies. The final reply is sent once all the childs sent theirs.
+async fn route_retained_query(
+    tables: &Tables,
+    face: &Arc<FaceState>,
+    key: &str,
+    origin: &str,
+    expr: &str,
+    qid: ZInt,
+) {
+    reply_retained_values(tables, face, expr, qid).await;
+    let outfaces = retained_query_faces(tables, origin, face);
+    if outfaces.is_empty() {
+        face.primitives.clone().send_reply_final(qid).await
+    } else {
+        let query = Arc::new(Query {
+            src_face: face.clone(),
+            src_qid: qid,
+            retained: false,
+            read_through: None,
+            canceled: AtomicBool::new(false),
+        });
+        send_retained_query(outfaces, key, &query).await;
+    }
+}
+
+/// Query the retained values matching the given subscription resource from
+/// the routers or peers, along the tree of this one. The replies are
+/// delivered as data to the subscriber face.
+pub(crate) async fn query_retained_values(
+    tables: &mut Tables,
+    face: &Arc<FaceState>,
+    res: &Arc<Resource>,
+) {
+    let outfaces = retained_query_faces(tables, &tables.pid.to_string(), face);
+    if outfaces.is_empty() {
+        return;
+    }
+    log::trace!("Query retained values for {}", res.name());
+    let query = Arc::new(Query {
+        src_face: face.clone(),
+        src_qid: 0,
+        retained: true,
+        read_through: None,
+        canceled: AtomicBool::new(false),
+    });
+    send_retained_query(outfaces, &retained_key(&tables.pid, res), &query).await;
+}
+
+/// Query the retained values matching the pull subscription of the given face
+/// on the given resource from the routers or peers, along the tree of this
+/// one, to populate its cache. Return false if no query was sent, because the
+/// read through is disabled or saturated or because there is nobody to query.
+pub(crate) async fn query_read_through(
+    tables: &mut Tables,
+    face: &Arc<FaceState>,
+    res: &Arc<Resource>,
+) -> bool {
+    match tables.read_through_limit {
+        Some(max) if tables.read_through_queries < max => (),
+        Some(_) => {
+            tables
+                .metrics
+                .read_through_saturated
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        None => return false,
+    }
+    let outfaces = retained_query_faces(tables, &tables.pid.to_string(), face);
+    if outfaces.is_empty() {
+        return false;
+    }
+    log::trace!("Read through {} for {}", res.name(), face);
+    let query = Arc::new(Query {
+        src_face: face.clone(),
+        src_qid: 0,
+        retained: true,
+        read_through: Some(res.clone()),
+        canceled: AtomicBool::new(false),
+    });
+    tables.read_through_queries += 1;
+    send_retained_query(outfaces, &retained_key(&tables.pid, res), &query).await;
+    true
+}
+
+/// The read through queries pending for the pulls of the given face, once
+/// each whatever the number of faces they were sent to.
+fn pending_pulls(tables: &Tables, face_id: usize) -> Vec<Arc<Query>> {
+    let mut pulls: Vec<Arc<Query>> = vec![];
+    for face in tables.faces.values() {
+        for query in face.pending_queries.values() {
+            if query.read_through.is_some()
+                && query.src_face.id == face_id
+                && !query.canceled.load(Ordering::Relaxed)
+                && !pulls.iter().any(|pull| Arc::ptr_eq(pull, query))
+            {
+                pulls.push(query.clone());
+            }
+        }
+    }
+    pulls
+}
+
+/// The names of the pull subscriptions of the given face with a pull waiting
+/// for its read through query, once per pending pull.
+pub(crate) fn in_flight_pulls(tables: &Tables, face_id: usize) -> Vec<String> {
+    pending_pulls(tables, face_id)
+        .iter()
+        .filter_map(|query| query.read_through.as_ref().map(|res| res.name()))
+        .collect()
+}
+
+/// Abort the pulls of the given face waiting for their read through query:
+/// the values are never sent and their read through slots are given back.
+/// The queried faces are not told, the protocol having no way to cancel a
+/// query: their replies are dropped, without being cached, until their final
+/// reply removes the query. Return the number of aborted pulls.
+pub(crate) fn cancel_pulls(tables: &mut Tables, face_id: usize) -> usize {
+    let canceled = pending_pulls(tables, face_id)
+        .iter()
+        .filter(|query| !query.canceled.swap(true, Ordering::Relaxed))
+        .count();
+    if canceled > 0 {
+        tables.read_through_queries -= canceled;
+        log::debug!("Canceled {} pulls of face {}", canceled, face_id);
+    }
+    canceled
+}
+
+/// Send the values collected by a read through query once all the queried
+/// faces replied, unless its pull was canceled.
+async fn complete_read_through(tables: &mut Tables, query: &Query) {
+    if let Some(res) = &query.read_through {
+        if !query.canceled.load(Ordering::Relaxed) {
+            tables.read_through_queries -= 1;
+            send_pulled_values(tables, &query.src_face, res).await;
+        }
+    }
+}
+
+async fn reply_retained_values(tables: &Tables, face: &Arc<FaceState>, expr: &str, qid: ZInt) {
+    if let Some(retained) = &tables.retained {
+        let values: Vec<(String, Option<DataInfo>, RBuf)> = zasynclock!(retained)
+            .iter()
+            .filter(|(key, _)| rname::intersect(expr, key))
+            .map(|(key, (info, payload))| (key.clone(), info.clone(), payload.clone()))
+            .collect();
+        for (key, info, payload) in values {
+            face.primitives
+                .clone()
+                .send_reply_data(
+                    qid,
+                    queryable::STORAGE,
+                    tables.pid.clone(),
+                    ResKey::RName(key),
+                    info,
+                    payload,
+                )
+                .await;
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
-pub(crate) async fn route_send_reply_data(
-    _tables: &mut Tables,
+pub async fn route_send_reply_data(
+    tables: &mut Tables,
     face: &mut Arc<FaceState>,
     qid: ZInt,
     source_kind: ZInt,
@@ -999,6 +1279,48 @@ pub(crate) async fn route_send_reply_data(
     payload: RBuf,
 ) {
     match face.pending_queries.get(&qid) {
+        Some(query) if query.canceled.load(Ordering::Relaxed) => {
+            log::trace!("Drop reply {}:{} of canceled pull", face, qid);
+        }
+        Some(query) if query.read_through.is_some() => {
+            let (prefixid, suffix) = (&reskey).into();
+            match tables.get_mapping(&face, &prefixid) {
+                Some(prefix) => {
+                    let res = Resource::get_resource(&prefix, suffix);
+                    let name = Resource::shared_name(&prefix, &res, suffix);
+                    let ctx = query
+                        .read_through
+                        .as_ref()
+                        .and_then(|res| res.session_ctxs.get(&query.src_face.id));
+                    if let Some(ctx) = ctx {
+                        ctx.cache_value(name, info, payload);
+                    }
+                }
+                None => log::error!("Read through reply with unknown rid {}!", prefixid),
+            }
+        }
+        Some(query) if query.retained => {
+            let (prefixid, suffix) = (&reskey).into();
+            match tables.get_mapping(&face, &prefixid) {
+                Some(prefix) => {
+                    let name = [&prefix.name(), suffix].concat();
+                    let reskey = Resource::get_face_key(&tables.root_res, &name, &query.src_face);
+                    query
+                        .src_face
+                        .primitives
+                        .send_data(
+                            &reskey,
+                            payload,
+                            Reliability::Reliable,
+                            CongestionControl::Block,
+                            info,
+                            None,
+                        )
+                        .await;
+                }
+                None => log::error!("Retained reply with unknown rid {}!", prefixid),
+            }
+        }
         Some(query) => {
             query
                 .src_face
@@ -1018,11 +1340,7 @@ pub(crate) async fn route_send_reply_data(
     }
 }
 
-pub(crate) async fn route_send_reply_final(
-    _tables: &mut Tables,
-    face: &mut Arc<FaceState>,
-    qid: ZInt,
-) {
+pub async fn route_send_reply_final(tables: &mut Tables, face: &mut Arc<FaceState>, qid: ZInt) {
     match face.pending_queries.get(&qid) {
         Some(query) => {
             log::debug!(
@@ -1032,6 +1350,9 @@ pub(crate) async fn route_send_reply_final(
                 face
             );
             if Arc::strong_count(&query) == 1 {
+                complete_read_through(tables, query).await;
+            }
+            if Arc::strong_count(&query) == 1 && !query.retained {
                 log::debug!("Propagate final reply {}:{}", query.src_face, qid);
                 query
                     .src_face
@@ -1046,7 +1367,7 @@ pub(crate) async fn route_send_reply_final(
     }
 }
 
-pub(crate) async fn finalize_pending_queries(_tables: &mut Tables, face: &mut Arc<FaceState>) {
+pub(crate) async fn finalize_pending_queries(tables: &mut Tables, face: &mut Arc<FaceState>) {
     for query in face.pending_queries.values() {
         log::debug!(
             "Finalize reply {}:{} for closing {}",
@@ -1055,6 +1376,9 @@ pub(crate) async fn finalize_pending_queries(_tables: &mut Tables, face: &mut Ar
             face
         );
         if Arc::strong_count(&query) == 1 {
+            complete_read_through(tables, query).await;
+        }
+        if Arc::strong_count(&query) == 1 && !query.retained {
             log::debug!("Propagate final reply {}:{}", query.src_face, query.src_qid);
             query
                 .src_face
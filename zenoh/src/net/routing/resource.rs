@@ -11,34 +11,794 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-use super::face::FaceState;
+use super::face::{DataDelivery, FaceState, KeyRepr};
 use super::protocol::core::rname;
-use super::protocol::core::{PeerId, ResKey, SubInfo, ZInt};
+use super::protocol::core::{
+    CongestionControl, PeerId, Reliability, ResKey, SubInfo, ZInt, NO_RESOURCE_ID,
+};
 use super::protocol::io::RBuf;
 use super::protocol::proto::{DataInfo, RoutingContext};
+use super::protocol::session::{DataBatch, DeliveryAck};
+use super::pubsub::{
+    drop_cleaned_retained, notify_new_matching_key, Batching, CacheFullPolicy, Dedup,
+    DeliveryError, DeliveryErrorHandler, Reordering, SubOptions,
+};
 use super::router::Tables;
 use async_std::sync::{Arc, Weak};
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::hash::{Hash, Hasher};
+#[cfg(feature = "stats")]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+#[cfg(feature = "stats")]
+use std::time::{SystemTime, UNIX_EPOCH};
 use zenoh_util::sync::get_mut_unchecked;
+use zenoh_util::zconfigurable;
+
+zconfigurable! {
+    static ref MIN_BUDGET_COMPACTION: usize = 1024;
+    static ref MAX_DEDUP_KEYS: usize = 1024;
+}
 
 pub(super) type Route = HashMap<usize, (Arc<FaceState>, ResKey, Option<RoutingContext>)>;
 pub(super) type PullCaches = Vec<Arc<SessionContext>>;
 
+type CachedValue = (Option<DataInfo>, RBuf);
+
+/// The values cached for a pull subscription, by key. They are kept in the
+/// order they were cached: a key cached again moves after all the others.
+/// Each key also has a version, changing each time it is cached again, and
+/// the time it was cached.
+#[derive(Clone, Default)]
+pub(super) struct CachedValues {
+    index: HashMap<Arc<str>, (usize, u64, Instant)>,
+    entries: Vec<Option<(Arc<str>, CachedValue)>>,
+    next_version: u64,
+    bytes: usize,
+}
+
+#[inline]
+fn value_size(value: &CachedValue) -> usize {
+    value.1.len()
+}
+
+impl CachedValues {
+    pub(super) fn insert(&mut self, name: Arc<str>, value: CachedValue) {
+        let version = self.next_version;
+        self.next_version += 1;
+        if let Some((idx, _, _)) = self
+            .index
+            .insert(name.clone(), (self.entries.len(), version, Instant::now()))
+        {
+            self.take_entry(idx);
+        }
+        self.bytes += value_size(&value);
+        self.entries.push(Some((name, value)));
+        self.compact_if_sparse();
+    }
+
+    fn take_entry(&mut self, idx: usize) -> Option<Arc<str>> {
+        self.entries[idx].take().map(|(name, value)| {
+            self.bytes -= value_size(&value);
+            name
+        })
+    }
+
+    /// Insert a value, handling a new key with the given policy when
+    /// `capacity` keys are already cached. Return false if the value was
+    /// rejected.
+    pub(super) fn insert_within(
+        &mut self,
+        name: Arc<str>,
+        value: CachedValue,
+        capacity: usize,
+        policy: CacheFullPolicy,
+    ) -> bool {
+        if !self.index.contains_key(&name) && self.index.len() >= capacity.max(1) {
+            match policy {
+                CacheFullPolicy::EvictOldest => self.evict_oldest(),
+                CacheFullPolicy::RejectNew => return false,
+                CacheFullPolicy::EvictExpiredFirst(ttl) => {
+                    if !self.evict_expired(ttl) {
+                        self.evict_oldest();
+                    }
+                }
+            }
+        }
+        self.insert(name, value);
+        true
+    }
+
+    /// The least recently cached key, first in the entries.
+    fn evict_oldest(&mut self) {
+        if let Some(idx) = self.entries.iter().position(Option::is_some) {
+            if let Some(name) = self.take_entry(idx) {
+                self.index.remove(&name);
+            }
+        }
+        self.compact_if_sparse();
+    }
+
+    /// Evict the keys cached longer than `ttl` ago, returning true if any.
+    fn evict_expired(&mut self, ttl: Duration) -> bool {
+        let mut evicted = false;
+        // The entries are ordered by the time they were cached
+        for idx in 0..self.entries.len() {
+            let expired = match &self.entries[idx] {
+                Some((name, _)) => self.index[name].2.elapsed() >= ttl,
+                None => continue,
+            };
+            if !expired {
+                break;
+            }
+            if let Some(name) = self.take_entry(idx) {
+                self.index.remove(&name);
+                evicted = true;
+            }
+        }
+        self.compact_if_sparse();
+        evicted
+    }
+
+    /// The version of each cached key.
+    pub(super) fn versions(&self) -> HashMap<Arc<str>, u64> {
+        self.index
+            .iter()
+            .map(|(name, (_, version, _))| (name.clone(), *version))
+            .collect()
+    }
+
+    /// The version of a cached key.
+    pub(super) fn version(&self, name: &str) -> Option<u64> {
+        self.index.get(name).map(|(_, version, _)| *version)
+    }
+
+    /// Remove the given keys, unless they were cached again since they had the
+    /// given versions.
+    pub(super) fn remove_versions(&mut self, versions: &HashMap<Arc<str>, u64>) {
+        for (name, version) in versions {
+            if let Some((idx, current, _)) = self.index.get(name) {
+                if current == version {
+                    let idx = *idx;
+                    self.take_entry(idx);
+                    self.index.remove(name);
+                }
+            }
+        }
+        self.compact_if_sparse();
+    }
+
+    fn compact_if_sparse(&mut self) {
+        if self.entries.len() > 2 * self.index.len() {
+            self.compact();
+        }
+    }
+
+    /// Drop the slots of the keys that were cached again or removed.
+    fn compact(&mut self) {
+        self.entries.retain(Option::is_some);
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if let Some((name, _)) = entry {
+                if let Some(slot) = self.index.get_mut(name) {
+                    slot.0 = idx;
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub(super) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    #[inline]
+    pub(super) fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// The size of the cached payloads.
+    #[inline]
+    pub(super) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.index.clear();
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    /// The cached values, in the order they were cached.
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&Arc<str>, &CachedValue)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.as_ref().map(|(name, value)| (name, value)))
+    }
+
+    pub(super) fn keys(&self) -> impl Iterator<Item = &Arc<str>> {
+        self.iter().map(|(name, _)| name)
+    }
+}
+
+impl Extend<(Arc<str>, CachedValue)> for CachedValues {
+    fn extend<T: IntoIterator<Item = (Arc<str>, CachedValue)>>(&mut self, iter: T) {
+        for (name, value) in iter {
+            self.insert(name, value);
+        }
+    }
+}
+
+impl IntoIterator for CachedValues {
+    type Item = (Arc<str>, CachedValue);
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<Option<Self::Item>>>;
+
+    /// Consume the cached values, in the order they were cached.
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter().flatten()
+    }
+}
+
+struct BudgetOrder {
+    values: VecDeque<(Weak<SessionContext>, Arc<str>, u64)>,
+    compacted_len: usize,
+}
+
+/// The bound of the size of the payloads cached for all the pull
+/// subscriptions of some tables, see `Tables::set_max_cached_bytes`. Beyond
+/// it, the values are evicted across the subscriptions in the order they were
+/// cached, the oldest first.
+pub(crate) struct PullCacheBudget {
+    max_bytes: usize,
+    used: AtomicUsize,
+    /// The cached values in the order they were cached, as their context, key
+    /// and version. The values removed or cached again since are skipped.
+    order: Mutex<BudgetOrder>,
+}
+
+impl PullCacheBudget {
+    pub(super) fn new(max_bytes: usize) -> PullCacheBudget {
+        PullCacheBudget {
+            max_bytes,
+            used: AtomicUsize::new(0),
+            order: Mutex::new(BudgetOrder {
+                values: VecDeque::new(),
+                compacted_len: 0,
+            }),
+        }
+    }
+
+    /// The size of the cached payloads accounted in this budget.
+    #[inline]
+    pub(super) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn adjust(&self, before: usize, after: usize) {
+        if after > before {
+            self.used.fetch_add(after - before, Ordering::Relaxed);
+        } else if before > after {
+            self.used.fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the values newly cached by a context, then evict the oldest
+    /// values of all the contexts until the bound is met.
+    fn record(&self, ctx: &Arc<SessionContext>, values: Vec<(Arc<str>, u64)>) {
+        let mut order = self.order.lock().unwrap();
+        for (name, version) in values {
+            order.values.push_back((Arc::downgrade(ctx), name, version));
+        }
+        while self.used() > self.max_bytes {
+            match order.values.pop_front() {
+                Some((ctx, name, version)) => {
+                    if let Some(ctx) = ctx.upgrade() {
+                        log::trace!("Cache budget exceeded, evict {} of {}", name, ctx.face);
+                        ctx.evict_version(&name, version);
+                    }
+                }
+                None => break,
+            }
+        }
+        if order.values.len() > 2 * order.compacted_len.max(*MIN_BUDGET_COMPACTION) {
+            order
+                .values
+                .retain(|(ctx, name, version)| match ctx.upgrade() {
+                    Some(ctx) => ctx.last_values.lock().unwrap().version(name) == Some(*version),
+                    None => false,
+                });
+            order.compacted_len = order.values.len();
+        }
+    }
+}
+
+/// The outcomes of the deliveries of the data pushed to a subscription
+/// tracking the acknowledgments, see `SubOptions::track_acks`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeliveryStats {
+    /// The data handed over to the transport.
+    pub delivered: usize,
+    /// The delivered data acknowledged by the transport. Only the primitives
+    /// whose `send_data_acked` reports `DeliveryAck::Acked` fill it: the
+    /// sessions transport doesn't acknowledge the data, so it stays 0 for the
+    /// faces of remote sessions.
+    pub acked: usize,
+    /// The data the transport failed to deliver, or that were dropped before
+    /// reaching it, e.g. by a send timeout.
+    pub failed: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct DeliveryCounters {
+    delivered: AtomicUsize,
+    acked: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl DeliveryCounters {
+    pub(super) fn record(&self, ack: DeliveryAck) {
+        match ack {
+            DeliveryAck::Sent => {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+            }
+            DeliveryAck::Acked => {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+                self.acked.fetch_add(1, Ordering::Relaxed);
+            }
+            DeliveryAck::Failed => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(super) fn snapshot(&self) -> DeliveryStats {
+        DeliveryStats {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            acked: self.acked.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The reporting of the outcome of the deliveries of the data pushed to a
+/// subscription: to its delivery counters if it tracks the acknowledgments,
+/// and of the failures to its error handler if it is reliable.
+#[derive(Clone)]
+pub(crate) struct DeliveryTracking {
+    counters: Option<Arc<DeliveryCounters>>,
+    on_error: Option<Arc<dyn DeliveryErrorHandler>>,
+}
+
+impl DeliveryTracking {
+    /// Record the acknowledgment of the transport for the data sent with the
+    /// given key.
+    pub(super) fn record(&self, reskey: &ResKey, ack: DeliveryAck) {
+        match ack {
+            DeliveryAck::Failed => self.fail(reskey, DeliveryError::Failed),
+            ack => {
+                if let Some(counters) = &self.counters {
+                    counters.record(ack);
+                }
+            }
+        }
+    }
+
+    /// Record the failure to deliver the data sent with the given key.
+    pub(super) fn fail(&self, reskey: &ResKey, error: DeliveryError) {
+        if let Some(counters) = &self.counters {
+            counters.record(DeliveryAck::Failed);
+        }
+        if let Some(on_error) = &self.on_error {
+            log::debug!("Failed to deliver {}: {:?}", reskey, error);
+            on_error.on_delivery_error(reskey, error);
+        }
+    }
+}
+
+/// The data buffered for a subscription batching its deliveries, see
+/// `SubOptions::batching`.
+pub(super) struct PendingBatch {
+    pub(super) deadline: Instant,
+    pub(super) samples: DataBatch,
+    pub(super) reliability: Reliability,
+    pub(super) congestion_control: CongestionControl,
+}
+
+/// The data of a source buffered for a subscription reordering its
+/// deliveries, see `SubOptions::reordering`.
+struct ReorderBuffer {
+    /// The sequence number of the next data to deliver.
+    next_sn: ZInt,
+    /// The data received ahead of a gap, by sequence number.
+    pending: BTreeMap<ZInt, DataDelivery>,
+    /// The time at which the gap is skipped, if data are pending.
+    deadline: Option<Instant>,
+}
+
+impl ReorderBuffer {
+    /// Take the data following the last delivered ones without gap, in
+    /// order, after skipping the gap if the window is full or its timeout
+    /// elapsed.
+    fn release(&mut self, reordering: &Reordering, now: Instant) -> Vec<DataDelivery> {
+        let skip = self.pending.len() > reordering.max_window
+            || self.deadline.map_or(false, |deadline| deadline <= now);
+        if skip {
+            if let Some(sn) = self.pending.keys().next() {
+                self.next_sn = *sn;
+            }
+        }
+        let mut ready = vec![];
+        while let Some(delivery) = self.pending.remove(&self.next_sn) {
+            ready.push(delivery);
+            self.next_sn += 1;
+        }
+        self.deadline = if self.pending.is_empty() {
+            None
+        } else if ready.is_empty() && self.deadline.is_some() {
+            self.deadline
+        } else {
+            Some(now + reordering.timeout)
+        };
+        ready
+    }
+}
+
 pub(super) struct SessionContext {
     pub(super) face: Arc<FaceState>,
     pub(super) local_rid: Option<ZInt>,
     pub(super) remote_rid: Option<ZInt>,
-    pub(super) subs: Option<SubInfo>,
     #[allow(dead_code)]
     pub(super) qabl: bool,
-    pub(super) last_values: HashMap<String, (Option<DataInfo>, RBuf)>,
+    subs: RwLock<Option<(SubInfo, Arc<SubOptions>)>>,
+    last_values: Mutex<CachedValues>,
+    budget: RwLock<Option<Arc<PullCacheBudget>>>,
+    /// The hash of the last payload delivered to a deduplicating
+    /// subscription, by key if deduplicated by key, and when it was delivered.
+    delivered: Mutex<HashMap<Option<String>, (u64, Instant)>>,
+    pub(super) deliveries: Arc<DeliveryCounters>,
+    /// The data buffered for a subscription batching its deliveries.
+    batch: Mutex<Option<PendingBatch>>,
+    /// The data buffered for a subscription reordering its deliveries, by
+    /// source.
+    reorder: Mutex<HashMap<PeerId, ReorderBuffer>>,
+}
+
+impl SessionContext {
+    pub(super) fn new(
+        face: Arc<FaceState>,
+        local_rid: Option<ZInt>,
+        remote_rid: Option<ZInt>,
+    ) -> SessionContext {
+        SessionContext {
+            face,
+            local_rid,
+            remote_rid,
+            qabl: false,
+            subs: RwLock::new(None),
+            last_values: Mutex::new(CachedValues::default()),
+            budget: RwLock::new(None),
+            delivered: Mutex::new(HashMap::new()),
+            deliveries: Arc::new(DeliveryCounters::default()),
+            batch: Mutex::new(None),
+            reorder: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The tracking of the deliveries of the subscription of the face with
+    /// the given options, if it tracks the acknowledgments or, being
+    /// reliable, the delivery errors.
+    pub(super) fn delivery_tracking(&self, options: &SubOptions) -> Option<DeliveryTracking> {
+        let reliable = self
+            .subs()
+            .map(|sub_info| sub_info.reliability == Reliability::Reliable)
+            .unwrap_or(false);
+        let tracking = DeliveryTracking {
+            counters: options.track_acks.then(|| self.deliveries.clone()),
+            on_error: options.on_delivery_error.clone().filter(|_| reliable),
+        };
+        (tracking.counters.is_some() || tracking.on_error.is_some()).then(|| tracking)
+    }
+
+    /// The info of the subscription of the face, if any.
+    #[inline]
+    pub(super) fn subs(&self) -> Option<SubInfo> {
+        self.subs
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(sub_info, _)| sub_info.clone())
+    }
+
+    #[inline]
+    pub(super) fn has_subs(&self) -> bool {
+        self.subs.read().unwrap().is_some()
+    }
+
+    /// The options of the subscription of the face, the default ones if the
+    /// face has no subscription.
+    #[inline]
+    pub(super) fn sub_options(&self) -> Arc<SubOptions> {
+        self.subs
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(_, options)| options.clone())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn set_subs(&self, sub_info: &SubInfo, options: &SubOptions) {
+        *self.subs.write().unwrap() = Some((sub_info.clone(), Arc::new(options.clone())));
+    }
+
+    pub(super) fn clear_subs(&self) {
+        *self.subs.write().unwrap() = None;
+    }
+
+    /// Whether a payload with the given hash is identical to the last one
+    /// delivered, for the given key if any, within the window of the
+    /// deduplication. Otherwise it is recorded as the last delivered one.
+    pub(super) fn is_duplicate(&self, dedup: &Dedup, key: Option<String>, hash: u64) -> bool {
+        let now = Instant::now();
+        let mut delivered = self.delivered.lock().unwrap();
+        if delivered.len() >= *MAX_DEDUP_KEYS {
+            delivered.retain(|_, (_, time)| now.duration_since(*time) < dedup.window);
+        }
+        match delivered.get(&key) {
+            Some((last, time)) if *last == hash && now.duration_since(*time) < dedup.window => true,
+            _ => {
+                delivered.insert(key, (hash, now));
+                false
+            }
+        }
+    }
+
+    /// Buffer data in the batch of the subscription, returning the batch if
+    /// it is full or its window elapsed. A batch is delivered with the
+    /// reliability and congestion control of its first data.
+    pub(super) fn push_batched(
+        &self,
+        batching: &Batching,
+        sample: (ResKey, RBuf, Option<DataInfo>),
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+    ) -> Option<PendingBatch> {
+        let now = Instant::now();
+        let mut batch = self.batch.lock().unwrap();
+        let pending = batch.get_or_insert_with(|| PendingBatch {
+            deadline: now + batching.window,
+            samples: vec![],
+            reliability,
+            congestion_control,
+        });
+        pending.samples.push(sample);
+        if pending.samples.len() >= batching.max_samples.max(1) || now >= pending.deadline {
+            batch.take()
+        } else {
+            None
+        }
+    }
+
+    /// Take the batch of the subscription if its window elapsed.
+    pub(super) fn take_due_batch(&self, now: Instant) -> Option<PendingBatch> {
+        let mut batch = self.batch.lock().unwrap();
+        match &*batch {
+            Some(pending) if pending.deadline <= now => batch.take(),
+            _ => None,
+        }
+    }
+
+    /// The time at which the pending batch of the subscription, if any, is
+    /// due.
+    pub(super) fn batch_deadline(&self) -> Option<Instant> {
+        self.batch
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|pending| pending.deadline)
+    }
+
+    /// Reorder data pushed to the subscription by the sequence number of
+    /// their source, returning the data to deliver now, in order. The data
+    /// without source id or sequence number, the first ones of a source and
+    /// the ones older than the next expected one are delivered at once.
+    pub(super) fn reorder(&self, delivery: DataDelivery) -> Vec<DataDelivery> {
+        let reordering = match self.sub_options().reordering {
+            Some(reordering) => reordering,
+            None => return vec![delivery],
+        };
+        let (source, sn) = match &delivery.data_info {
+            Some(DataInfo {
+                source_id: Some(source),
+                source_sn: Some(sn),
+                ..
+            }) => (source.clone(), *sn),
+            _ => return vec![delivery],
+        };
+        let mut buffers = self.reorder.lock().unwrap();
+        let buffer = buffers.entry(source).or_insert_with(|| ReorderBuffer {
+            next_sn: sn,
+            pending: BTreeMap::new(),
+            deadline: None,
+        });
+        if sn < buffer.next_sn {
+            return vec![delivery];
+        }
+        buffer.pending.insert(sn, delivery);
+        buffer.release(&reordering, Instant::now())
+    }
+
+    /// Take the data buffered for the subscription whose gap timed out, in
+    /// order for each source.
+    pub(super) fn take_due_reorders(&self, now: Instant) -> Vec<DataDelivery> {
+        let reordering = match self.sub_options().reordering {
+            Some(reordering) => reordering,
+            None => return vec![],
+        };
+        let mut buffers = self.reorder.lock().unwrap();
+        let mut ready = vec![];
+        for buffer in buffers.values_mut() {
+            if buffer.deadline.map_or(false, |deadline| deadline <= now) {
+                ready.extend(buffer.release(&reordering, now));
+            }
+        }
+        ready
+    }
+
+    /// The time at which the next gap of the data buffered for the
+    /// subscription, if any, is skipped.
+    pub(super) fn reorder_deadline(&self) -> Option<Instant> {
+        self.reorder
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|buffer| buffer.deadline)
+            .min()
+    }
+
+    /// Account the cached values of this context in the given budget, the
+    /// values already cached included.
+    pub(super) fn set_cache_budget(self: &Arc<Self>, budget: Option<Arc<PullCacheBudget>>) {
+        let mut current = self.budget.write().unwrap();
+        let unchanged = match (&*current, &budget) {
+            (Some(current), Some(budget)) => Arc::ptr_eq(current, budget),
+            (None, None) => true,
+            _ => false,
+        };
+        if unchanged {
+            return;
+        }
+        let (bytes, values) = {
+            let last_values = self.last_values.lock().unwrap();
+            let values: Vec<(Arc<str>, u64)> = last_values
+                .keys()
+                .filter_map(|name| last_values.version(name).map(|v| (name.clone(), v)))
+                .collect();
+            (last_values.bytes(), values)
+        };
+        if let Some(current) = current.as_ref() {
+            current.adjust(bytes, 0);
+        }
+        *current = budget.clone();
+        drop(current);
+        if let Some(budget) = budget {
+            budget.adjust(0, bytes);
+            budget.record(self, values);
+        }
+    }
+
+    /// Update the cached values, accounting the change of their size in the
+    /// budget of the context, if any.
+    fn update_cache<T>(&self, f: impl FnOnce(&mut CachedValues) -> T) -> T {
+        let budget = self.budget.read().unwrap().clone();
+        let mut last_values = self.last_values.lock().unwrap();
+        let before = last_values.bytes();
+        let result = f(&mut last_values);
+        if let Some(budget) = budget {
+            budget.adjust(before, last_values.bytes());
+        }
+        result
+    }
+
+    /// Record the values newly cached in the budget of the context, if any,
+    /// evicting the oldest cached values when it is exceeded.
+    fn record_cached(self: &Arc<Self>, values: Vec<(Arc<str>, u64)>) {
+        let budget = self.budget.read().unwrap().clone();
+        if let Some(budget) = budget {
+            budget.record(self, values);
+        }
+    }
+
+    /// Cache a value, within the bound of the options of the subscription and
+    /// of the budget of the context.
+    pub(super) fn cache_value(
+        self: &Arc<Self>,
+        name: Arc<str>,
+        info: Option<DataInfo>,
+        payload: RBuf,
+    ) {
+        let options = self.sub_options();
+        let version = self.update_cache(|last_values| {
+            match options.max_cached_keys {
+                Some(capacity) => {
+                    if !last_values.insert_within(
+                        name.clone(),
+                        (info, payload),
+                        capacity,
+                        options.cache_full,
+                    ) {
+                        return None;
+                    }
+                }
+                None => last_values.insert(name.clone(), (info, payload)),
+            }
+            last_values.version(&name)
+        });
+        match version {
+            Some(version) => self.record_cached(vec![(name, version)]),
+            None => log::debug!(
+                "Cache of {} full, drop value for new key {}",
+                self.face,
+                name
+            ),
+        }
+    }
+
+    pub(super) fn cache_values(self: &Arc<Self>, values: CachedValues) {
+        let names: Vec<Arc<str>> = values.keys().cloned().collect();
+        let versions = self.update_cache(|last_values| {
+            last_values.extend(values);
+            names
+                .into_iter()
+                .filter_map(|name| last_values.version(&name).map(|v| (name, v)))
+                .collect()
+        });
+        self.record_cached(versions);
+    }
+
+    /// Evict a cached value, unless it was cached again since it had the given
+    /// version.
+    fn evict_version(&self, name: &Arc<str>, version: u64) {
+        let mut versions = HashMap::new();
+        versions.insert(name.clone(), version);
+        self.update_cache(|last_values| last_values.remove_versions(&versions));
+    }
+
+    /// A copy of the cached values.
+    pub(super) fn cached_values(&self) -> CachedValues {
+        self.last_values.lock().unwrap().clone()
+    }
+
+    pub(super) fn has_cached_values(&self) -> bool {
+        !self.last_values.lock().unwrap().is_empty()
+    }
+
+    /// Remove the cached values that were delivered with the given versions,
+    /// keeping the values cached since they were copied.
+    pub(super) fn remove_delivered(&self, versions: &HashMap<Arc<str>, u64>) {
+        self.update_cache(|last_values| last_values.remove_versions(versions));
+    }
+
+    pub(super) fn clear_cache(&self) {
+        self.update_cache(CachedValues::clear);
+    }
+}
+
+impl Drop for SessionContext {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget.get_mut().unwrap() {
+            budget.adjust(self.last_values.get_mut().unwrap().bytes(), 0);
+        }
+    }
 }
 
 pub(super) struct ResourceContext {
     pub(super) router_subs: HashSet<PeerId>,
     pub(super) peer_subs: HashSet<PeerId>,
+    pub(super) peer_sub_reliabilities: HashMap<PeerId, Reliability>,
     pub(super) router_qabls: HashSet<PeerId>,
     pub(super) peer_qabls: HashSet<PeerId>,
     pub(super) matches: Vec<Weak<Resource>>,
@@ -56,6 +816,7 @@ impl ResourceContext {
         ResourceContext {
             router_subs: HashSet::new(),
             peer_subs: HashSet::new(),
+            peer_sub_reliabilities: HashMap::new(),
             router_qabls: HashSet::new(),
             peer_qabls: HashSet::new(),
             matches: Vec::new(),
@@ -70,55 +831,151 @@ impl ResourceContext {
     }
 }
 
+/// The access statistics of a resource.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyStats {
+    pub publications: usize,
+    pub bytes: usize,
+    pub last_access: SystemTime,
+}
+
+#[cfg(feature = "stats")]
+#[derive(Default)]
+pub(super) struct KeyCounters {
+    publications: AtomicUsize,
+    bytes: AtomicUsize,
+    last_access_ms: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl KeyCounters {
+    #[inline]
+    pub(super) fn record(&self, bytes: usize) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.publications.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.last_access_ms
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> KeyStats {
+        KeyStats {
+            publications: self.publications.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            last_access: UNIX_EPOCH
+                + Duration::from_millis(self.last_access_ms.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A custom matching of key expressions, used instead of the default one for
+/// the keys under a given prefix (see `Tables::set_key_matcher`). It must be
+/// pure, deterministic and symmetric.
+pub trait KeyMatcher: Send + Sync {
+    fn intersect(&self, key_expr1: &str, key_expr2: &str) -> bool;
+}
+
+/// The target of the logs of the operations on the traced resources, see
+/// `Tables::trace_resource`.
+pub const TRACE_TARGET: &str = "zenoh::routing::trace";
+
 pub struct Resource {
     pub(super) parent: Option<Arc<Resource>>,
-    pub(super) suffix: String,
+    pub(super) suffix: Arc<str>,
+    /// The full name of the resource, shared through the interner of the
+    /// tables.
+    pub(super) expr: Arc<str>,
     pub(super) nonwild_prefix: Option<(Arc<Resource>, String)>,
-    pub(super) childs: HashMap<String, Arc<Resource>>,
+    pub(super) childs: HashMap<Arc<str>, Arc<Resource>>,
     pub(super) context: Option<ResourceContext>,
     pub(super) session_ctxs: HashMap<usize, Arc<SessionContext>>,
+    /// Whether the operations on this resource are logged, see
+    /// `Tables::trace_resource`.
+    pub(super) traced: bool,
+    #[cfg(feature = "stats")]
+    pub(super) stats: KeyCounters,
 }
 
 impl PartialEq for Resource {
     fn eq(&self, other: &Self) -> bool {
-        self.name() == other.name()
+        self.expr == other.expr
     }
 }
 impl Eq for Resource {}
 
 impl Hash for Resource {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.name().hash(state);
+        self.expr.hash(state);
     }
 }
 
 impl Resource {
-    fn new(parent: &Arc<Resource>, suffix: &str, context: Option<ResourceContext>) -> Resource {
+    fn new(
+        parent: &Arc<Resource>,
+        suffix: Arc<str>,
+        expr: Arc<str>,
+        context: Option<ResourceContext>,
+    ) -> Resource {
         let nonwild_prefix = match &parent.nonwild_prefix {
             None => {
                 if suffix.contains('*') {
-                    Some((parent.clone(), String::from(suffix)))
+                    Some((parent.clone(), String::from(&*suffix)))
                 } else {
                     None
                 }
             }
-            Some((prefix, wildsuffix)) => Some((prefix.clone(), [wildsuffix, suffix].concat())),
+            Some((prefix, wildsuffix)) => Some((prefix.clone(), [wildsuffix, &*suffix].concat())),
         };
 
         Resource {
             parent: Some(parent.clone()),
-            suffix: String::from(suffix),
+            suffix,
+            expr,
             nonwild_prefix,
             childs: HashMap::new(),
             context,
             session_ctxs: HashMap::new(),
+            traced: false,
+            #[cfg(feature = "stats")]
+            stats: KeyCounters::default(),
+        }
+    }
+
+    /// Log at info level an operation on this resource if it is traced,
+    /// whatever the maximum log level. The records have the
+    /// [`TRACE_TARGET`] target, that the installed logger may still filter.
+    #[inline]
+    pub(super) fn trace(&self, args: fmt::Arguments) {
+        if self.traced {
+            log::logger().log(
+                &log::Record::builder()
+                    .args(args)
+                    .level(log::Level::Info)
+                    .target(TRACE_TARGET)
+                    .module_path(Some(module_path!()))
+                    .build(),
+            );
         }
     }
 
     pub fn name(&self) -> String {
-        match &self.parent {
-            Some(parent) => [&parent.name() as &str, &self.suffix].concat(),
-            None => String::from(""),
+        String::from(&*self.expr)
+    }
+
+    /// The shared name of the resource `suffix` relative to `prefix`: the
+    /// interned name of the resource if it is registered, a new string
+    /// otherwise.
+    pub(super) fn shared_name(
+        prefix: &Arc<Resource>,
+        res: &Option<Arc<Resource>>,
+        suffix: &str,
+    ) -> Arc<str> {
+        match res {
+            Some(res) => res.expr.clone(),
+            None => Arc::from([&*prefix.expr, suffix].concat()),
         }
     }
 
@@ -200,15 +1057,25 @@ impl Resource {
     pub fn root() -> Arc<Resource> {
         Arc::new(Resource {
             parent: None,
-            suffix: String::from(""),
+            suffix: Arc::from(""),
+            expr: Arc::from(""),
             nonwild_prefix: None,
             childs: HashMap::new(),
             context: None,
             session_ctxs: HashMap::new(),
+            traced: false,
+            #[cfg(feature = "stats")]
+            stats: KeyCounters::default(),
         })
     }
 
     pub fn clean(res: &mut Arc<Resource>) {
+        Resource::clean_with(res, &mut vec![])
+    }
+
+    /// Same as [`Resource::clean`], pushing the names of the unregistered
+    /// resources to `cleaned`.
+    pub fn clean_with(res: &mut Arc<Resource>, cleaned: &mut Vec<String>) {
         let mut resclone = res.clone();
         let mutres = get_mut_unchecked(&mut resclone);
         if let Some(ref mut parent) = mutres.parent {
@@ -229,7 +1096,8 @@ impl Resource {
                 {
                     get_mut_unchecked(parent).childs.remove(&res.suffix);
                 }
-                Resource::clean(parent);
+                cleaned.push(res.name());
+                Resource::clean_with(parent, cleaned);
             }
         }
     }
@@ -243,11 +1111,47 @@ impl Resource {
         result
     }
 
+    /// Normalize the `suffix` of a key expression relative to `from`, so that
+    /// the different ways of splitting a key expression in prefix and suffix
+    /// designate the same resource: a sequence of `/` is replaced by a single
+    /// `/`, including across the boundary between the name of `from` and
+    /// `suffix`. So `/a/` followed by `/b` and `/a//b` both designate `/a/b`.
+    ///
+    /// A trailing `/` is dropped too, as [`rname::intersect`] doesn't tell
+    /// `/a/` from `/a`: both designate `/a`. Only the `/` resource keeps it.
+    pub fn normalize_suffix<'a>(from: &Resource, suffix: &'a str) -> Cow<'a, str> {
+        let mut last_is_slash = from.suffix.ends_with('/');
+        let mut normalized =
+            if !suffix.contains("//") && (!last_is_slash || !suffix.starts_with('/')) {
+                Cow::from(suffix)
+            } else {
+                let mut normalized = String::with_capacity(suffix.len());
+                for c in suffix.chars() {
+                    if c != '/' || !last_is_slash {
+                        normalized.push(c);
+                    }
+                    last_is_slash = c == '/';
+                }
+                Cow::from(normalized)
+            };
+        if normalized.ends_with('/') && (normalized.len() > 1 || from.parent.is_some()) {
+            normalized = match normalized {
+                Cow::Borrowed(suffix) => Cow::from(&suffix[..suffix.len() - 1]),
+                Cow::Owned(mut suffix) => {
+                    suffix.pop();
+                    Cow::from(suffix)
+                }
+            };
+        }
+        normalized
+    }
+
     pub fn make_resource(
         tables: &mut Tables,
         from: &mut Arc<Resource>,
         suffix: &str,
     ) -> Arc<Resource> {
+        let suffix = &*Resource::normalize_suffix(from, suffix);
         if suffix.is_empty() {
             Resource::upgrade_resource(from);
             from.clone()
@@ -260,21 +1164,21 @@ impl Resource {
             match get_mut_unchecked(from).childs.get_mut(chunk) {
                 Some(mut res) => Resource::make_resource(tables, &mut res, rest),
                 None => {
-                    let mut new = Arc::new(Resource::new(from, chunk, None));
+                    let chunk = tables.interner.intern(chunk);
+                    let expr = tables.interner.intern(&[&*from.expr, &*chunk].concat());
+                    let mut new = Arc::new(Resource::new(from, chunk.clone(), expr, None));
                     if log::log_enabled!(log::Level::Debug) && rest.is_empty() {
                         log::debug!("Register resource {}", new.name());
                     }
                     let res = Resource::make_resource(tables, &mut new, rest);
-                    get_mut_unchecked(from)
-                        .childs
-                        .insert(String::from(chunk), new);
+                    get_mut_unchecked(from).childs.insert(chunk, new);
                     res
                 }
             }
         } else {
             match from.parent.clone() {
                 Some(mut parent) => {
-                    Resource::make_resource(tables, &mut parent, &[&from.suffix, suffix].concat())
+                    Resource::make_resource(tables, &mut parent, &[&*from.suffix, suffix].concat())
                 }
                 None => {
                     let (chunk, rest) = match suffix[1..].find('/') {
@@ -285,14 +1189,14 @@ impl Resource {
                     match get_mut_unchecked(from).childs.get_mut(chunk) {
                         Some(mut res) => Resource::make_resource(tables, &mut res, rest),
                         None => {
-                            let mut new = Arc::new(Resource::new(from, chunk, None));
+                            let chunk = tables.interner.intern(chunk);
+                            let expr = tables.interner.intern(&[&*from.expr, &*chunk].concat());
+                            let mut new = Arc::new(Resource::new(from, chunk.clone(), expr, None));
                             if log::log_enabled!(log::Level::Debug) && rest.is_empty() {
                                 log::debug!("Register resource {}", new.name());
                             }
                             let res = Resource::make_resource(tables, &mut new, rest);
-                            get_mut_unchecked(from)
-                                .childs
-                                .insert(String::from(chunk), new);
+                            get_mut_unchecked(from).childs.insert(chunk, new);
                             res
                         }
                     }
@@ -303,6 +1207,7 @@ impl Resource {
 
     #[inline]
     pub fn get_resource(from: &Arc<Resource>, suffix: &str) -> Option<Arc<Resource>> {
+        let suffix = &*Resource::normalize_suffix(from, suffix);
         if suffix.is_empty() {
             Some(from.clone())
         } else if let Some(stripped_suffix) = suffix.strip_prefix('/') {
@@ -317,7 +1222,7 @@ impl Resource {
             }
         } else {
             match &from.parent {
-                Some(parent) => Resource::get_resource(&parent, &[&from.suffix, suffix].concat()),
+                Some(parent) => Resource::get_resource(&parent, &[&*from.suffix, suffix].concat()),
                 None => {
                     let (chunk, rest) = match suffix[1..].find('/') {
                         Some(idx) => (&suffix[0..(idx + 1)], &suffix[(idx + 1)..]),
@@ -349,22 +1254,16 @@ impl Resource {
 
     #[inline]
     pub async fn decl_key(res: &Arc<Resource>, face: &mut Arc<FaceState>) -> ResKey {
+        if face.key_repr == KeyRepr::Name {
+            return res.name().into();
+        }
         let (nonwild_prefix, wildsuffix) = Resource::nonwild_prefix(res);
         match nonwild_prefix {
             Some(mut nonwild_prefix) => {
                 let mut ctx = get_mut_unchecked(&mut nonwild_prefix)
                     .session_ctxs
                     .entry(face.id)
-                    .or_insert_with(|| {
-                        Arc::new(SessionContext {
-                            face: face.clone(),
-                            local_rid: None,
-                            remote_rid: None,
-                            subs: None,
-                            qabl: false,
-                            last_values: HashMap::new(),
-                        })
-                    });
+                    .or_insert_with(|| Arc::new(SessionContext::new(face.clone(), None, None)));
 
                 let rid = match ctx.local_rid.or(ctx.remote_rid) {
                     Some(rid) => rid,
@@ -386,6 +1285,12 @@ impl Resource {
         }
     }
 
+    /// The key of `prefix` + `suffix` for the face `sid`: the id of the
+    /// longest registered prefix of the name the face has a mapping for,
+    /// declared to the face or else by the face, followed by the rest of the
+    /// name. When the face has no mapping for any prefix of the name, fall
+    /// back to the full name (see [`Resource::fallback_key`]), so that the
+    /// key is always valid for the face.
     #[inline]
     pub fn get_best_key(prefix: &Arc<Resource>, suffix: &str, sid: usize) -> ResKey {
         fn get_best_key_(
@@ -400,24 +1305,87 @@ impl Resource {
                     return get_best_key_(child, rest, sid, true);
                 }
             }
-            if let Some(ctx) = prefix.session_ctxs.get(&sid) {
-                if let Some(rid) = ctx.local_rid {
-                    return (rid, suffix).into();
-                } else if let Some(rid) = ctx.remote_rid {
-                    return (rid, suffix).into();
-                }
+            if let Some(rid) = Resource::face_rid(prefix, sid) {
+                return (rid, suffix).into();
             }
             match &prefix.parent {
                 Some(parent) => {
-                    get_best_key_(&parent, &[&prefix.suffix, suffix].concat(), sid, false)
+                    get_best_key_(&parent, &[&*prefix.suffix, suffix].concat(), sid, false)
                 }
-                None => (0, suffix).into(),
+                None => Resource::fallback_key(prefix, suffix),
             }
         }
         get_best_key_(prefix, suffix, sid, true)
     }
 
+    /// The id the face `sid` has a mapping with for the resource, the one
+    /// declared to the face first. `NO_RESOURCE_ID` is never a mapping.
+    #[inline]
+    pub fn face_rid(res: &Resource, sid: usize) -> Option<ZInt> {
+        res.session_ctxs.get(&sid).and_then(|ctx| {
+            ctx.local_rid
+                .filter(|rid| *rid != NO_RESOURCE_ID)
+                .or_else(|| ctx.remote_rid.filter(|rid| *rid != NO_RESOURCE_ID))
+        })
+    }
+
+    /// The key of `prefix` + `suffix` for a face with no mapping for any
+    /// prefix of it: the full name, never a numeric id, even for an empty
+    /// name.
+    #[inline]
+    pub fn fallback_key(prefix: &Resource, suffix: &str) -> ResKey {
+        ResKey::RName([&prefix.name(), suffix].concat())
+    }
+
+    /// The best key for the given face, in its preferred representation.
+    #[inline]
+    pub fn get_face_key(prefix: &Arc<Resource>, suffix: &str, face: &FaceState) -> ResKey {
+        match face.key_repr {
+            KeyRepr::Name => [&prefix.name(), suffix].concat().into(),
+            KeyRepr::Auto | KeyRepr::Numeric => Resource::get_best_key(prefix, suffix, face.id),
+        }
+    }
+
     pub fn get_matches(tables: &Tables, rname: &str) -> Vec<Weak<Resource>> {
+        match &tables.key_matcher {
+            Some((prefix, matcher))
+                if rname
+                    .strip_prefix(prefix.as_str())
+                    .map(|rest| rest.is_empty() || rest.starts_with('/'))
+                    .unwrap_or(false) =>
+            {
+                Resource::get_custom_matches_in(&tables.root_res, prefix, rname, matcher.as_ref())
+            }
+            _ => Resource::get_matches_in(&tables.root_res, rname),
+        }
+    }
+
+    /// Returns the resources of the tree of `root` under `prefix` whose names
+    /// intersect `rname` according to the given matcher.
+    fn get_custom_matches_in(
+        root: &Arc<Resource>,
+        prefix: &str,
+        rname: &str,
+        matcher: &dyn KeyMatcher,
+    ) -> Vec<Weak<Resource>> {
+        let rname = &*Resource::normalize_suffix(root, rname);
+        let mut matches = vec![];
+        let mut stack: Vec<Arc<Resource>> =
+            Resource::get_resource(root, prefix).into_iter().collect();
+        while let Some(res) = stack.pop() {
+            if res.context.is_some() && matcher.intersect(rname, &res.name()) {
+                matches.push(Arc::downgrade(&res));
+            }
+            stack.extend(res.childs.values().cloned());
+        }
+        matches
+    }
+
+    /// Returns the resources of the tree of `root` whose names intersect
+    /// `rname`. Only the resources with a context (i.e. that were registered
+    /// themselves, not as a prefix of another one) are returned.
+    pub fn get_matches_in(root: &Arc<Resource>, rname: &str) -> Vec<Weak<Resource>> {
+        let rname = &*Resource::normalize_suffix(root, rname);
         fn get_matches_from(
             rname: &str,
             is_admin: bool,
@@ -431,7 +1399,7 @@ impl Resource {
                 return matches;
             }
             if rname.is_empty() {
-                if from.suffix == "/**" || from.suffix == "/" {
+                if &*from.suffix == "/**" || &*from.suffix == "/" {
                     if from.context.is_some()
                         && is_admin == from.name().starts_with(rname::ADMIN_PREFIX)
                     {
@@ -445,29 +1413,30 @@ impl Resource {
             }
             let (chunk, rest) = Resource::fst_chunk(rname);
             if rname::intersect(chunk, &from.suffix) {
-                if rest.is_empty() || rest == "/" || rest == "/**" {
+                // `/**` possibly repeated matches an empty suffix
+                if rest.is_empty() || rest == "/" || rest.split('/').skip(1).all(|c| c == "**") {
                     if from.context.is_some()
                         && is_admin == from.name().starts_with(rname::ADMIN_PREFIX)
                     {
                         matches.push(Arc::downgrade(from));
                     }
-                } else if chunk == "/**" || from.suffix == "/**" {
+                } else if chunk == "/**" || &*from.suffix == "/**" {
                     matches.append(&mut get_matches_from(rest, is_admin, from));
                 }
                 for child in from.childs.values() {
                     matches.append(&mut get_matches_from(rest, is_admin, child));
-                    if chunk == "/**" || from.suffix == "/**" {
+                    if chunk == "/**" || &*from.suffix == "/**" {
                         matches.append(&mut get_matches_from(rname, is_admin, child));
                     }
                 }
             }
             matches
         }
-        get_matches_from(
-            rname,
-            rname.starts_with(rname::ADMIN_PREFIX),
-            &tables.root_res,
-        )
+        let mut matches = get_matches_from(rname, rname.starts_with(rname::ADMIN_PREFIX), root);
+        // The same resource can be reached through several expansions of `**`
+        let mut unique = HashSet::new();
+        matches.retain(|match_| unique.insert(match_.as_ptr() as *const ()));
+        matches
     }
 
     pub fn match_resource(tables: &Tables, res: &mut Arc<Resource>) {
@@ -520,21 +1489,18 @@ pub async fn declare_resource(
                 }
             }
             None => {
+                let registered = Resource::get_resource(&prefix, suffix)
+                    .map(|res| res.context.is_some())
+                    .unwrap_or(false);
                 let mut res = Resource::make_resource(tables, &mut prefix, suffix);
                 Resource::match_resource(&tables, &mut res);
+                if !registered {
+                    notify_new_matching_key(tables, &res);
+                }
                 let mut ctx = get_mut_unchecked(&mut res)
                     .session_ctxs
                     .entry(face.id)
-                    .or_insert_with(|| {
-                        Arc::new(SessionContext {
-                            face: face.clone(),
-                            local_rid: None,
-                            remote_rid: Some(rid),
-                            subs: None,
-                            qabl: false,
-                            last_values: HashMap::new(),
-                        })
-                    })
+                    .or_insert_with(|| Arc::new(SessionContext::new(face.clone(), None, Some(rid))))
                     .clone();
 
                 if face.local_mappings.get(&rid).is_some() && ctx.local_rid == None {
@@ -560,13 +1526,91 @@ pub async fn declare_resource(
     }
 }
 
-pub async fn undeclare_resource(_tables: &mut Tables, face: &mut Arc<FaceState>, rid: ZInt) {
+pub async fn undeclare_resource(tables: &mut Tables, face: &mut Arc<FaceState>, rid: ZInt) {
     match get_mut_unchecked(face).remote_mappings.remove(&rid) {
-        Some(mut res) => Resource::clean(&mut res),
+        Some(mut res) => {
+            let mut cleaned = vec![];
+            Resource::clean_with(&mut res, &mut cleaned);
+            tables.forget_history(&cleaned);
+            drop_cleaned_retained(tables, cleaned).await;
+        }
         None => log::error!("Undeclare unknown resource!"),
     }
 }
 
+/// Whether the local mapping `rid` of the face to `res` is still in use: by
+/// the declarations sent to the face, by the declarations or the cached
+/// values of the face on the resource, or by the contexts of the face on the
+/// descendants of the resource, whose keys may be built from it.
+fn is_live_local_mapping(face: &FaceState, rid: ZInt, res: &Arc<Resource>) -> bool {
+    fn has_descendant_ctx(res: &Resource, face_id: usize) -> bool {
+        res.childs.values().any(|child| {
+            child.session_ctxs.contains_key(&face_id) || has_descendant_ctx(child, face_id)
+        })
+    }
+    match res.session_ctxs.get(&face.id) {
+        Some(ctx) if ctx.local_rid == Some(rid) => {
+            ctx.remote_rid.is_some()
+                || ctx.has_subs()
+                || ctx.qabl
+                || ctx.has_cached_values()
+                || face.local_subs.contains(res)
+                || face.local_qabls.contains(res)
+                || face.prefetched.contains(res)
+                || has_descendant_ctx(res, face.id)
+        }
+        _ => false,
+    }
+}
+
+/// Remove the local mappings of the face that are no longer in use, e.g. the
+/// ones of the subscriptions once declared to the face then undeclared,
+/// forgetting them on the face. The routes are rebuilt if any was removed so
+/// that no route keeps using them. Return the number of removed mappings.
+pub async fn compact_face_mappings(tables: &mut Tables, face: &mut Arc<FaceState>) -> usize {
+    let stale: Vec<ZInt> = face
+        .local_mappings
+        .iter()
+        .filter(|(rid, res)| !is_live_local_mapping(face, **rid, res))
+        .map(|(rid, _)| *rid)
+        .collect();
+    if stale.is_empty() {
+        return 0;
+    }
+    for rid in &stale {
+        if let Some(mut res) = get_mut_unchecked(face).local_mappings.remove(rid) {
+            let unused_ctx = match get_mut_unchecked(&mut res).session_ctxs.get_mut(&face.id) {
+                Some(ctx) if ctx.local_rid == Some(*rid) => {
+                    get_mut_unchecked(ctx).local_rid = None;
+                    ctx.remote_rid.is_none() && !ctx.has_subs() && !ctx.qabl
+                }
+                _ => false,
+            };
+            if unused_ctx {
+                get_mut_unchecked(&mut res).session_ctxs.remove(&face.id);
+            }
+            face.primitives.forget_resource(*rid).await;
+            tables.clean_resource(&mut res);
+        }
+    }
+    log::debug!("Removed {} stale mappings of {}", stale.len(), face);
+
+    tables.route_cache.clear();
+    let mut resources = vec![];
+    let mut stack = vec![tables.root_res.clone()];
+    while let Some(res) = stack.pop() {
+        stack.extend(res.childs.values().cloned());
+        if res.context.is_some() {
+            resources.push(res);
+        }
+    }
+    for res in &mut resources {
+        compute_data_routes(tables, res);
+        compute_query_routes(tables, res);
+    }
+    stale.len()
+}
+
 #[inline]
 pub(super) fn elect_router<'a>(res_name: &str, routers: &'a [PeerId]) -> &'a PeerId {
     if routers.len() == 1 {
@@ -589,3 +1633,35 @@ pub(super) fn elect_router<'a>(res_name: &str, routers: &'a [PeerId]) -> &'a Pee
             .0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(byte: u8) -> CachedValue {
+        (None, RBuf::from(vec![byte]))
+    }
+
+    #[test]
+    fn test_remove_delivered_values() {
+        let mut cache = CachedValues::default();
+        cache.insert("/a".into(), value(0));
+        cache.insert("/b".into(), value(1));
+        let delivered = cache.versions();
+
+        // Values cached while the copy is delivered.
+        cache.insert("/c".into(), value(2));
+        cache.insert("/a".into(), value(3));
+
+        cache.remove_versions(&delivered);
+        let remaining: Vec<(String, Vec<u8>)> = cache
+            .iter()
+            .map(|(name, (_, payload))| (name.to_string(), payload.to_vec()))
+            .collect();
+        assert_eq!(
+            remaining,
+            vec![("/c".to_string(), vec![2]), ("/a".to_string(), vec![3])]
+        );
+        assert_eq!(cache.len(), 2);
+    }
+}
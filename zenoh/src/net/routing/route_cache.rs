@@ -0,0 +1,147 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// The entry evicted from a full [`RouteCache`] to make room for a new one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    /// The least recently used entry, approximated by giving the oldest
+    /// inserted entries used since their last pass a second chance.
+    Lru,
+    /// The oldest inserted entry.
+    Fifo,
+    /// A random entry.
+    Random,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> EvictionPolicy {
+        EvictionPolicy::Lru
+    }
+}
+
+struct CacheEntry<V> {
+    value: V,
+    used: AtomicBool,
+}
+
+struct CacheState<K, V> {
+    entries: HashMap<K, CacheEntry<V>>,
+    /// The keys of the entries, in the order they are considered for
+    /// eviction.
+    order: VecDeque<K>,
+}
+
+/// A bounded cache of the routes computed for the keys that have no
+/// registered resource. A capacity of 0 disables it. Lookups only share the
+/// cache, the entries being evicted in constant amortized time on insertion.
+pub struct RouteCache<K, V> {
+    capacity: usize,
+    policy: EvictionPolicy,
+    state: RwLock<CacheState<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> RouteCache<K, V> {
+    pub fn new(capacity: usize, policy: EvictionPolicy) -> RouteCache<K, V> {
+        RouteCache {
+            capacity,
+            policy,
+            state: RwLock::new(CacheState {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let state = self.state.read().unwrap();
+        state.entries.get(key).map(|entry| {
+            if self.policy == EvictionPolicy::Lru && !entry.used.load(Ordering::Relaxed) {
+                entry.used.store(true, Ordering::Relaxed);
+            }
+            entry.value.clone()
+        })
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.write().unwrap();
+        if let Some(entry) = state.entries.get_mut(&key) {
+            entry.value = value;
+            *entry.used.get_mut() = true;
+            return;
+        }
+        if state.entries.len() >= self.capacity {
+            if let Some(victim) = self.victim(&mut state) {
+                state.entries.remove(&victim);
+            }
+        }
+        state.order.push_back(key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                used: AtomicBool::new(false),
+            },
+        );
+    }
+
+    /// Remove the key of the entry to evict from the eviction order.
+    fn victim(&self, state: &mut CacheState<K, V>) -> Option<K> {
+        match self.policy {
+            EvictionPolicy::Lru => {
+                while let Some(key) = state.order.pop_front() {
+                    match state.entries.get(&key) {
+                        Some(entry) if entry.used.swap(false, Ordering::Relaxed) => {
+                            state.order.push_back(key)
+                        }
+                        _ => return Some(key),
+                    }
+                }
+                None
+            }
+            EvictionPolicy::Fifo => state.order.pop_front(),
+            EvictionPolicy::Random => {
+                let idx = rand::thread_rng().gen_range(0..state.order.len());
+                state.order.swap_remove_back(idx)
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the entries, to be called whenever the routes may change.
+    pub fn clear(&self) {
+        let mut state = self.state.write().unwrap();
+        state.entries.clear();
+        state.order.clear();
+    }
+}
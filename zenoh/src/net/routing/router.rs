@@ -13,40 +13,278 @@
 //
 use async_std::sync::{Arc, Mutex, RwLock, Weak};
 use async_std::task::{sleep, JoinHandle};
-use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use petgraph::graph::NodeIndex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use uhlc::HLC;
 use zenoh_util::sync::get_mut_unchecked;
 
-use super::protocol::core::{whatami, PeerId, WhatAmI, ZInt};
+use super::protocol::core::{
+    rname, whatami, CongestionControl, PeerId, Reliability, ResKey, SubInfo, SubMode, WhatAmI, ZInt,
+};
+use super::protocol::io::RBuf;
 use super::protocol::link::Link;
-use super::protocol::proto::{ZenohBody, ZenohMessage};
+use super::protocol::proto::{DataInfo, RoutingContext, ZenohBody, ZenohMessage};
 use super::protocol::session::{DeMux, Mux, Session};
 use super::OutSession;
 
 use zenoh_util::core::ZResult;
 use zenoh_util::zconfigurable;
 
+pub use super::admin::*;
+use super::broadcast::{broadcast, lagging_channel, LaggingSender};
+pub use super::face::KeyRepr;
 use super::face::{Face, FaceState};
-use super::network::{shared_nodes, Network};
+use super::fair_queue::{FairQueuing, FairScheduler};
+use super::interner::KeyInterner;
+use super::metrics::RoutingMetrics;
+use super::network::{shared_nodes, Network, TreeView};
 pub use super::pubsub::*;
 pub use super::queries::*;
 pub use super::resource::*;
+use super::route_cache::{EvictionPolicy, RouteCache};
 use super::runtime::orchestrator::SessionOrchestrator;
+pub use super::snapshot::*;
 
 zconfigurable! {
     static ref LINK_CLOSURE_DELAY: u64 = 200;
     static ref TREES_COMPUTATION_DELAY: u64 = 100;
+    static ref SUB_EVENTS_CAPACITY: usize = 256;
+    static ref IDEMPOTENCY_TOKENS_CAPACITY: usize = 1024;
+    static ref IDEMPOTENCY_WINDOW: u64 = 10000;
+    static ref MAX_RETAINED_VALUES: usize = 65536;
 }
 
+/// The data info and payload of the latest value routed for a key.
+pub type RetainedValue = (Option<DataInfo>, RBuf);
+
+/// The latest values routed for at most `capacity` keys, see
+/// `Tables::set_retain_latest`. The keys updated the longest ago are evicted
+/// first.
+pub(crate) struct RetainedValues {
+    capacity: usize,
+    values: HashMap<String, (u64, RetainedValue)>,
+    /// The keys in the order they were updated, with the version of each
+    /// update. The entries of the keys updated since are skipped.
+    order: VecDeque<(String, u64)>,
+    next_version: u64,
+}
+
+impl RetainedValues {
+    fn new(capacity: usize) -> RetainedValues {
+        RetainedValues {
+            capacity,
+            values: HashMap::new(),
+            order: VecDeque::new(),
+            next_version: 0,
+        }
+    }
+
+    /// Retain the value of the given key, returning the number of keys
+    /// evicted to make room for it.
+    pub(crate) fn insert(&mut self, name: String, value: RetainedValue) -> usize {
+        self.next_version += 1;
+        self.order.push_back((name.clone(), self.next_version));
+        self.values.insert(name, (self.next_version, value));
+        if self.order.len() > 2 * self.values.len().max(self.capacity) {
+            let values = &self.values;
+            self.order
+                .retain(|(name, version)| values.get(name).map(|(v, _)| v) == Some(version));
+        }
+        self.evict()
+    }
+
+    fn evict(&mut self) -> usize {
+        let mut evicted = 0;
+        while self.values.len() > self.capacity {
+            match self.order.pop_front() {
+                Some((name, version)) => {
+                    if self.values.get(&name).map(|(v, _)| *v) == Some(version) {
+                        log::debug!("Too many retained values, evict {}", name);
+                        self.values.remove(&name);
+                        evicted += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> Option<RetainedValue> {
+        self.values.remove(name).map(|(_, value)| value)
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&RetainedValue> {
+        self.values.get(name).map(|(_, value)| value)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &RetainedValue)> {
+        self.values.iter().map(|(name, (_, value))| (name, value))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+/// A breakdown of the state held by the tables, see [`Tables::stats`]. The
+/// estimated bytes only account for the keys and the payloads.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TablesStats {
+    /// Number of push subscriptions of the local faces.
+    pub push_subs: usize,
+    /// Number of pull subscriptions of the local faces.
+    pub pull_subs: usize,
+    /// Number of subscriptions of the remote routers.
+    pub router_subs: usize,
+    /// Number of subscriptions of the remote peers.
+    pub peer_subs: usize,
+    /// Number of values cached for the pull subscriptions.
+    pub cached_values: usize,
+    pub cached_bytes: usize,
+    /// Number of latest values retained (see `Tables::set_retain_latest`).
+    pub retained_values: usize,
+    pub retained_bytes: usize,
+}
+
+/// The state of the context of a face on a resource, see [`ResourceDump`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionContextDump {
+    pub face_id: usize,
+    /// The id the resource was declared with to the face, if any.
+    pub local_rid: Option<ZInt>,
+    /// The id the face declared the resource with, if any.
+    pub remote_rid: Option<ZInt>,
+    /// The subscription of the face, if any.
+    pub sub_info: Option<SubInfo>,
+    /// The keys of the values cached for a pull subscription, in the order
+    /// they will be delivered.
+    pub cached_keys: Vec<String>,
+}
+
+/// The full state of a resource, see [`Tables::dump_resource`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceDump {
+    pub name: String,
+    /// The routers and peers subscribed to the resource, sorted.
+    pub router_subs: Vec<PeerId>,
+    pub peer_subs: Vec<PeerId>,
+    /// The contexts of the faces on the resource, by face id.
+    pub contexts: Vec<SessionContextDump>,
+    /// The names of the resources matching this one, itself included, sorted.
+    pub matches: Vec<String>,
+    /// The faces, with the key sent to each of them, that the data published
+    /// by a client on the resource are routed to, if that route was computed.
+    pub route: Option<Vec<(usize, ResKey)>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoutingError {
+    UnknownPrefix(ZInt),
+    UnknownResource(String),
+    UnknownSubscription(String),
+    SubscriptionLimit(usize),
+    InvalidSnapshot(String),
+    MalformedKey(String),
+    ModeConflict(String),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutingError::UnknownPrefix(rid) => write!(f, "Unknown prefix {}", rid),
+            RoutingError::UnknownResource(name) => write!(f, "Unknown resource {}", name),
+            RoutingError::UnknownSubscription(name) => write!(f, "Unknown subscription {}", name),
+            RoutingError::SubscriptionLimit(max) => {
+                write!(f, "Subscription limit of {} per face reached", max)
+            }
+            RoutingError::InvalidSnapshot(descr) => write!(f, "Invalid snapshot ({})", descr),
+            RoutingError::MalformedKey(name) => write!(f, "Malformed key '{}'", name),
+            RoutingError::ModeConflict(name) => {
+                write!(f, "Subscription {} redeclared with another mode", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
 pub struct Tables {
     pub(crate) pid: PeerId,
     pub(crate) whatami: whatami::Type,
     face_counter: usize,
     pub(crate) hlc: Option<HLC>,
     pub(crate) root_res: Arc<Resource>,
+    pub(crate) interner: KeyInterner,
     pub(crate) faces: HashMap<usize, Arc<FaceState>>,
-    pub(crate) pull_caches_lock: Mutex<()>,
+    pub(crate) retained: Option<Mutex<RetainedValues>>,
+    pub(crate) max_retained_values: usize,
+    pub(crate) delete_on_clean: bool,
+    pub(crate) history_depths: Vec<(String, usize)>,
+    pub(crate) history: std::sync::Mutex<HashMap<String, VecDeque<RetainedValue>>>,
+    pub(crate) max_payload_size: Option<usize>,
+    pub(crate) oversized_payload_delete: bool,
+    pub(crate) congestion_controls: Vec<(whatami::Type, CongestionControl)>,
+    pub(crate) metrics: Arc<RoutingMetrics>,
+    pub(crate) sub_listeners: Vec<LaggingSender<SubEvent>>,
+    pub(crate) subscriber_waiters: Vec<(String, oneshot::Sender<()>)>,
+    pub(crate) matching_key_listeners: Vec<LaggingSender<MatchingKeyEvent>>,
+    pub(crate) parallel_fanout: bool,
+    pub(crate) subscriber_reliability: bool,
+    pub(crate) fair_scheduler: Option<Arc<FairScheduler>>,
+    pub(crate) reliable_first: bool,
+    pub(crate) memory_pressure: Option<Arc<MemoryPressure>>,
+    pub(crate) sub_link_classes: Option<HashSet<String>>,
+    pub(crate) tenants: bool,
+    pub(crate) shared_tenants: HashSet<String>,
+    pub(crate) dead_letter_face: Option<usize>,
+    pub(crate) key_matcher: Option<(String, Arc<dyn KeyMatcher>)>,
+    pub(crate) history_storage: Option<Arc<dyn HistoryStorage>>,
+    pub(crate) routing_policy: Option<Arc<dyn RoutingPolicy>>,
+    pub(crate) max_subs_per_face: Option<usize>,
+    pub(crate) close_on_subs_limit: bool,
+    pub(crate) empty_route: EmptyRoute,
+    pub(crate) admin_access: AdminAccess,
+    pub(crate) mode_conflict: ModeConflict,
+    pub(crate) data_filters: bool,
+    pub(crate) encoding_converters: HashMap<(ZInt, ZInt), Arc<dyn EncodingConverter>>,
+    pub(crate) loopback_subs: bool,
+    pub(crate) priority_subs: bool,
+    pub(crate) expected_keys: Option<Vec<String>>,
+    pub(crate) record_routing_path: bool,
+    pub(crate) untimestamped_kinds: Vec<ZInt>,
+    pub(crate) known_publications: HashMap<PeerId, Vec<String>>,
+    pub(crate) route_cache: RouteCache<DataRouteKey, Arc<Route>>,
+    pub(crate) deferred_rebuilds: Option<Vec<Weak<Resource>>>,
+    pub(crate) rebuild_waiters: Vec<oneshot::Sender<()>>,
+    pub(crate) pull_cache_budget: Option<Arc<PullCacheBudget>>,
+    pub(crate) traced_resources: Vec<Arc<Resource>>,
+    pub(crate) reserved_routes: Vec<Arc<Resource>>,
+    pub(super) batching_subs: Vec<(Weak<FaceState>, Weak<SessionContext>)>,
+    pub(crate) batches_task: Option<JoinHandle<()>>,
+    pub(super) reordering_subs: Vec<(Weak<FaceState>, Weak<SessionContext>)>,
+    pub(crate) tree_child_faces: std::sync::Mutex<HashMap<(whatami::Type, usize), ChildFaces>>,
+    pub(crate) read_through_limit: Option<usize>,
+    pub(crate) read_through_queries: usize,
+    pub(crate) idempotency_tokens: RouteCache<(usize, u64), TokenDeclaration>,
+    pub(crate) idempotency_window: Duration,
+    pub(crate) recent_samples: RouteCache<(PeerId, ZInt), Instant>,
+    pub(crate) loop_window: Duration,
+    pub(crate) loop_observer: Option<Arc<dyn LoopObserver>>,
+    pub(crate) flap_dampening: Option<FlapDampening>,
+    pub(crate) flap_states: HashMap<(usize, String), FlapState>,
+    pub(crate) flap_release_task: Option<JoinHandle<()>>,
+    pub(crate) heartbeats_task: Option<JoinHandle<()>>,
+    pub(crate) sub_expiry_task: Option<JoinHandle<()>>,
+    pub(crate) mapping_compaction_task: Option<JoinHandle<()>>,
+    pub(crate) paused_routing: Option<PausedRouting>,
+    pub(crate) publishers: Option<PublisherTracking>,
     pub(crate) router_subs: HashSet<Arc<Resource>>,
     pub(crate) peer_subs: HashSet<Arc<Resource>>,
     pub(crate) router_qabls: HashSet<Arc<Resource>>,
@@ -58,6 +296,36 @@ pub struct Tables {
     pub(crate) peers_trees_task: Option<JoinHandle<()>>,
 }
 
+/// A guard deferring the rebuilds of the data routes of some [`Tables`], see
+/// [`Tables::defer_rebuilds`]. It gives access to the tables it guards.
+pub struct DeferredRebuild<'a> {
+    tables: &'a mut Tables,
+    outer: bool,
+}
+
+impl Deref for DeferredRebuild<'_> {
+    type Target = Tables;
+
+    fn deref(&self) -> &Tables {
+        self.tables
+    }
+}
+
+impl DerefMut for DeferredRebuild<'_> {
+    fn deref_mut(&mut self) -> &mut Tables {
+        self.tables
+    }
+}
+
+impl Drop for DeferredRebuild<'_> {
+    fn drop(&mut self) {
+        // Nested guards leave the rebuilds to the outermost one
+        if self.outer {
+            flush_deferred_rebuilds(self.tables);
+        }
+    }
+}
+
 impl Tables {
     pub fn new(pid: PeerId, whatami: whatami::Type, hlc: Option<HLC>) -> Self {
         Tables {
@@ -66,8 +334,70 @@ impl Tables {
             face_counter: 0,
             hlc,
             root_res: Resource::root(),
+            interner: KeyInterner::new(),
             faces: HashMap::new(),
-            pull_caches_lock: Mutex::new(()),
+            retained: None,
+            max_retained_values: *MAX_RETAINED_VALUES,
+            delete_on_clean: false,
+            history_depths: vec![],
+            history: std::sync::Mutex::new(HashMap::new()),
+            max_payload_size: None,
+            oversized_payload_delete: false,
+            congestion_controls: vec![],
+            metrics: Arc::new(RoutingMetrics::default()),
+            sub_listeners: vec![],
+            subscriber_waiters: vec![],
+            matching_key_listeners: vec![],
+            parallel_fanout: false,
+            subscriber_reliability: false,
+            fair_scheduler: None,
+            reliable_first: false,
+            memory_pressure: None,
+            sub_link_classes: None,
+            tenants: false,
+            shared_tenants: HashSet::new(),
+            dead_letter_face: None,
+            key_matcher: None,
+            history_storage: None,
+            routing_policy: None,
+            max_subs_per_face: None,
+            close_on_subs_limit: false,
+            empty_route: EmptyRoute::default(),
+            admin_access: AdminAccess::default(),
+            mode_conflict: ModeConflict::default(),
+            data_filters: false,
+            encoding_converters: HashMap::new(),
+            loopback_subs: false,
+            priority_subs: false,
+            expected_keys: None,
+            record_routing_path: false,
+            untimestamped_kinds: vec![],
+            known_publications: HashMap::new(),
+            route_cache: RouteCache::new(0, EvictionPolicy::default()),
+            deferred_rebuilds: None,
+            rebuild_waiters: vec![],
+            pull_cache_budget: None,
+            traced_resources: vec![],
+            reserved_routes: vec![],
+            batching_subs: vec![],
+            batches_task: None,
+            reordering_subs: vec![],
+            tree_child_faces: std::sync::Mutex::new(HashMap::new()),
+            read_through_limit: None,
+            read_through_queries: 0,
+            idempotency_tokens: RouteCache::new(*IDEMPOTENCY_TOKENS_CAPACITY, EvictionPolicy::Lru),
+            idempotency_window: Duration::from_millis(*IDEMPOTENCY_WINDOW),
+            recent_samples: RouteCache::new(0, EvictionPolicy::Lru),
+            loop_window: Duration::from_secs(0),
+            loop_observer: None,
+            flap_dampening: None,
+            flap_states: HashMap::new(),
+            flap_release_task: None,
+            heartbeats_task: None,
+            sub_expiry_task: None,
+            mapping_compaction_task: None,
+            paused_routing: None,
+            publishers: None,
             router_subs: HashSet::new(),
             peer_subs: HashSet::new(),
             router_qabls: HashSet::new(),
@@ -89,6 +419,1097 @@ impl Tables {
         Resource::print_tree(&self.root_res)
     }
 
+    pub fn interner(&self) -> &KeyInterner {
+        &self.interner
+    }
+
+    /// Enable or disable the retention of the latest value routed for each key,
+    /// for at most `Tables::set_max_retained_values` keys.
+    pub fn set_retain_latest(&mut self, enabled: bool) {
+        match (enabled, self.retained.is_some()) {
+            (true, false) => {
+                self.retained = Some(Mutex::new(RetainedValues::new(self.max_retained_values)))
+            }
+            (false, true) => self.retained = None,
+            _ => (),
+        }
+    }
+
+    /// Set the maximum number of keys whose latest value is retained, the
+    /// keys updated the longest ago being evicted first.
+    pub async fn set_max_retained_values(&mut self, max: usize) {
+        self.max_retained_values = max;
+        if let Some(retained) = &self.retained {
+            let mut retained = zasynclock!(retained);
+            retained.capacity = max;
+            let evicted = retained.evict();
+            self.metrics
+                .retained_evictions
+                .fetch_add(evicted, Ordering::Relaxed);
+        }
+    }
+
+    /// When enabled, the retained value of a key is dropped once the resource
+    /// of that key is cleaned from the tables, and the push subscribers
+    /// matching that key are sent a DELETE.
+    pub fn set_delete_on_clean(&mut self, enabled: bool) {
+        self.delete_on_clean = enabled;
+    }
+
+    /// Set the maximum payload size forwarded to the faces that don't have
+    /// their own limit. `None` (the default) means unlimited.
+    pub fn set_max_payload_size(&mut self, max_payload_size: Option<usize>) {
+        self.max_payload_size = max_payload_size;
+    }
+
+    /// Set the maximum payload size forwarded to the given face, overriding
+    /// the global one.
+    pub fn set_face_max_payload_size(
+        &mut self,
+        face: &Weak<FaceState>,
+        max_payload_size: Option<usize>,
+    ) {
+        match face.upgrade() {
+            Some(mut face) => get_mut_unchecked(&mut face).max_payload_size = max_payload_size,
+            None => log::error!("Set max payload size on closed face!"),
+        }
+    }
+
+    /// Set the representation of the keys sent to the given face. With
+    /// `KeyRepr::Numeric`, only the subscriptions declared afterwards get a
+    /// numeric id.
+    pub fn set_face_key_repr(&mut self, face: &Weak<FaceState>, key_repr: KeyRepr) {
+        match face.upgrade() {
+            Some(mut face) => get_mut_unchecked(&mut face).key_repr = key_repr,
+            None => log::error!("Set key representation on closed face!"),
+        }
+    }
+
+    /// Stop waiting for the data sent to the given face, queued, batched or
+    /// tracked, that are not delivered within `timeout`, also quarantining
+    /// the face if `quarantine` is set: no data is delivered to a quarantined
+    /// face until it is resumed with [`Tables::resume_face`]. The late sends
+    /// still complete in the background. `None` (the default) waits for the
+    /// deliveries to complete.
+    pub fn set_face_send_timeout(
+        &mut self,
+        face: &Weak<FaceState>,
+        timeout: Option<Duration>,
+        quarantine: bool,
+    ) {
+        match face.upgrade() {
+            Some(mut face) => {
+                let face = get_mut_unchecked(&mut face);
+                face.send_timeout = timeout;
+                face.quarantine_on_timeout = quarantine;
+            }
+            None => log::error!("Set send timeout on closed face!"),
+        }
+    }
+
+    /// Whether the given face is quarantined after a send timeout.
+    pub fn is_face_quarantined(&self, face_id: usize) -> bool {
+        self.faces
+            .get(&face_id)
+            .map(|face| face.quarantined.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// When enabled, a face that doesn't receive an oversized payload
+    /// receives an empty DELETE for the same key instead.
+    pub fn set_oversized_payload_delete(&mut self, enabled: bool) {
+        self.oversized_payload_delete = enabled;
+    }
+
+    /// Override the congestion control of the data routed to the faces of the
+    /// given kind. With `None`, they are sent with the congestion control set
+    /// by the publisher.
+    pub fn set_congestion_control(
+        &mut self,
+        whatami: whatami::Type,
+        congestion_control: Option<CongestionControl>,
+    ) {
+        self.congestion_controls
+            .retain(|(kind, _)| *kind != whatami);
+        if let Some(congestion_control) = congestion_control {
+            self.congestion_controls.push((whatami, congestion_control));
+        }
+    }
+
+    /// When enabled, the data are delivered to the clients with the
+    /// reliability of their subscriptions matching them: best effort when
+    /// they only have best effort ones. Disabled by default, the data being
+    /// delivered reliably.
+    pub fn set_subscriber_reliability(&mut self, enabled: bool) {
+        self.subscriber_reliability = enabled;
+    }
+
+    /// Enable or disable the parallel fan-out of routed data. When enabled,
+    /// each face gets its own send queue: the data routed to different
+    /// faces are delivered concurrently, while the data routed to a same face
+    /// are always delivered in the order they were routed.
+    pub fn set_parallel_fanout(&mut self, enabled: bool) {
+        self.parallel_fanout = enabled;
+        for face in self.faces.values_mut() {
+            if enabled {
+                FaceState::start_send_queue(
+                    face,
+                    self.fair_scheduler.clone(),
+                    self.metrics.clone(),
+                );
+            } else {
+                get_mut_unchecked(face).stop_send_queue();
+            }
+        }
+    }
+
+    /// Enable or disable the weighted fair queuing of the parallel fan-out
+    /// across the priority classes. The send queues of the faces are
+    /// restarted to use the new scheduler.
+    pub fn set_fair_queuing(&mut self, fair_queuing: Option<FairQueuing>) {
+        self.fair_scheduler = fair_queuing.map(|config| Arc::new(FairScheduler::new(&config)));
+        if self.parallel_fanout {
+            for face in self.faces.values_mut() {
+                get_mut_unchecked(face).stop_send_queue();
+                FaceState::start_send_queue(
+                    face,
+                    self.fair_scheduler.clone(),
+                    self.metrics.clone(),
+                );
+            }
+        }
+    }
+
+    /// When enabled, the routed data are dispatched to the faces having a
+    /// reliable subscription before the faces having only best effort ones.
+    /// The routers and peers faces are considered reliable. With the parallel
+    /// fan-out, the data are queued to the reliable faces first.
+    pub fn set_reliable_first(&mut self, enabled: bool) {
+        self.reliable_first = enabled;
+    }
+
+    /// Consult the given memory pressure when routing data: above its high
+    /// watermark, only the faces that have a reliable subscription are
+    /// served. `None` (the default) never sheds data.
+    pub fn set_memory_pressure(&mut self, pressure: Option<Arc<MemoryPressure>>) {
+        self.memory_pressure = pressure;
+    }
+
+    /// Set the reaction to the data published on keys that no subscription
+    /// matches, dropped by default.
+    pub fn set_empty_route(&mut self, policy: EmptyRoute) {
+        self.empty_route = policy;
+    }
+
+    /// Set the faces allowed to query these tables through the reserved
+    /// admin keys, none by default.
+    pub fn set_admin_access(&mut self, access: AdminAccess) {
+        self.admin_access = access;
+    }
+
+    /// Set how a subscription redeclared by a face with another mode is
+    /// resolved.
+    pub fn set_mode_conflict(&mut self, policy: ModeConflict) {
+        self.mode_conflict = policy;
+    }
+
+    /// Enable or disable the read through of the pulls on an empty cache: the
+    /// retained values matching the pull subscription are queried from the
+    /// directly connected routers and peers, cached and then sent. At most
+    /// `max_in_flight` such queries are pending at once, the other pulls
+    /// being answered from the local cache only.
+    pub fn set_read_through(&mut self, max_in_flight: Option<usize>) {
+        self.read_through_limit = max_in_flight;
+    }
+
+    /// The names of the pull subscriptions of the given face with a pull
+    /// waiting for its read through query, once per pending pull.
+    pub fn in_flight_pulls(&self, face_id: usize) -> Vec<String> {
+        in_flight_pulls(self, face_id)
+    }
+
+    /// Abort the pulls of the given face waiting for their read through
+    /// query, see [`cancel_pulls`]. The pulls of a closing face are aborted.
+    /// Return the number of aborted pulls.
+    pub fn cancel_pulls(&mut self, face_id: usize) -> usize {
+        cancel_pulls(self, face_id)
+    }
+
+    /// Bound the size of the payloads cached for all the pull subscriptions
+    /// to `max_bytes`, evicting the values cached the longest ago across all
+    /// of them when it is exceeded. It applies to the subscriptions declared
+    /// afterwards. `None` (the default) doesn't bound the caches.
+    pub fn set_max_cached_bytes(&mut self, max_bytes: Option<usize>) {
+        self.pull_cache_budget = max_bytes.map(|max| Arc::new(PullCacheBudget::new(max)));
+    }
+
+    /// The size of the payloads cached for the pull subscriptions, if bounded
+    /// with `set_max_cached_bytes`.
+    pub fn cached_bytes(&self) -> Option<usize> {
+        self.pull_cache_budget.as_ref().map(|budget| budget.used())
+    }
+
+    /// Cache up to `capacity` of the data routes computed for the keys that
+    /// have no registered resource, evicting entries with the given policy
+    /// when full. A capacity of 0 (the default) disables the cache.
+    pub fn set_route_cache(&mut self, capacity: usize, policy: EvictionPolicy) {
+        self.route_cache = RouteCache::new(capacity, policy);
+    }
+
+    /// Remember up to `capacity` of the idempotency tokens of the subscription
+    /// declarations, the least recently used ones being forgotten first. A
+    /// declaration repeating the token of an accepted one of the same face
+    /// received less than `window` ago is ignored.
+    pub fn set_idempotency_tokens(&mut self, capacity: usize, window: Duration) {
+        self.idempotency_tokens = RouteCache::new(capacity, EvictionPolicy::Lru);
+        self.idempotency_window = window;
+    }
+
+    /// Detect the routing loops: remember the source id and sequence number
+    /// of up to `capacity` of the routed samples, dropping a sample routed
+    /// again within `window` and reporting it to `observer`, if any, and in
+    /// `RoutingMetrics::loops_detected`. A capacity of 0 (the default)
+    /// disables the detection.
+    pub fn set_loop_detection(
+        &mut self,
+        capacity: usize,
+        window: Duration,
+        observer: Option<Arc<dyn LoopObserver>>,
+    ) {
+        self.recent_samples = RouteCache::new(capacity, EvictionPolicy::Lru);
+        self.loop_window = window;
+        self.loop_observer = observer;
+    }
+
+    /// Enable or disable the dampening of flapping client subscriptions.
+    pub fn set_flap_dampening(&mut self, dampening: Option<FlapDampening>) {
+        self.flap_dampening = dampening;
+    }
+
+    /// Propagate the dampened subscriptions whose hold down has elapsed.
+    pub async fn release_dampened_subscriptions(&mut self) {
+        release_dampened_subscriptions(self).await;
+    }
+
+    /// Deliver the batches of the subscriptions whose window elapsed, see
+    /// `SubOptions::batching`. Returns the time at which the next batch is
+    /// due, if any.
+    pub async fn flush_batches(&self) -> Option<Instant> {
+        flush_batches(self).await
+    }
+
+    /// Deliver the data buffered by the subscriptions reordering their data
+    /// whose gap timed out, see `SubOptions::reordering`. Returns the time at
+    /// which the next gap times out, if any.
+    pub async fn flush_reorders(&self) -> Option<Instant> {
+        flush_reorders(self).await
+    }
+
+    /// Send a keep-alive to the idle subscriptions whose heartbeat is due, see
+    /// `SubOptions::heartbeat`. Returns the time at which the next heartbeat
+    /// is due, if any.
+    pub async fn send_heartbeats(&self) -> Option<Instant> {
+        send_heartbeats(self).await
+    }
+
+    /// Undeclare the subscriptions whose lease expired, see
+    /// `SubOptions::lease`. Returns the time at which the next lease expires,
+    /// if any.
+    pub async fn expire_subscriptions(&mut self) -> Option<Instant> {
+        expire_subscriptions(self).await
+    }
+
+    /// Revoke a subscription of the given face, see
+    /// [`revoke_client_subscription`].
+    pub async fn revoke_subscription(
+        &mut self,
+        face: &Weak<FaceState>,
+        expr: &str,
+    ) -> Result<(), RoutingError> {
+        match face.upgrade() {
+            Some(mut face) => revoke_client_subscription(self, &mut face, 0, expr).await,
+            None => Err(RoutingError::UnknownSubscription(expr.to_string())),
+        }
+    }
+
+    /// Remove the stale mappings of the given face, see
+    /// [`compact_face_mappings`].
+    pub async fn compact_face_mappings(&mut self, face_id: usize) -> usize {
+        match self.faces.get(&face_id).cloned() {
+            Some(mut face) => compact_face_mappings(self, &mut face).await,
+            None => {
+                log::error!("Compact the mappings of unknown face {}!", face_id);
+                0
+            }
+        }
+    }
+
+    /// Change the role of the given face, e.g. a client session promoted to
+    /// a peer, and re-evaluate the subscriptions propagated to and from it
+    /// and the data routes under the rules of its new role.
+    pub async fn on_face_whatami_changed(&mut self, face_id: usize, whatami: WhatAmI) {
+        match self.faces.get(&face_id).cloned() {
+            Some(mut face) => {
+                let old_whatami = face.whatami;
+                if old_whatami == whatami {
+                    return;
+                }
+                get_mut_unchecked(&mut face).whatami = whatami;
+                pubsub_face_whatami_changed(self, &mut face, old_whatami).await;
+            }
+            None => log::error!("Change the role of unknown face {}!", face_id),
+        }
+    }
+
+    /// Log at info level, whatever the maximum log level, the declarations,
+    /// undeclarations, routing and pulls touching exactly the resource
+    /// `resname` if `enable`, with the [`TRACE_TARGET`] target. The resource
+    /// is kept while traced.
+    pub fn trace_resource(&mut self, resname: &str, enable: bool) {
+        let traced = self
+            .traced_resources
+            .iter()
+            .position(|res| res.name() == resname);
+        match (traced, enable) {
+            (None, true) => {
+                let mut root = self.root_res.clone();
+                let mut res = Resource::make_resource(self, &mut root, resname);
+                Resource::match_resource(self, &mut res);
+                compute_data_routes(self, &mut res);
+                get_mut_unchecked(&mut res).traced = true;
+                log::debug!("Trace resource {}", resname);
+                self.traced_resources.push(res);
+            }
+            (Some(idx), false) => {
+                let mut res = self.traced_resources.remove(idx);
+                get_mut_unchecked(&mut res).traced = false;
+                log::debug!("Stop tracing resource {}", resname);
+                self.clean_resource(&mut res);
+            }
+            _ => (),
+        }
+    }
+
+    /// Register the resource `resname` and build its data routes ahead of
+    /// the first data published on it, e.g. by a high-rate publisher, so that
+    /// this data takes the prebuilt routes. The resource is kept until
+    /// [`Tables::release_route`] whatever its subscriptions. Return the number
+    /// of faces the data published on it by a local session is routed to.
+    pub fn reserve_route(&mut self, resname: &str) -> usize {
+        let mut res = match self
+            .reserved_routes
+            .iter()
+            .find(|res| res.name() == resname)
+        {
+            Some(res) => res.clone(),
+            None => {
+                let mut root = self.root_res.clone();
+                let mut res = Resource::make_resource(self, &mut root, resname);
+                Resource::match_resource(self, &mut res);
+                log::debug!("Reserve route of {}", resname);
+                self.reserved_routes.push(res.clone());
+                res
+            }
+        };
+        compute_data_routes(self, &mut res);
+        let route = match self.whatami {
+            whatami::ROUTER => res.routers_data_route(0),
+            whatami::PEER => res.peers_data_route(0),
+            _ => res.client_data_route(),
+        };
+        route.map(|route| route.len()).unwrap_or(0)
+    }
+
+    /// Release the resource reserved by [`Tables::reserve_route`], freeing it
+    /// and its routes if nothing else uses it.
+    pub fn release_route(&mut self, resname: &str) {
+        if let Some(idx) = self
+            .reserved_routes
+            .iter()
+            .position(|res| res.name() == resname)
+        {
+            let mut res = self.reserved_routes.remove(idx);
+            log::debug!("Release route of {}", resname);
+            self.clean_resource(&mut res);
+        }
+    }
+
+    /// Set the keys the given peer is known to publish on. The routes of those
+    /// keys are computed as soon as a face to that peer is opened.
+    pub fn set_known_publications(&mut self, pid: PeerId, key_exprs: Vec<String>) {
+        if key_exprs.is_empty() {
+            self.known_publications.remove(&pid);
+        } else {
+            self.known_publications.insert(pid, key_exprs);
+        }
+    }
+
+    /// Pause the delivery of data to the given face, see [`pause_face`].
+    pub fn pause_face(&mut self, face_id: usize, buffer_latest: bool) {
+        pause_face(self, face_id, buffer_latest)
+    }
+
+    /// Resume the delivery of data to the given face, see [`resume_face`].
+    pub async fn resume_face(&mut self, face_id: usize) {
+        resume_face(self, face_id).await
+    }
+
+    /// Pause the routing of all the data, see [`pause_routing`].
+    pub fn pause_routing(&mut self, buffer_latest: bool) {
+        pause_routing(self, buffer_latest)
+    }
+
+    /// Resume the routing of the data, see [`resume_routing`].
+    pub async fn resume_routing(&mut self) {
+        resume_routing(self).await
+    }
+
+    /// Track the faces publishing on each key, see [`track_publishers`].
+    pub fn track_publishers(&mut self, max_age: Option<Duration>) {
+        track_publishers(self, max_age)
+    }
+
+    /// The peers recently seen publishing on the keys matching a key
+    /// expression, see [`publishers`].
+    pub fn publishers(&self, resname: &str) -> Vec<(PeerId, Instant)> {
+        publishers(self, resname)
+    }
+
+    /// Move the subscriptions of a face to another one, see
+    /// [`migrate_subscriptions`].
+    pub async fn migrate_subscriptions(&mut self, from: &Weak<FaceState>, to: &Weak<FaceState>) {
+        match (from.upgrade(), to.upgrade()) {
+            (Some(mut from), Some(mut to)) => migrate_subscriptions(self, &mut from, &mut to).await,
+            _ => log::error!("Migrate subscriptions of closed face!"),
+        }
+    }
+
+    /// Tag the given face with a link class.
+    pub fn set_face_link_class(&mut self, face: &Weak<FaceState>, link_class: Option<String>) {
+        match face.upgrade() {
+            Some(mut face) => get_mut_unchecked(&mut face).link_class = link_class,
+            None => log::error!("Set link class on closed face!"),
+        }
+    }
+
+    /// Tag the given face with a tenant. Once a face is tagged, the data are
+    /// only routed between faces of the same tenant, the untagged faces
+    /// forming a tenant of their own, unless one of the faces belongs to a
+    /// shared tenant.
+    pub fn set_face_tenant(&mut self, face: &Weak<FaceState>, tenant_id: Option<String>) {
+        match face.upgrade() {
+            Some(mut face) => {
+                if tenant_id.is_some() {
+                    self.tenants = true;
+                }
+                get_mut_unchecked(&mut face).tenant_id = tenant_id;
+            }
+            None => log::error!("Set tenant on closed face!"),
+        }
+    }
+
+    /// Let the faces of the given tenants exchange data with the faces of any
+    /// tenant, see [`Tables::set_face_tenant`].
+    pub fn set_shared_tenants(&mut self, tenants: HashSet<String>) {
+        self.shared_tenants = tenants;
+    }
+
+    #[inline]
+    pub(crate) fn same_tenant(&self, face: &FaceState, other: &FaceState) -> bool {
+        let shared = |face: &FaceState| {
+            face.tenant_id
+                .as_ref()
+                .map(|tenant| self.shared_tenants.contains(tenant))
+                .unwrap_or(false)
+        };
+        face.tenant_id == other.tenant_id || shared(face) || shared(other)
+    }
+
+    /// Match the keys starting with the given prefix with the given matcher
+    /// rather than with the default key expressions semantics. The matcher
+    /// should be set before any resource is declared under the prefix, as the
+    /// matches already computed are not updated. `None` (the default) uses the
+    /// default semantics for all keys.
+    pub fn set_key_matcher(&mut self, matcher: Option<(String, Arc<dyn KeyMatcher>)>) {
+        self.key_matcher = matcher;
+    }
+
+    /// Register the storage whose history is replayed to the subscriptions
+    /// declared with `SubOptions::history`. `None` (the default) replays
+    /// nothing.
+    pub fn set_history_storage(&mut self, storage: Option<Arc<dyn HistoryStorage>>) {
+        self.history_storage = storage;
+    }
+
+    /// Register the converter of the payloads from the encoding `from` to the
+    /// encoding `to`, used for the subscriptions accepting only `to` (see
+    /// `SubOptions::accepted_encoding`).
+    pub fn add_encoding_converter(
+        &mut self,
+        from: ZInt,
+        to: ZInt,
+        converter: Arc<dyn EncodingConverter>,
+    ) {
+        self.encoding_converters.insert((from, to), converter);
+    }
+
+    /// Register a routing policy further restricting the routes computed by
+    /// the built-in rules. `None` (the default) only applies the built-in
+    /// rules.
+    pub fn set_routing_policy(&mut self, policy: Option<Arc<dyn RoutingPolicy>>) {
+        self.routing_policy = policy;
+    }
+
+    /// Reject the subscriptions declared by a client face beyond `max` ones,
+    /// also closing the face if `close_face` is set. `None` (the default)
+    /// doesn't limit the subscriptions.
+    pub fn set_max_subs_per_face(&mut self, max: Option<usize>, close_face: bool) {
+        self.max_subs_per_face = max;
+        self.close_on_subs_limit = close_face;
+    }
+
+    /// Deliver the data that match no subscription to the given face rather
+    /// than dropping them. `None` (the default) drops them.
+    pub fn set_dead_letter_face(&mut self, face: Option<&Weak<FaceState>>) {
+        self.dead_letter_face = face.and_then(|face| face.upgrade()).map(|face| face.id);
+    }
+
+    /// When enabled, the pid of these tables is appended to the routing path
+    /// of the `DataInfo` of the routed data, so that the subscribers see the
+    /// routers the data went through. Disabled by default.
+    pub fn set_record_routing_path(&mut self, enabled: bool) {
+        self.record_routing_path = enabled;
+    }
+
+    /// Never add a timestamp to the data of the given kinds (see
+    /// `data_kind`) when timestamping with the HLC of the tables, e.g. to
+    /// route the DELETE samples untimestamped. The HLC is still updated with
+    /// the timestamps of the received data of these kinds. Empty by default.
+    pub fn set_untimestamped_kinds(&mut self, kinds: Vec<ZInt>) {
+        self.untimestamped_kinds = kinds;
+    }
+
+    /// Report the data routed for a key that intersects none of the given key
+    /// expressions in `RoutingMetrics::key_anomalies`. Such data are still
+    /// routed. `None` (the default) expects any key.
+    pub fn set_expected_keys(&mut self, key_exprs: Option<Vec<String>>) {
+        self.expected_keys = key_exprs;
+    }
+
+    /// Restrict the propagation of subscriptions to the routers and peers
+    /// reached through faces of one of the given link classes. Faces without
+    /// a link class are then excluded. `None` (the default) propagates over
+    /// all faces.
+    pub fn set_sub_link_classes(&mut self, link_classes: Option<HashSet<String>>) {
+        self.sub_link_classes = link_classes;
+    }
+
+    #[inline]
+    pub(crate) fn propagates_subs_to(&self, face: &FaceState) -> bool {
+        match &self.sub_link_classes {
+            Some(classes) => face
+                .link_class
+                .as_ref()
+                .map(|class| classes.contains(class))
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// Build the tables of a router whose routers network has the given
+    /// topology, each entry linking a node to its neighbours. The network has
+    /// no underlying sessions: the faces to the neighbours of `pid` must be
+    /// opened with their peer ids for the declarations to reach them.
+    #[cfg(feature = "testing")]
+    pub async fn with_router_topology(
+        pid: PeerId,
+        hlc: Option<HLC>,
+        topology: &[(PeerId, &[PeerId])],
+    ) -> Tables {
+        let mut tables = Tables::new(pid.clone(), whatami::ROUTER, hlc);
+        let orchestrator = SessionOrchestrator::new(
+            whatami::ROUTER,
+            Arc::new(Router::new(pid, whatami::ROUTER, None)),
+        );
+        tables.init_link_state(orchestrator, false, false).await;
+        tables
+            .apply_topology_change(whatami::ROUTER, topology, &[])
+            .await;
+        tables
+    }
+
+    /// Add and remove links of the routers or peers network, each entry
+    /// linking a node to its neighbours, then recompute the trees right away
+    /// and propagate the subscriptions and queryables to the new childs. The
+    /// nodes no longer reachable are removed with their declarations.
+    #[cfg(feature = "testing")]
+    pub async fn apply_topology_change(
+        &mut self,
+        net_type: whatami::Type,
+        added: &[(PeerId, &[PeerId])],
+        removed: &[(PeerId, &[PeerId])],
+    ) {
+        let mut removed_nodes = vec![];
+        match self.get_net_mut(net_type) {
+            Some(net) => {
+                for (pid, neighbours) in added {
+                    for neighbour in neighbours.iter() {
+                        net.add_simulated_link(pid.clone(), neighbour.clone(), net_type);
+                    }
+                }
+                for (pid, neighbours) in removed {
+                    for neighbour in neighbours.iter() {
+                        removed_nodes.extend(net.remove_simulated_link(pid, neighbour));
+                    }
+                }
+            }
+            None => {
+                log::error!("Apply topology change on missing network!");
+                return;
+            }
+        }
+        for (_, removed_node) in removed_nodes {
+            pubsub_remove_node(self, &removed_node.pid, net_type).await;
+            queries_remove_node(self, &removed_node.pid, net_type).await;
+        }
+        self.update_shared_nodes();
+
+        let new_childs = match self.get_net_mut(net_type) {
+            Some(net) => net.compute_trees().await,
+            None => return,
+        };
+        pubsub_tree_change(self, &new_childs, net_type).await;
+        queries_tree_change(self, &new_childs, net_type).await;
+    }
+
+    /// Return the subscriptions registered in the tables, see
+    /// [`export_subscriptions`].
+    pub fn export_subscriptions(&self) -> SubscriptionSnapshot {
+        export_subscriptions(self)
+    }
+
+    /// Return the digest of the subscriptions registered in the tables, e.g.
+    /// for two routers to cheaply detect that they diverged, before
+    /// comparing their exported subscriptions with
+    /// [`SubscriptionSnapshot::diff`].
+    pub fn subscription_digest(&self) -> SubscriptionDigest {
+        export_subscriptions(self).digest()
+    }
+
+    /// Declare the subscriptions of a snapshot, see [`import_subscriptions`].
+    pub async fn import_subscriptions(&mut self, snapshot: &SubscriptionSnapshot) -> usize {
+        import_subscriptions(self, snapshot).await
+    }
+
+    /// Build the data routes of all the registered resources, which are
+    /// otherwise built as the subscriptions change or on the first routed
+    /// data. Return the number of resources whose routes were built.
+    pub fn rebuild_all_routes(&mut self) -> usize {
+        rebuild_all_data_routes(self)
+    }
+
+    /// Defer the rebuilds of the data routes until the returned guard is
+    /// dropped, including on unwind. The declarations and undeclarations
+    /// performed through the guard only record the resources whose routes
+    /// changed, and each of them is rebuilt once when it is dropped. Until
+    /// then the data are routed along the previous routes.
+    pub fn defer_rebuilds(&mut self) -> DeferredRebuild<'_> {
+        let outer = self.deferred_rebuilds.is_none();
+        if outer {
+            self.deferred_rebuilds = Some(vec![]);
+        }
+        DeferredRebuild {
+            tables: self,
+            outer,
+        }
+    }
+
+    /// Return a snapshot of the trees used to propagate declarations over the
+    /// routers or peers network: for each tree root, the parent and childs of
+    /// the local node.
+    pub fn routing_trees(&self, net_type: whatami::Type) -> Vec<TreeView> {
+        self.get_net(net_type)
+            .map(|net| net.tree_views())
+            .unwrap_or_default()
+    }
+
+    /// Count the subscriptions by kind and mode, and the values cached for
+    /// the pull subscriptions and retained with their estimated size.
+    pub async fn stats(&self) -> TablesStats {
+        let mut stats = TablesStats::default();
+        let mut stack = vec![self.root_res.clone()];
+        while let Some(res) = stack.pop() {
+            if let Some(context) = res.context.as_ref() {
+                stats.router_subs += context.router_subs.len();
+                stats.peer_subs += context.peer_subs.len();
+            }
+            for ctx in res.session_ctxs.values() {
+                match ctx.subs().map(|sub_info| sub_info.mode) {
+                    Some(SubMode::Push) => stats.push_subs += 1,
+                    Some(SubMode::Pull) => stats.pull_subs += 1,
+                    None => (),
+                }
+                let cached = ctx.cached_values();
+                stats.cached_values += cached.len();
+                stats.cached_bytes += cached
+                    .iter()
+                    .map(|(name, (_, payload))| name.len() + payload.len())
+                    .sum::<usize>();
+            }
+            stack.extend(res.childs.values().cloned());
+        }
+        if let Some(retained) = &self.retained {
+            let retained = zasynclock!(retained);
+            stats.retained_values = retained.len();
+            stats.retained_bytes = retained
+                .iter()
+                .map(|(name, (_, payload))| name.len() + payload.len())
+                .sum();
+        }
+        stats
+    }
+
+    /// Return the `n` resources with the most routed publications, the
+    /// busiest first. Data routed for keys that have no registered resource
+    /// are not counted.
+    #[cfg(feature = "stats")]
+    pub fn top_keys(&self, n: usize) -> Vec<(String, KeyStats)> {
+        let mut keys = vec![];
+        let mut stack = vec![self.root_res.clone()];
+        while let Some(res) = stack.pop() {
+            let stats = res.stats.snapshot();
+            if stats.publications > 0 {
+                keys.push((res.name(), stats));
+            }
+            stack.extend(res.childs.values().cloned());
+        }
+        keys.sort_by(|(name1, stats1), (name2, stats2)| {
+            (stats2.publications, stats2.bytes, name1).cmp(&(
+                stats1.publications,
+                stats1.bytes,
+                name2,
+            ))
+        });
+        keys.truncate(n);
+        keys
+    }
+
+    /// The names of the resources of the tree, sorted, see
+    /// [`iter_keys`](Tables::iter_keys).
+    pub fn list_keys(&self, prefix: Option<&str>) -> Vec<String> {
+        let mut keys: Vec<String> = self.iter_keys(prefix).collect();
+        keys.sort();
+        keys
+    }
+
+    /// Iterate over the names of the resources of the tree, depth first: the
+    /// resource named `prefix` and its descendants if any, all the resources
+    /// otherwise. The names are built as the resources are visited, so that
+    /// large trees can be walked without collecting them.
+    pub fn iter_keys(&self, prefix: Option<&str>) -> impl Iterator<Item = String> {
+        let mut stack: Vec<Arc<Resource>> = match prefix {
+            Some(prefix) => Resource::get_resource(&self.root_res, prefix)
+                .into_iter()
+                .collect(),
+            None => self.root_res.childs.values().cloned().collect(),
+        };
+        std::iter::from_fn(move || {
+            let res = stack.pop()?;
+            stack.extend(res.childs.values().cloned());
+            Some(res.name())
+        })
+    }
+
+    pub fn metrics(&self) -> &RoutingMetrics {
+        &self.metrics
+    }
+
+    /// Export the metrics of these tables as OpenTelemetry instruments of the
+    /// given meter, see [`register_metrics`](super::otel::register_metrics).
+    #[cfg(feature = "otel")]
+    pub fn register_metrics(&self, meter: &opentelemetry::metrics::Meter) {
+        super::otel::register_metrics(&self.metrics, meter)
+    }
+
+    /// Returns a stream of every subsequent subscription change. Several
+    /// streams can be observed at the same time. A stream lagging more than
+    /// `SUB_EVENTS_CAPACITY` events behind loses the oldest ones, which are
+    /// counted in [`RoutingMetrics::sub_events_dropped`].
+    pub fn subscription_events(&mut self) -> impl Stream<Item = SubEvent> {
+        self.subscription_events_with_capacity(*SUB_EVENTS_CAPACITY)
+    }
+
+    /// Same as [`Tables::subscription_events`], with a stream buffering at
+    /// most `capacity` events. The declarations never wait for the stream to
+    /// be consumed.
+    pub fn subscription_events_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> impl Stream<Item = SubEvent> {
+        let (sender, receiver) = lagging_channel(capacity);
+        self.sub_listeners.push(sender);
+        receiver
+    }
+
+    pub(crate) fn notify_subscription(&mut self, event: SubEvent) {
+        if event.change == SubChange::Declared && !self.subscriber_waiters.is_empty() {
+            let waiters = std::mem::take(&mut self.subscriber_waiters);
+            for (key_expr, waiter) in waiters {
+                if rname::intersect(&event.resource, &key_expr) {
+                    let _ = waiter.send(());
+                } else if !waiter.is_canceled() {
+                    self.subscriber_waiters.push((key_expr, waiter));
+                }
+            }
+        }
+        broadcast(&mut self.sub_listeners, event, &self.metrics);
+    }
+
+    /// Returns a stream of the concrete keys registered by the faces from now
+    /// on that match an existing client subscription, one event per matching
+    /// subscription. Like [`Tables::subscription_events`], a lagging stream
+    /// loses the oldest events.
+    pub fn matching_key_events(&mut self) -> impl Stream<Item = MatchingKeyEvent> {
+        let (sender, receiver) = lagging_channel(*SUB_EVENTS_CAPACITY);
+        self.matching_key_listeners.push(sender);
+        receiver
+    }
+
+    pub(crate) fn notify_matching_key(&mut self, event: MatchingKeyEvent) {
+        broadcast(&mut self.matching_key_listeners, event, &self.metrics);
+    }
+
+    /// Returns true if at least one subscription matching `key_expr` is
+    /// registered, either by a local face or by a remote router or peer.
+    pub fn has_subscriber(&self, key_expr: &str) -> bool {
+        Resource::get_matches(self, key_expr).iter().any(|res| {
+            res.upgrade()
+                .map(|res| {
+                    res.session_ctxs.values().any(|ctx| ctx.has_subs())
+                        || !res.context().router_subs.is_empty()
+                        || !res.context().peer_subs.is_empty()
+                })
+                .unwrap_or(false)
+        })
+    }
+
+    /// Summarize the subscriptions matching `resname`. The subscriptions of
+    /// the remote routers and peers are always propagated as reliable push
+    /// subscriptions.
+    pub fn effective_sub_profile(&self, resname: &str) -> SubProfile {
+        let mut profile = SubProfile::default();
+        for res in Resource::get_matches(self, resname)
+            .iter()
+            .filter_map(|res| res.upgrade())
+        {
+            if !res.context().router_subs.is_empty() || !res.context().peer_subs.is_empty() {
+                profile.reliability = Some(Reliability::Reliable);
+            }
+            for sub_info in res.session_ctxs.values().filter_map(|ctx| ctx.subs()) {
+                if sub_info.reliability == Reliability::Reliable || profile.reliability.is_none() {
+                    profile.reliability = Some(sub_info.reliability);
+                }
+                profile.has_pull |= sub_info.mode == SubMode::Pull;
+            }
+        }
+        profile
+    }
+
+    /// Return the full state of the resource `resname`, if registered: its
+    /// subscriptions, the contexts of the faces on it, its matches and its
+    /// data route. Unlike the other listings, it doesn't aggregate anything.
+    pub fn dump_resource(&self, resname: &str) -> Option<ResourceDump> {
+        let res = Resource::get_resource(&self.root_res, resname)?;
+        let sorted_pids = |pids: &HashSet<PeerId>| {
+            let mut pids: Vec<PeerId> = pids.iter().cloned().collect();
+            pids.sort_by_key(|pid| pid.to_string());
+            pids
+        };
+        let mut contexts: Vec<SessionContextDump> = res
+            .session_ctxs
+            .iter()
+            .map(|(face_id, ctx)| {
+                let cached_keys = ctx
+                    .cached_values()
+                    .keys()
+                    .map(|key| key.to_string())
+                    .collect();
+                SessionContextDump {
+                    face_id: *face_id,
+                    local_rid: ctx.local_rid,
+                    remote_rid: ctx.remote_rid,
+                    sub_info: ctx.subs(),
+                    cached_keys,
+                }
+            })
+            .collect();
+        contexts.sort_by_key(|ctx| ctx.face_id);
+        let (router_subs, peer_subs, mut matches) = match res.context.as_ref() {
+            Some(context) => (
+                sorted_pids(&context.router_subs),
+                sorted_pids(&context.peer_subs),
+                context
+                    .matches
+                    .iter()
+                    .filter_map(|match_| match_.upgrade())
+                    .map(|match_| match_.name())
+                    .collect(),
+            ),
+            None => (vec![], vec![], vec![]),
+        };
+        matches.sort();
+        let route = match self.whatami {
+            whatami::ROUTER => res.routers_data_route(0),
+            whatami::PEER => res.peers_data_route(0),
+            _ => res.client_data_route(),
+        }
+        .map(|route| {
+            let mut route: Vec<(usize, ResKey)> = route
+                .iter()
+                .map(|(face_id, (_, reskey, _))| (*face_id, reskey.clone()))
+                .collect();
+            route.sort_by_key(|(face_id, _)| *face_id);
+            route
+        });
+        Some(ResourceDump {
+            name: res.name(),
+            router_subs,
+            peer_subs,
+            contexts,
+            matches,
+            route,
+        })
+    }
+
+    /// The `SubInfo` of the subscription declared by the face `face_id` on
+    /// exactly `resname`, if any.
+    pub fn get_subscription_info(&self, face_id: usize, resname: &str) -> Option<SubInfo> {
+        Resource::get_resource(&self.root_res, resname)
+            .and_then(|res| res.session_ctxs.get(&face_id).and_then(|ctx| ctx.subs()))
+    }
+
+    /// The outcomes of the deliveries of the data pushed to the subscription
+    /// of a face on a resource, if it tracks the acknowledgments (see
+    /// `SubOptions::track_acks`). The acknowledged data are only counted for
+    /// the faces whose primitives acknowledge them, see `DeliveryStats::acked`.
+    pub fn delivery_stats(&self, face_id: usize, resname: &str) -> Option<DeliveryStats> {
+        Resource::get_resource(&self.root_res, resname)?
+            .session_ctxs
+            .get(&face_id)
+            .filter(|ctx| ctx.has_subs() && ctx.sub_options().track_acks)
+            .map(|ctx| ctx.deliveries.snapshot())
+    }
+
+    /// The subscriptions held for a face, as key expression and info, in
+    /// the order they were declared, so that the face can reconcile them
+    /// with its own view. Empty for an unknown face.
+    pub fn face_subscriptions(&self, face_id: usize) -> Vec<(String, SubInfo)> {
+        match self.faces.get(&face_id) {
+            Some(face) => face
+                .remote_subs
+                .iter()
+                .filter_map(|res| {
+                    res.session_ctxs
+                        .get(&face_id)
+                        .and_then(|ctx| ctx.subs())
+                        .map(|sub_info| (res.name(), sub_info))
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns a future resolving as soon as a subscription matching
+    /// `key_expr` is registered. It resolves immediately if one already
+    /// exists. The future doesn't borrow the tables, so it must be awaited
+    /// after the tables lock is released. It doesn't depend on the
+    /// subscription events, so it can't miss the declaration it waits for.
+    pub fn wait_for_subscriber(&mut self, key_expr: &str) -> impl Future<Output = ()> {
+        let declared = if self.has_subscriber(key_expr) {
+            None
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            self.subscriber_waiters.push((key_expr.to_string(), sender));
+            Some(receiver)
+        };
+        async move {
+            if let Some(declared) = declared {
+                // Canceled when the tables are dropped
+                let _ = declared.await;
+            }
+        }
+    }
+
+    /// Keep the last `depth` values routed for each key matching `key_expr`.
+    /// A `depth` of 0 stops recording them.
+    pub async fn set_history_depth(&mut self, key_expr: &str, depth: usize) {
+        self.history_depths.retain(|(expr, _)| expr != key_expr);
+        if depth > 0 {
+            self.history_depths.push((key_expr.to_string(), depth));
+        }
+        let history_depths = &self.history_depths;
+        self.history.lock().unwrap().retain(|name, values| {
+            match Tables::history_depth_of(history_depths, name) {
+                Some(depth) => {
+                    while values.len() > depth {
+                        values.pop_front();
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Unregister `res` as [`Resource::clean`] does, also forgetting the
+    /// values recorded for the unregistered resources.
+    pub(crate) fn clean_resource(&self, res: &mut Arc<Resource>) {
+        let mut cleaned = vec![];
+        Resource::clean_with(res, &mut cleaned);
+        self.forget_history(&cleaned);
+    }
+
+    /// Forget the values recorded for the given unregistered resources.
+    pub(crate) fn forget_history(&self, cleaned: &[String]) {
+        if !self.history_depths.is_empty() && !cleaned.is_empty() {
+            let mut history = self.history.lock().unwrap();
+            for name in cleaned {
+                history.remove(name);
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn history_depth_of(
+        history_depths: &[(String, usize)],
+        name: &str,
+    ) -> Option<usize> {
+        history_depths
+            .iter()
+            .filter(|(expr, _)| rname::intersect(expr, name))
+            .map(|(_, depth)| *depth)
+            .max()
+    }
+
+    /// Returns the last `n` values recorded for `resname`, oldest first.
+    pub async fn recent_samples(&self, resname: &str, n: usize) -> Vec<RetainedValue> {
+        match self.history.lock().unwrap().get(resname) {
+            Some(values) => values
+                .iter()
+                .skip(values.len().saturating_sub(n))
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub async fn get_retained(&self, name: &str) -> Option<RetainedValue> {
+        match &self.retained {
+            Some(retained) => zasynclock!(retained).get(name).cloned(),
+            None => None,
+        }
+    }
+
     #[inline]
     #[allow(clippy::trivially_copy_pass_by_ref)]
     pub(crate) fn get_mapping<'a>(
@@ -111,6 +1532,51 @@ impl Tables {
         }
     }
 
+    /// The indexes of the nodes of the given network, none if the network is
+    /// not initialized yet.
+    pub(crate) fn net_indexes(&self, net_type: whatami::Type) -> Vec<NodeIndex> {
+        self.get_net(net_type)
+            .map(|net| net.graph.node_indices().collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn get_net_mut(&mut self, net_type: whatami::Type) -> Option<&mut Network> {
+        match net_type {
+            whatami::ROUTER => self.routers_net.as_mut(),
+            whatami::PEER => self.peers_net.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Recompute the nodes shared by the routers and peers networks, once
+    /// both are initialized.
+    pub(crate) fn update_shared_nodes(&mut self) {
+        if let (Some(routers_net), Some(peers_net)) = (&self.routers_net, &self.peers_net) {
+            self.shared_nodes = shared_nodes(routers_net, peers_net);
+        }
+    }
+
+    /// The local context in the network of type `net_type` of a message
+    /// received from `face` with `routing_context`. None if that network is
+    /// not initialized yet, in which case the message is counted as dropped.
+    pub(crate) fn net_local_context(
+        &self,
+        net_type: whatami::Type,
+        face: &FaceState,
+        routing_context: Option<RoutingContext>,
+    ) -> Option<usize> {
+        match self.get_net(net_type) {
+            Some(net) => Some(net.get_local_context(routing_context.unwrap(), face.link_id)),
+            None => {
+                log::debug!("Drop message from {}: network not initialized", face);
+                self.metrics
+                    .uninitialized_net_drops
+                    .fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
     #[inline]
     pub(crate) fn get_face(&self, pid: &PeerId) -> Option<&Arc<FaceState>> {
         self.faces.values().find(|face| face.pid == *pid)
@@ -131,11 +1597,20 @@ impl Tables {
             .or_insert_with(|| FaceState::new(fid, pid, whatami, primitives.clone(), link_id))
             .clone();
         log::debug!("New {}", newface);
+        self.tree_child_faces.lock().unwrap().clear();
+        if self.parallel_fanout {
+            FaceState::start_send_queue(
+                &mut newface,
+                self.fair_scheduler.clone(),
+                self.metrics.clone(),
+            );
+        }
 
         if whatami == whatami::CLIENT {
             pubsub_new_client_face(self, &mut newface).await;
             queries_new_client_face(self, &mut newface).await;
         }
+        pubsub_prefetch_routes(self, &mut newface);
         Arc::downgrade(&newface)
     }
 
@@ -153,30 +1628,50 @@ impl Tables {
             Some(mut face) => {
                 log::debug!("Close {}", face);
                 finalize_pending_queries(self, &mut face).await;
+                cancel_pulls(self, face.id);
+                // Propagate the undeclarations of a closing face right away
+                self.flap_states
+                    .retain(|(face_id, _), _| *face_id != face.id);
 
                 let mut face_clone = face.clone();
+                for (name, result) in undeclare_all_for_face(self, &mut face_clone).await {
+                    if let Err(e) = result {
+                        log::error!(
+                            "Error undeclaring subscription {} for {}: {}",
+                            name,
+                            face,
+                            e
+                        );
+                    }
+                }
+
                 let face = get_mut_unchecked(&mut face);
+                let mut cleaned = vec![];
                 for mut res in face.remote_mappings.values_mut() {
                     get_mut_unchecked(res).session_ctxs.remove(&face.id);
-                    Resource::clean(&mut res);
+                    Resource::clean_with(&mut res, &mut cleaned);
                 }
                 face.remote_mappings.clear();
                 for mut res in face.local_mappings.values_mut() {
                     get_mut_unchecked(res).session_ctxs.remove(&face.id);
-                    Resource::clean(&mut res);
+                    self.clean_resource(&mut res);
                 }
                 face.local_mappings.clear();
-                while let Some(mut res) = face.remote_subs.pop() {
-                    get_mut_unchecked(&mut res).session_ctxs.remove(&face.id);
-                    undeclare_client_subscription(self, &mut face_clone, &mut res).await;
-                    Resource::clean(&mut res);
+                while let Some(mut res) = face.prefetched.pop() {
+                    self.clean_resource(&mut res);
                 }
                 while let Some(mut res) = face.remote_qabls.pop() {
                     get_mut_unchecked(&mut res).session_ctxs.remove(&face.id);
                     undeclare_client_queryable(self, &mut face_clone, &mut res).await;
-                    Resource::clean(&mut res);
+                    self.clean_resource(&mut res);
                 }
                 self.faces.remove(&face.id);
+                self.tree_child_faces.lock().unwrap().clear();
+                self.flap_states
+                    .retain(|(face_id, _), _| *face_id != face.id);
+                self.forget_history(&cleaned);
+                drop_cleaned_retained(self, cleaned).await;
+                self.interner.purge();
             }
             None => log::error!("Face already closed!"),
         }
@@ -201,6 +1696,159 @@ impl Tables {
         }
     }
 
+    /// Schedule the propagation of the dampened subscriptions once their hold
+    /// down elapses.
+    pub(crate) fn schedule_flap_release(&mut self, tables_ref: Arc<RwLock<Tables>>) {
+        if self.flap_release_task.is_none() {
+            let next = self
+                .flap_states
+                .values()
+                .filter_map(|state| state.held_until())
+                .min();
+            if let Some(mut next) = next {
+                self.flap_release_task = Some(async_std::task::spawn(async move {
+                    loop {
+                        sleep(next.saturating_duration_since(Instant::now())).await;
+                        let mut tables = zasyncwrite!(tables_ref);
+                        match release_dampened_subscriptions(&mut tables).await {
+                            Some(held_until) => next = held_until,
+                            None => {
+                                tables.flap_release_task = None;
+                                break;
+                            }
+                        }
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Schedule the heartbeats of the idle subscriptions until none of them
+    /// has a heartbeat anymore.
+    pub(crate) fn schedule_heartbeats(&mut self, tables_ref: Arc<RwLock<Tables>>) {
+        if self.heartbeats_task.is_none() {
+            let next = self
+                .faces
+                .values()
+                .filter_map(|face| face.next_heartbeat())
+                .min();
+            if let Some(mut next) = next {
+                self.heartbeats_task = Some(async_std::task::spawn(async move {
+                    loop {
+                        sleep(next.saturating_duration_since(Instant::now())).await;
+                        let mut tables = zasyncwrite!(tables_ref);
+                        match send_heartbeats(&tables).await {
+                            Some(due) => next = due,
+                            None => {
+                                tables.heartbeats_task = None;
+                                break;
+                            }
+                        }
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Deliver the batches of the subscriptions as their window elapses,
+    /// until none of them has a pending batch anymore.
+    pub(crate) fn schedule_batches(&mut self, tables_ref: Arc<RwLock<Tables>>) {
+        if self.batches_task.is_none() {
+            self.batches_task = Some(async_std::task::spawn(async move {
+                loop {
+                    let next = {
+                        let mut tables = zasyncwrite!(tables_ref);
+                        match flush_batches(&tables).await {
+                            Some(due) => due,
+                            None => {
+                                tables.batches_task = None;
+                                break;
+                            }
+                        }
+                    };
+                    sleep(next.saturating_duration_since(Instant::now())).await;
+                }
+            }));
+        }
+    }
+
+    /// Expire the subscriptions whose lease elapsed until none of them has a
+    /// lease anymore.
+    pub(crate) fn schedule_sub_expiry(&mut self, tables_ref: Arc<RwLock<Tables>>) {
+        if self.sub_expiry_task.is_none() {
+            if let Some(mut next) = next_sub_lease(self) {
+                self.sub_expiry_task = Some(async_std::task::spawn(async move {
+                    loop {
+                        sleep(next.saturating_duration_since(Instant::now())).await;
+                        let mut tables = zasyncwrite!(tables_ref);
+                        match expire_subscriptions(&mut tables).await {
+                            Some(due) => next = due,
+                            None => {
+                                tables.sub_expiry_task = None;
+                                break;
+                            }
+                        }
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Compact the mappings of all the faces every `interval`, replacing the
+    /// previously scheduled compaction. `None` stops it.
+    pub(crate) async fn schedule_mapping_compaction(
+        &mut self,
+        tables_ref: Arc<RwLock<Tables>>,
+        interval: Option<Duration>,
+    ) {
+        if let Some(task) = self.mapping_compaction_task.take() {
+            task.cancel().await;
+        }
+        if let Some(interval) = interval {
+            self.mapping_compaction_task = Some(async_std::task::spawn(async move {
+                loop {
+                    sleep(interval).await;
+                    let mut tables = zasyncwrite!(tables_ref);
+                    let face_ids: Vec<usize> = tables.faces.keys().copied().collect();
+                    for face_id in face_ids {
+                        tables.compact_face_mappings(face_id).await;
+                    }
+                }
+            }));
+        }
+    }
+
+    pub(crate) async fn init_link_state(
+        &mut self,
+        orchestrator: SessionOrchestrator,
+        peers_autoconnect: bool,
+        routers_autoconnect_gossip: bool,
+    ) {
+        self.peers_net = Some(
+            Network::new(
+                "[Peers network]".to_string(),
+                self.pid.clone(),
+                orchestrator.clone(),
+                peers_autoconnect,
+                routers_autoconnect_gossip,
+            )
+            .await,
+        );
+        if orchestrator.whatami == whatami::ROUTER {
+            self.routers_net = Some(
+                Network::new(
+                    "[Routers network]".to_string(),
+                    self.pid.clone(),
+                    orchestrator.clone(),
+                    peers_autoconnect,
+                    routers_autoconnect_gossip,
+                )
+                .await,
+            );
+            self.update_shared_nodes();
+        }
+    }
+
     pub(crate) fn schedule_compute_trees(
         &mut self,
         tables_ref: Arc<RwLock<Tables>>,
@@ -216,9 +1864,12 @@ impl Tables {
                 let mut tables = zasyncwrite!(tables_ref);
 
                 log::trace!("Compute trees");
-                let new_childs = match net_type {
-                    whatami::ROUTER => tables.routers_net.as_mut().unwrap().compute_trees().await,
-                    _ => tables.peers_net.as_mut().unwrap().compute_trees().await,
+                let new_childs = match tables.get_net_mut(net_type) {
+                    Some(net) => net.compute_trees().await,
+                    None => {
+                        log::error!("Compute trees of missing network!");
+                        vec![]
+                    }
                 };
 
                 log::trace!("Compute routes");
@@ -258,47 +1909,61 @@ impl Router {
         peers_autoconnect: bool,
         routers_autoconnect_gossip: bool,
     ) {
-        let mut tables = zasyncwrite!(self.tables);
-        tables.peers_net = Some(
-            Network::new(
-                "[Peers network]".to_string(),
-                tables.pid.clone(),
-                orchestrator.clone(),
-                peers_autoconnect,
-                routers_autoconnect_gossip,
-            )
-            .await,
-        );
-        if orchestrator.whatami == whatami::ROUTER {
-            tables.routers_net = Some(
-                Network::new(
-                    "[Routers network]".to_string(),
-                    tables.pid.clone(),
-                    orchestrator.clone(),
-                    peers_autoconnect,
-                    routers_autoconnect_gossip,
-                )
-                .await,
-            );
-            tables.shared_nodes = shared_nodes(
-                tables.routers_net.as_ref().unwrap(),
-                tables.peers_net.as_ref().unwrap(),
-            );
-        }
+        zasyncwrite!(self.tables)
+            .init_link_state(orchestrator, peers_autoconnect, routers_autoconnect_gossip)
+            .await;
+    }
+
+    /// Send the heartbeats of the idle subscriptions in the background, see
+    /// `SubOptions::heartbeat`. To be called again once new subscriptions
+    /// with a heartbeat are declared after all the previous ones were
+    /// undeclared.
+    pub async fn schedule_heartbeats(&self) {
+        zasyncwrite!(self.tables).schedule_heartbeats(self.tables.clone());
+    }
+
+    /// Deliver the batches of the subscriptions in the background as their
+    /// window elapses, see `SubOptions::batching`. To be called again once
+    /// data are batched after all the previous batches were delivered.
+    pub async fn schedule_batches(&self) {
+        zasyncwrite!(self.tables).schedule_batches(self.tables.clone());
+    }
+
+    /// Expire the subscriptions whose lease elapsed in the background, see
+    /// `SubOptions::lease`. To be called again once new subscriptions with a
+    /// lease are declared after all the previous ones expired or were
+    /// undeclared.
+    pub async fn schedule_sub_expiry(&self) {
+        zasyncwrite!(self.tables).schedule_sub_expiry(self.tables.clone());
+    }
+
+    /// Compact the mappings of all the faces every `interval` in the
+    /// background, see [`compact_face_mappings`]. `None` stops it.
+    pub async fn schedule_mapping_compaction(&self, interval: Option<Duration>) {
+        zasyncwrite!(self.tables)
+            .schedule_mapping_compaction(self.tables.clone(), interval)
+            .await;
     }
 
     pub async fn new_primitives(&self, primitives: OutSession) -> Arc<Face> {
+        let pid = zasyncread!(self.tables).pid.clone();
+        self.new_face(pid, whatami::CLIENT, primitives).await
+    }
+
+    /// Open a face to the given node, through which its messages are routed.
+    pub async fn new_face(
+        &self,
+        pid: PeerId,
+        whatami: WhatAmI,
+        primitives: OutSession,
+    ) -> Arc<Face> {
         Arc::new(Face {
             tables: self.tables.clone(),
-            state: {
-                let mut tables = zasyncwrite!(self.tables);
-                let pid = tables.pid.clone();
-                tables
-                    .open_face(pid, whatami::CLIENT, primitives)
-                    .await
-                    .upgrade()
-                    .unwrap()
-            },
+            state: zasyncwrite!(self.tables)
+                .open_face(pid, whatami, primitives)
+                .await
+                .upgrade()
+                .unwrap(),
         })
     }
 
@@ -307,32 +1972,27 @@ impl Router {
         let whatami = session.get_whatami()?;
 
         let link_id = match (self.whatami, whatami) {
-            (whatami::ROUTER, whatami::ROUTER) => {
-                tables
-                    .routers_net
-                    .as_mut()
-                    .unwrap()
-                    .add_link(session.clone())
-                    .await
-            }
+            (whatami::ROUTER, whatami::ROUTER) => match tables.get_net_mut(whatami::ROUTER) {
+                Some(net) => net.add_link(session.clone()).await,
+                None => {
+                    log::error!("Add link to missing network!");
+                    0
+                }
+            },
             (whatami::ROUTER, whatami::PEER)
             | (whatami::PEER, whatami::ROUTER)
-            | (whatami::PEER, whatami::PEER) => {
-                tables
-                    .peers_net
-                    .as_mut()
-                    .unwrap()
-                    .add_link(session.clone())
-                    .await
-            }
+            | (whatami::PEER, whatami::PEER) => match tables.get_net_mut(whatami::PEER) {
+                Some(net) => net.add_link(session.clone()).await,
+                None => {
+                    log::error!("Add link to missing network!");
+                    0
+                }
+            },
             _ => 0,
         };
 
         if tables.whatami == whatami::ROUTER {
-            tables.shared_nodes = shared_nodes(
-                tables.routers_net.as_ref().unwrap(),
-                tables.peers_net.as_ref().unwrap(),
-            );
+            tables.update_shared_nodes();
         }
 
         let handler = Arc::new(LinkStateInterceptor::new(
@@ -392,46 +2052,36 @@ impl LinkStateInterceptor {
                 let whatami = self.session.get_whatami()?;
                 match (tables.whatami, whatami) {
                     (whatami::ROUTER, whatami::ROUTER) => {
-                        for (_, removed_node) in tables
-                            .routers_net
-                            .as_mut()
-                            .unwrap()
-                            .link_states(list.link_states, pid)
-                            .await
-                        {
+                        let removed_nodes = match tables.get_net_mut(whatami::ROUTER) {
+                            Some(net) => net.link_states(list.link_states, pid).await,
+                            None => vec![],
+                        };
+                        for (_, removed_node) in removed_nodes {
                             pubsub_remove_node(&mut tables, &removed_node.pid, whatami::ROUTER)
                                 .await;
                             queries_remove_node(&mut tables, &removed_node.pid, whatami::ROUTER)
                                 .await;
                         }
 
-                        tables.shared_nodes = shared_nodes(
-                            tables.routers_net.as_ref().unwrap(),
-                            tables.peers_net.as_ref().unwrap(),
-                        );
+                        tables.update_shared_nodes();
 
                         tables.schedule_compute_trees(self.tables.clone(), whatami::ROUTER);
                     }
                     (whatami::ROUTER, whatami::PEER)
                     | (whatami::PEER, whatami::ROUTER)
                     | (whatami::PEER, whatami::PEER) => {
-                        for (_, removed_node) in tables
-                            .peers_net
-                            .as_mut()
-                            .unwrap()
-                            .link_states(list.link_states, pid)
-                            .await
-                        {
+                        let removed_nodes = match tables.get_net_mut(whatami::PEER) {
+                            Some(net) => net.link_states(list.link_states, pid).await,
+                            None => vec![],
+                        };
+                        for (_, removed_node) in removed_nodes {
                             pubsub_remove_node(&mut tables, &removed_node.pid, whatami::PEER).await;
                             queries_remove_node(&mut tables, &removed_node.pid, whatami::PEER)
                                 .await;
                         }
 
                         if tables.whatami == whatami::ROUTER {
-                            tables.shared_nodes = shared_nodes(
-                                tables.routers_net.as_ref().unwrap(),
-                                tables.peers_net.as_ref().unwrap(),
-                            );
+                            tables.update_shared_nodes();
                         }
 
                         tables.schedule_compute_trees(self.tables.clone(), whatami::PEER);
@@ -456,43 +2106,33 @@ impl LinkStateInterceptor {
         match self.session.get_whatami() {
             Ok(whatami) => match (tables.whatami, whatami) {
                 (whatami::ROUTER, whatami::ROUTER) => {
-                    for (_, removed_node) in tables
-                        .routers_net
-                        .as_mut()
-                        .unwrap()
-                        .remove_link(&self.session)
-                        .await
-                    {
+                    let removed_nodes = match tables.get_net_mut(whatami::ROUTER) {
+                        Some(net) => net.remove_link(&self.session).await,
+                        None => vec![],
+                    };
+                    for (_, removed_node) in removed_nodes {
                         pubsub_remove_node(&mut tables, &removed_node.pid, whatami::ROUTER).await;
                         queries_remove_node(&mut tables, &removed_node.pid, whatami::ROUTER).await;
                     }
 
-                    tables.shared_nodes = shared_nodes(
-                        tables.routers_net.as_ref().unwrap(),
-                        tables.peers_net.as_ref().unwrap(),
-                    );
+                    tables.update_shared_nodes();
 
                     tables.schedule_compute_trees(self.tables.clone(), whatami::ROUTER);
                 }
                 (whatami::ROUTER, whatami::PEER)
                 | (whatami::PEER, whatami::ROUTER)
                 | (whatami::PEER, whatami::PEER) => {
-                    for (_, removed_node) in tables
-                        .peers_net
-                        .as_mut()
-                        .unwrap()
-                        .remove_link(&self.session)
-                        .await
-                    {
+                    let removed_nodes = match tables.get_net_mut(whatami::PEER) {
+                        Some(net) => net.remove_link(&self.session).await,
+                        None => vec![],
+                    };
+                    for (_, removed_node) in removed_nodes {
                         pubsub_remove_node(&mut tables, &removed_node.pid, whatami::PEER).await;
                         queries_remove_node(&mut tables, &removed_node.pid, whatami::PEER).await;
                     }
 
                     if tables.whatami == whatami::ROUTER {
-                        tables.shared_nodes = shared_nodes(
-                            tables.routers_net.as_ref().unwrap(),
-                            tables.peers_net.as_ref().unwrap(),
-                        );
+                        tables.update_shared_nodes();
                     }
 
                     tables.schedule_compute_trees(self.tables.clone(), whatami::PEER);
@@ -0,0 +1,407 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use async_std::sync::Arc;
+use serde_json::{json, Value};
+
+use super::protocol::core::{whatami, PeerId, Period, Reliability, SubInfo, SubMode, ZInt};
+use super::protocol::io::{RBuf, WBuf};
+
+use super::face::FaceState;
+use super::pubsub::{
+    declare_client_subscription, declare_peer_subscription, declare_router_subscription,
+};
+use super::router::{RoutingError, Tables};
+
+/// The header of the binary snapshots, followed by the format version.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"ZSUB";
+const SNAPSHOT_VERSION: ZInt = 1;
+
+/// A subscription registered in the tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionRecord {
+    pub key_expr: String,
+    /// The kind of the subscription: router, peer or client.
+    pub kind: whatami::Type,
+    /// The router or peer that declared the subscription, or the peer id of
+    /// the face of a client subscription.
+    pub origin: PeerId,
+    pub sub_info: SubInfo,
+}
+
+impl SubscriptionRecord {
+    /// The JSON object of the record in [`SubscriptionSnapshot::to_json`].
+    pub fn to_json(&self) -> Value {
+        json!({
+            "key_expr": self.key_expr,
+            "kind": self.kind,
+            "origin": hex::encode_upper(self.origin.as_slice()),
+            "reliable": self.sub_info.reliability == Reliability::Reliable,
+            "pull": self.sub_info.mode == SubMode::Pull,
+            "period": self.sub_info.period.as_ref().map(|period| json!({
+                "origin": period.origin,
+                "period": period.period,
+                "duration": period.duration,
+            })),
+        })
+    }
+}
+
+/// A digest of the subscriptions registered in the tables, see
+/// [`Tables::subscription_digest`]. Tables with the same subscriptions have
+/// the same digest, whatever the build, the platform or the order the
+/// subscriptions were declared in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionDigest {
+    pub count: usize,
+    pub hash: u64,
+}
+
+/// The differences between two snapshots, see [`SubscriptionSnapshot::diff`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionDiff {
+    pub only_local: Vec<SubscriptionRecord>,
+    pub only_other: Vec<SubscriptionRecord>,
+}
+
+impl SubscriptionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_local.is_empty() && self.only_other.is_empty()
+    }
+}
+
+/// The 64 bits FNV-1a hash of some bytes, stable across builds unlike the
+/// hashers of the standard library.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn write_record(wbuf: &mut WBuf, record: &SubscriptionRecord) {
+    wbuf.write_string(&record.key_expr);
+    wbuf.write_zint(record.kind);
+    wbuf.write_peerid(&record.origin);
+    wbuf.write(match record.sub_info.reliability {
+        Reliability::BestEffort => 0,
+        Reliability::Reliable => 1,
+    });
+    wbuf.write(match record.sub_info.mode {
+        SubMode::Push => 0,
+        SubMode::Pull => 1,
+    });
+    match &record.sub_info.period {
+        Some(period) => {
+            wbuf.write(1);
+            wbuf.write_zint(period.origin);
+            wbuf.write_zint(period.period);
+            wbuf.write_zint(period.duration);
+        }
+        None => {
+            wbuf.write(0);
+        }
+    }
+}
+
+/// The subscriptions registered in the tables, see
+/// [`Tables::export_subscriptions`]. It has a compact binary form, and a JSON
+/// form for debugging.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionSnapshot {
+    pub subscriptions: Vec<SubscriptionRecord>,
+}
+
+impl SubscriptionSnapshot {
+    /// Encode the snapshot in the current binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut wbuf = WBuf::new(64, true);
+        wbuf.write_bytes(SNAPSHOT_MAGIC);
+        wbuf.write_zint(SNAPSHOT_VERSION);
+        wbuf.write_usize_as_zint(self.subscriptions.len());
+        for record in &self.subscriptions {
+            write_record(&mut wbuf, record);
+        }
+        RBuf::from(wbuf).to_vec()
+    }
+
+    /// The digest of the subscriptions, whatever their order.
+    pub fn digest(&self) -> SubscriptionDigest {
+        let mut hash = 0u64;
+        for record in &self.subscriptions {
+            let mut wbuf = WBuf::new(64, true);
+            write_record(&mut wbuf, record);
+            hash = hash.wrapping_add(fnv1a(&RBuf::from(wbuf).to_vec()));
+        }
+        SubscriptionDigest {
+            count: self.subscriptions.len(),
+            hash,
+        }
+    }
+
+    /// The subscriptions of this snapshot missing from `other` and those of
+    /// `other` missing from this one. A subscription whose info differs is
+    /// in both.
+    pub fn diff(&self, other: &SubscriptionSnapshot) -> SubscriptionDiff {
+        SubscriptionDiff {
+            only_local: self
+                .subscriptions
+                .iter()
+                .filter(|record| !other.subscriptions.contains(record))
+                .cloned()
+                .collect(),
+            only_other: other
+                .subscriptions
+                .iter()
+                .filter(|record| !self.subscriptions.contains(record))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Decode a snapshot encoded in any of the supported binary formats.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SubscriptionSnapshot, RoutingError> {
+        let mut rbuf = RBuf::from(bytes);
+        let mut magic = [0u8; 4];
+        if !rbuf.read_bytes(&mut magic) || &magic != SNAPSHOT_MAGIC {
+            return Err(RoutingError::InvalidSnapshot("missing header".to_string()));
+        }
+        match rbuf.read_zint() {
+            Some(1) => read_records_v1(&mut rbuf)
+                .map(|subscriptions| SubscriptionSnapshot { subscriptions })
+                .ok_or_else(|| RoutingError::InvalidSnapshot("truncated".to_string())),
+            Some(version) => Err(RoutingError::InvalidSnapshot(format!(
+                "unsupported version {}",
+                version
+            ))),
+            None => Err(RoutingError::InvalidSnapshot("missing version".to_string())),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Array(
+            self.subscriptions
+                .iter()
+                .map(SubscriptionRecord::to_json)
+                .collect(),
+        )
+    }
+
+    pub fn from_json(value: &Value) -> Result<SubscriptionSnapshot, RoutingError> {
+        let invalid = |descr: &str| RoutingError::InvalidSnapshot(descr.to_string());
+        let mut subscriptions = vec![];
+        for record in value.as_array().ok_or_else(|| invalid("not an array"))? {
+            let origin = record["origin"]
+                .as_str()
+                .and_then(|origin| hex::decode(origin).ok())
+                .filter(|origin| origin.len() <= PeerId::MAX_SIZE)
+                .ok_or_else(|| invalid("invalid origin"))?;
+            let mut id = [0u8; PeerId::MAX_SIZE];
+            id[..origin.len()].copy_from_slice(&origin);
+            let period = match &record["period"] {
+                Value::Null => None,
+                period => Some(Period {
+                    origin: period["origin"]
+                        .as_u64()
+                        .ok_or_else(|| invalid("invalid period"))?,
+                    period: period["period"]
+                        .as_u64()
+                        .ok_or_else(|| invalid("invalid period"))?,
+                    duration: period["duration"]
+                        .as_u64()
+                        .ok_or_else(|| invalid("invalid period"))?,
+                }),
+            };
+            subscriptions.push(SubscriptionRecord {
+                key_expr: record["key_expr"]
+                    .as_str()
+                    .ok_or_else(|| invalid("invalid key_expr"))?
+                    .to_string(),
+                kind: record["kind"]
+                    .as_u64()
+                    .ok_or_else(|| invalid("invalid kind"))?,
+                origin: PeerId::new(origin.len(), id),
+                sub_info: SubInfo {
+                    reliability: if record["reliable"].as_bool().unwrap_or(true) {
+                        Reliability::Reliable
+                    } else {
+                        Reliability::BestEffort
+                    },
+                    mode: if record["pull"].as_bool().unwrap_or(false) {
+                        SubMode::Pull
+                    } else {
+                        SubMode::Push
+                    },
+                    period,
+                },
+            });
+        }
+        Ok(SubscriptionSnapshot { subscriptions })
+    }
+}
+
+fn read_records_v1(rbuf: &mut RBuf) -> Option<Vec<SubscriptionRecord>> {
+    let len = rbuf.read_zint_as_usize()?;
+    let mut records = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        let key_expr = rbuf.read_string()?;
+        let kind = rbuf.read_zint()?;
+        let origin = rbuf.read_peerid()?;
+        let reliability = match rbuf.read()? {
+            0 => Reliability::BestEffort,
+            _ => Reliability::Reliable,
+        };
+        let mode = match rbuf.read()? {
+            0 => SubMode::Push,
+            _ => SubMode::Pull,
+        };
+        let period = match rbuf.read()? {
+            0 => None,
+            _ => Some(Period {
+                origin: rbuf.read_zint()?,
+                period: rbuf.read_zint()?,
+                duration: rbuf.read_zint()?,
+            }),
+        };
+        records.push(SubscriptionRecord {
+            key_expr,
+            kind,
+            origin,
+            sub_info: SubInfo {
+                reliability,
+                mode,
+                period,
+            },
+        });
+    }
+    Some(records)
+}
+
+/// Return the router, peer and client subscriptions registered in the tables,
+/// sorted by key expression.
+pub fn export_subscriptions(tables: &Tables) -> SubscriptionSnapshot {
+    let router_sub_info = SubInfo {
+        reliability: Reliability::Reliable,
+        mode: SubMode::Push,
+        period: None,
+    };
+    let mut subscriptions = vec![];
+    let mut stack = vec![tables.root_res.clone()];
+    while let Some(res) = stack.pop() {
+        if let Some(context) = res.context.as_ref() {
+            for (kind, subs) in &[
+                (whatami::ROUTER, &context.router_subs),
+                (whatami::PEER, &context.peer_subs),
+            ] {
+                for origin in subs.iter() {
+                    subscriptions.push(SubscriptionRecord {
+                        key_expr: res.name(),
+                        kind: *kind,
+                        origin: origin.clone(),
+                        sub_info: router_sub_info.clone(),
+                    });
+                }
+            }
+            for ctx in res.session_ctxs.values() {
+                if let (whatami::CLIENT, Some(sub_info)) = (ctx.face.whatami, ctx.subs()) {
+                    subscriptions.push(SubscriptionRecord {
+                        key_expr: res.name(),
+                        kind: whatami::CLIENT,
+                        origin: ctx.face.pid.clone(),
+                        sub_info: sub_info.clone(),
+                    });
+                }
+            }
+        }
+        stack.extend(res.childs.values().cloned());
+    }
+    subscriptions.sort_by(|record1, record2| {
+        (&record1.key_expr, record1.kind, record1.origin.as_slice()).cmp(&(
+            &record2.key_expr,
+            record2.kind,
+            record2.origin.as_slice(),
+        ))
+    });
+    SubscriptionSnapshot { subscriptions }
+}
+
+/// Declare the subscriptions of a snapshot. A client subscription is declared
+/// for the first client face with the peer id of its origin, and a router or
+/// peer subscription for the face directly connected to its origin. The
+/// subscriptions without such a face are skipped. The data routes of all the
+/// resources are then built. Return the number of declared subscriptions.
+pub async fn import_subscriptions(tables: &mut Tables, snapshot: &SubscriptionSnapshot) -> usize {
+    let mut imported = 0;
+    for record in &snapshot.subscriptions {
+        let face = tables
+            .faces
+            .values()
+            .find(|face| face.pid == record.origin && face.whatami == record.kind)
+            .cloned();
+        let mut face: Arc<FaceState> = match face {
+            Some(face) => face,
+            None => {
+                log::debug!(
+                    "Skip subscription {} of unknown {} {}",
+                    record.key_expr,
+                    whatami::to_string(record.kind),
+                    record.origin
+                );
+                continue;
+            }
+        };
+        let declared = match record.kind {
+            whatami::ROUTER => {
+                declare_router_subscription(
+                    tables,
+                    &mut face,
+                    0,
+                    &record.key_expr,
+                    &record.sub_info,
+                    record.origin.clone(),
+                )
+                .await
+            }
+            whatami::PEER => {
+                declare_peer_subscription(
+                    tables,
+                    &mut face,
+                    0,
+                    &record.key_expr,
+                    &record.sub_info,
+                    record.origin.clone(),
+                )
+                .await
+            }
+            _ => {
+                declare_client_subscription(
+                    tables,
+                    &mut face,
+                    0,
+                    &record.key_expr,
+                    &record.sub_info,
+                )
+                .await
+            }
+        };
+        if declared.is_ok() {
+            imported += 1;
+        }
+    }
+    log::debug!(
+        "Imported {} of {} subscriptions",
+        imported,
+        snapshot.subscriptions.len()
+    );
+    tables.rebuild_all_routes();
+    imported
+}
@@ -13,11 +13,12 @@
 use super::plugins::PluginsMgr;
 use super::protocol::{
     core::{
-        queryable::EVAL, rname, CongestionControl, PeerId, QueryConsolidation, QueryTarget,
-        Reliability, ResKey, SubInfo, ZInt,
+        queryable::EVAL, rname, CongestionControl, PeerId, Priority, QueryConsolidation,
+        QueryTarget, Reliability, ResKey, SubInfo, ZInt,
     },
     io::RBuf,
     proto::{encoding, DataInfo, RoutingContext},
+    session::{DeliveryAck, ForcedUndeclare},
 };
 use super::routing::face::Face;
 use super::routing::OutSession;
@@ -152,6 +153,14 @@ impl AdminSpace {
         trace!("recv Forget Subscriber {:?}", _reskey);
     }
 
+    pub(crate) async fn forced_forget_subscriber(
+        &self,
+        _reskey: &ResKey,
+        _reason: ForcedUndeclare,
+    ) {
+        trace!("recv Forced Forget Subscriber {:?} {:?}", _reskey, _reason);
+    }
+
     pub(crate) async fn decl_queryable(
         &self,
         _reskey: &ResKey,
@@ -187,6 +196,52 @@ impl AdminSpace {
         );
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_data_with_priority(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        _priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) {
+        self.send_data(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            data_info,
+            routing_context,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_data_acked(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) -> DeliveryAck {
+        self.send_data_with_priority(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            priority,
+            data_info,
+            routing_context,
+        )
+        .await;
+        DeliveryAck::Sent
+    }
+
     pub(crate) async fn send_query(
         &self,
         reskey: &ResKey,
@@ -231,6 +286,7 @@ impl AdminSpace {
                     timestamp: None,
                     kind: None,
                     encoding: Some(encoding),
+                    ..DataInfo::default()
                 };
                 primitives
                     .send_reply_data(
@@ -21,11 +21,12 @@ use async_std::task;
 use log::{error, trace, warn};
 use protocol::{
     core::{
-        queryable, rname, AtomicZInt, CongestionControl, QueryConsolidation, QueryTarget, ResKey,
-        ResourceId, ZInt,
+        queryable, rname, AtomicZInt, CongestionControl, Priority, QueryConsolidation, QueryTarget,
+        ResKey, ResourceId, ZInt,
     },
     io::RBuf,
     proto::RoutingContext,
+    session::{DeliveryAck, ForcedUndeclare},
 };
 use routing::OutSession;
 use runtime::Runtime;
@@ -890,6 +891,7 @@ impl Session {
             timestamp: None,
             kind: Some(kind),
             encoding: Some(encoding),
+            ..DataInfo::default()
         };
         let data_info = Some(info);
         primitives
@@ -916,6 +918,11 @@ impl Session {
         info: Option<DataInfo>,
         payload: RBuf,
     ) {
+        // The keep-alives of the idle subscriptions are not user data
+        if info.as_ref().and_then(|info| info.kind) == Some(data_kind::KEEP_ALIVE) {
+            trace!("Recv keep-alive {:?}", reskey);
+            return;
+        }
         let state = zasyncread!(self.state);
         if let ResKey::RId(rid) = reskey {
             match state.get_res(rid, local) {
@@ -1265,6 +1272,14 @@ impl Session {
         trace!("recv Forget Subscriber {:?}", _reskey);
     }
 
+    pub(crate) async fn forced_forget_subscriber(
+        &self,
+        _reskey: &ResKey,
+        _reason: ForcedUndeclare,
+    ) {
+        trace!("recv Forced Forget Subscriber {:?} {:?}", _reskey, _reason);
+    }
+
     pub(crate) async fn decl_queryable(
         &self,
         _reskey: &ResKey,
@@ -1301,6 +1316,52 @@ impl Session {
         self.handle_data(false, reskey, info, payload).await
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_data_with_priority(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        _priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) {
+        self.send_data(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            data_info,
+            routing_context,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn send_data_acked(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        data_info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) -> DeliveryAck {
+        self.send_data_with_priority(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            priority,
+            data_info,
+            routing_context,
+        )
+        .await;
+        DeliveryAck::Sent
+    }
+
     pub(crate) async fn send_query(
         &self,
         reskey: &ResKey,
@@ -62,6 +62,9 @@ pub use super::protocol::core::QueryConsolidation;
 /// The kind of congestion control.
 pub use super::protocol::core::CongestionControl;
 
+/// The transmission lane of a message.
+pub use super::protocol::core::Priority;
+
 /// The kind of reliability.
 pub use super::protocol::core::Reliability;
 
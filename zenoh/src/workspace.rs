@@ -555,6 +555,7 @@ impl Change {
             timestamp: Some(self.timestamp),
             kind: Some(self.kind as u64),
             encoding,
+            ..DataInfo::default()
         };
         Sample {
             res_name: self.path.to_string(),
@@ -612,6 +613,7 @@ fn path_value_to_sample(path: Path, value: Value) -> Sample {
         timestamp: None,
         kind: None,
         encoding: Some(encoding),
+        ..DataInfo::default()
     };
     Sample {
         res_name: path.to_string(),
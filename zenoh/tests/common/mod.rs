@@ -0,0 +1,733 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+//! Fixtures and recording primitives shared by the tables tests.
+#![allow(dead_code)]
+
+use async_std::sync::{Arc, Weak};
+use async_trait::async_trait;
+use futures::prelude::*;
+use uhlc::HLC;
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, Priority, QueryConsolidation, QueryTarget, Reliability,
+    ResKey, SubInfo, SubMode, ZInt,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::protocol::proto::{DataInfo, RoutingContext};
+use zenoh::net::protocol::session::{
+    DataBatch, DeliveryAck, ForcedUndeclare, Primitives, SubscriberBatch,
+};
+use zenoh::net::routing::face::{Face, FaceState};
+use zenoh::net::routing::router::*;
+use zenoh::net::routing::OutSession;
+use zenoh::net::runtime::orchestrator::SessionOrchestrator;
+
+/// The ids of the primitives the data were sent to, in order.
+pub type DeliveriesLog = Arc<std::sync::Mutex<Vec<usize>>>;
+
+pub struct ClientPrimitives {
+    data: std::sync::Mutex<Option<ResKey>>,
+    query: std::sync::Mutex<Option<(String, ZInt)>>,
+    query_key: std::sync::Mutex<Option<ResKey>>,
+    reply: std::sync::Mutex<Option<ResKey>>,
+    reply_finals: std::sync::Mutex<Vec<ZInt>>,
+    payloads: std::sync::Mutex<Vec<RBuf>>,
+    congestion_controls: std::sync::Mutex<Vec<CongestionControl>>,
+    reliabilities: std::sync::Mutex<Vec<Reliability>>,
+    priorities: std::sync::Mutex<Vec<Priority>>,
+    info: std::sync::Mutex<Option<DataInfo>>,
+    batches: std::sync::Mutex<Vec<DataBatch>>,
+    subs: std::sync::Mutex<Vec<String>>,
+    sub_reliabilities: std::sync::Mutex<Vec<Reliability>>,
+    forgotten: std::sync::Mutex<Vec<String>>,
+    forced_forgotten: std::sync::Mutex<Vec<(String, ForcedUndeclare)>>,
+    closed: std::sync::atomic::AtomicBool,
+    sub_frames: std::sync::atomic::AtomicUsize,
+    stuck: std::sync::atomic::AtomicBool,
+    deliveries_log: std::sync::Mutex<Option<(usize, DeliveriesLog)>>,
+    gate: std::sync::Mutex<Option<Arc<async_std::sync::Mutex<()>>>>,
+    mapping: std::sync::Mutex<std::collections::HashMap<ZInt, String>>,
+}
+
+impl ClientPrimitives {
+    pub fn new() -> ClientPrimitives {
+        ClientPrimitives {
+            data: std::sync::Mutex::new(None),
+            query: std::sync::Mutex::new(None),
+            query_key: std::sync::Mutex::new(None),
+            reply: std::sync::Mutex::new(None),
+            reply_finals: std::sync::Mutex::new(vec![]),
+            payloads: std::sync::Mutex::new(vec![]),
+            congestion_controls: std::sync::Mutex::new(vec![]),
+            reliabilities: std::sync::Mutex::new(vec![]),
+            priorities: std::sync::Mutex::new(vec![]),
+            info: std::sync::Mutex::new(None),
+            batches: std::sync::Mutex::new(vec![]),
+            subs: std::sync::Mutex::new(vec![]),
+            sub_reliabilities: std::sync::Mutex::new(vec![]),
+            forgotten: std::sync::Mutex::new(vec![]),
+            forced_forgotten: std::sync::Mutex::new(vec![]),
+            closed: std::sync::atomic::AtomicBool::new(false),
+            sub_frames: std::sync::atomic::AtomicUsize::new(0),
+            stuck: std::sync::atomic::AtomicBool::new(false),
+            deliveries_log: std::sync::Mutex::new(None),
+            gate: std::sync::Mutex::new(None),
+            mapping: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    pub fn clear_data(&self) {
+        *self.data.lock().unwrap() = None;
+    }
+
+    /// Record `id` in `log` for each data sent to these primitives.
+    pub fn log_deliveries(&self, id: usize, log: DeliveriesLog) {
+        *self.deliveries_log.lock().unwrap() = Some((id, log));
+    }
+
+    /// Make the data sent to these primitives wait for `gate` to be unlocked.
+    pub fn set_gate(&self, gate: Arc<async_std::sync::Mutex<()>>) {
+        *self.gate.lock().unwrap() = Some(gate);
+    }
+
+    /// Make the data sent to these primitives never complete.
+    pub fn set_stuck(&self, stuck: bool) {
+        self.stuck
+            .store(stuck, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+impl Default for ClientPrimitives {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientPrimitives {
+    pub fn get_name(&self, reskey: &ResKey) -> String {
+        let mapping = self.mapping.lock().unwrap();
+        match reskey {
+            ResKey::RName(name) => name.clone(),
+            ResKey::RId(id) => mapping.get(id).unwrap().clone(),
+            ResKey::RIdWithSuffix(id, suffix) => {
+                [&mapping.get(id).unwrap()[..], &suffix[..]].concat()
+            }
+        }
+    }
+
+    pub fn get_last_name(&self) -> Option<String> {
+        self.data
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|data| self.get_name(&data))
+    }
+
+    #[allow(dead_code)]
+    pub fn get_last_key(&self) -> Option<ResKey> {
+        self.data.lock().unwrap().as_ref().map(|data| data.clone())
+    }
+
+    pub fn get_payloads(&self) -> Vec<RBuf> {
+        self.payloads.lock().unwrap().clone()
+    }
+
+    pub fn get_last_info(&self) -> Option<DataInfo> {
+        self.info.lock().unwrap().clone()
+    }
+
+    pub fn get_congestion_controls(&self) -> Vec<CongestionControl> {
+        self.congestion_controls.lock().unwrap().clone()
+    }
+
+    pub fn get_reliabilities(&self) -> Vec<Reliability> {
+        self.reliabilities.lock().unwrap().clone()
+    }
+
+    pub fn get_priorities(&self) -> Vec<Priority> {
+        self.priorities.lock().unwrap().clone()
+    }
+
+    pub fn get_batches(&self) -> Vec<Vec<String>> {
+        self.batches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|batch| {
+                batch
+                    .iter()
+                    .map(|(reskey, _, _)| self.get_name(reskey))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn get_subs(&self) -> Vec<String> {
+        self.subs.lock().unwrap().clone()
+    }
+
+    pub fn get_sub_reliabilities(&self) -> Vec<Reliability> {
+        self.sub_reliabilities.lock().unwrap().clone()
+    }
+
+    pub fn get_forgotten(&self) -> Vec<String> {
+        self.forgotten.lock().unwrap().clone()
+    }
+
+    pub fn get_forced_forgotten(&self) -> Vec<(String, ForcedUndeclare)> {
+        self.forced_forgotten.lock().unwrap().clone()
+    }
+
+    pub fn get_mapped_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.mapping.lock().unwrap().values().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn get_sub_frames(&self) -> usize {
+        self.sub_frames.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn get_last_query(&self) -> Option<(String, ZInt)> {
+        self.query.lock().unwrap().clone()
+    }
+
+    pub fn get_last_query_name(&self) -> Option<String> {
+        self.query_key
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|reskey| self.get_name(&reskey))
+    }
+
+    pub fn get_last_reply_name(&self) -> Option<String> {
+        self.reply
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|reply| self.get_name(&reply))
+    }
+
+    pub fn get_reply_finals(&self) -> Vec<ZInt> {
+        self.reply_finals.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Primitives for ClientPrimitives {
+    async fn decl_resource(&self, rid: ZInt, reskey: &ResKey) {
+        let name = self.get_name(reskey);
+        self.mapping.lock().unwrap().insert(rid, name);
+    }
+    async fn forget_resource(&self, rid: ZInt) {
+        self.mapping.lock().unwrap().remove(&rid);
+    }
+
+    async fn decl_publisher(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+    async fn forget_publisher(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+
+    async fn decl_subscriber(
+        &self,
+        reskey: &ResKey,
+        sub_info: &SubInfo,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        let name = self.get_name(reskey);
+        self.subs.lock().unwrap().push(name);
+        self.sub_reliabilities
+            .lock()
+            .unwrap()
+            .push(sub_info.reliability);
+        self.sub_frames
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+    async fn forget_subscriber(&self, reskey: &ResKey, _routing_context: Option<RoutingContext>) {
+        let name = self.get_name(reskey);
+        self.forgotten.lock().unwrap().push(name);
+    }
+    async fn forced_forget_subscriber(&self, reskey: &ResKey, reason: ForcedUndeclare) {
+        let name = self.get_name(reskey);
+        self.forced_forgotten.lock().unwrap().push((name, reason));
+    }
+    async fn decl_subscriber_batch(
+        &self,
+        batch: SubscriberBatch,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        for (reskey, sub_info) in batch {
+            let name = self.get_name(&reskey);
+            self.subs.lock().unwrap().push(name);
+            self.sub_reliabilities
+                .lock()
+                .unwrap()
+                .push(sub_info.reliability);
+        }
+        self.sub_frames
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    async fn decl_queryable(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+    async fn forget_queryable(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+
+    async fn send_data(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        info: Option<DataInfo>,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        if self.stuck.load(std::sync::atomic::Ordering::Relaxed) {
+            future::pending::<()>().await;
+        }
+        let gate = self.gate.lock().unwrap().clone();
+        if let Some(gate) = gate {
+            drop(gate.lock().await);
+        }
+        if let Some((id, log)) = &*self.deliveries_log.lock().unwrap() {
+            log.lock().unwrap().push(*id);
+        }
+        *self.data.lock().unwrap() = Some(reskey.clone());
+        *self.info.lock().unwrap() = info;
+        self.payloads.lock().unwrap().push(payload);
+        self.reliabilities.lock().unwrap().push(reliability);
+        self.congestion_controls
+            .lock()
+            .unwrap()
+            .push(congestion_control);
+    }
+    async fn send_data_with_priority(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        reliability: Reliability,
+        congestion_control: CongestionControl,
+        priority: Priority,
+        info: Option<DataInfo>,
+        routing_context: Option<RoutingContext>,
+    ) {
+        self.priorities.lock().unwrap().push(priority);
+        self.send_data(
+            reskey,
+            payload,
+            reliability,
+            congestion_control,
+            info,
+            routing_context,
+        )
+        .await
+    }
+    async fn send_data_batch(
+        &self,
+        batch: DataBatch,
+        _reliability: Reliability,
+        _congestion_control: CongestionControl,
+    ) {
+        self.batches.lock().unwrap().push(batch);
+    }
+    async fn send_query(
+        &self,
+        reskey: &ResKey,
+        predicate: &str,
+        qid: ZInt,
+        _target: QueryTarget,
+        _consolidation: QueryConsolidation,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        *self.query.lock().unwrap() = Some((predicate.to_string(), qid));
+        *self.query_key.lock().unwrap() = Some(reskey.clone());
+    }
+    async fn send_reply_data(
+        &self,
+        _qid: ZInt,
+        _source_kind: ZInt,
+        _replier_id: PeerId,
+        reskey: ResKey,
+        _info: Option<DataInfo>,
+        _payload: RBuf,
+    ) {
+        *self.reply.lock().unwrap() = Some(reskey);
+    }
+    async fn send_reply_final(&self, qid: ZInt) {
+        self.reply_finals.lock().unwrap().push(qid);
+    }
+    async fn send_pull(
+        &self,
+        _is_final: bool,
+        _reskey: &ResKey,
+        _pull_id: ZInt,
+        _max_samples: &Option<ZInt>,
+    ) {
+    }
+
+    async fn send_close(&self) {
+        self.closed
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Client tables with an HLC.
+pub fn client_tables() -> Tables {
+    Tables::new(
+        PeerId::new(0, [0; 16]),
+        whatami::CLIENT,
+        Some(HLC::default()),
+    )
+}
+
+/// Router tables with an HLC.
+pub fn router_tables() -> Tables {
+    Tables::new(
+        PeerId::new(0, [0; 16]),
+        whatami::ROUTER,
+        Some(HLC::default()),
+    )
+}
+
+/// The info of an unperiodic subscription.
+pub fn sub_info_of(reliability: Reliability, mode: SubMode) -> SubInfo {
+    SubInfo {
+        reliability,
+        mode,
+        period: None,
+    }
+}
+
+/// The info of a reliable push subscription.
+pub fn reliable_push() -> SubInfo {
+    sub_info_of(Reliability::Reliable, SubMode::Push)
+}
+
+/// The info of a reliable pull subscription.
+pub fn reliable_pull() -> SubInfo {
+    sub_info_of(Reliability::Reliable, SubMode::Pull)
+}
+
+/// Open a client face recording what it is sent with `ClientPrimitives`.
+pub async fn open_client_face(
+    tables: &mut Tables,
+    pid: PeerId,
+) -> (Weak<FaceState>, Arc<ClientPrimitives>) {
+    let primitives = Arc::new(ClientPrimitives::new());
+    let face = tables
+        .open_face(
+            pid,
+            whatami::CLIENT,
+            OutSession::Primitives(primitives.clone()),
+        )
+        .await;
+    (face, primitives)
+}
+
+/// Declare a subscription of the given client face on `expr`, which must be
+/// accepted.
+pub async fn declare_sub(
+    tables: &mut Tables,
+    face: &mut Arc<FaceState>,
+    expr: &str,
+    sub_info: &SubInfo,
+) -> DeclareOutcome {
+    declare_client_subscription(tables, face, 0, expr, sub_info)
+        .await
+        .unwrap()
+}
+
+/// Open `count` client faces sharing the given primitives, each declaring a
+/// reliable push subscription on `expr`, which must be accepted.
+pub async fn open_subscribers(
+    tables: &mut Tables,
+    expr: &str,
+    count: usize,
+    primitives: OutSession,
+) -> Vec<Arc<FaceState>> {
+    let mut faces = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut id = [0u8; 16];
+        id[..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+        let mut face = tables
+            .open_face(PeerId::new(16, id), whatami::CLIENT, primitives.clone())
+            .await
+            .upgrade()
+            .unwrap();
+        declare_sub(tables, &mut face, expr, &reliable_push()).await;
+        faces.push(face);
+    }
+    faces
+}
+
+pub async fn new_router_tables(pid: PeerId) -> Router {
+    let mut router = Router::new(pid.clone(), whatami::ROUTER, Some(HLC::default()));
+    let orchestrator = SessionOrchestrator::new(
+        whatami::ROUTER,
+        Arc::new(Router::new(pid, whatami::ROUTER, None)),
+    );
+    router.init_link_state(orchestrator, false, false).await;
+    router
+}
+
+/// A call received by some [`RecordingPrimitives`], with its resource key
+/// resolved to a complete resource name.
+#[derive(Debug, Clone)]
+pub enum Recorded {
+    Subscriber(String, SubInfo),
+    ForgetSubscriber(String),
+    Data(String, Option<DataInfo>, RBuf),
+    Reply(String, Option<DataInfo>, RBuf),
+    ReplyFinal(ZInt),
+}
+
+/// Primitives recording, in order, all the subscriber declarations and the
+/// data and replies they receive, so that tests can assert on exactly what the
+/// tables forwarded to a face. The data sent with acknowledgment get the scripted
+/// acknowledgments, if any.
+#[derive(Default)]
+pub struct RecordingPrimitives {
+    mapping: std::sync::Mutex<std::collections::HashMap<ZInt, String>>,
+    records: std::sync::Mutex<Vec<Recorded>>,
+    acks: std::sync::Mutex<std::collections::VecDeque<DeliveryAck>>,
+}
+
+impl RecordingPrimitives {
+    pub fn get_name(&self, reskey: &ResKey) -> String {
+        let mapping = self.mapping.lock().unwrap();
+        match reskey {
+            ResKey::RName(name) => name.clone(),
+            ResKey::RId(id) => mapping.get(id).unwrap().clone(),
+            ResKey::RIdWithSuffix(id, suffix) => {
+                [&mapping.get(id).unwrap()[..], &suffix[..]].concat()
+            }
+        }
+    }
+
+    pub fn record(&self, record: Recorded) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    pub fn set_acks(&self, acks: Vec<DeliveryAck>) {
+        *self.acks.lock().unwrap() = acks.into();
+    }
+
+    /// The number of scripted acknowledgments not used yet.
+    pub fn remaining_acks(&self) -> usize {
+        self.acks.lock().unwrap().len()
+    }
+
+    pub fn records(&self) -> Vec<Recorded> {
+        self.records.lock().unwrap().clone()
+    }
+
+    pub fn subscribers(&self) -> Vec<(String, SubInfo)> {
+        self.records()
+            .into_iter()
+            .filter_map(|record| match record {
+                Recorded::Subscriber(name, sub_info) => Some((name, sub_info)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn forgotten_subscribers(&self) -> Vec<String> {
+        self.records()
+            .into_iter()
+            .filter_map(|record| match record {
+                Recorded::ForgetSubscriber(name) => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn data(&self) -> Vec<(String, Option<DataInfo>, RBuf)> {
+        self.records()
+            .into_iter()
+            .filter_map(|record| match record {
+                Recorded::Data(name, info, payload) => Some((name, info, payload)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn replies(&self) -> Vec<(String, Option<DataInfo>, RBuf)> {
+        self.records()
+            .into_iter()
+            .filter_map(|record| match record {
+                Recorded::Reply(name, info, payload) => Some((name, info, payload)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Primitives for RecordingPrimitives {
+    async fn decl_resource(&self, rid: ZInt, reskey: &ResKey) {
+        let name = self.get_name(reskey);
+        self.mapping.lock().unwrap().insert(rid, name);
+    }
+    async fn forget_resource(&self, rid: ZInt) {
+        self.mapping.lock().unwrap().remove(&rid);
+    }
+
+    async fn decl_publisher(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+    async fn forget_publisher(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+
+    async fn decl_subscriber(
+        &self,
+        reskey: &ResKey,
+        sub_info: &SubInfo,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        self.record(Recorded::Subscriber(
+            self.get_name(reskey),
+            sub_info.clone(),
+        ));
+    }
+    async fn forget_subscriber(&self, reskey: &ResKey, _routing_context: Option<RoutingContext>) {
+        self.record(Recorded::ForgetSubscriber(self.get_name(reskey)));
+    }
+    async fn decl_subscriber_batch(
+        &self,
+        batch: SubscriberBatch,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        for (reskey, sub_info) in batch {
+            self.record(Recorded::Subscriber(self.get_name(&reskey), sub_info));
+        }
+    }
+
+    async fn decl_queryable(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+    async fn forget_queryable(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
+
+    async fn send_data(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        _reliability: Reliability,
+        _congestion_control: CongestionControl,
+        info: Option<DataInfo>,
+        _routing_context: Option<RoutingContext>,
+    ) {
+        self.record(Recorded::Data(self.get_name(reskey), info, payload));
+    }
+    async fn send_data_acked(
+        &self,
+        reskey: &ResKey,
+        payload: RBuf,
+        _reliability: Reliability,
+        _congestion_control: CongestionControl,
+        _priority: Priority,
+        info: Option<DataInfo>,
+        _routing_context: Option<RoutingContext>,
+    ) -> DeliveryAck {
+        self.record(Recorded::Data(self.get_name(reskey), info, payload));
+        self.acks
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(DeliveryAck::Sent)
+    }
+    async fn send_data_batch(
+        &self,
+        batch: DataBatch,
+        _reliability: Reliability,
+        _congestion_control: CongestionControl,
+    ) {
+        for (reskey, payload, info) in batch {
+            self.record(Recorded::Data(self.get_name(&reskey), info, payload));
+        }
+    }
+    async fn send_query(
+        &self,
+        _reskey: &ResKey,
+        _predicate: &str,
+        _qid: ZInt,
+        _target: QueryTarget,
+        _consolidation: QueryConsolidation,
+        _routing_context: Option<RoutingContext>,
+    ) {
+    }
+    async fn send_reply_data(
+        &self,
+        _qid: ZInt,
+        _source_kind: ZInt,
+        _replier_id: PeerId,
+        reskey: ResKey,
+        info: Option<DataInfo>,
+        payload: RBuf,
+    ) {
+        self.record(Recorded::Reply(self.get_name(&reskey), info, payload));
+    }
+    async fn send_reply_final(&self, qid: ZInt) {
+        self.record(Recorded::ReplyFinal(qid));
+    }
+    async fn send_pull(
+        &self,
+        _is_final: bool,
+        _reskey: &ResKey,
+        _pull_id: ZInt,
+        _max_samples: &Option<ZInt>,
+    ) {
+    }
+
+    async fn send_close(&self) {}
+}
+
+/// The time of the clock of the deterministic HLCs. As it never advances,
+/// the HLC stamps the successive samples with `FIXED_TIME`, `FIXED_TIME + 1`,
+/// ..., unless it receives timestamps ahead of it.
+pub const FIXED_TIME: uhlc::NTP64 = uhlc::NTP64(1000 << 32);
+
+pub fn fixed_clock() -> uhlc::NTP64 {
+    FIXED_TIME
+}
+
+pub fn deterministic_hlc(pid: &PeerId) -> HLC {
+    HLC::with_clock(uhlc::ID::from(pid), fixed_clock)
+}
+
+/// Tables timestamping the data with a deterministic HLC.
+pub fn deterministic_tables(pid: PeerId, whatami: whatami::Type) -> Tables {
+    let hlc = deterministic_hlc(&pid);
+    Tables::new(pid, whatami, Some(hlc))
+}
+
+/// Open a face on the tables whose received calls are recorded.
+pub async fn open_recording_face(
+    tables: &mut Tables,
+    pid: PeerId,
+    whatami: whatami::Type,
+) -> (Arc<FaceState>, Arc<RecordingPrimitives>) {
+    let primitives = Arc::new(RecordingPrimitives::default());
+    let face = tables
+        .open_face(pid, whatami, OutSession::Primitives(primitives.clone()))
+        .await
+        .upgrade()
+        .unwrap();
+    (face, primitives)
+}
+
+pub async fn open_recording_router_face(
+    router: &Router,
+    pid: PeerId,
+    whatami: whatami::Type,
+) -> (Arc<Face>, Arc<RecordingPrimitives>) {
+    let primitives = Arc::new(RecordingPrimitives::default());
+    let face = router
+        .new_face(pid, whatami, OutSession::Primitives(primitives.clone()))
+        .await;
+    (face, primitives)
+}
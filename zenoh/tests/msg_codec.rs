@@ -191,6 +191,7 @@ fn gen_data_info() -> DataInfo {
         timestamp: option_gen!(gen_timestamp()),
         kind: option_gen!(gen!(ZInt)),
         encoding: option_gen!(gen!(ZInt)),
+        ..DataInfo::default()
     }
 }
 
@@ -644,6 +645,53 @@ fn codec_data() {
     }
 }
 
+#[test]
+fn codec_data_routing_path() {
+    for _ in 0..NUM_ITER {
+        let path = vec![gen_pid(), gen_pid()];
+        let legacy_info = gen_data_info();
+        let mut info = legacy_info.clone();
+        info.routing_path = Some(path.clone());
+
+        // The DataInfo is encoded as before the routing path
+        let mut legacy_buf = WBuf::new(164, false);
+        legacy_buf.write_data_info(&legacy_info);
+        let mut buf = WBuf::new(164, false);
+        buf.write_data_info(&info);
+        assert_eq!(RBuf::from(&buf).to_vec(), RBuf::from(&legacy_buf).to_vec());
+
+        // The routing path is carried in an attachment, which the decoders
+        // that don't know it read as any other attachment
+        let msg = ZenohMessage::make_data(
+            gen_key(),
+            RBuf::from(gen_buffer(MAX_PAYLOAD_SIZE)),
+            Reliability::Reliable,
+            CongestionControl::Block,
+            Some(info),
+            None,
+            None,
+            Some(Attachment::make_routing_path(&path)),
+        );
+        let mut buf = WBuf::new(164, false);
+        buf.write_zenoh_message(&msg);
+        let result = RBuf::from(&buf)
+            .read_zenoh_message(Reliability::Reliable)
+            .unwrap();
+        match &result.body {
+            ZenohBody::Data(Data { data_info, .. }) => {
+                assert_eq!(data_info, &Some(legacy_info))
+            }
+            _ => panic!("Unexpected message {:?}", result),
+        }
+        let attachment = result.attachment.unwrap();
+        assert_eq!(attachment.encoding, zmsg::attachment::ROUTING_PATH);
+        assert_eq!(attachment.read_routing_path(), Some(path));
+
+        // The other attachments carry no routing path
+        assert_eq!(gen_attachment().read_routing_path(), None);
+    }
+}
+
 #[test]
 fn codec_unit() {
     for _ in 0..NUM_ITER {
@@ -11,7 +11,28 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-use zenoh::net::protocol::core::rname::intersect;
+use proptest::prelude::*;
+use proptest::sample::{select, Index};
+use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+use zenoh::net::protocol::core::rname::{include, intersect};
+
+const PROPERTY_RUNS: u32 = 10_000;
+
+/// The environment variable giving the seed of `rname_properties_test`, to
+/// replay a failed run.
+const SEED_VAR: &str = "RNAME_TEST_SEED";
+
+fn key_from(chunks: &'static [&'static str]) -> impl Strategy<Value = String> {
+    prop::collection::vec(select(chunks), 1..5).prop_map(|chunks| format!("/{}", chunks.join("/")))
+}
+
+fn key() -> impl Strategy<Value = String> {
+    key_from(&["a", "b", "ab", "ba", "abc"])
+}
+
+fn key_expr() -> impl Strategy<Value = String> {
+    key_from(&["a", "b", "ab", "abc", "*", "a*", "*b", "a*c", "**"])
+}
 
 #[test]
 fn rname_test() {
@@ -64,3 +85,80 @@ fn rname_test() {
     assert!(!intersect("/x/c*", "/x/abc*"));
     assert!(!intersect("/x/*d", "/x/*e"));
 }
+
+#[test]
+fn rname_properties_test() {
+    let seed = match std::env::var(SEED_VAR) {
+        Ok(seed) => seed.parse().expect("Invalid seed"),
+        Err(_) => rand::random::<u64>(),
+    };
+    let mut seed_bytes = [0u8; 32];
+    seed_bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    let mut runner = TestRunner::new_with_rng(
+        Config::with_cases(PROPERTY_RUNS),
+        TestRng::from_seed(RngAlgorithm::ChaCha, &seed_bytes),
+    );
+    let strategy = (
+        key(),
+        key_expr(),
+        key_expr(),
+        any::<Index>(),
+        any::<Index>(),
+    );
+    let result = runner.run(&strategy, |(key, expr1, expr2, idx, end)| {
+        // A concrete key matches `/**`.
+        prop_assert!(intersect("/**", &key), "/** !~ {}", key);
+        prop_assert!(include("/**", &key), "/** !> {}", key);
+
+        // Matching is reflexive.
+        prop_assert!(intersect(&key, &key), "{} !~ {}", key, key);
+        prop_assert!(intersect(&expr1, &expr1), "{} !~ {}", expr1, expr1);
+        prop_assert!(include(&expr1, &expr1), "{} !> {}", expr1, expr1);
+
+        // Matching is symmetric.
+        prop_assert_eq!(
+            intersect(&expr1, &expr2),
+            intersect(&expr2, &expr1),
+            "{} ~ {}",
+            expr1,
+            expr2
+        );
+
+        // A key expression including another one intersects it, and on a
+        // concrete key both relations are the same.
+        if include(&expr1, &expr2) {
+            prop_assert!(intersect(&expr1, &expr2), "{} > {}", expr1, expr2);
+        }
+        prop_assert_eq!(
+            include(&expr1, &key),
+            intersect(&expr1, &key),
+            "{} ~ {}",
+            expr1,
+            key
+        );
+
+        // Replacing chunks of a key by `*` or `**` gives more general key
+        // expressions that include it.
+        let chunks: Vec<&str> = key[1..].split('/').collect();
+        let idx = idx.index(chunks.len());
+        let mut star = chunks.clone();
+        star[idx] = "*";
+        let star = format!("/{}", star.join("/"));
+        prop_assert!(include(&star, &key), "{} !> {}", star, key);
+        let end = idx + end.index(chunks.len() - idx);
+        let dstar = [&chunks[..idx], &["**"], &chunks[end + 1..]].concat();
+        let dstar = format!("/{}", dstar.join("/"));
+        prop_assert!(include(&dstar, &key), "{} !> {}", dstar, key);
+        prop_assert!(include(&dstar, &star), "{} !> {}", dstar, star);
+
+        // A key expression matching a more specific one matches the
+        // keys it matches.
+        if include(&expr1, &expr2) && intersect(&expr2, &key) {
+            prop_assert!(intersect(&expr1, &key), "{} > {} ~ {}", expr1, expr2, key);
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        panic!("{} (replay with {}={})", err, SEED_VAR, seed);
+    }
+}
@@ -11,19 +11,23 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-use async_std::sync::Arc;
+mod common;
+
+use async_std::sync::{Arc, Weak};
 use async_std::task;
-use async_trait::async_trait;
+use common::*;
 use std::convert::TryInto;
+use std::time::Duration;
 use uhlc::HLC;
 use zenoh::net::protocol::core::rname::intersect;
 use zenoh::net::protocol::core::{
-    whatami, CongestionControl, PeerId, QueryConsolidation, QueryTarget, Reliability, ResKey,
-    SubInfo, SubMode, ZInt,
+    whatami, CongestionControl, PeerId, Reliability, ResKey, SubInfo, SubMode, ZInt,
 };
 use zenoh::net::protocol::io::RBuf;
-use zenoh::net::protocol::proto::{DataInfo, RoutingContext};
-use zenoh::net::protocol::session::{DummyPrimitives, Primitives};
+use zenoh::net::protocol::session::DummyPrimitives;
+use zenoh::net::routing::face::FaceState;
+use zenoh::net::routing::partition::PartitionedTables;
+use zenoh::net::routing::route_cache::EvictionPolicy;
 use zenoh::net::routing::router::*;
 use zenoh::net::routing::OutSession;
 
@@ -72,7 +76,8 @@ fn base_test() {
             "/four/five",
             &sub_info,
         )
-        .await;
+        .await
+        .unwrap();
 
         Tables::print(&tables).await;
     });
@@ -136,11 +141,7 @@ fn match_test() {
             "/x/*e",
         ];
 
-        let mut tables = Tables::new(
-            PeerId::new(0, [0; 16]),
-            whatami::CLIENT,
-            Some(HLC::default()),
-        );
+        let mut tables = client_tables();
         let primitives = Arc::new(DummyPrimitives::new());
         let face = tables
             .open_face(
@@ -167,7 +168,10 @@ fn match_test() {
                 .map(|m| m.upgrade().unwrap().name())
                 .collect();
             for rname2 in rnames.iter() {
-                if matches.contains(&String::from(*rname2)) {
+                let name2 = Resource::get_resource(&tables._get_root(), rname2)
+                    .unwrap()
+                    .name();
+                if matches.contains(&name2) {
                     assert!(intersect(rname1, rname2));
                 } else {
                     assert!(!intersect(rname1, rname2));
@@ -254,10 +258,9 @@ fn clean_test() {
             period: None,
         };
 
-        declare_client_subscription(
+        declare_sub(
             &mut tables,
             &mut face0.upgrade().unwrap(),
-            0,
             "/todrop1/todrop11",
             &sub_info,
         )
@@ -275,7 +278,8 @@ fn clean_test() {
             "/todrop12",
             &sub_info,
         )
-        .await;
+        .await
+        .unwrap();
         let optres3 = Resource::get_resource(&tables._get_root(), "/todrop1/todrop12")
             .map(|res| Arc::downgrade(&res));
         assert!(optres3.is_some());
@@ -306,10 +310,9 @@ fn clean_test() {
 
         // --------------
         declare_resource(&mut tables, &mut face0.upgrade().unwrap(), 2, 0, "/todrop3").await;
-        declare_client_subscription(
+        declare_sub(
             &mut tables,
             &mut face0.upgrade().unwrap(),
-            0,
             "/todrop3",
             &sub_info,
         )
@@ -329,18 +332,16 @@ fn clean_test() {
         // --------------
         declare_resource(&mut tables, &mut face0.upgrade().unwrap(), 3, 0, "/todrop4").await;
         declare_resource(&mut tables, &mut face0.upgrade().unwrap(), 4, 0, "/todrop5").await;
-        declare_client_subscription(
+        declare_sub(
             &mut tables,
             &mut face0.upgrade().unwrap(),
-            0,
             "/todrop5",
             &sub_info,
         )
         .await;
-        declare_client_subscription(
+        declare_sub(
             &mut tables,
             &mut face0.upgrade().unwrap(),
-            0,
             "/todrop6",
             &sub_info,
         )
@@ -371,125 +372,6 @@ fn clean_test() {
     });
 }
 
-pub struct ClientPrimitives {
-    data: std::sync::Mutex<Option<ResKey>>,
-    mapping: std::sync::Mutex<std::collections::HashMap<ZInt, String>>,
-}
-
-impl ClientPrimitives {
-    pub fn new() -> ClientPrimitives {
-        ClientPrimitives {
-            data: std::sync::Mutex::new(None),
-            mapping: std::sync::Mutex::new(std::collections::HashMap::new()),
-        }
-    }
-
-    pub fn clear_data(&self) {
-        *self.data.lock().unwrap() = None;
-    }
-}
-
-impl Default for ClientPrimitives {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl ClientPrimitives {
-    fn get_name(&self, reskey: &ResKey) -> String {
-        let mapping = self.mapping.lock().unwrap();
-        match reskey {
-            ResKey::RName(name) => name.clone(),
-            ResKey::RId(id) => mapping.get(id).unwrap().clone(),
-            ResKey::RIdWithSuffix(id, suffix) => {
-                [&mapping.get(id).unwrap()[..], &suffix[..]].concat()
-            }
-        }
-    }
-
-    fn get_last_name(&self) -> Option<String> {
-        self.data
-            .lock()
-            .unwrap()
-            .as_ref()
-            .map(|data| self.get_name(&data))
-    }
-
-    #[allow(dead_code)]
-    fn get_last_key(&self) -> Option<ResKey> {
-        self.data.lock().unwrap().as_ref().map(|data| data.clone())
-    }
-}
-
-#[async_trait]
-impl Primitives for ClientPrimitives {
-    async fn decl_resource(&self, rid: ZInt, reskey: &ResKey) {
-        let name = self.get_name(reskey);
-        self.mapping.lock().unwrap().insert(rid, name);
-    }
-    async fn forget_resource(&self, rid: ZInt) {
-        self.mapping.lock().unwrap().remove(&rid);
-    }
-
-    async fn decl_publisher(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
-    async fn forget_publisher(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
-
-    async fn decl_subscriber(
-        &self,
-        _reskey: &ResKey,
-        _sub_info: &SubInfo,
-        _routing_context: Option<RoutingContext>,
-    ) {
-    }
-    async fn forget_subscriber(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
-
-    async fn decl_queryable(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
-    async fn forget_queryable(&self, _reskey: &ResKey, _routing_context: Option<RoutingContext>) {}
-
-    async fn send_data(
-        &self,
-        reskey: &ResKey,
-        _payload: RBuf,
-        _reliability: Reliability,
-        _congestion_control: CongestionControl,
-        _info: Option<DataInfo>,
-        _routing_context: Option<RoutingContext>,
-    ) {
-        *self.data.lock().unwrap() = Some(reskey.clone());
-    }
-    async fn send_query(
-        &self,
-        _reskey: &ResKey,
-        _predicate: &str,
-        _qid: ZInt,
-        _target: QueryTarget,
-        _consolidation: QueryConsolidation,
-        _routing_context: Option<RoutingContext>,
-    ) {
-    }
-    async fn send_reply_data(
-        &self,
-        _qid: ZInt,
-        _source_kind: ZInt,
-        _replier_id: PeerId,
-        _reskey: ResKey,
-        _info: Option<DataInfo>,
-        _payload: RBuf,
-    ) {
-    }
-    async fn send_reply_final(&self, _qid: ZInt) {}
-    async fn send_pull(
-        &self,
-        _is_final: bool,
-        _reskey: &ResKey,
-        _pull_id: ZInt,
-        _max_samples: &Option<ZInt>,
-    ) {
-    }
-
-    async fn send_close(&self) {}
-}
-
 #[test]
 fn client_test() {
     task::block_on(async {
@@ -530,7 +412,8 @@ fn client_test() {
             "/**",
             &sub_info,
         )
-        .await;
+        .await
+        .unwrap();
         declare_resource(
             &mut tables,
             &mut face0.upgrade().unwrap(),
@@ -569,7 +452,8 @@ fn client_test() {
             "/**",
             &sub_info,
         )
-        .await;
+        .await
+        .unwrap();
         declare_resource(
             &mut tables,
             &mut face1.upgrade().unwrap(),
@@ -608,7 +492,8 @@ fn client_test() {
             "/**",
             &sub_info,
         )
-        .await;
+        .await
+        .unwrap();
 
         primitives0.clear_data();
         primitives1.clear_data();
@@ -746,3 +631,1731 @@ fn client_test() {
         // assert_eq!(primitives2.get_last_key().unwrap(), ResKey::RIdWithSuffix(31, "/z2_pub1".to_string()));
     });
 }
+
+#[test]
+fn interner_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let primitives = Arc::new(DummyPrimitives::new());
+        let face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(primitives),
+            )
+            .await;
+
+        // 100 keys made of 300 chunks, only 11 of them distinct, and the 110
+        // other names of the resources.
+        for i in 0..100 {
+            let key = format!("/test/{}/{}", i / 10, i % 10);
+            declare_resource(&mut tables, &mut face.upgrade().unwrap(), i + 1, 0, &key).await;
+        }
+        assert_eq!(tables.interner().len(), 11 + 110);
+        assert!(Arc::ptr_eq(
+            &tables.interner().get("/5").unwrap(),
+            &tables.interner().get(&String::from("/5")).unwrap()
+        ));
+        assert!(tables.interner().get("/test/5/5").is_some());
+        assert_eq!(
+            tables
+                .print()
+                .await
+                .lines()
+                .filter(|l| l.matches('/').count() == 3)
+                .count(),
+            100
+        );
+
+        // The strings of cleaned resources are released once the interner
+        // doubled in size since the last purge.
+        let face2 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(DummyPrimitives::new())),
+            )
+            .await;
+        declare_resource(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            1,
+            0,
+            "/other/key",
+        )
+        .await;
+        assert_eq!(tables.interner().len(), 11 + 110 + 3);
+        tables.close_face(&face2).await;
+        assert_eq!(tables.interner().len(), 11 + 110 + 3);
+
+        let face3 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(DummyPrimitives::new())),
+            )
+            .await;
+        for i in 0..1500 {
+            let key = format!("/other/{}", i);
+            declare_resource(&mut tables, &mut face3.upgrade().unwrap(), i + 1, 0, &key).await;
+        }
+        tables.close_face(&face3).await;
+        assert_eq!(tables.interner().len(), 11 + 110);
+    });
+}
+
+#[test]
+fn tenant_isolation_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let mut faces = vec![];
+        let mut primitives = vec![];
+        for tenant in &["a", "a", "b", "b", "shared"] {
+            let (face, prim) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+            tables.set_face_tenant(&face, Some(tenant.to_string()));
+            faces.push(face);
+            primitives.push(prim);
+        }
+        tables.set_shared_tenants(vec!["shared".to_string()].into_iter().collect());
+        // Overlapping subscriptions of the subscribers of both tenants.
+        for (face, expr) in &[(1, "/test/**"), (3, "/test/*"), (4, "/**")] {
+            declare_sub(
+                &mut tables,
+                &mut faces[*face].upgrade().unwrap(),
+                expr,
+                &sub_info,
+            )
+            .await;
+        }
+
+        macro_rules! publish {
+            ($face:expr) => {
+                route_data(
+                    &tables,
+                    &faces[$face].upgrade().unwrap(),
+                    0,
+                    "/test/key",
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![$face as u8]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        publish!(0);
+        publish!(2);
+        let payloads = |idx: usize| -> Vec<Vec<u8>> {
+            primitives[idx]
+                .get_payloads()
+                .iter()
+                .map(|payload| payload.to_vec())
+                .collect()
+        };
+        assert_eq!(payloads(1), vec![vec![0]]);
+        assert_eq!(payloads(3), vec![vec![2]]);
+        assert_eq!(payloads(4), vec![vec![0], vec![2]]);
+
+        // The data published by a shared tenant reach all the tenants.
+        publish!(4);
+        assert_eq!(payloads(1), vec![vec![0], vec![4]]);
+        assert_eq!(payloads(3), vec![vec![2], vec![4]]);
+
+        let route = get_route(&tables, &faces[0].upgrade().unwrap(), 0, "/test/key", None).unwrap();
+        let face_ids = |idxs: &[usize]| -> Vec<usize> {
+            idxs.iter()
+                .map(|idx| faces[*idx].upgrade().unwrap().id())
+                .collect()
+        };
+        let mut route_ids: Vec<usize> = route.keys().cloned().collect();
+        route_ids.sort_unstable();
+        assert_eq!(route_ids, face_ids(&[1, 4]));
+    });
+}
+
+#[cfg(feature = "otel")]
+#[test]
+fn otel_metrics_test() {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::sdk::export::metrics::{CheckpointSet, ExportKindSelector};
+    use opentelemetry::sdk::metrics::{controllers, selectors};
+
+    task::block_on(async {
+        let mut tables = client_tables();
+        let mut controller = controllers::pull(
+            Box::new(selectors::simple::Selector::Exact),
+            Box::new(ExportKindSelector::Cumulative),
+        )
+        .with_memory(true)
+        .build();
+        tables.register_metrics(&controller.provider().meter("zenoh", None));
+
+        let face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        route_data(
+            &tables,
+            &face.upgrade().unwrap(),
+            0,
+            "/test/otel",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+        assert_eq!(tables.metrics().unregistered_data_routes(), 1);
+
+        controller.collect().unwrap();
+        let mut names = vec![];
+        controller
+            .try_for_each(&ExportKindSelector::Cumulative, &mut |record| {
+                names.push(record.descriptor().name().to_string());
+                Ok(())
+            })
+            .unwrap();
+        for (counter, _) in tables.metrics().counters() {
+            assert!(names.contains(&format!("zenoh.routing.{}", counter)));
+        }
+        assert!(names.contains(&"zenoh.routing.router_sub_fan_out".to_string()));
+        assert!(names.contains(&"zenoh.routing.peer_sub_fan_out".to_string()));
+    });
+}
+
+#[test]
+fn publishers_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.track_publishers(Some(Duration::from_millis(200)));
+        let mut pub_faces = vec![];
+        for i in 1..3 {
+            pub_faces.push(
+                tables
+                    .open_face(
+                        PeerId::new(i, [i as u8; 16]),
+                        whatami::CLIENT,
+                        OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                    )
+                    .await,
+            );
+        }
+        let before = std::time::Instant::now();
+        for face in &pub_faces {
+            route_data(
+                &tables,
+                &face.upgrade().unwrap(),
+                0,
+                "/test/pub",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+
+        let publishers = tables.publishers("/test/pub");
+        assert_eq!(publishers.len(), 2);
+        for i in 1..3 {
+            let (_, seen) = publishers
+                .iter()
+                .find(|(pid, _)| *pid == PeerId::new(i, [i as u8; 16]))
+                .unwrap();
+            assert!(*seen >= before);
+        }
+        assert_eq!(tables.publishers("/test/*").len(), 2);
+        assert!(tables.publishers("/other").is_empty());
+
+        // the publishers age out
+        task::sleep(Duration::from_millis(300)).await;
+        assert!(tables.publishers("/test/pub").is_empty());
+    });
+}
+
+#[test]
+fn compact_face_mappings_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let mut faces = vec![];
+        for i in 0..3 {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(i, [0; 16])).await;
+            faces.push((face, primitives));
+        }
+        let (observer, observer_primitives) = &faces[1];
+        declare_sub(
+            &mut tables,
+            &mut observer.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+
+        // the subscriptions of the first face are declared to the observer
+        let churner = &mut faces[0].0.upgrade().unwrap();
+        for i in 0..10 {
+            let key = format!("/test/{}", i);
+            declare_sub(&mut tables, churner, &key, &sub_info).await;
+            forget_client_subscription(&mut tables, churner, 0, &key).await;
+        }
+        declare_sub(&mut tables, churner, "/test/live", &sub_info).await;
+        assert_eq!(observer_primitives.get_mapped_names().len(), 11);
+
+        let observer_id = observer.upgrade().unwrap().id();
+        assert_eq!(tables.compact_face_mappings(observer_id).await, 10);
+        assert_eq!(
+            observer_primitives.get_mapped_names(),
+            vec!["/test/live".to_string()]
+        );
+        assert_eq!(tables.compact_face_mappings(observer_id).await, 0);
+
+        // the routes no longer use the forgotten mappings
+        for key in &["/test/live", "/test/3"] {
+            route_data(
+                &tables,
+                &faces[2].0.upgrade().unwrap(),
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+            assert_eq!(observer_primitives.get_last_name(), Some(key.to_string()));
+        }
+    });
+}
+
+#[test]
+fn list_keys_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let (mut face, _) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        for (rid, name) in ["/a/b", "/a/c", "/d"].iter().enumerate() {
+            declare_resource(&mut tables, &mut face, rid as ZInt + 1, 0, name).await;
+        }
+
+        assert_eq!(tables.list_keys(None), vec!["/a", "/a/b", "/a/c", "/d"]);
+        assert_eq!(tables.list_keys(Some("/a")), vec!["/a", "/a/b", "/a/c"]);
+        assert_eq!(tables.list_keys(Some("/a/c")), vec!["/a/c"]);
+        assert!(tables.list_keys(Some("/x")).is_empty());
+        assert_eq!(tables.iter_keys(None).count(), 4);
+    });
+}
+
+#[test]
+fn face_whatami_changed_test() {
+    task::block_on(async {
+        let mut tables = Tables::new(PeerId::new(0, [0; 16]), whatami::CLIENT, None);
+        let sub_info = reliable_push();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::PEER).await;
+        let (mut face1, primitives1) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::PEER).await;
+        let (face2, primitives2) =
+            open_recording_face(&mut tables, PeerId::new(3, [0; 16]), whatami::CLIENT).await;
+        declare_sub(&mut tables, &mut face0, "/test/a", &sub_info).await;
+        declare_sub(&mut tables, &mut face1, "/test/b", &sub_info).await;
+        let names = |primitives: &RecordingPrimitives| -> Vec<String> {
+            primitives
+                .subscribers()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        // Only the client gets the subscriptions of the peers
+        assert!(primitives0.subscribers().is_empty());
+        assert!(primitives1.subscribers().is_empty());
+        assert_eq!(
+            names(&primitives2),
+            vec!["/test/a".to_string(), "/test/b".to_string()]
+        );
+
+        // A client gets the subscriptions of all the faces and its own ones
+        // are propagated to all the faces
+        tables
+            .on_face_whatami_changed(face0.id(), whatami::CLIENT)
+            .await;
+        assert_eq!(names(&primitives0), vec!["/test/b".to_string()]);
+        assert_eq!(names(&primitives1), vec!["/test/a".to_string()]);
+        assert_eq!(names(&primitives2).len(), 2);
+
+        // The promoted client no longer gets the subscriptions of the peers
+        tables
+            .on_face_whatami_changed(face2.id(), whatami::PEER)
+            .await;
+        assert_eq!(
+            primitives2.forgotten_subscribers(),
+            vec!["/test/b".to_string()]
+        );
+        assert!(primitives0.forgotten_subscribers().is_empty());
+        assert!(primitives1.forgotten_subscribers().is_empty());
+
+        // Unchanged or unknown faces are left as is
+        tables
+            .on_face_whatami_changed(face2.id(), whatami::PEER)
+            .await;
+        tables.on_face_whatami_changed(42, whatami::PEER).await;
+        assert_eq!(primitives2.records().len(), 3);
+
+        route_data(
+            &tables,
+            &face2,
+            0,
+            "/test/a",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0u8]),
+            None,
+        )
+        .await;
+        assert_eq!(primitives0.data().len(), 1);
+        assert!(primitives1.data().is_empty());
+    });
+}
+
+/// A logger recording the logs of the traced resources.
+struct TraceLogger(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl log::Log for TraceLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == TRACE_TARGET
+    }
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+    fn flush(&self) {}
+}
+
+#[test]
+fn trace_resource_test() {
+    task::block_on(async {
+        // The maximum log level is left to Off
+        let logs = Arc::new(std::sync::Mutex::new(vec![]));
+        log::set_boxed_logger(Box::new(TraceLogger(logs.clone()))).unwrap();
+        let traced_logs = || -> Vec<String> {
+            logs.lock()
+                .unwrap()
+                .iter()
+                .filter(|log| log.contains("/test/traced") || log.contains("/test/quiet"))
+                .cloned()
+                .collect()
+        };
+
+        let mut tables = Tables::new(PeerId::new(0, [0; 16]), whatami::CLIENT, None);
+        let sub_info = reliable_push();
+        let (mut face0, _) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::PEER).await;
+        let (face1, _) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::PEER).await;
+        tables.trace_resource("/test/traced", true);
+        // Tracing twice is harmless
+        tables.trace_resource("/test/traced", true);
+
+        for key in &["/test/traced", "/test/quiet"] {
+            declare_sub(&mut tables, &mut face0, key, &sub_info).await;
+            route_data(
+                &tables,
+                &face1,
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8]),
+                None,
+            )
+            .await;
+            pull_data(&mut tables, &face0, true, 0, key, 0, &None).await;
+            forget_client_subscription(&mut tables, &mut face0, 0, key).await;
+        }
+        let logs0 = traced_logs();
+        assert_eq!(logs0.len(), 4);
+        assert!(logs0.iter().all(|log| log.contains("/test/traced")));
+        assert!(logs0[0].starts_with("Declare subscription /test/traced"));
+        assert!(logs0[1].starts_with("Route data on /test/traced"));
+        assert!(logs0[1].ends_with("to 1 faces and 0 pull subscriptions"));
+        assert!(logs0[2].starts_with("Pull data on /test/traced"));
+        assert!(logs0[3].starts_with("Undeclare subscription /test/traced"));
+
+        declare_sub(&mut tables, &mut face0, "/test/traced", &sub_info).await;
+        tables.trace_resource("/test/traced", false);
+        route_data(
+            &tables,
+            &face1,
+            0,
+            "/test/traced",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0u8]),
+            None,
+        )
+        .await;
+        assert_eq!(traced_logs().len(), 5);
+    });
+}
+
+#[test]
+fn empty_route_close_face_test() {
+    task::block_on(async {
+        let mut tables = Tables::new(PeerId::new(0, [0; 16]), whatami::CLIENT, None);
+        tables.set_empty_route(EmptyRoute::CloseFace(3));
+        let sub_info = reliable_push();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::PEER).await;
+        declare_sub(&mut tables, &mut face0, "/test/sub", &sub_info).await;
+
+        let primitives = Arc::new(ClientPrimitives::new());
+        let face1 = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(primitives.clone()),
+            )
+            .await
+            .upgrade()
+            .unwrap();
+        macro_rules! publish {
+            ($key:expr) => {
+                route_data(
+                    &tables,
+                    &face1,
+                    0,
+                    $key,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0u8]),
+                    None,
+                )
+                .await
+            };
+        }
+
+        publish!("/test/void");
+        publish!("/test/void");
+        // Only the data matching no subscription count
+        for _ in 0..5 {
+            publish!("/test/sub");
+        }
+        assert_eq!(primitives0.data().len(), 5);
+        assert!(!primitives.is_closed());
+        publish!("/test/other");
+        assert!(primitives.is_closed());
+    });
+}
+
+#[test]
+fn reserve_route_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+
+        assert_eq!(tables.reserve_route("/test/reserved"), 0);
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+        assert_eq!(tables.reserve_route("/test/reserved"), 1);
+
+        macro_rules! publish {
+            ($suffix:expr) => {
+                route_data(
+                    &tables,
+                    &face1.upgrade().unwrap(),
+                    0,
+                    $suffix,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        publish!("/test/reserved");
+        assert_eq!(primitives0.get_last_name().unwrap(), "/test/reserved");
+        assert_eq!(tables.metrics().unregistered_data_routes(), 0);
+
+        tables.release_route("/test/reserved");
+        primitives0.clear_data();
+        publish!("/test/reserved");
+        assert_eq!(primitives0.get_last_name().unwrap(), "/test/reserved");
+        assert_eq!(tables.metrics().unregistered_data_routes(), 1);
+    });
+}
+
+#[test]
+fn fallback_key_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let face0 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_resource(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            11,
+            0,
+            "/test/client",
+        )
+        .await;
+
+        // No mapping on the subscriber face
+        let (face1, primitives1) = open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            "/test/client/**",
+            &sub_info,
+        )
+        .await;
+
+        // A mapping declared by the subscriber face
+        let (face2, primitives2) = open_client_face(&mut tables, PeerId::new(2, [2; 16])).await;
+        declare_resource(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            21,
+            0,
+            "/test/client",
+        )
+        .await;
+        declare_client_subscription(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            21,
+            "/**",
+            &sub_info,
+        )
+        .await
+        .unwrap();
+
+        route_data(
+            &tables,
+            &face0.upgrade().unwrap(),
+            11,
+            "/z1",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+        assert_eq!(
+            primitives1.get_last_key().unwrap(),
+            ResKey::RName("/test/client/z1".to_string())
+        );
+        assert_eq!(
+            primitives2.get_last_key().unwrap(),
+            ResKey::RIdWithSuffix(21, "/z1".to_string())
+        );
+    });
+}
+
+#[test]
+fn prefetch_routes_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let pub_pid = PeerId::new(1, [1; 16]);
+        tables.set_known_publications(pub_pid.clone(), vec!["/test/prefetched".to_string()]);
+
+        let face1 = tables
+            .open_face(
+                pub_pid,
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+
+        macro_rules! publish {
+            ($suffix:expr) => {
+                route_data(
+                    &tables,
+                    &face1.upgrade().unwrap(),
+                    0,
+                    $suffix,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        publish!("/test/prefetched");
+        assert_eq!(primitives0.get_last_name().unwrap(), "/test/prefetched");
+        assert_eq!(tables.metrics().unregistered_data_routes(), 0);
+
+        publish!("/test/other");
+        assert_eq!(primitives0.get_last_name().unwrap(), "/test/other");
+        assert_eq!(tables.metrics().unregistered_data_routes(), 1);
+    });
+}
+
+#[test]
+fn normalization_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let (face1, primitives1) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_resource(&mut tables, &mut face0.upgrade().unwrap(), 1, 0, "/test/").await;
+        declare_client_subscription(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            1,
+            "/norm",
+            &sub_info,
+        )
+        .await
+        .unwrap();
+        declare_sub(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            "/test//norm",
+            &sub_info,
+        )
+        .await;
+
+        let res = Resource::get_resource(&tables._get_root(), "/test/norm").unwrap();
+        assert_eq!(res.name(), "/test/norm");
+        for key in &["//test/norm", "/test///norm"] {
+            let other = Resource::get_resource(&tables._get_root(), key).unwrap();
+            assert!(Arc::ptr_eq(&res, &other));
+        }
+        let prefix = Resource::get_resource(&tables._get_root(), "/test/").unwrap();
+        assert!(Arc::ptr_eq(
+            &res,
+            &Resource::get_resource(&prefix, "/norm").unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            &prefix,
+            &Resource::get_resource(&tables._get_root(), "/test").unwrap()
+        ));
+        assert_eq!(prefix.name(), "/test");
+
+        route_data(
+            &tables,
+            &face2.upgrade().unwrap(),
+            0,
+            "/test//norm",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+        assert_eq!(primitives0.get_payloads().len(), 1);
+        assert_eq!(primitives1.get_payloads().len(), 1);
+    });
+}
+
+#[test]
+fn matches_properties_test() {
+    use rand::seq::SliceRandom;
+    use rand::*;
+
+    fn gen_key_expr() -> String {
+        let chunks = ["a", "b", "ab", "abc", "*", "a*", "*b", "**"];
+        let len = thread_rng().gen_range(1..4);
+        let mut key = String::new();
+        for _ in 0..len {
+            key.push('/');
+            key.push_str(chunks.choose(&mut thread_rng()).unwrap());
+        }
+        key
+    }
+
+    task::block_on(async {
+        for _ in 0..200 {
+            let mut tables = client_tables();
+            let face = tables
+                .open_face(
+                    PeerId::new(0, [0; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(DummyPrimitives::new())),
+                )
+                .await;
+            let mut rnames: Vec<String> = (0..20).map(|_| gen_key_expr()).collect();
+            rnames.sort();
+            rnames.dedup();
+            for (i, rname) in rnames.iter().enumerate() {
+                declare_resource(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    (i + 1).try_into().unwrap(),
+                    0,
+                    rname,
+                )
+                .await;
+            }
+
+            for _ in 0..20 {
+                let expr = gen_key_expr();
+                let mut matches: Vec<String> = Resource::get_matches_in(&tables._get_root(), &expr)
+                    .iter()
+                    .map(|m| m.upgrade().unwrap().name())
+                    .collect();
+                matches.sort();
+                let mut expected: Vec<String> = rnames
+                    .iter()
+                    .filter(|rname| intersect(&expr, rname))
+                    .cloned()
+                    .collect();
+                expected.sort();
+                assert_eq!(matches, expected, "matches of {} in {:?}", expr, rnames);
+            }
+        }
+    });
+}
+
+#[test]
+fn route_cache_eviction_test() {
+    async fn hits(policy: EvictionPolicy, pattern: &[&str]) -> usize {
+        let mut tables = client_tables();
+        tables.set_route_cache(2, policy);
+        let sub_info = reliable_push();
+        let sub_face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut sub_face.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+        let pub_face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for _ in 0..50 {
+            for key in pattern {
+                route_data(
+                    &tables,
+                    &pub_face.upgrade().unwrap(),
+                    0,
+                    key,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0]),
+                    None,
+                )
+                .await;
+            }
+        }
+        assert_eq!(
+            tables.metrics().route_cache_hits() + tables.metrics().unregistered_data_routes(),
+            50 * pattern.len()
+        );
+        tables.metrics().route_cache_hits()
+    }
+
+    task::block_on(async {
+        // A hot key among cold ones: LRU keeps the hot key cached, FIFO
+        // periodically evicts it.
+        let hot = ["/test/a", "/test/b", "/test/a", "/test/c"];
+        let lru = hits(EvictionPolicy::Lru, &hot).await;
+        let fifo = hits(EvictionPolicy::Fifo, &hot).await;
+        assert!(lru > fifo, "lru: {}, fifo: {}", lru, fifo);
+
+        // A scan larger than the cache: LRU and FIFO always evict the next
+        // key accessed, random eviction doesn't.
+        let scan = ["/test/a", "/test/b", "/test/c"];
+        assert_eq!(hits(EvictionPolicy::Lru, &scan).await, 0);
+        assert_eq!(hits(EvictionPolicy::Fifo, &scan).await, 0);
+        assert!(hits(EvictionPolicy::Random, &scan).await > 0);
+    });
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn top_keys_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for key in &["/test/hot", "/test/warm", "/test/cold", "/test/idle"] {
+            declare_sub(
+                &mut tables,
+                &mut sub_face.upgrade().unwrap(),
+                key,
+                &reliable_push(),
+            )
+            .await;
+        }
+
+        let pub_face = pub_face.upgrade().unwrap();
+        for (key, count) in &[("/test/cold", 1), ("/test/hot", 10), ("/test/warm", 4)] {
+            for _ in 0..*count {
+                route_data(
+                    &tables,
+                    &pub_face,
+                    0,
+                    key,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0u8; 8]),
+                    None,
+                )
+                .await;
+            }
+        }
+
+        let top = tables.top_keys(2);
+        assert_eq!(
+            top.iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["/test/hot", "/test/warm"]
+        );
+        assert_eq!(top[0].1.publications, 10);
+        assert_eq!(top[0].1.bytes, 80);
+        assert_eq!(tables.top_keys(10).len(), 3);
+    });
+}
+
+#[test]
+fn dump_resource_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let pull_info = SubInfo {
+            reliability: Reliability::Reliable,
+            mode: SubMode::Pull,
+            period: None,
+        };
+        let push_info = SubInfo {
+            reliability: Reliability::BestEffort,
+            mode: SubMode::Push,
+            period: None,
+        };
+
+        let mut faces = vec![];
+        for i in 0..3 {
+            faces.push(
+                tables
+                    .open_face(
+                        PeerId::new(i + 1, [i as u8 + 1; 16]),
+                        whatami::CLIENT,
+                        OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                    )
+                    .await
+                    .upgrade()
+                    .unwrap(),
+            );
+        }
+        declare_resource(&mut tables, &mut faces[0], 1, 0, "/test/dump").await;
+        declare_client_subscription(&mut tables, &mut faces[0], 1, "", &pull_info)
+            .await
+            .unwrap();
+        declare_sub(&mut tables, &mut faces[1], "/test/*", &push_info).await;
+        for key in &["/test/dump", "/test/dump"] {
+            route_data(
+                &tables,
+                &faces[2],
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8; 4]),
+                None,
+            )
+            .await;
+        }
+
+        assert!(tables.dump_resource("/test/none").is_none());
+        let dump = tables.dump_resource("/test/dump").unwrap();
+        assert_eq!(dump.name, "/test/dump");
+        assert!(dump.router_subs.is_empty());
+        assert!(dump.peer_subs.is_empty());
+        assert_eq!(
+            dump.contexts,
+            vec![SessionContextDump {
+                face_id: faces[0].id(),
+                local_rid: None,
+                remote_rid: Some(1),
+                sub_info: Some(pull_info),
+                cached_keys: vec!["/test/dump".to_string()],
+            }]
+        );
+        assert_eq!(
+            dump.matches,
+            vec!["/test/*".to_string(), "/test/dump".to_string()]
+        );
+        assert_eq!(
+            dump.route
+                .unwrap()
+                .iter()
+                .map(|(face_id, _)| *face_id)
+                .collect::<Vec<usize>>(),
+            vec![faces[1].id()]
+        );
+
+        let dump = tables.dump_resource("/test/*").unwrap();
+        assert_eq!(dump.contexts.len(), 1);
+        assert_eq!(dump.contexts[0].sub_info, Some(push_info));
+    });
+}
+
+#[test]
+fn key_matcher_test() {
+    // Matches the versions of a service with a major version range, such as
+    // `/svc/api/1.4.0` with `/svc/api/^1`
+    struct MajorVersionMatcher;
+    impl KeyMatcher for MajorVersionMatcher {
+        fn intersect(&self, key_expr1: &str, key_expr2: &str) -> bool {
+            fn matches(range: &str, version: &str) -> bool {
+                match (range.rsplit_once('/'), version.rsplit_once('/')) {
+                    (Some((path1, range)), Some((path2, version))) => {
+                        path1 == path2
+                            && range.starts_with('^')
+                            && version.split('.').next() == Some(&range[1..])
+                    }
+                    _ => false,
+                }
+            }
+            key_expr1 == key_expr2 || matches(key_expr1, key_expr2) || matches(key_expr2, key_expr1)
+        }
+    }
+
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_key_matcher(Some(("/svc".to_string(), Arc::new(MajorVersionMatcher))));
+        let sub_info = reliable_push();
+
+        let (sub_face, sub_primitives) =
+            open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for key in &["/svc/api/^1", "/other/**"] {
+            declare_sub(
+                &mut tables,
+                &mut sub_face.upgrade().unwrap(),
+                key,
+                &sub_info,
+            )
+            .await;
+        }
+
+        let pub_face = pub_face.upgrade().unwrap();
+        for (key, value) in &[
+            ("/svc/api/1.4.0", 1),
+            ("/svc/api/2.0.0", 2),
+            ("/svc/api/**", 3),
+            ("/other/x", 4),
+        ] {
+            route_data(
+                &tables,
+                &pub_face,
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![*value]),
+                None,
+            )
+            .await;
+        }
+
+        assert!(!intersect("/svc/api/1.4.0", "/svc/api/^1"));
+        assert_eq!(
+            sub_primitives.get_payloads(),
+            vec![RBuf::from(vec![1]), RBuf::from(vec![4])]
+        );
+    });
+}
+
+#[test]
+fn rebuild_all_routes_test() {
+    task::block_on(async {
+        let sub_info = reliable_push();
+        let mut tables = client_tables();
+        for i in 0..4u8 {
+            let face = tables
+                .open_face(
+                    PeerId::new(1, [i; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                &format!("/test/warm/{}", i),
+                &sub_info,
+            )
+            .await;
+        }
+        let snapshot = tables.export_subscriptions();
+
+        let mut imported = client_tables();
+        let mut primitives = vec![];
+        for i in 0..4u8 {
+            let prims = Arc::new(ClientPrimitives::new());
+            imported
+                .open_face(
+                    PeerId::new(1, [i; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(prims.clone()),
+                )
+                .await;
+            primitives.push(prims);
+        }
+        let pub_face = imported
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        assert_eq!(imported.import_subscriptions(&snapshot).await, 4);
+
+        // The routes built by the import are rebuilt identically.
+        let built = imported.rebuild_all_routes();
+        assert!(built >= 4);
+        assert_eq!(imported.rebuild_all_routes(), built);
+
+        for i in 0..4 {
+            route_data(
+                &imported,
+                &pub_face.upgrade().unwrap(),
+                0,
+                &format!("/test/warm/{}", i),
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+            assert_eq!(
+                primitives[i].get_last_name(),
+                Some(format!("/test/warm/{}", i))
+            );
+        }
+        assert_eq!(imported.metrics().unregistered_data_routes(), 0);
+    });
+}
+
+#[test]
+fn rid_anomalies_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let face0 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let (face1, primitives) = open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(1, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_resource(&mut tables, &mut face0.upgrade().unwrap(), 5, 0, "/test/a").await;
+        declare_sub(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            "/test/**",
+            &reliable_push(),
+        )
+        .await;
+
+        async fn route(tables: &Tables, face: &Weak<FaceState>, rid: ZInt, suffix: &str) {
+            route_data(
+                tables,
+                &face.upgrade().unwrap(),
+                rid,
+                suffix,
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+
+        // A rid only declared by another face.
+        route(&tables, &face2, 5, "").await;
+        assert!(primitives.get_last_name().is_none());
+        assert_eq!(tables.metrics().foreign_rids(), 1);
+        assert_eq!(tables.metrics().unknown_rids(), 0);
+
+        // A rid declared by no face.
+        route(&tables, &face2, 7, "").await;
+        assert_eq!(tables.metrics().foreign_rids(), 1);
+        assert_eq!(tables.metrics().unknown_rids(), 1);
+
+        // A malformed key is dropped.
+        route(&tables, &face2, 0, "test/a").await;
+        assert!(primitives.get_last_name().is_none());
+        assert_eq!(tables.metrics().key_anomalies(), 1);
+
+        // An unexpected key is reported but still routed.
+        tables.set_expected_keys(Some(vec!["/other/**".to_string()]));
+        route(&tables, &face2, 0, "/test/b").await;
+        assert_eq!(primitives.get_last_name(), Some("/test/b".to_string()));
+        assert_eq!(tables.metrics().key_anomalies(), 2);
+        route(&tables, &face0, 5, "").await;
+        assert_eq!(tables.metrics().key_anomalies(), 3);
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn tables_stats_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let router = PeerId::new(1, [1; 16]);
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[router.clone()][..])],
+        )
+        .await;
+        tables.set_retain_latest(true);
+        assert_eq!(tables.stats().await, TablesStats::default());
+
+        let router_face = tables
+            .open_face(
+                router.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_router_subscription(
+            &mut tables,
+            &mut router_face.upgrade().unwrap(),
+            0,
+            "/test/stats/router",
+            &reliable_push(),
+            router,
+        )
+        .await
+        .unwrap();
+        for (i, (key, mode)) in [
+            ("/test/stats/push", SubMode::Push),
+            ("/test/stats/*", SubMode::Pull),
+            ("/test/stats/pull", SubMode::Pull),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let face = tables
+                .open_face(
+                    PeerId::new(2, [i as u8; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                key,
+                &SubInfo {
+                    reliability: Reliability::Reliable,
+                    mode: *mode,
+                    period: None,
+                },
+            )
+            .await;
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(3, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for (key, len) in &[("/test/stats/pull", 10), ("/test/stats/other", 20)] {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8; *len]),
+                None,
+            )
+            .await;
+        }
+
+        // Both pull subscriptions cache "/test/stats/pull", only the
+        // wildcard one caches "/test/stats/other".
+        let key_len = "/test/stats/pull".len();
+        assert_eq!(
+            tables.stats().await,
+            TablesStats {
+                push_subs: 1,
+                pull_subs: 2,
+                router_subs: 1,
+                peer_subs: 0,
+                cached_values: 3,
+                cached_bytes: 2 * (key_len + 10) + (key_len + 1 + 20),
+                retained_values: 2,
+                retained_bytes: (key_len + 10) + (key_len + 1 + 20),
+            }
+        );
+    });
+}
+
+#[test]
+fn face_key_repr_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let mut faces = vec![];
+        for (i, key_repr) in [KeyRepr::Auto, KeyRepr::Numeric, KeyRepr::Name]
+            .iter()
+            .enumerate()
+        {
+            let (face, primitives) =
+                open_client_face(&mut tables, PeerId::new(1, [i as u8; 16])).await;
+            tables.set_face_key_repr(&face, *key_repr);
+            if *key_repr == KeyRepr::Name {
+                // A mapping that the default representation would use.
+                declare_resource(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    1,
+                    0,
+                    "/test/repr",
+                )
+                .await;
+                declare_client_subscription(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    1,
+                    "/*",
+                    &sub_info,
+                )
+                .await
+                .unwrap();
+            } else {
+                declare_sub(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    "/test/repr/*",
+                    &sub_info,
+                )
+                .await;
+            }
+            faces.push(primitives);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        route_data(
+            &tables,
+            &pub_face.upgrade().unwrap(),
+            0,
+            "/test/repr/a",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            faces[0].get_last_key(),
+            Some(ResKey::RName("/test/repr/a".to_string()))
+        );
+        match faces[1].get_last_key() {
+            Some(ResKey::RIdWithSuffix(_, suffix)) => assert_eq!(suffix, "/a"),
+            key => panic!("Unexpected key {:?}", key),
+        }
+        assert_eq!(faces[1].get_last_name(), Some("/test/repr/a".to_string()));
+        assert_eq!(
+            faces[2].get_last_key(),
+            Some(ResKey::RName("/test/repr/a".to_string()))
+        );
+    });
+}
+
+#[test]
+fn partitioned_tables_test() {
+    task::block_on(async {
+        let tables = PartitionedTables::new(
+            PeerId::new(0, [0; 16]),
+            whatami::CLIENT,
+            vec!["/shard/a/**".to_string(), "/shard/b/**".to_string()],
+            || Some(HLC::default()),
+        );
+        assert_eq!(tables.len(), 3);
+        assert_eq!(tables.partition("/shard/a/x"), 0);
+        assert_eq!(tables.partition("/shard/b/x"), 1);
+        assert_eq!(tables.partition("/other/x"), 2);
+        assert_eq!(tables.partitions_of("/shard/a/*"), vec![0]);
+        assert_eq!(tables.partitions_of("/shard/*/x"), vec![0, 1, 2]);
+
+        let sub_info = reliable_push();
+        let primitives_a = Arc::new(ClientPrimitives::new());
+        let face_a = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(primitives_a.clone()),
+            )
+            .await;
+        tables
+            .declare_subscription(&face_a, "/shard/a/x", &sub_info)
+            .await;
+        let primitives_b = Arc::new(ClientPrimitives::new());
+        let face_b = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(primitives_b.clone()),
+            )
+            .await;
+        tables
+            .declare_subscription(&face_b, "/shard/b/x", &sub_info)
+            .await;
+        let primitives_wild = Arc::new(ClientPrimitives::new());
+        let face_wild = tables
+            .open_face(
+                PeerId::new(3, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(primitives_wild.clone()),
+            )
+            .await;
+        tables
+            .declare_subscription(&face_wild, "/shard/*/x", &sub_info)
+            .await;
+
+        // each partition only holds the subscriptions concerning its keys
+        assert_eq!(tables.tables(0).read().await.stats().await.push_subs, 2);
+        assert_eq!(tables.tables(1).read().await.stats().await.push_subs, 2);
+        assert_eq!(tables.tables(2).read().await.stats().await.push_subs, 1);
+
+        let pub_face = tables
+            .open_face(
+                PeerId::new(4, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        tables
+            .route_data(
+                &pub_face,
+                "/shard/a/x",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+            )
+            .await;
+        assert_eq!(primitives_a.get_last_name(), Some("/shard/a/x".to_string()));
+        assert!(primitives_b.get_last_name().is_none());
+        assert_eq!(
+            primitives_wild.get_last_name(),
+            Some("/shard/a/x".to_string())
+        );
+        primitives_a.clear_data();
+        primitives_wild.clear_data();
+
+        tables
+            .route_data(
+                &pub_face,
+                "/shard/b/x",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+            )
+            .await;
+        assert!(primitives_a.get_last_name().is_none());
+        assert_eq!(primitives_b.get_last_name(), Some("/shard/b/x".to_string()));
+        assert_eq!(
+            primitives_wild.get_last_name(),
+            Some("/shard/b/x".to_string())
+        );
+
+        // With overlapping partitions, a key belongs to the first one
+        let tables = PartitionedTables::new(
+            PeerId::new(0, [0; 16]),
+            whatami::CLIENT,
+            vec!["/a/*/c".to_string(), "/a/b/**".to_string()],
+            || Some(HLC::default()),
+        );
+        assert_eq!(tables.partition("/a/b/c"), 0);
+        assert_eq!(tables.partitions_of("/a/b/*"), vec![0, 1, 2]);
+        assert_eq!(tables.partitions_of("/a/b/d/**"), vec![1]);
+
+        let primitives = Arc::new(ClientPrimitives::new());
+        let face = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(primitives.clone()),
+            )
+            .await;
+        tables
+            .declare_subscription(&face, "/a/b/*", &sub_info)
+            .await;
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        tables
+            .route_data(
+                &pub_face,
+                "/a/b/c",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+            )
+            .await;
+        assert_eq!(primitives.get_last_name(), Some("/a/b/c".to_string()));
+    });
+}
+
+#[test]
+fn deferred_rebuild_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        {
+            let mut guard = tables.defer_rebuilds();
+            for i in 0..10 {
+                declare_sub(
+                    &mut guard,
+                    &mut face0.upgrade().unwrap(),
+                    &format!("/test/defer/{}", i),
+                    &sub_info,
+                )
+                .await;
+                if i % 2 == 1 {
+                    forget_client_subscription(
+                        &mut guard,
+                        &mut face0.upgrade().unwrap(),
+                        0,
+                        &format!("/test/defer/{}", i),
+                    )
+                    .await;
+                }
+            }
+            // a nested guard leaves the rebuilds to the outer one
+            drop(guard.defer_rebuilds());
+            assert_eq!(guard.metrics().deferred_rebuilds(), 0);
+        }
+        assert_eq!(tables.metrics().deferred_rebuilds(), 1);
+
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/defer/0",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        assert_eq!(
+            primitives0.get_last_name(),
+            Some("/test/defer/0".to_string())
+        );
+        assert_eq!(tables.metrics().unregistered_data_routes(), 0);
+        primitives0.clear_data();
+
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/defer/1",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        assert!(primitives0.get_last_name().is_none());
+    });
+}
+
+#[test]
+fn deferred_rebuild_unwind_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        let mut guard = tables.defer_rebuilds();
+        declare_sub(
+            &mut guard,
+            &mut face0.upgrade().unwrap(),
+            "/test/unwind",
+            &sub_info,
+        )
+        .await;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _guard = guard;
+            panic!("failure during the bulk operation");
+        }));
+        assert!(result.is_err());
+        assert_eq!(tables.metrics().deferred_rebuilds(), 1);
+
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/unwind",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        assert_eq!(
+            primitives0.get_last_name(),
+            Some("/test/unwind".to_string())
+        );
+        assert_eq!(tables.metrics().unregistered_data_routes(), 0);
+    });
+}
+
+#[test]
+fn get_route_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let face0 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/route",
+            &sub_info,
+        )
+        .await;
+        let face1 = face1.upgrade().unwrap();
+
+        assert_eq!(
+            get_route(&tables, &face1, 42, "/test/route", None).err(),
+            Some(RoutingError::UnknownPrefix(42))
+        );
+        assert_eq!(tables.metrics().unknown_rids(), 0);
+        assert_eq!(
+            get_route(&tables, &face1, 0, "test/route", None).err(),
+            Some(RoutingError::MalformedKey("test/route".to_string()))
+        );
+
+        let route = get_route(&tables, &face1, 0, "/test/none", None).unwrap();
+        assert!(route.is_empty());
+
+        let route = get_route(&tables, &face1, 0, "/test/route", None).unwrap();
+        assert_eq!(route.len(), 1);
+        let (outface, _, _) = route.values().next().unwrap();
+        assert!(Arc::ptr_eq(outface, &face0.upgrade().unwrap()));
+    });
+}
@@ -0,0 +1,2425 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+mod common;
+
+use async_std::future::timeout;
+use async_std::sync::{Arc, RwLock, Weak};
+use async_std::task;
+use common::*;
+use std::convert::TryInto;
+use std::time::Duration;
+use uhlc::HLC;
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, Priority, Reliability, ResKey, SubMode, ZInt,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::protocol::proto::{data_kind, encoding, DataInfo};
+use zenoh::net::protocol::session::{DeliveryAck, DummyPrimitives};
+use zenoh::net::routing::face::FaceState;
+use zenoh::net::routing::fair_queue::FairQueuing;
+use zenoh::net::routing::router::*;
+use zenoh::net::routing::OutSession;
+
+#[test]
+fn max_payload_size_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let (face1, primitives1) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        for face in &[&face0, &face1] {
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/big",
+                &sub_info,
+            )
+            .await;
+        }
+        tables.set_face_max_payload_size(&face0, Some(4));
+
+        route_data(
+            &tables,
+            &face2.upgrade().unwrap(),
+            0,
+            "/test/big",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0u8; 8]),
+            None,
+        )
+        .await;
+        assert!(primitives0.get_last_name().is_none());
+        assert_eq!(primitives1.get_last_name(), Some("/test/big".to_string()));
+        assert_eq!(tables.metrics().oversized_payloads_dropped(), 1);
+
+        // Payloads within the limit are still forwarded.
+        route_data(
+            &tables,
+            &face2.upgrade().unwrap(),
+            0,
+            "/test/big",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0u8; 4]),
+            None,
+        )
+        .await;
+        assert_eq!(primitives0.get_last_name(), Some("/test/big".to_string()));
+        assert_eq!(tables.metrics().oversized_payloads_dropped(), 1);
+
+        // The limited face receives a DELETE instead of the oversized payload.
+        primitives0.clear_data();
+        tables.set_oversized_payload_delete(true);
+        route_data(
+            &tables,
+            &face2.upgrade().unwrap(),
+            0,
+            "/test/big",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0u8; 8]),
+            None,
+        )
+        .await;
+        assert_eq!(primitives0.get_last_name(), Some("/test/big".to_string()));
+        assert_eq!(tables.metrics().oversized_payloads_dropped(), 2);
+    });
+}
+
+#[test]
+fn wait_for_subscriber_test() {
+    task::block_on(async {
+        let tables = Arc::new(RwLock::new(client_tables()));
+        let face = tables
+            .write()
+            .await
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        let waiting = tables.write().await.wait_for_subscriber("/test/**");
+
+        let tables2 = tables.clone();
+        let face2 = face.clone();
+        let declaring = task::spawn(async move {
+            task::sleep(Duration::from_millis(10)).await;
+            declare_sub(
+                &mut *tables2.write().await,
+                &mut face2.upgrade().unwrap(),
+                "/test/a",
+                &reliable_push(),
+            )
+            .await;
+        });
+
+        timeout(Duration::from_secs(5), waiting).await.unwrap();
+        declaring.await;
+
+        // Doesn't miss the declaration behind many other ones.
+        let waiting = tables.write().await.wait_for_subscriber("/late");
+        {
+            let mut tables = tables.write().await;
+            let keys = (0..1000).map(|i| format!("/other/{}", i));
+            for key in keys.chain(std::iter::once("/late".to_string())) {
+                declare_sub(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    &key,
+                    &reliable_push(),
+                )
+                .await;
+            }
+        }
+        timeout(Duration::from_secs(5), waiting).await.unwrap();
+        assert_eq!(tables.read().await.metrics().sub_events_dropped(), 0);
+
+        // Resolves immediately when a matching subscription already exists.
+        let waiting = tables.write().await.wait_for_subscriber("/test/a");
+        timeout(Duration::from_millis(100), waiting).await.unwrap();
+        assert!(!tables.read().await.has_subscriber("/other"));
+    });
+}
+
+#[test]
+fn parallel_fanout_ordering_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_parallel_fanout(true);
+        let sub_info = reliable_push();
+
+        let mut sub_primitives = vec![];
+        for _ in 0..2 {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/ordered",
+                &sub_info,
+            )
+            .await;
+            sub_primitives.push(primitives);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        let count: u32 = 500;
+        for i in 0..count {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/ordered",
+                CongestionControl::Block,
+                None,
+                RBuf::from(i.to_le_bytes().to_vec()),
+                None,
+            )
+            .await;
+        }
+
+        for primitives in &sub_primitives {
+            timeout(Duration::from_secs(5), async {
+                while primitives.get_payloads().len() < count as usize {
+                    task::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .unwrap();
+            let received: Vec<u32> = primitives
+                .get_payloads()
+                .iter()
+                .map(|payload| u32::from_le_bytes(payload.to_vec()[..].try_into().unwrap()))
+                .collect();
+            assert_eq!(received, (0..count).collect::<Vec<u32>>());
+        }
+    });
+}
+
+#[test]
+fn send_queue_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_parallel_fanout(true);
+        tables.set_subscriber_reliability(true);
+
+        let gate = Arc::new(async_std::sync::Mutex::new(()));
+        let mut sub_primitives = vec![];
+        for reliability in &[Reliability::Reliable, Reliability::BestEffort] {
+            let primitives = Arc::new(ClientPrimitives::new());
+            primitives.set_gate(gate.clone());
+            let face = tables
+                .open_face(
+                    PeerId::new(0, [0; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(primitives.clone()),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/queued",
+                &sub_info_of(*reliability, SubMode::Push),
+            )
+            .await;
+            sub_primitives.push(primitives);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        // Data with a Drop congestion control are dropped when the queue is full.
+        let count = 1100;
+        let guard = gate.lock().await;
+        for _ in 0..count {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/queued",
+                CongestionControl::Drop,
+                None,
+                RBuf::from(vec![0u8; 8]),
+                None,
+            )
+            .await;
+        }
+        let drops = tables.metrics().send_queue_drops();
+        assert!(drops >= 2 * (count - 1025));
+        drop(guard);
+        task::sleep(Duration::from_millis(100)).await;
+        let received: usize = sub_primitives
+            .iter()
+            .map(|primitives| primitives.get_payloads().len())
+            .sum();
+        assert_eq!(received + drops, 2 * count);
+
+        // Data with a Block congestion control wait for room in the queue.
+        let before = sub_primitives[0].get_payloads().len();
+        let guard = gate.lock().await;
+        let release = task::spawn(async move {
+            task::sleep(Duration::from_millis(50)).await;
+            drop(guard);
+        });
+        for _ in 0..count {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/queued",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8; 8]),
+                None,
+            )
+            .await;
+        }
+        release.await;
+        timeout(Duration::from_secs(5), async {
+            while sub_primitives
+                .iter()
+                .any(|primitives| primitives.get_payloads().len() < before + count)
+            {
+                task::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(tables.metrics().send_queue_drops(), drops);
+
+        // The queued data keep the reliability of the subscriptions.
+        assert!(sub_primitives[0]
+            .get_reliabilities()
+            .iter()
+            .all(|reliability| *reliability == Reliability::Reliable));
+        assert!(sub_primitives[1]
+            .get_reliabilities()
+            .iter()
+            .all(|reliability| *reliability == Reliability::BestEffort));
+    });
+}
+
+#[test]
+fn pause_face_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+        let face0_id = face0.upgrade().unwrap().id();
+
+        macro_rules! publish {
+            ($suffix:expr, $value:expr) => {
+                route_data(
+                    &tables,
+                    &face1.upgrade().unwrap(),
+                    0,
+                    $suffix,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![$value]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        // Without buffering, the data routed while paused are skipped.
+        tables.pause_face(face0_id, false);
+        publish!("/test/a", 1);
+        tables.resume_face(face0_id).await;
+        assert!(primitives0.get_payloads().is_empty());
+        publish!("/test/a", 2);
+        assert_eq!(primitives0.get_payloads().len(), 1);
+
+        // With buffering, the latest value of each key is delivered on resume,
+        // in the order it was routed.
+        tables.pause_face(face0_id, true);
+        publish!("/test/a", 3);
+        publish!("/test/b", 4);
+        publish!("/test/a", 5);
+        publish!("/test/c", 6);
+        assert_eq!(primitives0.get_payloads().len(), 1);
+        tables.resume_face(face0_id).await;
+        let resumed: Vec<Vec<u8>> = primitives0.get_payloads()[1..]
+            .iter()
+            .map(|payload| payload.to_vec())
+            .collect();
+        assert_eq!(resumed, vec![vec![4], vec![5], vec![6]]);
+    });
+}
+
+#[test]
+fn routing_pause_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/a",
+            &sub_info,
+        )
+        .await;
+
+        macro_rules! publish {
+            ($suffix:expr, $value:expr) => {
+                route_data(
+                    &tables,
+                    &face1.upgrade().unwrap(),
+                    0,
+                    $suffix,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![$value]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        // With buffering, the latest value of each key is routed on resume,
+        // along the routes declared while paused.
+        tables.pause_routing(true);
+        publish!("/test/a", 1);
+        publish!("/test/a", 2);
+        publish!("/test/b", 3);
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/b",
+            &sub_info,
+        )
+        .await;
+        assert!(primitives0.get_payloads().is_empty());
+        tables.resume_routing().await;
+        let mut resumed: Vec<Vec<u8>> = primitives0
+            .get_payloads()
+            .iter()
+            .map(|payload| payload.to_vec())
+            .collect();
+        resumed.sort();
+        assert_eq!(resumed, vec![vec![2], vec![3]]);
+
+        // Without buffering, the data routed while paused are dropped.
+        tables.pause_routing(false);
+        publish!("/test/a", 4);
+        tables.resume_routing().await;
+        assert_eq!(primitives0.get_payloads().len(), 2);
+        publish!("/test/a", 5);
+        assert_eq!(primitives0.get_payloads().len(), 3);
+    });
+}
+
+#[test]
+fn overlapping_subs_delivery_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        // Two subscriptions of the same face, declared with different
+        // mappings, both matching the published key.
+        declare_resource(&mut tables, &mut face0.upgrade().unwrap(), 1, 0, "/test/a").await;
+        declare_client_subscription(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            1,
+            "/*",
+            &sub_info,
+        )
+        .await
+        .unwrap();
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+
+        let route = get_route(&tables, &face1.upgrade().unwrap(), 0, "/test/a/b", None).unwrap();
+        assert_eq!(route.len(), 1);
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/a/b",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+        // Delivered once, with the published key.
+        assert_eq!(primitives0.get_payloads().len(), 1);
+        assert_eq!(primitives0.get_last_name(), Some("/test/a/b".to_string()));
+    });
+}
+
+#[test]
+fn fair_queuing_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_parallel_fanout(true);
+        tables.set_fair_queuing(Some(FairQueuing {
+            control_weight: 4,
+            data_weight: 1,
+            max_in_flight: 1,
+        }));
+        let sub_info = reliable_push();
+        let gate = Arc::new(async_std::sync::Mutex::new(()));
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+
+        // 4 faces with a control subscription and 4 with a data one
+        for i in 0..8 {
+            let (class, priority) = if i < 4 {
+                (0, Priority::Control)
+            } else {
+                (1, Priority::Data)
+            };
+            let primitives = Arc::new(ClientPrimitives::new());
+            primitives.set_gate(gate.clone());
+            primitives.log_deliveries(class, log.clone());
+            let face = tables
+                .open_face(
+                    PeerId::new(i, [0; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(primitives),
+                )
+                .await;
+            declare_client_subscription_with_options(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                0,
+                "/test/fair",
+                &sub_info,
+                &SubOptions {
+                    priority,
+                    ..SubOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(8, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        // hold the deliveries until every face has a backlog
+        let guard = gate.lock().await;
+        let count = 50;
+        for _ in 0..count {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/fair",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+        task::sleep(Duration::from_millis(50)).await;
+        drop(guard);
+
+        timeout(Duration::from_secs(5), async {
+            while log.lock().unwrap().len() < 8 * count {
+                task::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // while both classes are backlogged, 4 control deliveries for 1 data
+        let log = log.lock().unwrap();
+        let control = log[..100].iter().filter(|class| **class == 0).count();
+        assert!(
+            (70..=90).contains(&control),
+            "{} control deliveries",
+            control
+        );
+        assert_eq!(log.iter().filter(|class| **class == 0).count(), 4 * count);
+    });
+}
+
+/// A routing policy only forwarding the data within a region.
+struct RegionPolicy {
+    regions: std::collections::HashMap<usize, &'static str>,
+}
+
+impl RoutingPolicy for RegionPolicy {
+    fn allow_forward(&self, src_face: &FaceState, dst_face: &FaceState, _resname: &str) -> bool {
+        self.regions.get(&src_face.id()) == self.regions.get(&dst_face.id())
+    }
+}
+
+#[test]
+fn routing_policy_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let mut regions = std::collections::HashMap::new();
+        let mut faces = vec![];
+        for (i, region) in ["eu", "eu", "us", "us"].iter().enumerate() {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(i, [0; 16])).await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/geo",
+                &sub_info,
+            )
+            .await;
+            regions.insert(face.upgrade().unwrap().id(), *region);
+            faces.push((face, primitives));
+        }
+        tables.set_routing_policy(Some(Arc::new(RegionPolicy { regions })));
+
+        // the first face of each region publishes
+        for (i, publisher) in [0, 2].iter().enumerate() {
+            route_data(
+                &tables,
+                &faces[*publisher].0.upgrade().unwrap(),
+                0,
+                "/test/geo",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i as u8]),
+                None,
+            )
+            .await;
+        }
+        let received: Vec<Vec<Vec<u8>>> = faces
+            .iter()
+            .map(|(_, primitives)| {
+                primitives
+                    .get_payloads()
+                    .iter()
+                    .map(|payload| payload.to_vec())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(received, vec![vec![], vec![vec![0]], vec![], vec![vec![1]]]);
+
+        // without policy, the data cross the regions
+        tables.set_routing_policy(None);
+        route_data(
+            &tables,
+            &faces[0].0.upgrade().unwrap(),
+            0,
+            "/test/geo",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![2]),
+            None,
+        )
+        .await;
+        assert_eq!(faces[3].1.get_payloads().len(), 2);
+    });
+}
+
+#[test]
+fn untimestamped_kinds_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_untimestamped_kinds(vec![data_kind::DELETE]);
+        let sub_info = reliable_push();
+        let (face0, primitives) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/ts",
+            &sub_info,
+        )
+        .await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        macro_rules! publish {
+            ($kind:expr, $timestamp:expr) => {{
+                route_data(
+                    &tables,
+                    &face1.upgrade().unwrap(),
+                    0,
+                    "/test/ts",
+                    CongestionControl::Block,
+                    Some(DataInfo {
+                        timestamp: $timestamp,
+                        kind: $kind,
+                        ..DataInfo::default()
+                    }),
+                    RBuf::new(),
+                    None,
+                )
+                .await;
+                primitives.get_last_info().unwrap()
+            }};
+        }
+
+        let delete = publish!(Some(data_kind::DELETE), None);
+        assert_eq!(delete.kind, Some(data_kind::DELETE));
+        assert!(delete.timestamp.is_none());
+        assert!(publish!(Some(data_kind::PUT), None).timestamp.is_some());
+        assert!(publish!(None, None).timestamp.is_some());
+
+        // the HLC is still updated with the timestamps of the delete samples
+        let id = HLC::default().new_timestamp().await.get_id().clone();
+        let ahead = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            + Duration::from_millis(50);
+        let timestamp = uhlc::Timestamp::new(ahead.into(), id);
+        let delete = publish!(Some(data_kind::DELETE), Some(timestamp.clone()));
+        assert_eq!(delete.timestamp, Some(timestamp.clone()));
+        assert!(publish!(None, None).timestamp.unwrap() > timestamp);
+    });
+}
+
+/// A loop observer recording the key and sequence number of the looping
+/// samples.
+#[derive(Default)]
+struct LoopRecorder {
+    loops: std::sync::Mutex<Vec<(String, ZInt)>>,
+}
+
+impl LoopObserver for LoopRecorder {
+    fn loop_detected(&self, _face: &FaceState, key: &str, _source_id: &PeerId, source_sn: ZInt) {
+        self.loops
+            .lock()
+            .unwrap()
+            .push((key.to_string(), source_sn));
+    }
+}
+
+#[test]
+fn loop_detection_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let recorder = Arc::new(LoopRecorder::default());
+        tables.set_loop_detection(16, Duration::from_secs(10), Some(recorder.clone()));
+        let sub_info = reliable_push();
+        let (face0, primitives) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/loop",
+            &sub_info,
+        )
+        .await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        // the second sample is a duplicate of the first one
+        for (source_sn, value) in [(7, 0), (7, 1), (8, 2)].iter() {
+            route_data(
+                &tables,
+                &face1.upgrade().unwrap(),
+                0,
+                "/test/loop",
+                CongestionControl::Block,
+                Some(DataInfo {
+                    source_id: Some(PeerId::new(1, [1; 16])),
+                    source_sn: Some(*source_sn),
+                    ..DataInfo::default()
+                }),
+                RBuf::from(vec![*value]),
+                None,
+            )
+            .await;
+        }
+
+        let payloads: Vec<Vec<u8>> = primitives
+            .get_payloads()
+            .iter()
+            .map(|payload| payload.to_vec())
+            .collect();
+        assert_eq!(payloads, vec![vec![0], vec![2]]);
+        assert_eq!(tables.metrics().loops_detected(), 1);
+        assert_eq!(
+            *recorder.loops.lock().unwrap(),
+            vec![("/test/loop".to_string(), 7)]
+        );
+    });
+}
+
+/// A converter of the CBOR unsigned integers above 23 to JSON.
+struct CborToJson;
+
+impl EncodingConverter for CborToJson {
+    fn convert(&self, payload: &RBuf) -> Option<RBuf> {
+        match payload.to_vec()[..] {
+            [0x18, value] => Some(RBuf::from(value.to_string().into_bytes())),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn encoding_conversion_test() {
+    task::block_on(async {
+        const CBOR: ZInt = 60;
+        let mut tables = client_tables();
+        tables.add_encoding_converter(CBOR, encoding::APP_JSON, Arc::new(CborToJson));
+        let sub_info = reliable_push();
+
+        // a JSON subscription, a raw one and a JSON only one
+        let mut subs = vec![];
+        for (i, options) in [
+            SubOptions {
+                accepted_encoding: Some(encoding::APP_JSON),
+                ..SubOptions::default()
+            },
+            SubOptions::default(),
+            SubOptions {
+                accepted_encoding: Some(encoding::APP_JSON),
+                unconverted: UnconvertedPolicy::Drop,
+                ..SubOptions::default()
+            },
+        ]
+        .iter()
+        .enumerate()
+        {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(i, [0; 16])).await;
+            declare_client_subscription_with_options(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                0,
+                "/test/enc",
+                &sub_info,
+                options,
+            )
+            .await
+            .unwrap();
+            subs.push(primitives);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(3, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        // a CBOR sample, then an XML one that no converter converts
+        for (data_encoding, payload) in [
+            (CBOR, vec![0x18, 42]),
+            (encoding::APP_XML, b"<a/>".to_vec()),
+        ]
+        .iter()
+        {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/enc",
+                CongestionControl::Block,
+                Some(DataInfo {
+                    encoding: Some(*data_encoding),
+                    ..DataInfo::default()
+                }),
+                RBuf::from(payload.clone()),
+                None,
+            )
+            .await;
+        }
+
+        let received: Vec<Vec<Vec<u8>>> = subs
+            .iter()
+            .map(|primitives| {
+                primitives
+                    .get_payloads()
+                    .iter()
+                    .map(|payload| payload.to_vec())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                vec![b"42".to_vec(), b"<a/>".to_vec()],
+                vec![vec![0x18, 42], b"<a/>".to_vec()],
+                vec![b"42".to_vec()],
+            ]
+        );
+        assert_eq!(
+            subs[2].get_last_info().unwrap().encoding,
+            Some(encoding::APP_JSON)
+        );
+        assert_eq!(
+            subs[1].get_last_info().unwrap().encoding,
+            Some(encoding::APP_XML)
+        );
+    });
+}
+
+#[test]
+fn deterministic_timestamp_test() {
+    task::block_on(async {
+        let pid = PeerId::new(0, [0; 16]);
+        let mut tables = deterministic_tables(pid.clone(), whatami::CLIENT);
+        let sub_info = reliable_push();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (face1, primitives1) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+        declare_sub(&mut tables, &mut face0, "/test/clock", &sub_info).await;
+        assert_eq!(
+            primitives1.subscribers(),
+            vec![("/test/clock".to_string(), sub_info.clone())]
+        );
+
+        macro_rules! publish {
+            ($info:expr) => {
+                route_data(
+                    &tables,
+                    &face1,
+                    0,
+                    "/test/clock",
+                    CongestionControl::Block,
+                    $info,
+                    RBuf::from(vec![0u8]),
+                    None,
+                )
+                .await
+            };
+        }
+
+        publish!(None);
+        publish!(None);
+        // a sample timestamped upstream is forwarded as is and moves the HLC
+        let ahead = uhlc::Timestamp::new(
+            uhlc::NTP64(FIXED_TIME.0 + 100),
+            uhlc::ID::from(&PeerId::new(2, [0; 16])),
+        );
+        publish!(Some(DataInfo {
+            timestamp: Some(ahead.clone()),
+            ..DataInfo::default()
+        }));
+        publish!(None);
+
+        let data = primitives0.data();
+        assert_eq!(data.len(), 4);
+        assert!(data
+            .iter()
+            .all(|(name, _, payload)| name == "/test/clock" && payload.to_vec() == vec![0u8]));
+        let timestamps: Vec<uhlc::Timestamp> = data
+            .into_iter()
+            .map(|(_, info, _)| info.unwrap().timestamp.unwrap())
+            .collect();
+        assert_eq!(timestamps[2], ahead);
+        for (idx, offset) in [(0, 0), (1, 1), (3, 101)].iter() {
+            assert_eq!(timestamps[*idx].get_id(), &uhlc::ID::from(&pid));
+            assert_eq!(timestamps[*idx].get_time().0, FIXED_TIME.0 + offset);
+        }
+        assert!(primitives1.data().is_empty());
+
+        forget_client_subscription(&mut tables, &mut face0, 0, "/test/clock").await;
+        assert_eq!(
+            primitives1.forgotten_subscribers(),
+            vec!["/test/clock".to_string()]
+        );
+    });
+}
+
+#[test]
+fn dedup_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (mut face1, primitives1) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+        let (face2, _) =
+            open_recording_face(&mut tables, PeerId::new(3, [0; 16]), whatami::CLIENT).await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            0,
+            "/test/dedup/*",
+            &sub_info,
+            &SubOptions {
+                dedup: Some(Dedup {
+                    window: Duration::from_secs(60),
+                    by_key: true,
+                }),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_sub(&mut tables, &mut face1, "/test/dedup/*", &sub_info).await;
+
+        for (key, payload) in &[
+            ("/test/dedup/a", 1u8),
+            ("/test/dedup/a", 1u8),
+            ("/test/dedup/a", 2u8),
+            ("/test/dedup/b", 2u8),
+        ] {
+            route_data(
+                &tables,
+                &face2,
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![*payload]),
+                None,
+            )
+            .await;
+        }
+
+        let received = |primitives: &RecordingPrimitives| -> Vec<(String, Vec<u8>)> {
+            primitives
+                .data()
+                .into_iter()
+                .map(|(name, _, payload)| (name, payload.to_vec()))
+                .collect()
+        };
+        assert_eq!(
+            received(&primitives0),
+            vec![
+                ("/test/dedup/a".to_string(), vec![1]),
+                ("/test/dedup/a".to_string(), vec![2]),
+                ("/test/dedup/b".to_string(), vec![2]),
+            ]
+        );
+        assert_eq!(received(&primitives1).len(), 4);
+        assert_eq!(tables.metrics().duplicates_suppressed(), 1);
+    });
+}
+
+#[test]
+fn delivery_acks_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (mut face1, primitives1) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+        let (face2, _) =
+            open_recording_face(&mut tables, PeerId::new(3, [0; 16]), whatami::CLIENT).await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            0,
+            "/test/acks",
+            &sub_info,
+            &SubOptions {
+                track_acks: true,
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_sub(&mut tables, &mut face1, "/test/acks", &sub_info).await;
+        assert_eq!(
+            tables.delivery_stats(face0.id(), "/test/acks"),
+            Some(DeliveryStats::default())
+        );
+        assert_eq!(tables.delivery_stats(face1.id(), "/test/acks"), None);
+
+        primitives0.set_acks(vec![
+            DeliveryAck::Acked,
+            DeliveryAck::Failed,
+            DeliveryAck::Acked,
+        ]);
+        primitives1.set_acks(vec![DeliveryAck::Failed; 4]);
+        for _ in 0..4 {
+            route_data(
+                &tables,
+                &face2,
+                0,
+                "/test/acks",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8]),
+                None,
+            )
+            .await;
+        }
+
+        // The last one isn't acknowledged, the transport doesn't tell
+        assert_eq!(
+            tables.delivery_stats(face0.id(), "/test/acks"),
+            Some(DeliveryStats {
+                delivered: 3,
+                acked: 2,
+                failed: 1,
+            })
+        );
+        // The untracked subscription is served without acknowledgment
+        assert_eq!(primitives1.data().len(), 4);
+        assert_eq!(primitives1.remaining_acks(), 4);
+    });
+}
+
+#[test]
+fn shared_payload_fan_out_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let mut destinations = vec![];
+        for i in 0..1000u16 {
+            let mut id = [0u8; 16];
+            id[..2].copy_from_slice(&i.to_le_bytes());
+            let (mut face, primitives) =
+                open_recording_face(&mut tables, PeerId::new(2, id), whatami::PEER).await;
+            declare_sub(&mut tables, &mut face, "/test/shared", &sub_info).await;
+            destinations.push(primitives);
+        }
+        let (face, _) =
+            open_recording_face(&mut tables, PeerId::new(1, [0xff; 16]), whatami::PEER).await;
+
+        let bytes: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let payload = RBuf::from(bytes.clone());
+        let shared = payload.get_slice(0).unwrap().as_slice().as_ptr();
+        route_data(
+            &tables,
+            &face,
+            0,
+            "/test/shared",
+            CongestionControl::Block,
+            None,
+            payload,
+            None,
+        )
+        .await;
+
+        let mut delivered: Vec<RBuf> = destinations
+            .iter()
+            .map(|primitives| {
+                let mut data = primitives.data();
+                assert_eq!(data.len(), 1);
+                data.pop().unwrap().2
+            })
+            .collect();
+        // The bytes of the payload are never copied
+        assert!(delivered
+            .iter()
+            .all(|payload| payload.get_slice(0).unwrap().as_slice().as_ptr() == shared));
+        // Reading a payload doesn't affect the ones of the other destinations
+        assert_eq!(delivered[0].read_vec(), bytes);
+        assert!(!delivered[0].can_read());
+        assert!(delivered[1..]
+            .iter()
+            .all(|payload| payload.readable() == bytes.len() && payload.to_vec() == bytes));
+    });
+}
+
+#[test]
+fn batching_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let mut faces = vec![];
+        let mut primitives = vec![];
+        for i in 0..3 {
+            let face_primitives = Arc::new(ClientPrimitives::new());
+            faces.push(
+                tables
+                    .open_face(
+                        PeerId::new(1, [i; 16]),
+                        whatami::CLIENT,
+                        OutSession::Primitives(face_primitives.clone()),
+                    )
+                    .await
+                    .upgrade()
+                    .unwrap(),
+            );
+            primitives.push(face_primitives);
+        }
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut faces[0],
+            0,
+            "/test/batch/*",
+            &sub_info,
+            &SubOptions {
+                batching: Some(Batching {
+                    window: Duration::from_millis(100),
+                    max_samples: 4,
+                }),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_sub(&mut tables, &mut faces[1], "/test/batch/*", &sub_info).await;
+
+        for i in 0..10 {
+            route_data(
+                &tables,
+                &faces[2],
+                0,
+                &format!("/test/batch/{}", i),
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i as u8]),
+                None,
+            )
+            .await;
+        }
+        let keys = |range: std::ops::Range<usize>| -> Vec<String> {
+            range.map(|i| format!("/test/batch/{}", i)).collect()
+        };
+        // The full batches are delivered right away, in order
+        assert_eq!(primitives[0].get_batches(), vec![keys(0..4), keys(4..8)]);
+        assert!(primitives[0].get_payloads().is_empty());
+        // The other subscription is not batched
+        assert_eq!(primitives[1].get_payloads().len(), 10);
+        assert!(primitives[1].get_batches().is_empty());
+
+        // The last batch is delivered once its window elapsed
+        assert!(tables.flush_batches().await.is_some());
+        assert_eq!(primitives[0].get_batches().len(), 2);
+        task::sleep(Duration::from_millis(150)).await;
+        assert_eq!(tables.flush_batches().await, None);
+        assert_eq!(
+            primitives[0].get_batches(),
+            vec![keys(0..4), keys(4..8), keys(8..10)]
+        );
+    });
+}
+
+#[test]
+fn reordering_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let (mut sub_face, sub_primitives) =
+            open_recording_face(&mut tables, PeerId::new(1, [1; 16]), whatami::CLIENT).await;
+        let (pub_face, _) =
+            open_recording_face(&mut tables, PeerId::new(1, [2; 16]), whatami::CLIENT).await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut sub_face,
+            0,
+            "/test/reorder",
+            &sub_info,
+            &SubOptions {
+                reordering: Some(Reordering {
+                    max_window: 4,
+                    timeout: Duration::from_millis(100),
+                }),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let received = || -> Vec<u8> {
+            sub_primitives
+                .data()
+                .into_iter()
+                .map(|(_, _, payload)| payload.to_vec()[0])
+                .collect()
+        };
+        macro_rules! publish {
+            ($sn:expr) => {
+                route_data(
+                    &tables,
+                    &pub_face,
+                    0,
+                    "/test/reorder",
+                    CongestionControl::Block,
+                    Some(DataInfo {
+                        source_id: Some(PeerId::new(1, [2; 16])),
+                        source_sn: Some($sn),
+                        ..DataInfo::default()
+                    }),
+                    RBuf::from(vec![$sn as u8]),
+                    None,
+                )
+                .await
+            };
+        }
+
+        publish!(1);
+        publish!(3);
+        assert_eq!(received(), vec![1]);
+        publish!(2);
+        assert_eq!(received(), vec![1, 2, 3]);
+
+        // A gap is skipped once its timeout elapsed
+        publish!(5);
+        assert_eq!(received(), vec![1, 2, 3]);
+        assert!(tables.flush_reorders().await.is_some());
+        task::sleep(Duration::from_millis(150)).await;
+        assert_eq!(tables.flush_reorders().await, None);
+        assert_eq!(received(), vec![1, 2, 3, 5]);
+    });
+}
+
+#[derive(Default)]
+struct DeliveryErrors {
+    errors: std::sync::Mutex<Vec<(ResKey, DeliveryError)>>,
+}
+
+impl DeliveryErrorHandler for DeliveryErrors {
+    fn on_delivery_error(&self, reskey: &ResKey, error: DeliveryError) {
+        self.errors.lock().unwrap().push((reskey.clone(), error));
+    }
+}
+
+#[test]
+fn delivery_error_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (mut face1, primitives1) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+        let (face2, _) =
+            open_recording_face(&mut tables, PeerId::new(3, [0; 16]), whatami::CLIENT).await;
+        let reliable_errors = Arc::new(DeliveryErrors::default());
+        let best_effort_errors = Arc::new(DeliveryErrors::default());
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            0,
+            "/test/errors",
+            &reliable_push(),
+            &SubOptions {
+                on_delivery_error: Some(reliable_errors.clone()),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face1,
+            0,
+            "/test/errors",
+            &sub_info_of(Reliability::BestEffort, SubMode::Push),
+            &SubOptions {
+                track_acks: true,
+                on_delivery_error: Some(best_effort_errors.clone()),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        primitives0.set_acks(vec![DeliveryAck::Acked, DeliveryAck::Failed]);
+        primitives1.set_acks(vec![DeliveryAck::Failed; 2]);
+        for _ in 0..2 {
+            route_data(
+                &tables,
+                &face2,
+                0,
+                "/test/errors",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8]),
+                None,
+            )
+            .await;
+        }
+
+        assert_eq!(primitives0.data().len(), 2);
+        let errors: Vec<(String, DeliveryError)> = reliable_errors
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reskey, error)| (primitives0.get_name(reskey), *error))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![("/test/errors".to_string(), DeliveryError::Failed)]
+        );
+        // The failures of a best effort subscription are only counted
+        assert_eq!(
+            tables
+                .delivery_stats(face1.id(), "/test/errors")
+                .unwrap()
+                .failed,
+            2
+        );
+        assert!(best_effort_errors.errors.lock().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn data_filter_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let (face1, primitives1) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/x",
+            &sub_info,
+        )
+        .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            0,
+            "/test/x",
+            &sub_info,
+            &SubOptions {
+                data_filter: Some(DataInfoPredicate::Kind(data_kind::PUT)),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        macro_rules! publish {
+            ($kind:expr, $value:expr) => {
+                route_data(
+                    &tables,
+                    &face2.upgrade().unwrap(),
+                    0,
+                    "/test/x",
+                    CongestionControl::Block,
+                    $kind.map(|kind| DataInfo {
+                        kind: Some(kind),
+                        ..DataInfo::default()
+                    }),
+                    RBuf::from(vec![$value]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        publish!(None::<ZInt>, 1);
+        publish!(Some(data_kind::DELETE), 2);
+        publish!(Some(data_kind::PUT), 3);
+
+        let payloads0: Vec<Vec<u8>> = primitives0
+            .get_payloads()
+            .iter()
+            .map(|p| p.to_vec())
+            .collect();
+        let payloads1: Vec<Vec<u8>> = primitives1
+            .get_payloads()
+            .iter()
+            .map(|p| p.to_vec())
+            .collect();
+        assert_eq!(payloads0, vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(payloads1, vec![vec![1], vec![3]]);
+
+        let filter = DataInfoPredicate::Not(Box::new(DataInfoPredicate::Or(vec![
+            DataInfoPredicate::Kind(data_kind::DELETE),
+            DataInfoPredicate::Encoding(4),
+        ])));
+        assert!(filter.matches(&None));
+    });
+}
+
+#[test]
+fn congestion_control_policy_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_congestion_control(whatami::ROUTER, Some(CongestionControl::Block));
+        let sub_info = reliable_push();
+
+        let router_primitives = Arc::new(ClientPrimitives::new());
+        let router_face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::ROUTER,
+                OutSession::Primitives(router_primitives.clone()),
+            )
+            .await;
+        let (client_face, client_primitives) =
+            open_client_face(&mut tables, PeerId::new(2, [2; 16])).await;
+        let pub_face = tables
+            .open_face(
+                PeerId::new(3, [3; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for face in &[&router_face, &client_face] {
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/congestion",
+                &sub_info,
+            )
+            .await;
+        }
+
+        route_data(
+            &tables,
+            &pub_face.upgrade().unwrap(),
+            0,
+            "/test/congestion",
+            CongestionControl::Drop,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            router_primitives.get_congestion_controls(),
+            vec![CongestionControl::Block]
+        );
+        assert_eq!(
+            client_primitives.get_congestion_controls(),
+            vec![CongestionControl::Drop]
+        );
+    });
+}
+
+#[test]
+fn max_staleness_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let (face1, primitives1) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/stale",
+            &sub_info,
+            &SubOptions {
+                max_staleness: Some(Duration::from_secs(1)),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_sub(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            "/test/stale",
+            &sub_info,
+        )
+        .await;
+
+        let id = HLC::default().new_timestamp().await.get_id().clone();
+        let old_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            - Duration::from_secs(10);
+        let old_info = DataInfo {
+            timestamp: Some(uhlc::Timestamp::new(old_time.into(), id)),
+            ..DataInfo::default()
+        };
+        for info in vec![Some(old_info), None] {
+            route_data(
+                &tables,
+                &face2.upgrade().unwrap(),
+                0,
+                "/test/stale",
+                CongestionControl::Block,
+                info,
+                RBuf::from(vec![0]),
+                None,
+            )
+            .await;
+        }
+
+        assert_eq!(primitives0.get_payloads().len(), 1);
+        assert_eq!(primitives1.get_payloads().len(), 2);
+        assert_eq!(tables.metrics().stale_data_dropped(), 1);
+    });
+}
+
+#[test]
+fn idempotency_token_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let options = SubOptions {
+            idempotency_token: Some(42),
+            ..SubOptions::default()
+        };
+
+        let face0 = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let (_face1, primitives1) = open_client_face(&mut tables, PeerId::new(2, [2; 16])).await;
+
+        let mut face0 = face0.upgrade().unwrap();
+        declare_resource(&mut tables, &mut face0, 1, 0, "/test").await;
+        for _ in 0..2 {
+            let outcome = declare_client_subscription_with_options(
+                &mut tables,
+                &mut face0,
+                1,
+                "/idempotent",
+                &sub_info,
+                &options,
+            )
+            .await
+            .unwrap();
+            // The resent declaration returns the outcome of the accepted one
+            assert_eq!(outcome.resname, "/test/idempotent");
+            assert!(outcome.created);
+            forget_client_subscription(&mut tables, &mut face0, 0, "/test/idempotent").await;
+        }
+        assert_eq!(primitives1.get_subs(), vec!["/test/idempotent"]);
+        assert_eq!(tables.metrics().resent_declarations_ignored(), 1);
+
+        // A rejected declaration doesn't record its token
+        let retried = SubOptions {
+            idempotency_token: Some(44),
+            ..SubOptions::default()
+        };
+        assert!(declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            2,
+            "/retried",
+            &sub_info,
+            &retried,
+        )
+        .await
+        .is_err());
+        declare_resource(&mut tables, &mut face0, 2, 0, "/test").await;
+        let outcome = declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            2,
+            "/retried",
+            &sub_info,
+            &retried,
+        )
+        .await
+        .unwrap();
+        assert!(outcome.created);
+        assert_eq!(primitives1.get_subs().len(), 2);
+        forget_client_subscription(&mut tables, &mut face0, 0, "/test/retried").await;
+
+        // Without token or with another token, declarations are applied
+        declare_sub(&mut tables, &mut face0, "/test/idempotent", &sub_info).await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            0,
+            "/test/other",
+            &sub_info,
+            &SubOptions {
+                idempotency_token: Some(43),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(primitives1.get_subs().len(), 4);
+
+        // Once the window has elapsed, the token can be reused
+        tables.set_idempotency_tokens(16, Duration::from_millis(50));
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            0,
+            "/test/later",
+            &sub_info,
+            &options,
+        )
+        .await
+        .unwrap();
+        task::sleep(Duration::from_millis(100)).await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0,
+            0,
+            "/test/later/again",
+            &sub_info,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(primitives1.get_subs().len(), 6);
+    });
+}
+
+#[test]
+fn dead_letter_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+
+        let (dead_letter_face, dead_letter_primitives) =
+            open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let (sub_face, sub_primitives) =
+            open_client_face(&mut tables, PeerId::new(2, [2; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut sub_face.upgrade().unwrap(),
+            "/test/subscribed",
+            &reliable_push(),
+        )
+        .await;
+        tables.set_dead_letter_face(Some(&dead_letter_face));
+
+        let pub_face = sub_face.upgrade().unwrap();
+        for (face, key, value) in &[
+            (&pub_face, "/test/subscribed", 1),
+            (&pub_face, "/test/unsubscribed", 2),
+            (
+                &dead_letter_face.upgrade().unwrap(),
+                "/test/unsubscribed",
+                3,
+            ),
+        ] {
+            route_data(
+                &tables,
+                face,
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![*value]),
+                None,
+            )
+            .await;
+        }
+
+        assert_eq!(
+            dead_letter_primitives.get_payloads(),
+            vec![RBuf::from(vec![2])]
+        );
+        assert_eq!(
+            dead_letter_primitives.get_last_name(),
+            Some("/test/unsubscribed".to_string())
+        );
+        assert_eq!(tables.metrics().dead_lettered_samples(), 1);
+
+        tables.set_dead_letter_face(None);
+        route_data(
+            &tables,
+            &pub_face,
+            0,
+            "/test/unsubscribed",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![4]),
+            None,
+        )
+        .await;
+        assert_eq!(dead_letter_primitives.get_payloads().len(), 1);
+    });
+}
+
+#[test]
+fn route_data_fanout_test() {
+    task::block_on(async {
+        let mut tables = router_tables();
+        let mut face0 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(DummyPrimitives::new())),
+            )
+            .await
+            .upgrade()
+            .unwrap();
+        declare_resource(&mut tables, &mut face0, 1, 0, "/fanout/data").await;
+        let primitives = Arc::new(ClientPrimitives::new());
+        let faces = open_subscribers(
+            &mut tables,
+            "/fanout/*",
+            64,
+            OutSession::Primitives(primitives.clone()),
+        )
+        .await;
+        assert_eq!(faces.len(), 64);
+
+        let payload = RBuf::from(vec![0u8; 64]);
+        for _ in 0..100 {
+            route_data(
+                &tables,
+                &face0,
+                1,
+                "",
+                CongestionControl::Drop,
+                None,
+                payload.clone(),
+                None,
+            )
+            .await;
+        }
+        // Each message is delivered once to each subscriber
+        assert_eq!(primitives.get_payloads().len(), 100 * 64);
+    });
+}
+
+#[test]
+fn source_filter_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let router_a = PeerId::new(1, [10; 16]);
+        let router_b = PeerId::new(1, [11; 16]);
+        let sub_info = reliable_push();
+
+        let (face0, filtered) = open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let (face1, unfiltered) = open_client_face(&mut tables, PeerId::new(1, [2; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(1, [3; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/source",
+            &sub_info,
+            &SubOptions {
+                source_filter: Some(router_a.clone()),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_sub(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            "/test/source",
+            &sub_info,
+        )
+        .await;
+
+        let info = |source_id: Option<PeerId>, first_router_id: Option<PeerId>| DataInfo {
+            source_id,
+            first_router_id,
+            ..DataInfo::default()
+        };
+        for info in vec![
+            Some(info(None, Some(router_a.clone()))),
+            Some(info(None, Some(router_b.clone()))),
+            Some(info(Some(router_a.clone()), None)),
+            None,
+        ] {
+            route_data(
+                &tables,
+                &face2.upgrade().unwrap(),
+                0,
+                "/test/source",
+                CongestionControl::Block,
+                info,
+                RBuf::from(vec![0u8; 4]),
+                None,
+            )
+            .await;
+        }
+        assert_eq!(filtered.get_payloads().len(), 2);
+        assert_eq!(unfiltered.get_payloads().len(), 4);
+    });
+}
+
+#[test]
+fn send_timeout_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let mut sub_faces = vec![];
+        let mut sub_primitives = vec![];
+        for i in 0..2 {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(1, [i; 16])).await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/timeout",
+                &sub_info,
+            )
+            .await;
+            sub_faces.push(face);
+            sub_primitives.push(primitives);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let stuck_id = sub_faces[0].upgrade().unwrap().id();
+        sub_primitives[0].set_stuck(true);
+        tables.set_face_send_timeout(&sub_faces[0], Some(Duration::from_millis(50)), true);
+
+        macro_rules! publish {
+            ($value:expr) => {
+                timeout(
+                    Duration::from_secs(5),
+                    route_data(
+                        &tables,
+                        &pub_face.upgrade().unwrap(),
+                        0,
+                        "/test/timeout",
+                        CongestionControl::Block,
+                        None,
+                        RBuf::from(vec![$value]),
+                        None,
+                    ),
+                )
+                .await
+                .unwrap();
+            };
+        }
+
+        // The routing proceeds with the other faces after the timeout and the
+        // stuck face is quarantined.
+        publish!(1);
+        assert_eq!(sub_primitives[1].get_payloads().len(), 1);
+        assert_eq!(tables.metrics().send_timeouts(), 1);
+        assert!(tables.is_face_quarantined(stuck_id));
+
+        // Nothing is sent to the quarantined face anymore.
+        publish!(2);
+        assert_eq!(sub_primitives[1].get_payloads().len(), 2);
+        assert_eq!(tables.metrics().send_timeouts(), 1);
+
+        // Resuming the face lifts the quarantine.
+        sub_primitives[0].set_stuck(false);
+        tables.resume_face(stuck_id).await;
+        assert!(!tables.is_face_quarantined(stuck_id));
+        publish!(3);
+        assert_eq!(sub_primitives[0].get_payloads().len(), 1);
+        assert_eq!(sub_primitives[1].get_payloads().len(), 3);
+
+        // The queued data are bounded by the timeout as well, while a late
+        // send still completes in the background.
+        tables.set_parallel_fanout(true);
+        let gate = Arc::new(async_std::sync::Mutex::new(()));
+        sub_primitives[0].set_gate(gate.clone());
+        let guard = gate.lock().await;
+        publish!(4);
+        timeout(Duration::from_secs(5), async {
+            while !tables.is_face_quarantined(stuck_id) {
+                task::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(tables.metrics().send_timeouts(), 2);
+        drop(guard);
+        timeout(Duration::from_secs(5), async {
+            while sub_primitives[0].get_payloads().len() < 2 {
+                task::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+    });
+}
+
+struct EveryNth {
+    n: usize,
+    count: std::sync::atomic::AtomicUsize,
+}
+
+impl PayloadTransform for EveryNth {
+    fn transform(&self, _info: &Option<DataInfo>, payload: &RBuf) -> Option<RBuf> {
+        let count = self
+            .count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if count % self.n == 0 {
+            Some(payload.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn payload_transform_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let (face1, primitives1) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/sampled",
+            &sub_info,
+        )
+        .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            0,
+            "/test/sampled",
+            &sub_info,
+            &SubOptions {
+                transform: Some(Arc::new(EveryNth {
+                    n: 3,
+                    count: std::sync::atomic::AtomicUsize::new(0),
+                })),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        for i in 0..9u8 {
+            route_data(
+                &tables,
+                &face2.upgrade().unwrap(),
+                0,
+                "/test/sampled",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i]),
+                None,
+            )
+            .await;
+        }
+
+        let payloads0: Vec<Vec<u8>> = primitives0
+            .get_payloads()
+            .iter()
+            .map(|p| p.to_vec())
+            .collect();
+        let payloads1: Vec<Vec<u8>> = primitives1
+            .get_payloads()
+            .iter()
+            .map(|p| p.to_vec())
+            .collect();
+        assert_eq!(payloads0, (0..9u8).map(|i| vec![i]).collect::<Vec<_>>());
+        assert_eq!(payloads1, vec![vec![0], vec![3], vec![6]]);
+    });
+}
+
+#[test]
+fn reliable_first_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_reliable_first(true);
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+
+        // The subscriber 0 is the only reliable one.
+        let subs: usize = 8;
+        for i in 0..subs {
+            let primitives = Arc::new(ClientPrimitives::new());
+            primitives.log_deliveries(i, log.clone());
+            let face = tables
+                .open_face(
+                    PeerId::new(1, [i as u8; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(primitives),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/reliable",
+                &sub_info_of(
+                    if i == 0 {
+                        Reliability::Reliable
+                    } else {
+                        Reliability::BestEffort
+                    },
+                    SubMode::Push,
+                ),
+            )
+            .await;
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        let count = 100;
+        for i in 0..count {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/reliable",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i as u8]),
+                None,
+            )
+            .await;
+        }
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), count * subs);
+        for deliveries in log.chunks(subs) {
+            assert_eq!(deliveries[0], 0);
+        }
+    });
+}
+
+#[test]
+fn memory_pressure_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let pressure = Arc::new(MemoryPressure::new(1024));
+        tables.set_memory_pressure(Some(pressure.clone()));
+
+        let mut primitives = vec![];
+        for reliability in &[Reliability::Reliable, Reliability::BestEffort] {
+            let prims = Arc::new(ClientPrimitives::new());
+            let face = tables
+                .open_face(
+                    PeerId::new(1, [primitives.len() as u8; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(prims.clone()),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                "/test/pressure",
+                &sub_info_of(*reliability, SubMode::Push),
+            )
+            .await;
+            primitives.push(prims);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        async fn route(tables: &Tables, face: &Weak<FaceState>) {
+            route_data(
+                tables,
+                &face.upgrade().unwrap(),
+                0,
+                "/test/pressure",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8]),
+                None,
+            )
+            .await;
+        }
+
+        // Below the high watermark both subscribers are served.
+        pressure.set_used(512);
+        route(&tables, &pub_face).await;
+        assert!(primitives[0].get_last_name().is_some());
+        assert!(primitives[1].get_last_name().is_some());
+        assert_eq!(tables.metrics().best_effort_shed(), 0);
+
+        // Above it only the reliable subscriber is served.
+        primitives[0].clear_data();
+        primitives[1].clear_data();
+        pressure.set_used(2048);
+        route(&tables, &pub_face).await;
+        assert!(primitives[0].get_last_name().is_some());
+        assert!(primitives[1].get_last_name().is_none());
+        assert_eq!(tables.metrics().best_effort_shed(), 1);
+
+        // Once the pressure drops the best effort subscriber is served again.
+        primitives[0].clear_data();
+        pressure.set_used(0);
+        route(&tables, &pub_face).await;
+        assert!(primitives[0].get_last_name().is_some());
+        assert!(primitives[1].get_last_name().is_some());
+        assert_eq!(tables.metrics().best_effort_shed(), 1);
+    });
+}
+
+#[test]
+fn receive_own_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let mut faces = vec![];
+        for (i, receive_own) in [false, true].iter().enumerate() {
+            let (face, primitives) =
+                open_client_face(&mut tables, PeerId::new(1, [i as u8; 16])).await;
+            declare_client_subscription_with_options(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                0,
+                "/test/loopback/**",
+                &sub_info,
+                &SubOptions {
+                    receive_own: *receive_own,
+                    ..SubOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            faces.push((face, primitives));
+        }
+
+        async fn publish(tables: &Tables, face: &Weak<FaceState>, name: &str) {
+            route_data(
+                tables,
+                &face.upgrade().unwrap(),
+                0,
+                name,
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+
+        // The data of the default subscriber's face are not echoed back.
+        publish(&tables, &faces[0].0, "/test/loopback/a").await;
+        assert!(faces[0].1.get_last_name().is_none());
+        assert_eq!(
+            faces[1].1.get_last_name(),
+            Some("/test/loopback/a".to_string())
+        );
+
+        // The data of the loopback subscriber's face are.
+        faces[1].1.clear_data();
+        publish(&tables, &faces[1].0, "/test/loopback/b").await;
+        assert_eq!(
+            faces[0].1.get_last_name(),
+            Some("/test/loopback/b".to_string())
+        );
+        assert_eq!(
+            faces[1].1.get_last_name(),
+            Some("/test/loopback/b".to_string())
+        );
+
+        // Including when it is the only subscriber.
+        faces[0].1.clear_data();
+        faces[1].1.clear_data();
+        forget_client_subscription(
+            &mut tables,
+            &mut faces[0].0.upgrade().unwrap(),
+            0,
+            "/test/loopback/**",
+        )
+        .await;
+        publish(&tables, &faces[1].0, "/test/loopback/c").await;
+        assert!(faces[0].1.get_last_name().is_none());
+        assert_eq!(
+            faces[1].1.get_last_name(),
+            Some("/test/loopback/c".to_string())
+        );
+    });
+}
+
+#[test]
+fn routing_path_test() {
+    task::block_on(async {
+        let sub_info = reliable_push();
+        let router1 = PeerId::new(1, [1; 16]);
+        let router2 = PeerId::new(1, [2; 16]);
+
+        // router1 forwards the data of its publisher to router2, which
+        // delivers them to its subscriber.
+        let mut tables1 = deterministic_tables(router1.clone(), whatami::CLIENT);
+        tables1.set_record_routing_path(true);
+        let pub_face = tables1
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let link_primitives = Arc::new(ClientPrimitives::new());
+        let link_face = tables1
+            .open_face(
+                router2.clone(),
+                whatami::CLIENT,
+                OutSession::Primitives(link_primitives.clone()),
+            )
+            .await;
+        declare_sub(
+            &mut tables1,
+            &mut link_face.upgrade().unwrap(),
+            "/test/path",
+            &sub_info,
+        )
+        .await;
+
+        let mut tables2 = deterministic_tables(router2.clone(), whatami::CLIENT);
+        tables2.set_record_routing_path(true);
+        let link_face = tables2
+            .open_face(
+                router1.clone(),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let (sub_face, sub_primitives) =
+            open_client_face(&mut tables2, PeerId::new(2, [1; 16])).await;
+        declare_sub(
+            &mut tables2,
+            &mut sub_face.upgrade().unwrap(),
+            "/test/path",
+            &sub_info,
+        )
+        .await;
+
+        route_data(
+            &tables1,
+            &pub_face.upgrade().unwrap(),
+            0,
+            "/test/path",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        let info = link_primitives.get_last_info();
+        assert_eq!(
+            info.as_ref().unwrap().routing_path,
+            Some(vec![router1.clone()])
+        );
+
+        route_data(
+            &tables2,
+            &link_face.upgrade().unwrap(),
+            0,
+            "/test/path",
+            CongestionControl::Block,
+            info,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        assert_eq!(
+            sub_primitives.get_last_info().unwrap().routing_path,
+            Some(vec![router1, router2])
+        );
+
+        // No path is recorded by default.
+        tables1.set_record_routing_path(false);
+        route_data(
+            &tables1,
+            &pub_face.upgrade().unwrap(),
+            0,
+            "/test/path",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        assert!(link_primitives
+            .get_last_info()
+            .unwrap()
+            .routing_path
+            .is_none());
+    });
+}
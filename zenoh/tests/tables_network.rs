@@ -0,0 +1,519 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+mod common;
+
+use async_std::sync::Arc;
+use async_std::task;
+use common::*;
+use uhlc::HLC;
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, Period, Reliability, SubInfo, SubMode, ZInt,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::routing::network::TreeView;
+use zenoh::net::routing::router::*;
+use zenoh::net::routing::OutSession;
+
+#[test]
+fn uninitialized_net_test() {
+    task::block_on(async {
+        // Router tables whose routers and peers networks are not initialized.
+        let mut tables = router_tables();
+        let sub_info = reliable_push();
+
+        let router_face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::ROUTER,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_router_subscription(
+            &mut tables,
+            &mut router_face.upgrade().unwrap(),
+            0,
+            "/test/a",
+            &sub_info,
+            PeerId::new(1, [1; 16]),
+        )
+        .await
+        .unwrap();
+        assert!(tables.metrics().deferred_propagations() > 0);
+        assert_eq!(
+            tables.dump_resource("/test/a").unwrap().router_subs,
+            vec![PeerId::new(1, [1; 16])]
+        );
+
+        // The clients are still served locally.
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(2, [2; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(3, [3; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/*",
+            &sub_info,
+        )
+        .await;
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/a",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+        assert_eq!(primitives0.get_payloads().len(), 1);
+
+        // The data of the routers is dropped until the network is initialized.
+        route_data(
+            &tables,
+            &router_face.upgrade().unwrap(),
+            0,
+            "/test/a",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![1]),
+            Some(0),
+        )
+        .await;
+        assert_eq!(primitives0.get_payloads().len(), 1);
+        assert_eq!(tables.metrics().uninitialized_net_drops(), 1);
+
+        forget_router_subscription(
+            &mut tables,
+            &mut router_face.upgrade().unwrap(),
+            0,
+            "/test/a",
+            &PeerId::new(1, [1; 16]),
+        )
+        .await;
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn tree_walks_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let a = PeerId::new(1, [1; 16]);
+        let b = PeerId::new(1, [2; 16]);
+        let c = PeerId::new(1, [3; 16]);
+        let d = PeerId::new(1, [4; 16]);
+        // a - local - b, local - c
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[a.clone(), b.clone(), c.clone()][..])],
+        )
+        .await;
+        let sub_info = reliable_push();
+        let mut faces = vec![];
+        for pid in &[a.clone(), b.clone(), c.clone(), d.clone()] {
+            faces.push(
+                tables
+                    .open_face(
+                        pid.clone(),
+                        whatami::ROUTER,
+                        OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                    )
+                    .await
+                    .upgrade()
+                    .unwrap(),
+            );
+        }
+        let mut face_a = faces[0].clone();
+
+        declare_router_subscription(&mut tables, &mut face_a, 0, "/test/0", &sub_info, a.clone())
+            .await
+            .unwrap();
+        let walks = tables.metrics().tree_walks();
+        assert!(walks > 0);
+        // The burst along the same tree reuses its walk
+        for i in 1..50 {
+            declare_router_subscription(
+                &mut tables,
+                &mut face_a,
+                0,
+                &format!("/test/{}", i),
+                &sub_info,
+                a.clone(),
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(tables.metrics().tree_walks(), walks);
+        // All propagated to b and c
+        assert_eq!(
+            tables.metrics().router_sub_fan_out().buckets(),
+            vec![0, 0, 50, 0, 0, 0, 0]
+        );
+
+        // local - d: the tree is walked again and reaches d
+        tables
+            .apply_topology_change(whatami::ROUTER, &[(local.clone(), &[d.clone()][..])], &[])
+            .await;
+        declare_router_subscription(
+            &mut tables,
+            &mut face_a,
+            0,
+            "/test/50",
+            &sub_info,
+            a.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(tables.metrics().tree_walks() > walks);
+        assert_eq!(
+            tables.metrics().router_sub_fan_out().buckets(),
+            vec![0, 0, 50, 1, 0, 0, 0]
+        );
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn aggregated_period_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let router = PeerId::new(1, [1; 16]);
+        // local - router
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[router.clone()][..])],
+        )
+        .await;
+        let (_, router_primitives) =
+            open_recording_face(&mut tables, router.clone(), whatami::ROUTER).await;
+        let period = |period| SubInfo {
+            period: Some(Period {
+                origin: 0,
+                period,
+                duration: 10,
+            }),
+            ..reliable_push()
+        };
+        let upstream_periods = || -> Vec<ZInt> {
+            router_primitives
+                .subscribers()
+                .into_iter()
+                .map(|(_, sub_info)| sub_info.period.unwrap().period)
+                .collect()
+        };
+
+        for (i, client_period) in [100, 50, 200].iter().enumerate() {
+            let (mut face, _) = open_recording_face(
+                &mut tables,
+                PeerId::new(1, [i as u8 + 2; 16]),
+                whatami::CLIENT,
+            )
+            .await;
+            declare_sub(
+                &mut tables,
+                &mut face,
+                "/test/period",
+                &period(*client_period),
+            )
+            .await;
+        }
+        // Propagated again for the faster client only
+        assert_eq!(upstream_periods(), vec![100, 50]);
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn routing_trees_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let a = PeerId::new(1, [1; 16]);
+        let b = PeerId::new(1, [2; 16]);
+        let c = PeerId::new(1, [3; 16]);
+        let router = new_router_tables(local.clone()).await;
+        let mut tables = router.tables.write().await;
+
+        // b - a - local - c, the links being added twice
+        for _ in 0..2 {
+            tables
+                .apply_topology_change(
+                    whatami::ROUTER,
+                    &[
+                        (local.clone(), &[a.clone(), c.clone()][..]),
+                        (a.clone(), &[b.clone()][..]),
+                    ],
+                    &[],
+                )
+                .await;
+        }
+
+        let trees = tables.routing_trees(whatami::ROUTER);
+        assert_eq!(trees.len(), 4);
+        let tree = |root: &PeerId| trees.iter().find(|tree| tree.root == *root).unwrap();
+        assert_eq!(
+            tree(&local),
+            &TreeView {
+                root: local.clone(),
+                parent: None,
+                childs: vec![a.clone(), c.clone()],
+            }
+        );
+        assert_eq!(tree(&a).parent, Some(a.clone()));
+        assert_eq!(tree(&a).childs, vec![c.clone()]);
+        assert_eq!(tree(&b).parent, Some(a.clone()));
+        assert_eq!(tree(&b).childs, vec![c.clone()]);
+        assert_eq!(tree(&c).parent, Some(c.clone()));
+        assert_eq!(tree(&c).childs, vec![a]);
+
+        assert!(tables.routing_trees(whatami::CLIENT).is_empty());
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn topology_change_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let a = PeerId::new(1, [1; 16]);
+        let b = PeerId::new(1, [2; 16]);
+        let c = PeerId::new(1, [3; 16]);
+        // b - a - local
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[
+                (local.clone(), &[a.clone()][..]),
+                (a.clone(), &[b.clone()][..]),
+            ],
+        )
+        .await;
+        let sub_info = reliable_push();
+
+        let face_a = tables
+            .open_face(
+                a.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let primitives_c = Arc::new(ClientPrimitives::new());
+        tables
+            .open_face(
+                c.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives_c.clone()),
+            )
+            .await;
+        declare_router_subscription(
+            &mut tables,
+            &mut face_a.upgrade().unwrap(),
+            0,
+            "/test/topology",
+            &sub_info,
+            b.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(primitives_c.get_subs().is_empty());
+
+        // b - a - local - c: c becomes a child of local in the tree of b.
+        tables
+            .apply_topology_change(whatami::ROUTER, &[(local.clone(), &[c.clone()][..])], &[])
+            .await;
+        let trees = tables.routing_trees(whatami::ROUTER);
+        let tree_b = trees.iter().find(|tree| tree.root == b).unwrap();
+        assert_eq!(tree_b.childs, vec![c.clone()]);
+        assert_eq!(primitives_c.get_subs(), vec!["/test/topology".to_string()]);
+
+        // a - local - c: b is no longer reachable and removed.
+        tables
+            .apply_topology_change(whatami::ROUTER, &[], &[(a.clone(), &[b.clone()][..])])
+            .await;
+        assert!(tables
+            .routing_trees(whatami::ROUTER)
+            .iter()
+            .all(|tree| tree.root != b));
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn subscription_fan_out_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let a = PeerId::new(1, [1; 16]);
+        let b = PeerId::new(1, [2; 16]);
+        let c = PeerId::new(1, [3; 16]);
+        let p = PeerId::new(1, [4; 16]);
+        // a - local - b, local - c
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[a.clone(), b.clone(), c.clone()][..])],
+        )
+        .await;
+        // local - p
+        tables
+            .apply_topology_change(whatami::PEER, &[(local.clone(), &[p.clone()][..])], &[])
+            .await;
+        let sub_info = reliable_push();
+
+        let mut faces = vec![];
+        for (pid, whatami) in &[
+            (a.clone(), whatami::ROUTER),
+            (b.clone(), whatami::ROUTER),
+            (c.clone(), whatami::ROUTER),
+            (p.clone(), whatami::PEER),
+        ] {
+            faces.push(
+                tables
+                    .open_face(
+                        pid.clone(),
+                        *whatami,
+                        OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                    )
+                    .await,
+            );
+        }
+
+        // Propagated to b and c over the routers network, to p over the
+        // peers network.
+        declare_router_subscription(
+            &mut tables,
+            &mut faces[0].upgrade().unwrap(),
+            0,
+            "/test/fanout",
+            &sub_info,
+            a.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            tables.metrics().router_sub_fan_out().buckets(),
+            vec![0, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            tables.metrics().peer_sub_fan_out().buckets(),
+            vec![0, 1, 0, 0, 0, 0, 0]
+        );
+
+        // Propagated to a, b and c over the routers network, not back to p
+        // over the peers network.
+        declare_peer_subscription(
+            &mut tables,
+            &mut faces[3].upgrade().unwrap(),
+            0,
+            "/test/fanout/peer",
+            &sub_info,
+            p.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            tables.metrics().router_sub_fan_out().buckets(),
+            vec![0, 0, 1, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            tables.metrics().peer_sub_fan_out().buckets(),
+            vec![1, 1, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(tables.metrics().router_sub_fan_out().count(), 2);
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn peer_subs_reliability_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let router = PeerId::new(1, [1; 16]);
+        let peer1 = PeerId::new(1, [2; 16]);
+        let peer2 = PeerId::new(1, [3; 16]);
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[router.clone()][..])],
+        )
+        .await;
+        tables
+            .apply_topology_change(
+                whatami::PEER,
+                &[(local.clone(), &[peer1.clone(), peer2.clone()][..])],
+                &[],
+            )
+            .await;
+
+        let primitives_router = Arc::new(ClientPrimitives::new());
+        tables
+            .open_face(
+                router.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives_router.clone()),
+            )
+            .await;
+        let face1 = tables
+            .open_face(
+                peer1.clone(),
+                whatami::PEER,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let face2 = tables
+            .open_face(
+                peer2.clone(),
+                whatami::PEER,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        macro_rules! declare {
+            ($face:expr, $peer:expr, $reliability:expr) => {
+                declare_peer_subscription(
+                    &mut tables,
+                    &mut $face.upgrade().unwrap(),
+                    0,
+                    "/test/reliability",
+                    &sub_info_of($reliability, SubMode::Pull),
+                    $peer.clone(),
+                )
+                .await
+                .unwrap()
+            };
+        }
+
+        declare!(face1, peer1, Reliability::BestEffort);
+        assert_eq!(
+            primitives_router.get_sub_reliabilities(),
+            vec![Reliability::BestEffort]
+        );
+
+        // The router subscription is redeclared with the strongest reliability
+        declare!(face2, peer2, Reliability::Reliable);
+        assert_eq!(
+            primitives_router.get_sub_reliabilities(),
+            vec![Reliability::BestEffort, Reliability::Reliable]
+        );
+
+        // A weaker peer subscription doesn't weaken it
+        declare!(face1, peer1, Reliability::BestEffort);
+        assert_eq!(primitives_router.get_sub_reliabilities().len(), 2);
+    });
+}
@@ -0,0 +1,785 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+mod common;
+
+use async_std::sync::{Arc, RwLock};
+use async_std::task;
+use common::*;
+use std::time::Duration;
+use zenoh::net::protocol::core::rname::intersect;
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, QueryConsolidation, QueryTarget, ResKey,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::protocol::proto::DataInfo;
+use zenoh::net::routing::router::*;
+use zenoh::net::routing::OutSession;
+
+#[test]
+fn batched_pull_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_pull();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/**",
+            &sub_info,
+            &SubOptions {
+                batch_pulls: true,
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        for suffix in &["/test/a", "/test/b", "/test/c"] {
+            route_data(
+                &tables,
+                &face1.upgrade().unwrap(),
+                0,
+                suffix,
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+        assert!(primitives0.get_last_name().is_none());
+
+        pull_data(
+            &mut tables,
+            &face0.upgrade().unwrap(),
+            true,
+            0,
+            "/test/**",
+            0,
+            &None,
+        )
+        .await;
+        assert!(primitives0.get_last_name().is_none());
+        let batches = primitives0.get_batches();
+        assert_eq!(batches.len(), 1);
+        let mut names = batches[0].clone();
+        names.sort();
+        assert_eq!(names, vec!["/test/a", "/test/b", "/test/c"]);
+    });
+}
+
+#[test]
+fn pull_order_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_pull();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/**",
+            &sub_info,
+        )
+        .await;
+
+        // "/test/a" is cached again after the others
+        for (i, key) in ["/test/c", "/test/a", "/test/b", "/test/d", "/test/a"]
+            .iter()
+            .enumerate()
+        {
+            route_data(
+                &tables,
+                &face1.upgrade().unwrap(),
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i as u8]),
+                None,
+            )
+            .await;
+        }
+        pull_data(
+            &mut tables,
+            &face0.upgrade().unwrap(),
+            true,
+            0,
+            "/test/**",
+            0,
+            &None,
+        )
+        .await;
+        assert_eq!(
+            primitives0
+                .get_payloads()
+                .iter()
+                .map(|payload| payload.to_vec()[0])
+                .collect::<Vec<u8>>(),
+            vec![0, 2, 3, 4]
+        );
+        assert_eq!(primitives0.get_last_name(), Some("/test/a".to_string()));
+    });
+}
+
+#[test]
+fn recent_samples_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_history_depth("/test/**", 3).await;
+        let face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        for i in 0u8..5 {
+            route_data(
+                &tables,
+                &face.upgrade().unwrap(),
+                0,
+                "/test/history",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i]),
+                None,
+            )
+            .await;
+        }
+
+        let recent: Vec<Vec<u8>> = tables
+            .recent_samples("/test/history", 5)
+            .await
+            .iter()
+            .map(|(_, payload)| payload.to_vec())
+            .collect();
+        assert_eq!(recent, vec![vec![2], vec![3], vec![4]]);
+        let recent: Vec<Vec<u8>> = tables
+            .recent_samples("/test/history", 2)
+            .await
+            .iter()
+            .map(|(_, payload)| payload.to_vec())
+            .collect();
+        assert_eq!(recent, vec![vec![3], vec![4]]);
+        assert!(tables.recent_samples("/other", 3).await.is_empty());
+
+        // The values recorded for a resource are forgotten when it is cleaned.
+        declare_resource(
+            &mut tables,
+            &mut face.upgrade().unwrap(),
+            1,
+            0,
+            "/test/cleaned",
+        )
+        .await;
+        route_data(
+            &tables,
+            &face.upgrade().unwrap(),
+            1,
+            "",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0]),
+            None,
+        )
+        .await;
+        assert_eq!(tables.recent_samples("/test/cleaned", 3).await.len(), 1);
+        undeclare_resource(&mut tables, &mut face.upgrade().unwrap(), 1).await;
+        assert!(tables.recent_samples("/test/cleaned", 3).await.is_empty());
+        assert_eq!(tables.recent_samples("/test/history", 3).await.len(), 3);
+
+        tables.set_history_depth("/test/**", 0).await;
+        assert!(tables.recent_samples("/test/history", 3).await.is_empty());
+    });
+}
+
+#[test]
+fn pull_on_push_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let (face, primitives) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut face.upgrade().unwrap(),
+            "/test/push",
+            &reliable_push(),
+        )
+        .await;
+
+        pull_data(
+            &mut tables,
+            &face.upgrade().unwrap(),
+            true,
+            0,
+            "/test/push",
+            0,
+            &None,
+        )
+        .await;
+        assert!(primitives.get_batches().is_empty());
+        assert!(primitives.get_last_name().is_none());
+        assert_eq!(tables.metrics().pulls_on_push_subs(), 1);
+    });
+}
+
+struct MockStorage {
+    samples: Vec<(String, u8)>,
+}
+
+impl HistoryStorage for MockStorage {
+    fn history(
+        &self,
+        key_expr: &str,
+        bound: HistoryBound,
+    ) -> Vec<(String, Option<DataInfo>, RBuf)> {
+        let matching: Vec<(String, Option<DataInfo>, RBuf)> = self
+            .samples
+            .iter()
+            .filter(|(name, _)| intersect(key_expr, name))
+            .map(|(name, value)| (name.clone(), None, RBuf::from(vec![*value])))
+            .collect();
+        match bound {
+            HistoryBound::Count(count) => matching[matching.len().saturating_sub(count)..].to_vec(),
+            HistoryBound::Since(_) => matching,
+        }
+    }
+}
+
+#[test]
+fn history_replay_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_history_storage(Some(Arc::new(MockStorage {
+            samples: vec![
+                ("/test/a".to_string(), 1),
+                ("/other".to_string(), 2),
+                ("/test/b".to_string(), 3),
+                ("/test/a".to_string(), 4),
+            ],
+        })));
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/*",
+            &sub_info,
+            &SubOptions {
+                history: Some(HistoryBound::Count(2)),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/a",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![5]),
+            None,
+        )
+        .await;
+
+        // The latest two historical samples, then the live one.
+        let payloads: Vec<Vec<u8>> = primitives0
+            .get_payloads()
+            .iter()
+            .map(|payload| payload.to_vec())
+            .collect();
+        assert_eq!(payloads, vec![vec![3], vec![4], vec![5]]);
+
+        // No history is replayed to the subscriptions without the option.
+        let (face2, primitives2) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            "/test/*",
+            &sub_info,
+        )
+        .await;
+        assert!(primitives2.get_payloads().is_empty());
+    });
+}
+
+#[test]
+fn cache_full_policy_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_pull();
+
+        let mut pull_faces = vec![];
+        for policy in [
+            CacheFullPolicy::EvictOldest,
+            CacheFullPolicy::RejectNew,
+            CacheFullPolicy::EvictExpiredFirst(Duration::from_millis(50)),
+        ]
+        .iter()
+        {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+            declare_client_subscription_with_options(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                0,
+                "/test/**",
+                &sub_info,
+                &SubOptions {
+                    max_cached_keys: Some(3),
+                    cache_full: *policy,
+                    ..SubOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            pull_faces.push((face, primitives));
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        // "/test/a" and "/test/b" expire before "/test/d" finds the caches full
+        for (i, key) in ["/test/a", "/test/b", "/test/c", "/test/d"]
+            .iter()
+            .enumerate()
+        {
+            if i == 2 {
+                task::sleep(Duration::from_millis(100)).await;
+            }
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![i as u8]),
+                None,
+            )
+            .await;
+        }
+
+        let mut pulled = vec![];
+        for (face, primitives) in &pull_faces {
+            pull_data(
+                &mut tables,
+                &face.upgrade().unwrap(),
+                true,
+                0,
+                "/test/**",
+                0,
+                &None,
+            )
+            .await;
+            pulled.push(
+                primitives
+                    .get_payloads()
+                    .iter()
+                    .map(|payload| payload.to_vec()[0])
+                    .collect::<Vec<u8>>(),
+            );
+        }
+        assert_eq!(pulled, vec![vec![1, 2, 3], vec![0, 1, 2], vec![2, 3]]);
+    });
+}
+
+#[test]
+fn max_cached_bytes_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_max_cached_bytes(Some(10));
+        let sub_info = reliable_pull();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (mut face1, primitives1) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+        let (face2, _) =
+            open_recording_face(&mut tables, PeerId::new(3, [0; 16]), whatami::CLIENT).await;
+        declare_sub(&mut tables, &mut face0, "/a/*", &sub_info).await;
+        declare_sub(&mut tables, &mut face1, "/b/*", &sub_info).await;
+        assert_eq!(tables.cached_bytes(), Some(0));
+
+        for key in &["/a/1", "/b/1", "/a/2", "/b/2"] {
+            route_data(
+                &tables,
+                &face2,
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8; 4]),
+                None,
+            )
+            .await;
+            assert!(tables.cached_bytes().unwrap() <= 10);
+        }
+        // The oldest values were evicted, whatever their subscription
+        assert_eq!(tables.cached_bytes(), Some(8));
+
+        pull_data(&mut tables, &face0, true, 0, "/a/*", 0, &None).await;
+        pull_data(&mut tables, &face1, true, 0, "/b/*", 0, &None).await;
+        let names = |primitives: &RecordingPrimitives| -> Vec<String> {
+            primitives
+                .data()
+                .into_iter()
+                .map(|(name, _, _)| name)
+                .collect()
+        };
+        assert_eq!(names(&primitives0), vec!["/a/2".to_string()]);
+        assert_eq!(names(&primitives1), vec!["/b/2".to_string()]);
+        assert_eq!(tables.cached_bytes(), Some(0));
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cancel_pulls_test() {
+    task::block_on(async {
+        let pid = PeerId::new(2, [0; 16]);
+        let router_pid = PeerId::new(2, [1; 16]);
+        let router = new_router_tables(pid.clone()).await;
+        let router_primitives = Arc::new(ClientPrimitives::new());
+        let router_face = router
+            .new_face(
+                router_pid.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(router_primitives.clone()),
+            )
+            .await;
+        let sub_primitives = Arc::new(ClientPrimitives::new());
+        let sub_face = {
+            let mut tables = router.tables.write().await;
+            tables.set_read_through(Some(1));
+            tables
+                .apply_topology_change(whatami::ROUTER, &[(pid, &[router_pid.clone()][..])], &[])
+                .await;
+            let sub_face = tables
+                .open_face(
+                    PeerId::new(2, [2; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(sub_primitives.clone()),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut sub_face.upgrade().unwrap(),
+                "/rt/*",
+                &reliable_pull(),
+            )
+            .await;
+            sub_face
+        };
+        let sub_face_id = sub_face.upgrade().unwrap().id();
+
+        macro_rules! pull {
+            () => {
+                pull_data(
+                    &mut *router.tables.write().await,
+                    &sub_face.upgrade().unwrap(),
+                    true,
+                    0,
+                    "/rt/*",
+                    0,
+                    &None,
+                )
+                .await;
+            };
+        }
+
+        // The read through query is never answered
+        pull!();
+        let (_, qid) = router_primitives.get_last_query().unwrap();
+        let mut tables = router.tables.write().await;
+        assert_eq!(
+            tables.in_flight_pulls(sub_face_id),
+            vec!["/rt/*".to_string()]
+        );
+
+        assert_eq!(tables.cancel_pulls(sub_face_id), 1);
+        assert!(tables.in_flight_pulls(sub_face_id).is_empty());
+        assert_eq!(tables.cancel_pulls(sub_face_id), 0);
+        drop(tables);
+
+        // The late replies are dropped without being cached
+        router_face
+            .send_reply_data(
+                qid,
+                0,
+                router_pid.clone(),
+                ResKey::RName("/rt/a".to_string()),
+                None,
+                RBuf::from(vec![1]),
+            )
+            .await;
+        router_face.send_reply_final(qid).await;
+        assert!(sub_primitives.get_last_name().is_none());
+
+        // The read through slot is given back
+        pull!();
+        let (_, next_qid) = router_primitives.get_last_query().unwrap();
+        assert_ne!(next_qid, qid);
+        let mut tables = router.tables.write().await;
+        assert_eq!(tables.metrics().read_through_saturated(), 0);
+        assert_eq!(tables.in_flight_pulls(sub_face_id).len(), 1);
+
+        tables.close_face(&sub_face).await;
+        assert!(tables.in_flight_pulls(sub_face_id).is_empty());
+    });
+}
+
+#[test]
+fn concurrent_pull_cache_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_pull();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/conc/*",
+            &sub_info,
+            &SubOptions {
+                batch_pulls: true,
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let tables = Arc::new(RwLock::new(tables));
+        let publishers: Vec<_> = (0..16)
+            .map(|i| {
+                let tables = tables.clone();
+                let face1 = face1.upgrade().unwrap();
+                task::spawn(async move {
+                    full_reentrant_route_data(
+                        &tables,
+                        &face1,
+                        0,
+                        &format!("/test/conc/{}", i),
+                        CongestionControl::Block,
+                        None,
+                        RBuf::new(),
+                        None,
+                    )
+                    .await;
+                })
+            })
+            .collect();
+        futures::future::join_all(publishers).await;
+        assert!(primitives0.get_last_name().is_none());
+
+        let mut tables = tables.write().await;
+        for _ in 0..2 {
+            pull_data(
+                &mut tables,
+                &face0.upgrade().unwrap(),
+                true,
+                0,
+                "/test/conc/*",
+                0,
+                &None,
+            )
+            .await;
+        }
+        // the second pull finds an empty cache
+        let batches = primitives0.get_batches();
+        assert_eq!(batches.len(), 2);
+        assert!(batches[1].is_empty());
+        let mut names = batches[0].clone();
+        names.sort();
+        let mut expected: Vec<String> = (0..16).map(|i| format!("/test/conc/{}", i)).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn read_through_pull_test() {
+    task::block_on(async {
+        let pid0 = PeerId::new(1, [0; 16]);
+        let pid1 = PeerId::new(2, [0; 16]);
+        let topology = [(pid1.clone(), &[pid0.clone()][..])];
+
+        // Router holding the retained value.
+        let router0 = new_router_tables(pid0.clone()).await;
+        let primitives0 = Arc::new(ClientPrimitives::new());
+        let router_face0 = router0
+            .new_face(
+                pid1.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives0.clone()),
+            )
+            .await;
+        {
+            let mut tables0 = router0.tables.write().await;
+            tables0.set_retain_latest(true);
+            tables0
+                .apply_topology_change(whatami::ROUTER, &topology, &[])
+                .await;
+            let pub_face = tables0
+                .open_face(
+                    PeerId::new(1, [1; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                )
+                .await;
+            route_data(
+                &tables0,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/rt/key",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+
+        // Router with the pull subscriber.
+        let router1 = new_router_tables(pid1.clone()).await;
+        let primitives1 = Arc::new(ClientPrimitives::new());
+        let router_face1 = router1
+            .new_face(
+                pid0.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives1.clone()),
+            )
+            .await;
+        let sub_primitives = Arc::new(ClientPrimitives::new());
+        let sub_face = {
+            let mut tables1 = router1.tables.write().await;
+            tables1.set_read_through(Some(1));
+            tables1
+                .apply_topology_change(whatami::ROUTER, &topology, &[])
+                .await;
+            let sub_face = tables1
+                .open_face(
+                    PeerId::new(2, [2; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(sub_primitives.clone()),
+                )
+                .await;
+            declare_sub(
+                &mut tables1,
+                &mut sub_face.upgrade().unwrap(),
+                "/rt/*",
+                &reliable_pull(),
+            )
+            .await;
+            sub_face
+        };
+
+        macro_rules! pull {
+            () => {
+                pull_data(
+                    &mut *router1.tables.write().await,
+                    &sub_face.upgrade().unwrap(),
+                    true,
+                    0,
+                    "/rt/*",
+                    0,
+                    &None,
+                )
+                .await;
+            };
+        }
+
+        pull!();
+        let (predicate, qid) = primitives1.get_last_query().unwrap();
+        let key = primitives1.get_last_query_name().unwrap();
+        assert_eq!(predicate, "");
+        assert_eq!(key, format!("/@/retained/{}/rt/*", pid1));
+        assert!(sub_primitives.get_last_name().is_none());
+
+        // router0 answers the query of router1
+        router_face0
+            .send_query(
+                &ResKey::RName(key),
+                &predicate,
+                qid,
+                QueryTarget::default(),
+                QueryConsolidation::default(),
+                None,
+            )
+            .await;
+        let name = primitives0.get_last_reply_name().unwrap();
+        assert_eq!(name, "/rt/key");
+        router_face1
+            .send_reply_data(qid, 0, pid0.clone(), ResKey::RName(name), None, RBuf::new())
+            .await;
+        assert!(sub_primitives.get_last_name().is_none());
+        router_face1.send_reply_final(qid).await;
+        assert_eq!(sub_primitives.get_last_name(), Some("/rt/key".to_string()));
+
+        // only one read through query may be in flight
+        for _ in 0..2 {
+            pull!();
+        }
+        assert_eq!(
+            router1
+                .tables
+                .read()
+                .await
+                .metrics()
+                .read_through_saturated(),
+            1
+        );
+    });
+}
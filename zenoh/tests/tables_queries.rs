@@ -0,0 +1,326 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+mod common;
+
+use async_std::sync::Arc;
+use async_std::task;
+use common::*;
+use uhlc::HLC;
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, QueryConsolidation, QueryTarget, ResKey,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::protocol::proto::encoding;
+use zenoh::net::routing::router::*;
+use zenoh::net::routing::OutSession;
+
+#[cfg(feature = "testing")]
+#[test]
+fn retained_query_test() {
+    task::block_on(async {
+        let sub_info = reliable_push();
+        // Three routers in a line: router0 - router1 - router2.
+        let pid0 = PeerId::new(1, [0; 16]);
+        let pid1 = PeerId::new(1, [1; 16]);
+        let pid2 = PeerId::new(1, [2; 16]);
+        let topology = [
+            (pid0.clone(), &[pid1.clone()][..]),
+            (pid1.clone(), &[pid2.clone()][..]),
+        ];
+
+        // Router holding the retained value.
+        let router0 = new_router_tables(pid0.clone()).await;
+        let primitives01 = Arc::new(ClientPrimitives::new());
+        let face01 = router0
+            .new_face(
+                pid1.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives01.clone()),
+            )
+            .await;
+        {
+            let mut tables0 = router0.tables.write().await;
+            tables0.set_retain_latest(true);
+            tables0
+                .apply_topology_change(whatami::ROUTER, &topology, &[])
+                .await;
+            let pub_face = tables0
+                .open_face(
+                    PeerId::new(1, [10; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                )
+                .await;
+            route_data(
+                &tables0,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/retained/key",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+            assert!(tables0.get_retained("/retained/key").await.is_some());
+        }
+
+        // Router relaying the retained queries.
+        let router1 = new_router_tables(pid1.clone()).await;
+        let primitives10 = Arc::new(ClientPrimitives::new());
+        let face10 = router1
+            .new_face(
+                pid0.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives10.clone()),
+            )
+            .await;
+        let primitives12 = Arc::new(ClientPrimitives::new());
+        let face12 = router1
+            .new_face(
+                pid2.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives12.clone()),
+            )
+            .await;
+        router1
+            .tables
+            .write()
+            .await
+            .apply_topology_change(whatami::ROUTER, &topology, &[])
+            .await;
+
+        // Router with the new subscriber.
+        let router2 = new_router_tables(pid2.clone()).await;
+        let primitives21 = Arc::new(ClientPrimitives::new());
+        let face21 = router2
+            .new_face(
+                pid1.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(primitives21.clone()),
+            )
+            .await;
+        let sub_primitives = Arc::new(ClientPrimitives::new());
+        {
+            let mut tables2 = router2.tables.write().await;
+            tables2
+                .apply_topology_change(whatami::ROUTER, &topology, &[])
+                .await;
+            let sub_face = tables2
+                .open_face(
+                    PeerId::new(1, [20; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(sub_primitives.clone()),
+                )
+                .await;
+            declare_client_subscription_with_options(
+                &mut tables2,
+                &mut sub_face.upgrade().unwrap(),
+                0,
+                "/retained/**",
+                &sub_info,
+                &SubOptions {
+                    query_retained: true,
+                    ..SubOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        // The retained query is sent on a reserved key with no predicate.
+        let key = format!("/@/retained/{}/retained/**", pid2);
+        let (predicate, qid21) = primitives21.get_last_query().unwrap();
+        assert_eq!(predicate, "");
+        assert_eq!(primitives21.get_last_query_name(), Some(key.clone()));
+        assert!(sub_primitives.get_last_name().is_none());
+
+        // router1 has no retained value and forwards the query to router0.
+        face12
+            .send_query(
+                &ResKey::RName(key.clone()),
+                "",
+                qid21,
+                QueryTarget::default(),
+                QueryConsolidation::none(),
+                None,
+            )
+            .await;
+        assert!(primitives12.get_last_reply_name().is_none());
+        assert!(primitives12.get_reply_finals().is_empty());
+        let (_, qid10) = primitives10.get_last_query().unwrap();
+        assert_eq!(primitives10.get_last_query_name(), Some(key.clone()));
+
+        // router0 replies its retained value, and is a leaf of the tree of
+        // router2.
+        face01
+            .send_query(
+                &ResKey::RName(key.clone()),
+                "",
+                qid10,
+                QueryTarget::default(),
+                QueryConsolidation::none(),
+                None,
+            )
+            .await;
+        assert_eq!(
+            primitives01.get_last_reply_name(),
+            Some("/retained/key".to_string())
+        );
+        assert_eq!(primitives01.get_reply_finals(), vec![qid10]);
+
+        // router1 relays the replies of router0.
+        face10
+            .send_reply_data(
+                qid10,
+                0,
+                pid0.clone(),
+                ResKey::RName("/retained/key".to_string()),
+                None,
+                RBuf::new(),
+            )
+            .await;
+        face10.send_reply_final(qid10).await;
+        assert_eq!(
+            primitives12.get_last_reply_name(),
+            Some("/retained/key".to_string())
+        );
+        assert_eq!(primitives12.get_reply_finals(), vec![qid21]);
+
+        // router2 delivers them as data to the subscriber.
+        face21
+            .send_reply_data(
+                qid21,
+                0,
+                pid0.clone(),
+                ResKey::RName("/retained/key".to_string()),
+                None,
+                RBuf::new(),
+            )
+            .await;
+        face21.send_reply_final(qid21).await;
+        assert_eq!(
+            sub_primitives.get_last_name(),
+            Some("/retained/key".to_string())
+        );
+
+        // The retained values are bounded.
+        let mut tables0 = router0.tables.write().await;
+        let pub_face = tables0
+            .open_face(
+                PeerId::new(1, [11; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        tables0.set_max_retained_values(2).await;
+        for key in &["/retained/other", "/retained/last"] {
+            route_data(
+                &tables0,
+                &pub_face.upgrade().unwrap(),
+                0,
+                key,
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+        assert_eq!(tables0.metrics().retained_evictions(), 1);
+        assert!(tables0.get_retained("/retained/key").await.is_none());
+        assert!(tables0.get_retained("/retained/other").await.is_some());
+        tables0.set_max_retained_values(1).await;
+        assert_eq!(tables0.metrics().retained_evictions(), 2);
+        assert!(tables0.get_retained("/retained/other").await.is_none());
+        assert!(tables0.get_retained("/retained/last").await.is_some());
+    });
+}
+
+#[test]
+fn admin_query_test() {
+    task::block_on(async {
+        let pid = PeerId::new(1, [9; 16]);
+        let router = Router::new(pid.clone(), whatami::ROUTER, Some(HLC::default()));
+        let sub_info = reliable_push();
+        let (sub_face, _) =
+            open_recording_router_face(&router, PeerId::new(1, [1; 16]), whatami::CLIENT).await;
+        sub_face
+            .decl_subscriber(&ResKey::RName("/test/admin".to_string()), &sub_info, None)
+            .await;
+        let (admin_face, admin_primitives) =
+            open_recording_router_face(&router, PeerId::new(1, [2; 16]), whatami::CLIENT).await;
+        let (other_face, other_primitives) =
+            open_recording_router_face(&router, PeerId::new(1, [3; 16]), whatami::CLIENT).await;
+        let query = [&admin_root(&pid), "/subscriptions/**"].concat();
+        macro_rules! query {
+            ($face:expr, $key:expr, $qid:expr) => {
+                $face
+                    .send_query(
+                        &ResKey::RName($key.to_string()),
+                        "",
+                        $qid,
+                        QueryTarget::default(),
+                        QueryConsolidation::default(),
+                        None,
+                    )
+                    .await
+            };
+        }
+
+        // Routed as any other query while disabled
+        query!(admin_face, &query, 1);
+        assert!(admin_primitives.replies().is_empty());
+
+        router
+            .tables
+            .write()
+            .await
+            .set_admin_access(AdminAccess::Allowed(vec![PeerId::new(1, [2; 16])]));
+        query!(admin_face, &query, 2);
+        let replies = admin_primitives.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            replies[0].0,
+            [&admin_root(&pid), "/subscriptions/test/admin"].concat()
+        );
+        assert_eq!(
+            replies[0].1.as_ref().unwrap().encoding,
+            Some(encoding::APP_JSON)
+        );
+        let records: serde_json::Value = serde_json::from_slice(&replies[0].2.to_vec()).unwrap();
+        assert_eq!(records[0]["key_expr"], "/test/admin");
+        assert_eq!(records[0]["kind"], whatami::CLIENT);
+        assert_eq!(
+            records[0]["origin"],
+            hex::encode_upper(PeerId::new(1, [1; 16]).as_slice())
+        );
+        assert!(matches!(
+            admin_primitives.records().last(),
+            Some(Recorded::ReplyFinal(2))
+        ));
+
+        // The faces that are not allowed get no reply
+        query!(other_face, &query, 3);
+        assert!(other_primitives.replies().is_empty());
+        assert!(matches!(
+            other_primitives.records().last(),
+            Some(Recorded::ReplyFinal(3))
+        ));
+
+        // The user keys are not served from the tables
+        query!(admin_face, "/test/**", 4);
+        assert_eq!(admin_primitives.replies().len(), 1);
+    });
+}
@@ -0,0 +1,1524 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+mod common;
+
+use async_std::future::timeout;
+use async_std::sync::{Arc, Weak};
+use async_std::task;
+use common::*;
+use futures::prelude::*;
+use std::time::Duration;
+use uhlc::HLC;
+use zenoh::net::protocol::core::{
+    whatami, CongestionControl, PeerId, Period, Priority, Reliability, SubInfo, SubMode,
+};
+use zenoh::net::protocol::io::RBuf;
+use zenoh::net::protocol::proto::data_kind;
+use zenoh::net::protocol::session::{DummyPrimitives, ForcedUndeclare};
+use zenoh::net::routing::face::FaceState;
+use zenoh::net::routing::router::*;
+use zenoh::net::routing::OutSession;
+
+#[test]
+fn duplicate_sub_context_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_pull();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/dup",
+            &sub_info,
+        )
+        .await;
+        route_data(
+            &tables,
+            &face1.upgrade().unwrap(),
+            0,
+            "/test/dup",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+        assert_eq!(tables.metrics().duplicate_sub_contexts(), 0);
+
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/dup",
+            &sub_info,
+        )
+        .await;
+        // A redeclaration is neither counted nor tracked twice
+        assert_eq!(tables.metrics().duplicate_sub_contexts(), 0);
+        assert_eq!(
+            tables
+                .face_subscriptions(face0.upgrade().unwrap().id())
+                .len(),
+            1
+        );
+
+        // The value cached before the second declaration is still there.
+        pull_data(
+            &mut tables,
+            &face0.upgrade().unwrap(),
+            true,
+            0,
+            "/test/dup",
+            0,
+            &None,
+        )
+        .await;
+        assert_eq!(primitives0.get_last_name(), Some("/test/dup".to_string()));
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn sub_link_classes_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let router = new_router_tables(local.clone()).await;
+        let mut tables = router.tables.write().await;
+        tables.set_sub_link_classes(Some(vec!["backbone".to_string()].into_iter().collect()));
+
+        let mut neighbours = vec![];
+        for (i, class) in ["backbone", "management"].iter().enumerate() {
+            let pid = PeerId::new(1, [i as u8 + 1; 16]);
+            let primitives = Arc::new(ClientPrimitives::new());
+            let face = tables
+                .open_face(
+                    pid.clone(),
+                    whatami::ROUTER,
+                    OutSession::Primitives(primitives.clone()),
+                )
+                .await;
+            tables.set_face_link_class(&face, Some(class.to_string()));
+            tables
+                .apply_topology_change(whatami::ROUTER, &[(local.clone(), &[pid][..])], &[])
+                .await;
+            neighbours.push(primitives);
+        }
+
+        let face = tables
+            .open_face(
+                PeerId::new(1, [9; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut face.upgrade().unwrap(),
+            "/test/backbone",
+            &reliable_push(),
+        )
+        .await;
+
+        assert_eq!(neighbours[0].get_subs(), vec!["/test/backbone"]);
+        assert!(neighbours[1].get_subs().is_empty());
+    });
+}
+
+#[test]
+fn subscription_lease_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/leased",
+            &sub_info,
+            &SubOptions {
+                lease: Some(Duration::from_millis(50)),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        declare_sub(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            "/test/kept",
+            &sub_info,
+        )
+        .await;
+        assert!(tables.expire_subscriptions().await.is_some());
+        assert!(primitives0.get_forced_forgotten().is_empty());
+
+        task::sleep(Duration::from_millis(100)).await;
+        assert!(tables.expire_subscriptions().await.is_none());
+        assert_eq!(
+            primitives0.get_forced_forgotten(),
+            vec![("/test/leased".to_string(), ForcedUndeclare::LeaseExpired)]
+        );
+        assert!(primitives0.get_forgotten().is_empty());
+
+        macro_rules! publish {
+            ($suffix:expr) => {
+                route_data(
+                    &tables,
+                    &face1.upgrade().unwrap(),
+                    0,
+                    $suffix,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0]),
+                    None,
+                )
+                .await;
+            };
+        }
+        publish!("/test/leased");
+        assert!(primitives0.get_payloads().is_empty());
+        publish!("/test/kept");
+        assert_eq!(primitives0.get_payloads().len(), 1);
+
+        // A revoked subscription is notified as well.
+        tables
+            .revoke_subscription(&face0, "/test/kept")
+            .await
+            .unwrap();
+        assert_eq!(
+            primitives0.get_forced_forgotten()[1],
+            ("/test/kept".to_string(), ForcedUndeclare::Revoked)
+        );
+        publish!("/test/kept");
+        assert_eq!(primitives0.get_payloads().len(), 1);
+        assert!(tables
+            .revoke_subscription(&face0, "/test/kept")
+            .await
+            .is_err());
+    });
+}
+
+#[test]
+fn subscription_ready_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let (mut face0, primitives0) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (face1, _) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+
+        // Ready at once when the routes are built on declaration
+        let ready = declare_sub(&mut tables, &mut face0, "/test/now", &sub_info)
+            .await
+            .ready;
+        assert!(ready.is_ready());
+
+        let ready = {
+            let mut guard = tables.defer_rebuilds();
+            let ready = declare_sub(&mut guard, &mut face0, "/test/later", &sub_info)
+                .await
+                .ready;
+            assert!(!ready.is_ready());
+            ready
+        };
+        timeout(Duration::from_secs(1), ready).await.unwrap();
+        route_data(
+            &tables,
+            &face1,
+            0,
+            "/test/later",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![0u8]),
+            None,
+        )
+        .await;
+        let data = primitives0.data();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].0, "/test/later");
+    });
+}
+
+#[test]
+fn face_subscriptions_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let (mut face0, _) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        let (face1, _) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::CLIENT).await;
+        let subs = vec![
+            ("/test/push".to_string(), reliable_push()),
+            (
+                "/test/pull/**".to_string(),
+                sub_info_of(Reliability::BestEffort, SubMode::Pull),
+            ),
+            ("/test/forgotten".to_string(), reliable_push()),
+        ];
+        for (key, sub_info) in &subs {
+            declare_sub(&mut tables, &mut face0, key, sub_info).await;
+        }
+        forget_client_subscription(&mut tables, &mut face0, 0, "/test/forgotten").await;
+
+        assert_eq!(tables.face_subscriptions(face0.id()), subs[..2].to_vec());
+        assert!(tables.face_subscriptions(face1.id()).is_empty());
+        assert!(tables.face_subscriptions(usize::MAX).is_empty());
+    });
+}
+
+#[test]
+fn subscription_digest_test() {
+    task::block_on(async {
+        let sub_info = reliable_push();
+        let pull_info = sub_info_of(Reliability::BestEffort, SubMode::Pull);
+        let mut tables = vec![];
+        let mut faces = vec![];
+        for keys in &[
+            vec!["/test/a", "/test/b", "/test/c"],
+            vec!["/test/c", "/test/a", "/test/b", "/test/d"],
+        ] {
+            let mut router = client_tables();
+            let mut face = router
+                .open_face(
+                    PeerId::new(1, [1; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(DummyPrimitives::new())),
+                )
+                .await
+                .upgrade()
+                .unwrap();
+            for key in keys {
+                let info = if *key == "/test/b" {
+                    &pull_info
+                } else {
+                    &sub_info
+                };
+                declare_sub(&mut router, &mut face, key, info).await;
+            }
+            tables.push(router);
+            faces.push(face);
+        }
+        let (digest0, digest1) = (
+            tables[0].subscription_digest(),
+            tables[1].subscription_digest(),
+        );
+        assert_ne!(digest0, digest1);
+        assert_eq!(digest0.count, 3);
+        assert_eq!(digest1.count, 4);
+
+        let snapshot0 = tables[0].export_subscriptions();
+        let snapshot1 = tables[1].export_subscriptions();
+        let diff = snapshot0.diff(&snapshot1);
+        assert!(diff.only_local.is_empty());
+        assert_eq!(
+            diff.only_other,
+            vec![SubscriptionRecord {
+                key_expr: "/test/d".to_string(),
+                kind: whatami::CLIENT,
+                origin: PeerId::new(1, [1; 16]),
+                sub_info: sub_info.clone(),
+            }]
+        );
+        assert_eq!(snapshot1.diff(&snapshot0).only_local, diff.only_other);
+
+        // The same subscriptions, declared in another order, have the same
+        // digest
+        declare_sub(&mut tables[0], &mut faces[0], "/test/d", &sub_info).await;
+        assert_eq!(tables[0].subscription_digest(), digest1);
+        assert!(tables[0].export_subscriptions().diff(&snapshot1).is_empty());
+        let mut reversed = snapshot1.clone();
+        reversed.subscriptions.reverse();
+        assert_eq!(reversed.digest(), digest1);
+    });
+}
+
+#[test]
+fn declare_outcome_test() {
+    task::block_on(async {
+        let mut tables = router_tables();
+        let sub_info = reliable_push();
+        let (mut client_face, _) =
+            open_recording_face(&mut tables, PeerId::new(1, [0; 16]), whatami::CLIENT).await;
+        declare_resource(&mut tables, &mut client_face, 1, 0, "/test").await;
+
+        let outcome =
+            declare_client_subscription(&mut tables, &mut client_face, 1, "/outcome", &sub_info)
+                .await
+                .unwrap();
+        assert_eq!(outcome.resname, "/test/outcome");
+        assert!(outcome.created);
+        let outcome = declare_sub(&mut tables, &mut client_face, "/test/outcome", &sub_info).await;
+        assert_eq!(outcome.resname, "/test/outcome");
+        assert!(!outcome.created);
+
+        let (mut router_face, _) =
+            open_recording_face(&mut tables, PeerId::new(2, [0; 16]), whatami::ROUTER).await;
+        for created in &[true, false] {
+            let outcome = declare_router_subscription(
+                &mut tables,
+                &mut router_face,
+                0,
+                "/test/outcome",
+                &sub_info,
+                PeerId::new(2, [0; 16]),
+            )
+            .await
+            .unwrap();
+            assert_eq!(outcome.resname, "/test/outcome");
+            assert_eq!(outcome.created, *created);
+        }
+
+        assert_eq!(
+            declare_client_subscription(&mut tables, &mut client_face, 42, "/outcome", &sub_info)
+                .await
+                .err(),
+            Some(RoutingError::UnknownPrefix(42))
+        );
+    });
+}
+
+#[test]
+fn subscription_events_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let events1 = tables.subscription_events();
+        let events2 = tables.subscription_events();
+        futures::pin_mut!(events1);
+        futures::pin_mut!(events2);
+
+        let face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let face_id = face.upgrade().unwrap().id();
+        declare_sub(
+            &mut tables,
+            &mut face.upgrade().unwrap(),
+            "/test/events",
+            &sub_info,
+        )
+        .await;
+
+        let expected = SubEvent {
+            resource: "/test/events".to_string(),
+            face_id,
+            kind: whatami::CLIENT,
+            change: SubChange::Declared,
+        };
+        assert_eq!(events1.next().await, Some(expected.clone()));
+        assert_eq!(events2.next().await, Some(expected));
+        assert_eq!(tables.metrics().sub_events_dropped(), 0);
+    });
+}
+
+#[test]
+fn delete_on_clean_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_retain_latest(true);
+        tables.set_delete_on_clean(true);
+        let sub_info = reliable_push();
+
+        let (sub_face, sub_primitives) =
+            open_client_face(&mut tables, PeerId::new(0, [0; 16])).await;
+        declare_sub(
+            &mut tables,
+            &mut sub_face.upgrade().unwrap(),
+            "/test/*",
+            &sub_info,
+        )
+        .await;
+
+        let pub_face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_resource(
+            &mut tables,
+            &mut pub_face.upgrade().unwrap(),
+            1,
+            0,
+            "/test/retained",
+        )
+        .await;
+        route_data(
+            &tables,
+            &pub_face.upgrade().unwrap(),
+            1,
+            "",
+            CongestionControl::Block,
+            None,
+            RBuf::from(vec![1]),
+            None,
+        )
+        .await;
+        assert!(tables.get_retained("/test/retained").await.is_some());
+        assert_eq!(sub_primitives.get_payloads().len(), 1);
+
+        tables.close_face(&pub_face).await;
+
+        assert!(Resource::get_resource(&tables._get_root(), "/test/retained").is_none());
+        assert!(tables.get_retained("/test/retained").await.is_none());
+        let payloads = sub_primitives.get_payloads();
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads[1].is_empty());
+        assert_eq!(sub_primitives.get_last_name().unwrap(), "/test/retained");
+        assert_eq!(
+            sub_primitives.get_last_info().unwrap().kind,
+            Some(data_kind::DELETE)
+        );
+    });
+}
+
+#[test]
+fn migrate_subscriptions_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let (old_face, old_primitives) =
+            open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for key in &["/test/migrate/a", "/test/migrate/**"] {
+            declare_sub(
+                &mut tables,
+                &mut old_face.upgrade().unwrap(),
+                key,
+                &sub_info,
+            )
+            .await;
+        }
+        declare_sub(
+            &mut tables,
+            &mut pub_face.upgrade().unwrap(),
+            "/test/other",
+            &sub_info_of(Reliability::BestEffort, SubMode::Push),
+        )
+        .await;
+
+        macro_rules! publish {
+            ($value:expr) => {
+                route_data(
+                    &tables,
+                    &pub_face.upgrade().unwrap(),
+                    0,
+                    "/test/migrate/a",
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![$value]),
+                    None,
+                )
+                .await;
+            };
+        }
+
+        publish!(1);
+        let (new_face, new_primitives) =
+            open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        tables.migrate_subscriptions(&old_face, &new_face).await;
+        publish!(2);
+        tables.close_face(&old_face).await;
+        publish!(3);
+
+        assert_eq!(old_primitives.get_payloads(), vec![RBuf::from(vec![1])]);
+        assert_eq!(
+            new_primitives.get_payloads(),
+            vec![RBuf::from(vec![2]), RBuf::from(vec![3])]
+        );
+        assert!(tables.has_subscriber("/test/migrate/b"));
+        assert_eq!(tables.metrics().duplicate_sub_contexts(), 0);
+
+        // The subscription declared to the old face is moved with its info.
+        assert_eq!(old_primitives.get_forgotten(), vec!["/test/other"]);
+        let new_subs: Vec<(String, Reliability)> = new_primitives
+            .get_subs()
+            .into_iter()
+            .zip(new_primitives.get_sub_reliabilities())
+            .collect();
+        assert_eq!(
+            new_subs,
+            vec![("/test/other".to_string(), Reliability::BestEffort)]
+        );
+    });
+}
+
+#[test]
+fn migrate_subscriptions_conflict_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_mode_conflict(ModeConflict::Reject);
+        let push = reliable_push();
+        let pull = reliable_pull();
+
+        let old_face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let new_face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        for key in &["/test/migrate/a", "/test/migrate/b"] {
+            declare_sub(&mut tables, &mut old_face.upgrade().unwrap(), key, &push).await;
+        }
+        declare_sub(
+            &mut tables,
+            &mut new_face.upgrade().unwrap(),
+            "/test/migrate/a",
+            &pull,
+        )
+        .await;
+
+        // The conflicting subscription stays on the old face.
+        tables.migrate_subscriptions(&old_face, &new_face).await;
+        let old_id = old_face.upgrade().unwrap().id();
+        let new_id = new_face.upgrade().unwrap().id();
+        let old_subs = tables.face_subscriptions(old_id);
+        assert_eq!(old_subs.len(), 1);
+        assert_eq!(old_subs[0].0, "/test/migrate/a");
+        assert_eq!(old_subs[0].1.mode, SubMode::Push);
+        let mut new_subs = tables.face_subscriptions(new_id);
+        new_subs.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        let new_subs: Vec<(String, SubMode)> = new_subs
+            .into_iter()
+            .map(|(name, sub_info)| (name, sub_info.mode))
+            .collect();
+        assert_eq!(
+            new_subs,
+            vec![
+                ("/test/migrate/a".to_string(), SubMode::Pull),
+                ("/test/migrate/b".to_string(), SubMode::Push)
+            ]
+        );
+    });
+}
+
+#[test]
+fn flap_dampening_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_flap_dampening(Some(FlapDampening {
+            max_cycles: 2,
+            window: Duration::from_secs(60),
+            hold_down: Duration::from_millis(200),
+        }));
+        let sub_info = reliable_push();
+
+        let face0 = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let (_face1, primitives1) = open_client_face(&mut tables, PeerId::new(2, [2; 16])).await;
+
+        let mut face0 = face0.upgrade().unwrap();
+        for _ in 0..2 {
+            declare_sub(&mut tables, &mut face0, "/test/flap", &sub_info).await;
+            forget_client_subscription(&mut tables, &mut face0, 0, "/test/flap").await;
+        }
+        assert_eq!(primitives1.get_subs().len(), 2);
+        assert_eq!(primitives1.get_forgotten().len(), 2);
+
+        // The subscription is flapping: its propagation is held down
+        declare_sub(&mut tables, &mut face0, "/test/flap", &sub_info).await;
+        assert_eq!(primitives1.get_subs().len(), 2);
+        assert_eq!(tables.metrics().dampened_sub_propagations(), 1);
+        assert!(tables.has_subscriber("/test/flap"));
+
+        tables.release_dampened_subscriptions().await;
+        assert_eq!(primitives1.get_subs().len(), 2);
+
+        task::sleep(Duration::from_millis(250)).await;
+        tables.release_dampened_subscriptions().await;
+        assert_eq!(primitives1.get_subs().len(), 3);
+        assert_eq!(primitives1.get_forgotten().len(), 2);
+    });
+}
+
+#[test]
+fn max_subs_per_face_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_max_subs_per_face(Some(2), false);
+        let sub_info = reliable_push();
+
+        let (face, primitives) = open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let mut face = face.upgrade().unwrap();
+        macro_rules! declare {
+            ($key:expr) => {
+                declare_client_subscription_with_options(
+                    &mut tables,
+                    &mut face,
+                    0,
+                    $key,
+                    &sub_info,
+                    &SubOptions::default(),
+                )
+                .await
+                .map(|_| ())
+            };
+        }
+        assert_eq!(declare!("/test/a"), Ok(()));
+        assert_eq!(declare!("/test/b"), Ok(()));
+        assert_eq!(declare!("/test/c"), Err(RoutingError::SubscriptionLimit(2)));
+        // Redeclaring an existing subscription is still accepted
+        assert_eq!(declare!("/test/a"), Ok(()));
+        assert!(!primitives.is_closed());
+
+        assert!(tables.has_subscriber("/test/a"));
+        assert!(tables.has_subscriber("/test/b"));
+        assert!(!tables.has_subscriber("/test/c"));
+
+        tables.set_max_subs_per_face(Some(2), true);
+        declare_sub(&mut tables, &mut face, "/test/c", &sub_info).await;
+        assert!(primitives.is_closed());
+        assert!(!tables.has_subscriber("/test/c"));
+        assert!(tables.has_subscriber("/test/a"));
+    });
+}
+
+#[test]
+fn subscription_alias_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        tables.set_max_subs_per_face(Some(2), false);
+        let sub_info = reliable_push();
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(2, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let mut face0 = face0.upgrade().unwrap();
+        let face1 = face1.upgrade().unwrap();
+        macro_rules! publish {
+            ($key:expr) => {
+                route_data(
+                    &tables,
+                    &face1,
+                    0,
+                    $key,
+                    CongestionControl::Block,
+                    None,
+                    RBuf::from(vec![0u8; 4]),
+                    None,
+                )
+                .await
+            };
+        }
+
+        let alias = declare_client_subscription_alias(
+            &mut tables,
+            &mut face0,
+            0,
+            &["/a/**", "/b/**"],
+            &sub_info,
+            &SubOptions::default(),
+        )
+        .await
+        .unwrap();
+        publish!("/a/x");
+        publish!("/b/y");
+        publish!("/c/z");
+        assert_eq!(primitives0.get_payloads().len(), 2);
+
+        // The alias group counts as a single subscription
+        assert_eq!(
+            declare_client_subscription_with_options(
+                &mut tables,
+                &mut face0,
+                0,
+                "/c/**",
+                &sub_info,
+                &SubOptions::default(),
+            )
+            .await
+            .map(|_| ()),
+            Ok(())
+        );
+
+        assert_eq!(
+            forget_client_subscription_alias(&mut tables, &mut face0, alias).await,
+            Ok(())
+        );
+        assert!(!tables.has_subscriber("/a/x"));
+        assert!(!tables.has_subscriber("/b/y"));
+        assert!(tables.has_subscriber("/c/z"));
+        publish!("/a/x");
+        publish!("/b/y");
+        assert_eq!(primitives0.get_payloads().len(), 2);
+        assert_eq!(
+            forget_client_subscription_alias(&mut tables, &mut face0, alias).await,
+            Err(RoutingError::UnknownSubscription(alias.to_string()))
+        );
+
+        // The subscriptions the face declared on its own are kept
+        tables.set_max_subs_per_face(None, false);
+        declare_sub(&mut tables, &mut face0, "/d/**", &sub_info).await;
+        let alias = declare_client_subscription_alias(
+            &mut tables,
+            &mut face0,
+            0,
+            &["/d/**", "/e/**"],
+            &sub_info,
+            &SubOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            forget_client_subscription_alias(&mut tables, &mut face0, alias).await,
+            Ok(())
+        );
+        assert!(tables.has_subscriber("/d/x"));
+        assert!(!tables.has_subscriber("/e/x"));
+    });
+}
+
+#[test]
+fn subscription_heartbeat_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = SubInfo {
+            reliability: Reliability::BestEffort,
+            ..reliable_push()
+        };
+
+        let (face0, primitives0) = open_client_face(&mut tables, PeerId::new(1, [1; 16])).await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(2, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_client_subscription_with_options(
+            &mut tables,
+            &mut face0.upgrade().unwrap(),
+            0,
+            "/test/idle",
+            &sub_info,
+            &SubOptions {
+                heartbeat: Some(Duration::from_millis(100)),
+                ..SubOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let heartbeats = || {
+            primitives0
+                .get_payloads()
+                .iter()
+                .filter(|payload| payload.is_empty())
+                .count()
+        };
+
+        assert!(tables.send_heartbeats().await.is_some());
+        assert_eq!(heartbeats(), 0);
+
+        // Idle: a keep-alive is sent once the interval elapsed
+        task::sleep(Duration::from_millis(150)).await;
+        tables.send_heartbeats().await;
+        assert_eq!(heartbeats(), 1);
+        assert_eq!(
+            primitives0.get_last_info().unwrap().kind,
+            Some(data_kind::KEEP_ALIVE)
+        );
+        // with the reliability of the subscription
+        assert_eq!(
+            primitives0.get_reliabilities().last(),
+            Some(&Reliability::BestEffort)
+        );
+
+        // Paused: no keep-alive
+        let face0_id = face0.upgrade().unwrap().id();
+        tables.pause_face(face0_id, true);
+        task::sleep(Duration::from_millis(150)).await;
+        tables.send_heartbeats().await;
+        tables.resume_face(face0_id).await;
+        assert_eq!(heartbeats(), 1);
+
+        // Data flowing: no keep-alive
+        for _ in 0..5 {
+            task::sleep(Duration::from_millis(40)).await;
+            route_data(
+                &tables,
+                &face1.upgrade().unwrap(),
+                0,
+                "/test/idle",
+                CongestionControl::Block,
+                None,
+                RBuf::from(vec![0u8; 4]),
+                None,
+            )
+            .await;
+            tables.send_heartbeats().await;
+        }
+        assert_eq!(heartbeats(), 1);
+        assert_eq!(primitives0.get_payloads().len(), 6);
+
+        forget_client_subscription(&mut tables, &mut face0.upgrade().unwrap(), 0, "/test/idle")
+            .await;
+        assert!(tables.send_heartbeats().await.is_none());
+    });
+}
+
+#[test]
+fn mode_conflict_test() {
+    // Declare a push then a pull subscription on a key, and a pull then a
+    // push one on another, returning the outcomes of the redeclarations and
+    // the resulting modes.
+    async fn redeclare(
+        policy: Option<ModeConflict>,
+    ) -> (
+        Result<(), RoutingError>,
+        SubMode,
+        Result<(), RoutingError>,
+        SubMode,
+    ) {
+        let mut tables = client_tables();
+        if let Some(policy) = policy {
+            tables.set_mode_conflict(policy);
+        }
+        let mut face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await
+            .upgrade()
+            .unwrap();
+        let mut outcomes = vec![];
+        for (key, first, second) in &[
+            ("/test/push", SubMode::Push, SubMode::Pull),
+            ("/test/pull", SubMode::Pull, SubMode::Push),
+        ] {
+            for mode in &[*first, *second] {
+                outcomes.push(
+                    declare_client_subscription_with_options(
+                        &mut tables,
+                        &mut face,
+                        0,
+                        key,
+                        &sub_info_of(Reliability::Reliable, *mode),
+                        &SubOptions::default(),
+                    )
+                    .await
+                    .map(|_| ()),
+                );
+            }
+        }
+        let mode = |key| tables.get_subscription_info(face.id(), key).unwrap().mode;
+        (
+            outcomes[1].clone(),
+            mode("/test/push"),
+            outcomes[3].clone(),
+            mode("/test/pull"),
+        )
+    }
+
+    task::block_on(async {
+        assert_eq!(
+            redeclare(None).await,
+            redeclare(Some(ModeConflict::StrongestWins)).await
+        );
+        assert_eq!(
+            redeclare(Some(ModeConflict::StrongestWins)).await,
+            (Ok(()), SubMode::Push, Ok(()), SubMode::Push)
+        );
+        assert_eq!(
+            redeclare(Some(ModeConflict::LastWins)).await,
+            (Ok(()), SubMode::Pull, Ok(()), SubMode::Push)
+        );
+        assert_eq!(
+            redeclare(Some(ModeConflict::Reject)).await,
+            (
+                Err(RoutingError::ModeConflict("/test/push".to_string())),
+                SubMode::Push,
+                Err(RoutingError::ModeConflict("/test/pull".to_string())),
+                SubMode::Pull
+            )
+        );
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn batched_subscriptions_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let router = new_router_tables(local.clone()).await;
+        let mut tables = router.tables.write().await;
+        let sub_info = reliable_push();
+
+        let client_face = tables
+            .open_face(
+                PeerId::new(1, [9; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let keys: Vec<String> = (0..20).map(|i| format!("/test/batch/{}", i)).collect();
+        for key in &keys {
+            declare_sub(
+                &mut tables,
+                &mut client_face.upgrade().unwrap(),
+                key,
+                &sub_info,
+            )
+            .await;
+        }
+
+        // A new router child receives all the subscriptions in a single frame
+        let pid = PeerId::new(1, [1; 16]);
+        let router_primitives = Arc::new(ClientPrimitives::new());
+        tables
+            .open_face(
+                pid.clone(),
+                whatami::ROUTER,
+                OutSession::Primitives(router_primitives.clone()),
+            )
+            .await;
+        tables
+            .apply_topology_change(whatami::ROUTER, &[(local, &[pid][..])], &[])
+            .await;
+        let mut subs = router_primitives.get_subs();
+        subs.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(subs, expected);
+        assert_eq!(router_primitives.get_sub_frames(), 1);
+
+        // So does a new client face
+        let new_client_primitives = Arc::new(ClientPrimitives::new());
+        tables
+            .open_face(
+                PeerId::new(1, [8; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(new_client_primitives.clone()),
+            )
+            .await;
+        assert_eq!(new_client_primitives.get_subs().len(), keys.len());
+        assert_eq!(new_client_primitives.get_sub_frames(), 1);
+
+        // A single subscription is still declared on its own
+        declare_sub(
+            &mut tables,
+            &mut client_face.upgrade().unwrap(),
+            "/test/batch/single",
+            &sub_info,
+        )
+        .await;
+        assert_eq!(router_primitives.get_subs().len(), keys.len() + 1);
+        assert_eq!(router_primitives.get_sub_frames(), 2);
+    });
+}
+
+#[test]
+fn effective_sub_profile_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        for (i, (key, reliability, mode)) in [
+            ("/test/profile/a", Reliability::BestEffort, SubMode::Push),
+            ("/test/profile/*", Reliability::BestEffort, SubMode::Pull),
+            ("/test/other", Reliability::Reliable, SubMode::Push),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let face = tables
+                .open_face(
+                    PeerId::new(1, [i as u8; 16]),
+                    whatami::CLIENT,
+                    OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                )
+                .await;
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                key,
+                &sub_info_of(*reliability, *mode),
+            )
+            .await;
+        }
+
+        assert_eq!(
+            tables.effective_sub_profile("/test/profile/a"),
+            SubProfile {
+                reliability: Some(Reliability::BestEffort),
+                has_pull: true,
+            }
+        );
+        assert_eq!(
+            tables.effective_sub_profile("/test/other"),
+            SubProfile {
+                reliability: Some(Reliability::Reliable),
+                has_pull: false,
+            }
+        );
+        assert_eq!(
+            tables.effective_sub_profile("/test/**"),
+            SubProfile {
+                reliability: Some(Reliability::Reliable),
+                has_pull: true,
+            }
+        );
+        assert_eq!(
+            tables.effective_sub_profile("/nothing"),
+            SubProfile::default()
+        );
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn subscription_snapshot_test() {
+    task::block_on(async {
+        let local = PeerId::new(1, [0; 16]);
+        let a = PeerId::new(1, [1; 16]);
+        let clients: Vec<PeerId> = (0..20u8).map(|i| PeerId::new(2, [i; 16])).collect();
+
+        async fn open_faces(
+            tables: &mut Tables,
+            a: &PeerId,
+            clients: &[PeerId],
+        ) -> Vec<Weak<FaceState>> {
+            let mut faces = vec![
+                tables
+                    .open_face(
+                        a.clone(),
+                        whatami::ROUTER,
+                        OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                    )
+                    .await,
+            ];
+            for client in clients {
+                faces.push(
+                    tables
+                        .open_face(
+                            client.clone(),
+                            whatami::CLIENT,
+                            OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+                        )
+                        .await,
+                );
+            }
+            faces
+        }
+
+        let mut tables = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[a.clone()][..])],
+        )
+        .await;
+        let faces = open_faces(&mut tables, &a, &clients).await;
+        declare_router_subscription(
+            &mut tables,
+            &mut faces[0].upgrade().unwrap(),
+            0,
+            "/test/router/**",
+            &reliable_push(),
+            a.clone(),
+        )
+        .await
+        .unwrap();
+        for (i, face) in faces[1..].iter().enumerate() {
+            declare_sub(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                &format!("/test/client/{}", i),
+                &sub_info_of(
+                    if i % 2 == 0 {
+                        Reliability::Reliable
+                    } else {
+                        Reliability::BestEffort
+                    },
+                    if i % 3 == 0 {
+                        SubMode::Pull
+                    } else {
+                        SubMode::Push
+                    },
+                ),
+            )
+            .await;
+        }
+
+        let snapshot = tables.export_subscriptions();
+        assert!(snapshot.subscriptions.len() > clients.len());
+
+        let bytes = snapshot.to_bytes();
+        let json = snapshot.to_json();
+        assert_eq!(SubscriptionSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+        assert_eq!(SubscriptionSnapshot::from_json(&json).unwrap(), snapshot);
+        assert!(bytes.len() < json.to_string().len());
+        assert!(SubscriptionSnapshot::from_bytes(&bytes[1..]).is_err());
+
+        let mut imported = Tables::with_router_topology(
+            local.clone(),
+            Some(HLC::default()),
+            &[(local.clone(), &[a.clone()][..])],
+        )
+        .await;
+        open_faces(&mut imported, &a, &clients).await;
+        imported
+            .import_subscriptions(&SubscriptionSnapshot::from_bytes(&bytes).unwrap())
+            .await;
+        assert_eq!(imported.export_subscriptions(), snapshot);
+    });
+}
+
+#[test]
+fn get_subscription_info_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let face = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let face_id = face.upgrade().unwrap().id();
+        let sub_info = SubInfo {
+            period: Some(Period {
+                origin: 1,
+                period: 100,
+                duration: 10,
+            }),
+            ..sub_info_of(Reliability::BestEffort, SubMode::Pull)
+        };
+        declare_sub(
+            &mut tables,
+            &mut face.upgrade().unwrap(),
+            "/test/info/*",
+            &sub_info,
+        )
+        .await;
+
+        assert_eq!(
+            tables.get_subscription_info(face_id, "/test/info/*"),
+            Some(sub_info)
+        );
+        // Only the exact declared key is recorded.
+        assert_eq!(tables.get_subscription_info(face_id, "/test/info/a"), None);
+        assert_eq!(tables.get_subscription_info(face_id, "/test"), None);
+        assert_eq!(
+            tables.get_subscription_info(face_id + 1, "/test/info/*"),
+            None
+        );
+    });
+}
+
+#[test]
+fn shadow_subscription_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+
+        let mut primitives = vec![];
+        for (i, shadow) in [false, true].iter().enumerate() {
+            let (face, prims) = open_client_face(&mut tables, PeerId::new(1, [i as u8; 16])).await;
+            declare_client_subscription_with_options(
+                &mut tables,
+                &mut face.upgrade().unwrap(),
+                0,
+                "/test/shadow/**",
+                &sub_info,
+                &SubOptions {
+                    shadow: *shadow,
+                    ..SubOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            primitives.push(prims);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(2, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        let count = 3;
+        for _ in 0..count {
+            route_data(
+                &tables,
+                &pub_face.upgrade().unwrap(),
+                0,
+                "/test/shadow/a",
+                CongestionControl::Block,
+                None,
+                RBuf::new(),
+                None,
+            )
+            .await;
+        }
+        assert_eq!(
+            primitives[0].get_last_name(),
+            Some("/test/shadow/a".to_string())
+        );
+        assert!(primitives[1].get_last_name().is_none());
+        assert_eq!(tables.metrics().shadow_deliveries(), count);
+    });
+}
+
+#[test]
+fn lagging_subscription_events_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        // An observer that never consumes its events.
+        let capacity = 4;
+        let events = tables.subscription_events_with_capacity(capacity);
+        futures::pin_mut!(events);
+
+        let face = tables
+            .open_face(
+                PeerId::new(0, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let count = 10;
+        for i in 0..count {
+            timeout(
+                Duration::from_secs(1),
+                declare_client_subscription(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    0,
+                    &format!("/test/lag/{}", i),
+                    &sub_info,
+                ),
+            )
+            .await
+            .expect("Declaration blocked by a lagging observer")
+            .unwrap();
+        }
+        for i in 0..count {
+            assert!(tables.has_subscriber(&format!("/test/lag/{}", i)));
+        }
+        assert_eq!(tables.metrics().sub_events_dropped(), count - capacity);
+
+        // The latest events are still delivered, the oldest ones being
+        // dropped, and the following ones again once consumed.
+        for i in count - capacity..count {
+            assert_eq!(
+                events.next().await.unwrap().resource,
+                format!("/test/lag/{}", i)
+            );
+        }
+        declare_sub(
+            &mut tables,
+            &mut face.upgrade().unwrap(),
+            "/test/lag/last",
+            &sub_info,
+        )
+        .await;
+        assert_eq!(events.next().await.unwrap().resource, "/test/lag/last");
+        assert_eq!(tables.metrics().sub_events_dropped(), count - capacity);
+    });
+}
+
+#[test]
+fn matching_key_events_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let events = tables.matching_key_events();
+        futures::pin_mut!(events);
+
+        let sub_face = tables
+            .open_face(
+                PeerId::new(1, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        declare_sub(
+            &mut tables,
+            &mut sub_face.upgrade().unwrap(),
+            "/test/discovery/*",
+            &reliable_push(),
+        )
+        .await;
+        let face1 = tables
+            .open_face(
+                PeerId::new(1, [1; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        let face2 = tables
+            .open_face(
+                PeerId::new(1, [2; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+
+        declare_resource(
+            &mut tables,
+            &mut face1.upgrade().unwrap(),
+            1,
+            0,
+            "/test/discovery/a",
+        )
+        .await;
+        assert_eq!(
+            events.next().await,
+            Some(MatchingKeyEvent {
+                key: "/test/discovery/a".to_string(),
+                subscription: "/test/discovery/*".to_string(),
+                face_id: sub_face.upgrade().unwrap().id(),
+            })
+        );
+
+        // Neither an already registered key, a wildcard expression nor a
+        // key matching no subscription is notified.
+        declare_resource(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            1,
+            0,
+            "/test/discovery/a",
+        )
+        .await;
+        declare_resource(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            2,
+            0,
+            "/test/discovery/b*",
+        )
+        .await;
+        declare_resource(
+            &mut tables,
+            &mut face2.upgrade().unwrap(),
+            3,
+            0,
+            "/test/other",
+        )
+        .await;
+        assert!(events.next().now_or_never().is_none());
+    });
+}
+
+#[test]
+fn subscription_priority_test() {
+    task::block_on(async {
+        let mut tables = client_tables();
+        let sub_info = reliable_push();
+        let control = SubOptions {
+            priority: Priority::Control,
+            ..SubOptions::default()
+        };
+
+        // a face with a control subscription, a face with a default one, and
+        // a face with both
+        let mut faces = vec![];
+        for (i, options) in [
+            vec![("/test/prio", &control)],
+            vec![("/test/prio", &SubOptions::default())],
+            vec![
+                ("/test/prio", &SubOptions::default()),
+                ("/test/*", &control),
+            ],
+        ]
+        .iter()
+        .enumerate()
+        {
+            let (face, primitives) = open_client_face(&mut tables, PeerId::new(i, [0; 16])).await;
+            for (expr, options) in options {
+                declare_client_subscription_with_options(
+                    &mut tables,
+                    &mut face.upgrade().unwrap(),
+                    0,
+                    expr,
+                    &sub_info,
+                    options,
+                )
+                .await
+                .unwrap();
+            }
+            faces.push(primitives);
+        }
+        let pub_face = tables
+            .open_face(
+                PeerId::new(3, [0; 16]),
+                whatami::CLIENT,
+                OutSession::Primitives(Arc::new(ClientPrimitives::new())),
+            )
+            .await;
+        route_data(
+            &tables,
+            &pub_face.upgrade().unwrap(),
+            0,
+            "/test/prio",
+            CongestionControl::Block,
+            None,
+            RBuf::new(),
+            None,
+        )
+        .await;
+
+        assert_eq!(faces[0].get_priorities(), vec![Priority::Control]);
+        assert_eq!(faces[1].get_priorities(), vec![Priority::Data]);
+        assert_eq!(faces[2].get_priorities(), vec![Priority::Control]);
+    });
+}